@@ -0,0 +1,51 @@
+//! Compares `Utf16Decoder` with `char::decode_utf16`, run with
+//! `cargo fuzz run utf16` from the crate directory.
+#![no_main]
+
+// keydisp is a binary, so the module is compiled in on its own.
+#[path = "../../src/common/utf16.rs"]
+mod utf16;
+
+use libfuzzer_sys::fuzz_target;
+use std::time::{Duration, Instant};
+use utf16::{UnpairedSurrogate, Utf16Decoder, SURROGATE_TIMEOUT};
+
+fuzz_target!(|data: &[u8]| {
+    // Each unit is followed by how long to wait before the next, in
+    // milliseconds, so some high surrogates time out.
+    let mut now = Instant::now();
+    let mut decoder = Utf16Decoder::new(SURROGATE_TIMEOUT);
+    let mut decoded = Vec::new();
+    let mut expected = Vec::new();
+    let mut held = Vec::new();
+
+    for chunk in data.chunks_exact(3) {
+        let unit = u16::from_le_bytes([chunk[0], chunk[1]]);
+        let gap = Duration::from_millis(u64::from(chunk[2]) * 4);
+
+        decoded.extend(decoder.push(unit, now));
+        held.push(unit);
+
+        // A high surrogate left waiting too long ends what std sees as one run.
+        if gap >= SURROGATE_TIMEOUT {
+            expected.extend(std_decode(&held));
+            held.clear();
+        }
+
+        now += gap;
+    }
+
+    expected.extend(std_decode(&held));
+
+    if let Some(deadline) = decoder.deadline() {
+        decoded.extend(decoder.flush_expired(deadline));
+    }
+
+    assert_eq!(decoded, expected);
+});
+
+fn std_decode(units: &[u16]) -> Vec<Result<char, UnpairedSurrogate>> {
+    char::decode_utf16(units.iter().cloned())
+        .map(|decoded| decoded.map_err(|e| UnpairedSurrogate(e.unpaired_surrogate())))
+        .collect()
+}
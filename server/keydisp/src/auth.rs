@@ -0,0 +1,181 @@
+//! Access checks for incoming websocket handshakes.
+//!
+//! A client authenticates with one of the configured tokens, either as a
+//! `token` query parameter (`ws://host:2945/?token=secret`) or as a
+//! `keydisp-token.secret` subprotocol, which browsers can send without
+//! putting the secret in the URL.
+
+use crate::config::AuthConfig;
+use crate::server::{percent_decode, Request};
+
+use std::net::{SocketAddr, ToSocketAddrs};
+
+const TOKEN_PROTOCOL_PREFIX: &str = "keydisp-token.";
+
+/// Checks a handshake against the auth settings. On success returns the token
+/// subprotocol to echo back, if the client authenticated with one, on failure
+/// the HTTP status to answer with.
+pub fn check(auth: &AuthConfig, request: &Request) -> Result<Option<String>, u16> {
+    if !origin_allowed(auth, request) {
+        return Err(403);
+    }
+
+    if auth.tokens.is_empty() {
+        return Ok(None);
+    }
+
+    if let Some(token) = request.query("token").map(percent_decode) {
+        if token_matches(auth, &token) {
+            return Ok(None);
+        }
+    }
+
+    for protocol in request.subprotocols() {
+        if protocol.starts_with(TOKEN_PROTOCOL_PREFIX)
+            && token_matches(auth, &protocol[TOKEN_PROTOCOL_PREFIX.len()..])
+        {
+            return Ok(Some(protocol.to_string()));
+        }
+    }
+
+    Err(401)
+}
+
+/// Whether it is safe to listen on `bind` with these settings. Anything that
+/// isn't loopback would expose every keystroke, so it requires tokens.
+pub fn bind_allowed(auth: &AuthConfig, bind: &str) -> bool {
+    if !auth.tokens.is_empty() {
+        return true;
+    }
+
+    match bind.to_socket_addrs() {
+        Ok(mut addrs) => addrs.all(|addr: SocketAddr| addr.ip().is_loopback()),
+        Err(_) => false,
+    }
+}
+
+fn origin_allowed(auth: &AuthConfig, request: &Request) -> bool {
+    if auth.allowed_origins.is_empty() {
+        return true;
+    }
+
+    match request.header("Origin") {
+        Some(origin) => auth
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes() == origin),
+        // Non-browser clients don't send an origin, they are covered by tokens.
+        None => true,
+    }
+}
+
+fn token_matches(auth: &AuthConfig, candidate: &str) -> bool {
+    auth.tokens
+        .iter()
+        .fold(false, |found, token| constant_time_eq(token.as_bytes(), candidate.as_bytes()) | found)
+}
+
+/// Compares without short-circuiting, so response timing doesn't leak how much
+/// of a guessed token was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str, headers: &[&str]) -> Request {
+        let mut raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n", path);
+
+        for header in headers {
+            raw.push_str(header);
+            raw.push_str("\r\n");
+        }
+
+        raw.push_str("\r\n");
+        Request::parse(raw.as_bytes()).unwrap().0
+    }
+
+    fn auth(tokens: &[&str], allowed_origins: &[&str]) -> AuthConfig {
+        AuthConfig {
+            tokens: tokens.iter().map(|token| token.to_string()).collect(),
+            allowed_origins: allowed_origins
+                .iter()
+                .map(|origin| origin.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn everyone_is_let_in_without_tokens() {
+        assert_eq!(check(&auth(&[], &[]), &request("/", &[])), Ok(None));
+    }
+
+    #[test]
+    fn query_tokens_are_decoded() {
+        let auth = auth(&["a+b %c&d=é"], &[]);
+        let encoded = "/?device=x&token=a%2Bb+%25c%26d%3D%C3%A9";
+
+        assert_eq!(check(&auth, &request(encoded, &[])), Ok(None));
+        assert_eq!(check(&auth, &request("/?token=a+b", &[])), Err(401));
+    }
+
+    #[test]
+    fn wrong_or_missing_tokens_are_refused() {
+        let auth = auth(&["secret", "other"], &[]);
+
+        assert_eq!(check(&auth, &request("/?token=other", &[])), Ok(None));
+        assert_eq!(check(&auth, &request("/?token=secre", &[])), Err(401));
+        assert_eq!(check(&auth, &request("/?token=", &[])), Err(401));
+        assert_eq!(check(&auth, &request("/", &[])), Err(401));
+    }
+
+    #[test]
+    fn subprotocol_tokens_are_echoed() {
+        let auth = auth(&["secret"], &[]);
+        let protocols = "Sec-WebSocket-Protocol: keydisp.json, keydisp-token.secret";
+
+        assert_eq!(
+            check(&auth, &request("/", &[protocols])),
+            Ok(Some("keydisp-token.secret".to_string()))
+        );
+
+        let wrong = "Sec-WebSocket-Protocol: keydisp-token.guess";
+        assert_eq!(check(&auth, &request("/", &[wrong])), Err(401));
+    }
+
+    #[test]
+    fn origins_outside_the_list_are_forbidden() {
+        let auth = auth(&[], &["null", "http://localhost:8080"]);
+
+        let allowed = request("/", &["Origin: http://localhost:8080"]);
+        assert_eq!(check(&auth, &allowed), Ok(None));
+
+        let file = request("/", &["Origin: null"]);
+        assert_eq!(check(&auth, &file), Ok(None));
+
+        let other = request("/", &["Origin: https://evil.example"]);
+        assert_eq!(check(&auth, &other), Err(403));
+
+        // Only browsers send an origin.
+        assert_eq!(check(&auth, &request("/", &[])), Ok(None));
+    }
+
+    #[test]
+    fn only_loopback_binds_without_tokens() {
+        let open = auth(&[], &[]);
+
+        assert!(bind_allowed(&open, "127.0.0.1:2945"));
+        assert!(bind_allowed(&open, "[::1]:2945"));
+        assert!(!bind_allowed(&open, "0.0.0.0:2945"));
+        assert!(!bind_allowed(&open, "192.168.1.20:2945"));
+        assert!(!bind_allowed(&open, "not an address"));
+
+        assert!(bind_allowed(&auth(&["secret"], &[]), "0.0.0.0:2945"));
+    }
+}
@@ -0,0 +1,110 @@
+//! `keydisp bench [keystrokes]`, measures how long input takes to get through.
+//!
+//! A synthetic source types into the same queue and processing thread the
+//! hook uses, and the time each event spends in `Sender::send`, which is all
+//! the hook thread pays, and until its outputs are broadcast is reported.
+//! The latter includes looking up the text each key types.
+
+use crate::common::Timestamp;
+use crate::config::{Config, FilterMode};
+#[cfg(target_os = "linux")]
+use crate::linux::synthetic;
+#[cfg(target_os = "windows")]
+use crate::windows::synthetic;
+use crate::process::Processor;
+use crate::state::State;
+use crate::EVENT_BUFFER;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+const DEFAULT_KEYSTROKES: usize = 10_000;
+/// Faster than anyone types, slow enough not to overrun `EVENT_BUFFER`.
+const INTERVAL: Duration = Duration::from_micros(500);
+
+pub fn run(keystrokes: Option<&str>) {
+    let keystrokes = match keystrokes.map(str::parse) {
+        Some(Ok(keystrokes)) => keystrokes,
+        Some(Err(e)) => {
+            println!("Invalid number of keystrokes: {}", e);
+            return;
+        }
+        None => DEFAULT_KEYSTROKES,
+    };
+
+    Timestamp::start();
+
+    let mut state = State::new(Config::default());
+    state.filter_mode = FilterMode::Everything;
+
+    let (events, mut outputs) = broadcast::channel(EVENT_BUFFER);
+
+    let queue = match Processor::new(state.shared(), events).spawn() {
+        Ok(queue) => queue,
+        Err(e) => {
+            println!("Could not start processing: {}", e);
+            return;
+        }
+    };
+
+    // Ends once processing stops and drops its sender.
+    let collector = thread::spawn(move || {
+        let mut latencies = Vec::new();
+        let mut skipped = 0;
+
+        loop {
+            match outputs.blocking_recv() {
+                Ok(tagged) => {
+                    latencies.push(Timestamp::now().as_micros() - tagged.time.as_micros())
+                }
+                Err(RecvError::Lagged(n)) => skipped += n,
+                Err(RecvError::Closed) => return (latencies, skipped),
+            }
+        }
+    });
+
+    println!("Typing {} keystrokes, one every {:?}", keystrokes, INTERVAL);
+
+    let mut enqueue = Vec::with_capacity(keystrokes * 3);
+
+    synthetic::run(keystrokes, INTERVAL, |input| {
+        let start = Instant::now();
+        let _ = queue.send(input);
+        enqueue.push(start.elapsed().as_micros() as u64);
+    });
+
+    drop(queue);
+
+    let (latencies, skipped) = collector.join().expect("collector to not panic.");
+
+    report("Enqueue, on the hook thread", enqueue);
+    report("Input to broadcast", latencies);
+
+    if skipped > 0 {
+        println!("{} outputs were skipped, the collector fell behind", skipped);
+    }
+}
+
+fn report(what: &str, mut micros: Vec<u64>) {
+    if micros.is_empty() {
+        println!("{}: no samples", what);
+        return;
+    }
+
+    micros.sort_unstable();
+
+    let percentile = |p: usize| micros[(micros.len() - 1) * p / 1000];
+
+    println!(
+        "{} ({} samples): p50 {}µs, p90 {}µs, p99 {}µs, p99.9 {}µs, max {}µs",
+        what,
+        micros.len(),
+        percentile(500),
+        percentile(900),
+        percentile(990),
+        percentile(999),
+        micros[micros.len() - 1]
+    );
+}
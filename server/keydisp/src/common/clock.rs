@@ -0,0 +1,47 @@
+//! The monotonic clock event times are given in, shared by the whole process.
+//!
+//! Sources stamp events with clocks of their own, so their times are turned
+//! into how long ago the event happened and taken off the current time.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Microseconds since keydisp started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Starts the clock, times before this are all 0.
+    pub fn start() {
+        LazyLock::force(&START);
+    }
+
+    pub fn now() -> Self {
+        Self::at(Instant::now())
+    }
+
+    pub fn at(instant: Instant) -> Self {
+        Timestamp(instant.saturating_duration_since(*START).as_micros() as u64)
+    }
+
+    /// The time `age` before now.
+    pub fn ago(age: Duration) -> Self {
+        let now = Instant::now();
+        Self::at(now.checked_sub(age).unwrap_or(*START))
+    }
+
+    /// A time read back, as given by `as_micros`.
+    pub fn from_micros(micros: u64) -> Self {
+        Timestamp(micros)
+    }
+
+    pub fn as_micros(self) -> u64 {
+        self.0
+    }
+
+    pub fn to_instant(self) -> Instant {
+        *START + Duration::from_micros(self.0)
+    }
+}
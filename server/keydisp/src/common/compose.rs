@@ -0,0 +1,440 @@
+//! Dead key and compose key sequences.
+//!
+//! Platforms report dead keys as the spacing accent they stand for, and the
+//! characters typed after them unchanged, without touching the dead key state
+//! of the focused application. `Composer` puts the two together the way the
+//! application will, so `´` followed by `e` is displayed as `é`, and reports
+//! an unfinished sequence as pending so it can be shown while it is typed.
+
+/// Characters each dead key composes with, as pairs of base and result.
+const DEAD_KEYS: &[(char, &str)] = &[
+    ('´', "aácćeégǵiíkḱlĺmḿnńoópṕrŕsśuúwẃyýzźAÁCĆEÉGǴIÍKḰLĹMḾNŃOÓPṔRŔSŚUÚWẂYÝZŹ"),
+    ('`', "aàeèiìnǹoòuùwẁyỳAÀEÈIÌNǸOÒUÙWẀYỲ"),
+    ('^', "aâcĉeêgĝhĥiîjĵoôsŝuûwŵyŷzẑAÂCĈEÊGĜHĤIÎJĴOÔSŜUÛWŴYŶZẐ"),
+    ('¨', "aäeëhḧiïoötẗuüwẅxẍyÿAÄEËHḦIÏOÖUÜWẄXẌYŸ"),
+    ('~', "aãeẽiĩnñoõuũvṽyỹAÃEẼIĨNÑOÕUŨVṼYỸ"),
+    ('˚', "aåuůwẘyẙAÅUŮ"),
+    ('¸', "cçdḑeȩgģhḩkķlļnņrŗsştţCÇDḐEȨGĢHḨKĶLĻNŅRŖSŞTŢ"),
+    ('ˇ', "aǎcčdďeěgǧhȟiǐjǰkǩlľnňoǒrřsštťuǔzžAǍCČDĎEĚGǦHȞIǏKǨLĽNŇOǑRŘSŠTŤUǓZŽ"),
+    ('¯', "aāeēgḡiīoōuūyȳAĀEĒGḠIĪOŌUŪYȲ"),
+    ('˘', "aăeĕgğiĭoŏuŭAĂEĔGĞIĬOŎUŬ"),
+    ('˝', "oőuűOŐUŰ"),
+    ('˛', "aąeęiįoǫuųAĄEĘIĮOǪUŲ"),
+    ('˙', "aȧbḃcċdḋeėfḟgġhḣmṁnṅoȯpṗrṙsṡtṫwẇxẋyẏzżAȦBḂCĊDḊEĖFḞGĠHḢIİMṀNṄOȮPṖRṘSṠTṪWẆXẊYẎZŻ"),
+];
+
+/// Compose key sequences that aren't an accent on a letter.
+const COMPOSE_SEQUENCES: &[(&str, char)] = &[
+    ("ae", 'æ'),
+    ("AE", 'Æ'),
+    ("o/", 'ø'),
+    ("O/", 'Ø'),
+    ("aa", 'å'),
+    ("AA", 'Å'),
+    ("oe", 'œ'),
+    ("OE", 'Œ'),
+    ("ss", 'ß'),
+    ("<<", '«'),
+    (">>", '»'),
+    ("!!", '¡'),
+    ("??", '¿'),
+    ("=e", '€'),
+    ("-l", '£'),
+    ("=y", '¥'),
+    ("oc", '©'),
+    ("or", '®'),
+    ("tm", '™'),
+    ("oo", '°'),
+    ("..", '…'),
+    ("+-", '±'),
+    ("xx", '×'),
+    (":-", '÷'),
+    ("12", '½'),
+    ("14", '¼'),
+    ("34", '¾'),
+];
+
+/// Accents a compose sequence can start (or end) with, as in `'` `e` for `é`.
+const COMPOSE_ACCENTS: &[(char, char)] = &[
+    ('\'', '´'),
+    ('`', '`'),
+    ('^', '^'),
+    ('"', '¨'),
+    ('~', '~'),
+    ('o', '˚'),
+    (',', '¸'),
+    ('c', 'ˇ'),
+    ('-', '¯'),
+    ('_', '¯'),
+    ('U', '˘'),
+    ('=', '˝'),
+    (';', '˛'),
+    ('.', '˙'),
+];
+
+/// What the overlay shows for a compose sequence that is being typed.
+const COMPOSE_GLYPH: char = '⎄';
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Composed {
+    /// A sequence is being typed, with what has been typed so far.
+    Pending(String),
+    /// The pending sequence was abandoned without producing anything.
+    Cancelled,
+    Char(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sequence {
+    Idle,
+    Dead(char),
+    Compose(Option<char>),
+}
+
+#[derive(Debug)]
+pub struct Composer {
+    sequence: Sequence,
+}
+
+impl Composer {
+    pub fn new() -> Self {
+        Self {
+            sequence: Sequence::Idle,
+        }
+    }
+
+    pub fn dead_key(&mut self, accent: char) -> Vec<Composed> {
+        let accent = normalize_accent(accent);
+
+        match self.sequence {
+            // Pressing a dead key twice types its accent.
+            Sequence::Dead(pending) if pending == accent => {
+                self.sequence = Sequence::Idle;
+                vec![Composed::Char(accent)]
+            }
+            Sequence::Dead(pending) => {
+                self.sequence = Sequence::Dead(accent);
+                vec![Composed::Char(pending), Composed::Pending(accent.to_string())]
+            }
+            Sequence::Compose(_) => self.char(accent),
+            Sequence::Idle => {
+                self.sequence = Sequence::Dead(accent);
+                vec![Composed::Pending(accent.to_string())]
+            }
+        }
+    }
+
+    pub fn compose_key(&mut self) -> Vec<Composed> {
+        let mut composed = match self.sequence {
+            Sequence::Idle => vec![],
+            _ => vec![Composed::Cancelled],
+        };
+
+        self.sequence = Sequence::Compose(None);
+        composed.push(Composed::Pending(COMPOSE_GLYPH.to_string()));
+        composed
+    }
+
+    pub fn char(&mut self, c: char) -> Vec<Composed> {
+        let sequence = self.sequence;
+
+        // Enter, escape, backspace and the like abandon a sequence.
+        if c.is_control() {
+            self.sequence = Sequence::Idle;
+
+            return match sequence {
+                Sequence::Idle => vec![Composed::Char(c)],
+                _ => vec![Composed::Cancelled, Composed::Char(c)],
+            };
+        }
+
+        match sequence {
+            Sequence::Idle => vec![Composed::Char(c)],
+            Sequence::Dead(accent) => {
+                self.sequence = Sequence::Idle;
+
+                if c == ' ' {
+                    return vec![Composed::Char(accent)];
+                }
+
+                match with_accent(accent, c) {
+                    Some(composed) => vec![Composed::Char(composed)],
+                    // Like the platforms do, type the accent and the character.
+                    None => vec![Composed::Char(accent), Composed::Char(c)],
+                }
+            }
+            Sequence::Compose(None) => {
+                self.sequence = Sequence::Compose(Some(c));
+                vec![Composed::Pending(format!("{}{}", COMPOSE_GLYPH, c))]
+            }
+            Sequence::Compose(Some(first)) => {
+                self.sequence = Sequence::Idle;
+
+                match compose(first, c) {
+                    Some(composed) => vec![Composed::Char(composed)],
+                    None => vec![Composed::Cancelled],
+                }
+            }
+        }
+    }
+}
+
+/// Some layouts produce the degree sign for the dead ring.
+fn normalize_accent(accent: char) -> char {
+    match accent {
+        '°' => '˚',
+        accent => accent,
+    }
+}
+
+fn with_accent(accent: char, base: char) -> Option<char> {
+    let (_, pairs) = DEAD_KEYS.iter().find(|(dead, _)| *dead == accent)?;
+    let mut chars = pairs.chars();
+
+    while let (Some(from), Some(to)) = (chars.next(), chars.next()) {
+        if from == base {
+            return Some(to);
+        }
+    }
+
+    None
+}
+
+fn compose(first: char, second: char) -> Option<char> {
+    // A dead key pressed during a sequence stands for its own accent.
+    let accent = |c| {
+        COMPOSE_ACCENTS
+            .iter()
+            .find(|(key, _)| *key == c)
+            .map(|(_, accent)| *accent)
+            .or_else(|| {
+                DEAD_KEYS
+                    .iter()
+                    .find(|(dead, _)| *dead == c)
+                    .map(|(dead, _)| *dead)
+            })
+    };
+
+    COMPOSE_SEQUENCES
+        .iter()
+        .find(|(sequence, _)| sequence.chars().eq([first, second].iter().cloned()))
+        .map(|(_, composed)| *composed)
+        .or_else(|| accent(first).and_then(|accent| with_accent(accent, second)))
+        .or_else(|| accent(second).and_then(|accent| with_accent(accent, first)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &str) -> Vec<(char, char)> {
+        let chars: Vec<char> = pairs.chars().collect();
+        assert_eq!(chars.len() % 2, 0, "{} has a base without a result", pairs);
+        chars.chunks(2).map(|pair| (pair[0], pair[1])).collect()
+    }
+
+    fn typed(composer: &mut Composer, text: &str) -> Vec<Composed> {
+        text.chars().flat_map(|c| composer.char(c)).collect()
+    }
+
+    #[test]
+    fn dead_keys_compose_with_every_base() {
+        for (accent, bases) in DEAD_KEYS {
+            for (base, result) in pairs(bases) {
+                let mut composer = Composer::new();
+
+                assert_eq!(
+                    composer.dead_key(*accent),
+                    [Composed::Pending(accent.to_string())]
+                );
+                assert_eq!(
+                    composer.char(base),
+                    [Composed::Char(result)],
+                    "{} {}",
+                    accent,
+                    base
+                );
+                assert_eq!(composer.char(base), [Composed::Char(base)]);
+            }
+        }
+    }
+
+    #[test]
+    fn dead_key_bases_are_unique() {
+        for (accent, bases) in DEAD_KEYS {
+            let mut seen: Vec<char> = pairs(bases).into_iter().map(|(base, _)| base).collect();
+            let count = seen.len();
+            seen.sort_unstable();
+            seen.dedup();
+            assert_eq!(seen.len(), count, "{} lists a base twice", accent);
+        }
+
+        let mut accents: Vec<char> = DEAD_KEYS.iter().map(|(accent, _)| *accent).collect();
+        accents.sort_unstable();
+        accents.dedup();
+        assert_eq!(accents.len(), DEAD_KEYS.len());
+    }
+
+    #[test]
+    fn compose_sequences() {
+        for (sequence, result) in COMPOSE_SEQUENCES {
+            let mut composer = Composer::new();
+            let first = sequence.chars().next().unwrap();
+
+            assert_eq!(composer.compose_key(), [Composed::Pending("⎄".to_string())]);
+            assert_eq!(
+                composer.char(first),
+                [Composed::Pending(format!("⎄{}", first))]
+            );
+            assert_eq!(
+                typed(&mut composer, &sequence[first.len_utf8()..]),
+                [Composed::Char(*result)],
+                "{}",
+                sequence
+            );
+        }
+    }
+
+    #[test]
+    fn compose_accents_compose_with_every_base() {
+        for (key, accent) in COMPOSE_ACCENTS {
+            let (_, bases) = DEAD_KEYS.iter().find(|(dead, _)| dead == accent).unwrap();
+
+            for (base, result) in pairs(bases) {
+                let sequence: String = [*key, base].iter().collect();
+
+                // Listed sequences take precedence, as in `oo` for `°`.
+                if COMPOSE_SEQUENCES
+                    .iter()
+                    .any(|(listed, _)| *listed == sequence)
+                {
+                    continue;
+                }
+
+                let mut composer = Composer::new();
+                composer.compose_key();
+                assert_eq!(
+                    typed(&mut composer, &sequence),
+                    [
+                        Composed::Pending(format!("⎄{}", key)),
+                        Composed::Char(result),
+                    ]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn compose_accent_after_base() {
+        let mut composer = Composer::new();
+
+        composer.compose_key();
+        assert_eq!(
+            typed(&mut composer, "e'"),
+            [Composed::Pending("⎄e".to_string()), Composed::Char('é'),]
+        );
+        composer.compose_key();
+        assert_eq!(typed(&mut composer, "c,")[1], Composed::Char('ç'));
+    }
+
+    #[test]
+    fn unknown_compose_sequence_is_cancelled() {
+        let mut composer = Composer::new();
+
+        composer.compose_key();
+        assert_eq!(typed(&mut composer, "qq")[1], Composed::Cancelled);
+        assert_eq!(composer.char('q'), [Composed::Char('q')]);
+    }
+
+    #[test]
+    fn dead_key_twice_types_the_accent() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('^');
+        assert_eq!(composer.dead_key('^'), [Composed::Char('^')]);
+        assert_eq!(composer.char('a'), [Composed::Char('a')]);
+    }
+
+    #[test]
+    fn another_dead_key_types_the_first() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('´');
+        assert_eq!(
+            composer.dead_key('`'),
+            [Composed::Char('´'), Composed::Pending("`".to_string()),]
+        );
+        assert_eq!(composer.char('e'), [Composed::Char('è')]);
+    }
+
+    #[test]
+    fn dead_key_then_space_types_the_accent() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('¨');
+        assert_eq!(composer.char(' '), [Composed::Char('¨')]);
+    }
+
+    #[test]
+    fn dead_key_without_composition_types_both() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('˝');
+        assert_eq!(
+            composer.char('x'),
+            [Composed::Char('˝'), Composed::Char('x')]
+        );
+    }
+
+    #[test]
+    fn degree_sign_is_the_dead_ring() {
+        let mut composer = Composer::new();
+
+        assert_eq!(composer.dead_key('°'), [Composed::Pending("˚".to_string())]);
+        assert_eq!(composer.char('a'), [Composed::Char('å')]);
+    }
+
+    #[test]
+    fn control_characters_cancel() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('~');
+        assert_eq!(
+            composer.char('\x1b'),
+            [Composed::Cancelled, Composed::Char('\x1b')]
+        );
+        assert_eq!(composer.char('n'), [Composed::Char('n')]);
+
+        composer.compose_key();
+        composer.char('a');
+        assert_eq!(
+            composer.char('\x08'),
+            [Composed::Cancelled, Composed::Char('\x08')]
+        );
+        assert_eq!(composer.char('e'), [Composed::Char('e')]);
+    }
+
+    #[test]
+    fn compose_key_restarts_a_sequence() {
+        let mut composer = Composer::new();
+
+        composer.dead_key('´');
+        assert_eq!(
+            composer.compose_key(),
+            [Composed::Cancelled, Composed::Pending("⎄".to_string()),]
+        );
+        assert_eq!(typed(&mut composer, "ae")[1], Composed::Char('æ'));
+    }
+
+    #[test]
+    fn dead_key_during_compose_is_its_accent() {
+        let mut composer = Composer::new();
+
+        composer.compose_key();
+        assert_eq!(
+            composer.dead_key('´'),
+            [Composed::Pending("⎄´".to_string())]
+        );
+        assert_eq!(composer.char('e'), [Composed::Char('é')]);
+    }
+}
@@ -0,0 +1,131 @@
+//! Which physical device input came from.
+//!
+//! Devices are picked out with patterns, as used by the `[devices]` config
+//! section and the `device` query parameter of clients. A pattern matches a
+//! device when it is its `vendor:product` id in hex, like `046d:c52b`, its
+//! physical path, or part of its name, ignoring case.
+
+use std::fmt;
+
+// Only Linux says which device input came from.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct DeviceId {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    /// Where the device is plugged in, like `usb-0000:00:14.0-2/input0`. Stays
+    /// the same across restarts as long as the device keeps its port.
+    pub phys: String,
+}
+
+impl DeviceId {
+    pub fn matches(&self, pattern: &str) -> bool {
+        let pattern = pattern.trim();
+
+        !pattern.is_empty()
+            && (pattern.eq_ignore_ascii_case(&self.usb_id())
+                || pattern == self.phys
+                || self.name.to_lowercase().contains(&pattern.to_lowercase()))
+    }
+
+    pub fn matches_any<S: AsRef<str>>(&self, patterns: &[S]) -> bool {
+        patterns.iter().any(|pattern| self.matches(pattern.as_ref()))
+    }
+
+    fn usb_id(&self) -> String {
+        format!("{:04x}:{:04x}", self.vendor, self.product)
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} [{}", self.name, self.usb_id())?;
+
+        if !self.phys.is_empty() {
+            write!(f, " {}", self.phys)?;
+        }
+
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard() -> DeviceId {
+        DeviceId {
+            name: "Logitech USB Receiver Keyboard".to_string(),
+            vendor: 0x046d,
+            product: 0xc52b,
+            phys: "usb-0000:00:14.0-2/input0".to_string(),
+        }
+    }
+
+    #[test]
+    fn matches_by_usb_id() {
+        let device = keyboard();
+
+        assert!(device.matches("046d:c52b"));
+        assert!(device.matches("046D:C52B"));
+        assert!(device.matches(" 046d:c52b "));
+        assert!(!device.matches("046d:c52c"));
+        assert!(!device.matches("46d:c52b"));
+
+        let padded = DeviceId {
+            vendor: 0x1,
+            product: 0x2a,
+            ..keyboard()
+        };
+        assert!(padded.matches("0001:002a"));
+    }
+
+    #[test]
+    fn matches_by_physical_path() {
+        let device = keyboard();
+
+        assert!(device.matches("usb-0000:00:14.0-2/input0"));
+        // Another port is another device, even of the same model.
+        assert!(!device.matches("usb-0000:00:14.0-1/input0"));
+    }
+
+    #[test]
+    fn matches_by_part_of_the_name() {
+        let device = keyboard();
+
+        assert!(device.matches("Receiver"));
+        assert!(device.matches("logitech usb"));
+        assert!(!device.matches("Macro Pad"));
+    }
+
+    #[test]
+    fn empty_patterns_match_nothing() {
+        let device = keyboard();
+
+        assert!(!device.matches(""));
+        assert!(!device.matches("  "));
+        assert!(!device.matches_any::<&str>(&[]));
+        assert!(device.matches_any(&["Macro Pad", "046d:c52b"]));
+        assert!(!device.matches_any(&["Macro Pad", ""]));
+    }
+
+    #[test]
+    fn shown_with_id_and_path() {
+        assert_eq!(
+            keyboard().to_string(),
+            "Logitech USB Receiver Keyboard [046d:c52b usb-0000:00:14.0-2/input0]"
+        );
+
+        let virtual_device = DeviceId {
+            name: "ydotoold virtual device".to_string(),
+            vendor: 0x2333,
+            product: 0x6666,
+            phys: String::new(),
+        };
+        assert_eq!(
+            virtual_device.to_string(),
+            "ydotoold virtual device [2333:6666]"
+        );
+    }
+}
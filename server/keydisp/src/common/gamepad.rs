@@ -0,0 +1,232 @@
+//! Gamepad and joystick input.
+//!
+//! Face buttons are named by position, like the Linux gamepad API does, and
+//! get their printed label from the configured label set, so the bottom face
+//! button is `A` on an Xbox pad, `✕` on a PlayStation pad and `B` on a
+//! Nintendo pad.
+
+#[cfg(target_os = "linux")]
+use super::KeyState;
+
+// Gamepads are only read on Linux.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GamepadButton {
+    South,
+    East,
+    North,
+    West,
+    LeftBumper,
+    RightBumper,
+    /// Triggers with only a digital switch, most pads report them as axes.
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    /// The vendor button in the middle of the pad.
+    Mode,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    /// Numbered buttons of joysticks and pads without a standard layout.
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonLabels {
+    Xbox,
+    #[serde(rename = "playstation")]
+    PlayStation,
+    Nintendo,
+}
+
+impl GamepadButton {
+    pub fn label(self, labels: ButtonLabels) -> String {
+        use self::ButtonLabels::*;
+        use self::GamepadButton::*;
+
+        let label = match (self, labels) {
+            (Other(n), _) => return format!("Button {}", n),
+            (South, Xbox) => "A",
+            (East, Xbox) => "B",
+            (North, Xbox) => "Y",
+            (West, Xbox) => "X",
+            (South, PlayStation) => "✕",
+            (East, PlayStation) => "○",
+            (North, PlayStation) => "△",
+            (West, PlayStation) => "□",
+            (South, Nintendo) => "B",
+            (East, Nintendo) => "A",
+            (North, Nintendo) => "X",
+            (West, Nintendo) => "Y",
+            (LeftBumper, Xbox) => "LB",
+            (RightBumper, Xbox) => "RB",
+            (LeftTrigger, Xbox) => "LT",
+            (RightTrigger, Xbox) => "RT",
+            (LeftBumper, PlayStation) => "L1",
+            (RightBumper, PlayStation) => "R1",
+            (LeftTrigger, PlayStation) => "L2",
+            (RightTrigger, PlayStation) => "R2",
+            (LeftBumper, Nintendo) => "L",
+            (RightBumper, Nintendo) => "R",
+            (LeftTrigger, Nintendo) => "ZL",
+            (RightTrigger, Nintendo) => "ZR",
+            (Select, Xbox) => "View",
+            (Start, Xbox) => "Menu",
+            (Mode, Xbox) => "Xbox",
+            (Select, PlayStation) => "Share",
+            (Start, PlayStation) => "Options",
+            (Mode, PlayStation) => "PS",
+            (Select, Nintendo) => "−",
+            (Start, Nintendo) => "+",
+            (Mode, Nintendo) => "Home",
+            (LeftStick, PlayStation) => "L3",
+            (RightStick, PlayStation) => "R3",
+            (LeftStick, _) => "LS",
+            (RightStick, _) => "RS",
+            (DPadUp, _) => "⬆",
+            (DPadDown, _) => "⬇",
+            (DPadLeft, _) => "⬅",
+            (DPadRight, _) => "➡",
+        };
+
+        label.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl Axis {
+    const ALL: [Axis; 6] = [
+        Axis::LeftX,
+        Axis::LeftY,
+        Axis::RightX,
+        Axis::RightY,
+        Axis::LeftTrigger,
+        Axis::RightTrigger,
+    ];
+
+    /// Triggers go from 0 to 1, sticks from -1 to 1.
+    #[cfg(target_os = "linux")]
+    pub fn is_trigger(self) -> bool {
+        self == Axis::LeftTrigger || self == Axis::RightTrigger
+    }
+
+    fn index(self) -> usize {
+        Axis::ALL
+            .iter()
+            .position(|&axis| axis == self)
+            .expect("axis to be listed.")
+    }
+}
+
+/// Smallest change in an axis worth reporting.
+const AXIS_STEP: f32 = 0.05;
+
+/// Applies the dead zone to axis positions and drops changes too small to see.
+#[derive(Debug, Default)]
+pub struct AxisFilter {
+    values: [f32; 6],
+}
+
+impl AxisFilter {
+    /// The position to report, if it changed enough since the last one.
+    pub fn update(&mut self, axis: Axis, value: f32, deadzone: f32) -> Option<f32> {
+        let deadzone = deadzone.clamp(0.0, 0.99);
+
+        // Rescaled so the edge of the dead zone is 0 rather than a jump.
+        let value = if value.abs() < deadzone {
+            0.0
+        } else {
+            value.signum() * (value.abs() - deadzone) / (1.0 - deadzone)
+        };
+
+        let last = &mut self.values[axis.index()];
+        let settled = value == 0.0 || value.abs() >= 1.0;
+
+        if (value - *last).abs() >= AXIS_STEP || (settled && value != *last) {
+            *last = value;
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+/// Turns a hat switch, reported as two axes of -1, 0 or 1, into presses and
+/// releases of the D-pad buttons.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct DPad {
+    x: i32,
+    y: i32,
+}
+
+#[cfg(target_os = "linux")]
+impl DPad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Left is negative.
+    pub fn set_x(&mut self, x: i32) -> Vec<(GamepadButton, KeyState)> {
+        let changes = Self::changes(
+            self.x,
+            x.signum(),
+            GamepadButton::DPadLeft,
+            GamepadButton::DPadRight,
+        );
+        self.x = x.signum();
+        changes
+    }
+
+    /// Up is negative.
+    pub fn set_y(&mut self, y: i32) -> Vec<(GamepadButton, KeyState)> {
+        let changes = Self::changes(
+            self.y,
+            y.signum(),
+            GamepadButton::DPadUp,
+            GamepadButton::DPadDown,
+        );
+        self.y = y.signum();
+        changes
+    }
+
+    fn changes(
+        from: i32,
+        to: i32,
+        negative: GamepadButton,
+        positive: GamepadButton,
+    ) -> Vec<(GamepadButton, KeyState)> {
+        let button = |direction| if direction < 0 { negative } else { positive };
+        let mut changes = Vec::new();
+
+        if from == to {
+            return changes;
+        }
+
+        if from != 0 {
+            changes.push((button(from), KeyState::Released));
+        }
+
+        if to != 0 {
+            changes.push((button(to), KeyState::Pressed));
+        }
+
+        changes
+    }
+}
@@ -0,0 +1,521 @@
+//! Keys, independent of the platform they were captured on.
+//!
+//! Every key is listed once in the table below, with its stable name as used
+//! on the wire and in config files, its USB HID usage (page, id), its Linux
+//! evdev code, its Windows virtual key code, its X11 keysym and the glyph the
+//! overlay shows for it. A code of 0 means the platform has no such key.
+//! Keys that type a character have no glyph, the character is shown instead.
+//! Each platform only converts its own codes, the tests check all of them.
+//!
+//! The table has every usage of the HID keyboard/keypad page. Of the generic
+//! desktop and consumer pages, which are mostly not keys, it has the system,
+//! media, launch and application control usages keyboards have keys for.
+//! The usages are only there for the tests to check that.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::LazyLock;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+macro_rules! keys {
+    ($($key:ident = $name:expr, ($page:expr, $usage:expr), $evdev:expr, $vk:expr, $keysym:expr, $glyph:expr;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub enum Key {
+            $($key,)*
+        }
+
+        impl Key {
+            pub const ALL: &'static [Key] = &[$(Key::$key,)*];
+
+            /// Stable snake case name, e.g. `left_shift`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Key::$key => $name,)*
+                }
+            }
+
+            /// USB HID usage page and usage id.
+            #[cfg(test)]
+            pub fn hid_usage(self) -> Option<(u16, u16)> {
+                match self {
+                    $(Key::$key => nonzero(($page, $usage)),)*
+                }
+            }
+
+            #[cfg(any(target_os = "linux", test))]
+            pub fn to_evdev(self) -> Option<u16> {
+                match self {
+                    $(Key::$key => nonzero($evdev),)*
+                }
+            }
+
+            #[cfg(any(target_os = "windows", test))]
+            pub fn to_vk(self) -> Option<u16> {
+                match self {
+                    $(Key::$key => nonzero($vk),)*
+                }
+            }
+
+            #[cfg(any(target_os = "linux", test))]
+            pub fn to_keysym(self) -> Option<u32> {
+                match self {
+                    $(Key::$key => nonzero($keysym),)*
+                }
+            }
+
+            /// What the overlay shows for the key, `None` for keys that type a character.
+            pub fn glyph(self) -> Option<&'static str> {
+                match self {
+                    $(Key::$key => $glyph,)*
+                }
+            }
+        }
+    };
+}
+
+keys! {
+    // Keyboard/keypad page (0x07)
+    A = "a", (0x07, 0x04), 30, 0x41, 0x0041, None;
+    B = "b", (0x07, 0x05), 48, 0x42, 0x0042, None;
+    C = "c", (0x07, 0x06), 46, 0x43, 0x0043, None;
+    D = "d", (0x07, 0x07), 32, 0x44, 0x0044, None;
+    E = "e", (0x07, 0x08), 18, 0x45, 0x0045, None;
+    F = "f", (0x07, 0x09), 33, 0x46, 0x0046, None;
+    G = "g", (0x07, 0x0A), 34, 0x47, 0x0047, None;
+    H = "h", (0x07, 0x0B), 35, 0x48, 0x0048, None;
+    I = "i", (0x07, 0x0C), 23, 0x49, 0x0049, None;
+    J = "j", (0x07, 0x0D), 36, 0x4A, 0x004a, None;
+    K = "k", (0x07, 0x0E), 37, 0x4B, 0x004b, None;
+    L = "l", (0x07, 0x0F), 38, 0x4C, 0x004c, None;
+    M = "m", (0x07, 0x10), 50, 0x4D, 0x004d, None;
+    N = "n", (0x07, 0x11), 49, 0x4E, 0x004e, None;
+    O = "o", (0x07, 0x12), 24, 0x4F, 0x004f, None;
+    P = "p", (0x07, 0x13), 25, 0x50, 0x0050, None;
+    Q = "q", (0x07, 0x14), 16, 0x51, 0x0051, None;
+    R = "r", (0x07, 0x15), 19, 0x52, 0x0052, None;
+    S = "s", (0x07, 0x16), 31, 0x53, 0x0053, None;
+    T = "t", (0x07, 0x17), 20, 0x54, 0x0054, None;
+    U = "u", (0x07, 0x18), 22, 0x55, 0x0055, None;
+    V = "v", (0x07, 0x19), 47, 0x56, 0x0056, None;
+    W = "w", (0x07, 0x1A), 17, 0x57, 0x0057, None;
+    X = "x", (0x07, 0x1B), 45, 0x58, 0x0058, None;
+    Y = "y", (0x07, 0x1C), 21, 0x59, 0x0059, None;
+    Z = "z", (0x07, 0x1D), 44, 0x5A, 0x005a, None;
+    Digit1 = "1", (0x07, 0x1E), 2, 0x31, 0x0031, None;
+    Digit2 = "2", (0x07, 0x1F), 3, 0x32, 0x0032, None;
+    Digit3 = "3", (0x07, 0x20), 4, 0x33, 0x0033, None;
+    Digit4 = "4", (0x07, 0x21), 5, 0x34, 0x0034, None;
+    Digit5 = "5", (0x07, 0x22), 6, 0x35, 0x0035, None;
+    Digit6 = "6", (0x07, 0x23), 7, 0x36, 0x0036, None;
+    Digit7 = "7", (0x07, 0x24), 8, 0x37, 0x0037, None;
+    Digit8 = "8", (0x07, 0x25), 9, 0x38, 0x0038, None;
+    Digit9 = "9", (0x07, 0x26), 10, 0x39, 0x0039, None;
+    Digit0 = "0", (0x07, 0x27), 11, 0x30, 0x0030, None;
+    Enter = "enter", (0x07, 0x28), 28, 0x0D, 0xff0d, Some("⏎");
+    Escape = "escape", (0x07, 0x29), 1, 0x1B, 0xff1b, Some("⎋");
+    Backspace = "backspace", (0x07, 0x2A), 14, 0x08, 0xff08, Some("⌫");
+    Tab = "tab", (0x07, 0x2B), 15, 0x09, 0xff09, Some("⇥");
+    Space = "space", (0x07, 0x2C), 57, 0x20, 0x0020, Some("␣");
+    Minus = "minus", (0x07, 0x2D), 12, 0xBD, 0x002d, None;
+    Equal = "equal", (0x07, 0x2E), 13, 0xBB, 0x003d, None;
+    LeftBracket = "left_bracket", (0x07, 0x2F), 26, 0xDB, 0x005b, None;
+    RightBracket = "right_bracket", (0x07, 0x30), 27, 0xDD, 0x005d, None;
+    Backslash = "backslash", (0x07, 0x31), 43, 0xDC, 0x005c, None;
+    NonUsHash = "non_us_hash", (0x07, 0x32), 0, 0x00, 0x0000, None;
+    Semicolon = "semicolon", (0x07, 0x33), 39, 0xBA, 0x003b, None;
+    Apostrophe = "apostrophe", (0x07, 0x34), 40, 0xDE, 0x0027, None;
+    Grave = "grave", (0x07, 0x35), 41, 0xC0, 0x0060, None;
+    Comma = "comma", (0x07, 0x36), 51, 0xBC, 0x002c, None;
+    Period = "period", (0x07, 0x37), 52, 0xBE, 0x002e, None;
+    Slash = "slash", (0x07, 0x38), 53, 0xBF, 0x002f, None;
+    CapsLock = "caps_lock", (0x07, 0x39), 58, 0x14, 0xffe5, Some("⇪");
+    F1 = "f1", (0x07, 0x3A), 59, 0x70, 0xffbe, Some("F1");
+    F2 = "f2", (0x07, 0x3B), 60, 0x71, 0xffbf, Some("F2");
+    F3 = "f3", (0x07, 0x3C), 61, 0x72, 0xffc0, Some("F3");
+    F4 = "f4", (0x07, 0x3D), 62, 0x73, 0xffc1, Some("F4");
+    F5 = "f5", (0x07, 0x3E), 63, 0x74, 0xffc2, Some("F5");
+    F6 = "f6", (0x07, 0x3F), 64, 0x75, 0xffc3, Some("F6");
+    F7 = "f7", (0x07, 0x40), 65, 0x76, 0xffc4, Some("F7");
+    F8 = "f8", (0x07, 0x41), 66, 0x77, 0xffc5, Some("F8");
+    F9 = "f9", (0x07, 0x42), 67, 0x78, 0xffc6, Some("F9");
+    F10 = "f10", (0x07, 0x43), 68, 0x79, 0xffc7, Some("F10");
+    F11 = "f11", (0x07, 0x44), 87, 0x7A, 0xffc8, Some("F11");
+    F12 = "f12", (0x07, 0x45), 88, 0x7B, 0xffc9, Some("F12");
+    PrintScreen = "print_screen", (0x07, 0x46), 99, 0x2C, 0xff61, Some("⎙");
+    ScrollLock = "scroll_lock", (0x07, 0x47), 70, 0x91, 0xff14, Some("⇳");
+    Pause = "pause", (0x07, 0x48), 119, 0x13, 0xff13, Some("⎉");
+    Insert = "insert", (0x07, 0x49), 110, 0x2D, 0xff63, Some("⎀");
+    Home = "home", (0x07, 0x4A), 102, 0x24, 0xff50, Some("⇱");
+    PageUp = "page_up", (0x07, 0x4B), 104, 0x21, 0xff55, Some("⇞");
+    Delete = "delete", (0x07, 0x4C), 111, 0x2E, 0xffff, Some("⌦");
+    End = "end", (0x07, 0x4D), 107, 0x23, 0xff57, Some("⇲");
+    PageDown = "page_down", (0x07, 0x4E), 109, 0x22, 0xff56, Some("⇟");
+    Right = "right", (0x07, 0x4F), 106, 0x27, 0xff53, Some("→");
+    Left = "left", (0x07, 0x50), 105, 0x25, 0xff51, Some("←");
+    Down = "down", (0x07, 0x51), 108, 0x28, 0xff54, Some("↓");
+    Up = "up", (0x07, 0x52), 103, 0x26, 0xff52, Some("↑");
+    NumLock = "num_lock", (0x07, 0x53), 69, 0x90, 0xff7f, Some("⇭");
+    KeypadDivide = "keypad_divide", (0x07, 0x54), 98, 0x6F, 0xffaf, None;
+    KeypadMultiply = "keypad_multiply", (0x07, 0x55), 55, 0x6A, 0xffaa, None;
+    KeypadMinus = "keypad_minus", (0x07, 0x56), 74, 0x6D, 0xffad, None;
+    KeypadPlus = "keypad_plus", (0x07, 0x57), 78, 0x6B, 0xffab, None;
+    KeypadEnter = "keypad_enter", (0x07, 0x58), 96, 0x00, 0xff8d, Some("⏎");
+    Keypad1 = "keypad_1", (0x07, 0x59), 79, 0x61, 0xffb1, None;
+    Keypad2 = "keypad_2", (0x07, 0x5A), 80, 0x62, 0xffb2, None;
+    Keypad3 = "keypad_3", (0x07, 0x5B), 81, 0x63, 0xffb3, None;
+    Keypad4 = "keypad_4", (0x07, 0x5C), 75, 0x64, 0xffb4, None;
+    Keypad5 = "keypad_5", (0x07, 0x5D), 76, 0x65, 0xffb5, None;
+    Keypad6 = "keypad_6", (0x07, 0x5E), 77, 0x66, 0xffb6, None;
+    Keypad7 = "keypad_7", (0x07, 0x5F), 71, 0x67, 0xffb7, None;
+    Keypad8 = "keypad_8", (0x07, 0x60), 72, 0x68, 0xffb8, None;
+    Keypad9 = "keypad_9", (0x07, 0x61), 73, 0x69, 0xffb9, None;
+    Keypad0 = "keypad_0", (0x07, 0x62), 82, 0x60, 0xffb0, None;
+    KeypadDecimal = "keypad_decimal", (0x07, 0x63), 83, 0x6E, 0xffae, None;
+    NonUsBackslash = "non_us_backslash", (0x07, 0x64), 86, 0xE2, 0x0000, None;
+    Application = "application", (0x07, 0x65), 127, 0x5D, 0xff67, Some("▤");
+    Power = "power", (0x07, 0x66), 116, 0x00, 0x1008ff2a, Some("⏻");
+    KeypadEqual = "keypad_equal", (0x07, 0x67), 117, 0x92, 0xffbd, None;
+    F13 = "f13", (0x07, 0x68), 183, 0x7C, 0xffca, Some("F13");
+    F14 = "f14", (0x07, 0x69), 184, 0x7D, 0xffcb, Some("F14");
+    F15 = "f15", (0x07, 0x6A), 185, 0x7E, 0xffcc, Some("F15");
+    F16 = "f16", (0x07, 0x6B), 186, 0x7F, 0xffcd, Some("F16");
+    F17 = "f17", (0x07, 0x6C), 187, 0x80, 0xffce, Some("F17");
+    F18 = "f18", (0x07, 0x6D), 188, 0x81, 0xffcf, Some("F18");
+    F19 = "f19", (0x07, 0x6E), 189, 0x82, 0xffd0, Some("F19");
+    F20 = "f20", (0x07, 0x6F), 190, 0x83, 0xffd1, Some("F20");
+    F21 = "f21", (0x07, 0x70), 191, 0x84, 0xffd2, Some("F21");
+    F22 = "f22", (0x07, 0x71), 192, 0x85, 0xffd3, Some("F22");
+    F23 = "f23", (0x07, 0x72), 193, 0x86, 0xffd4, Some("F23");
+    F24 = "f24", (0x07, 0x73), 194, 0x87, 0xffd5, Some("F24");
+    Execute = "execute", (0x07, 0x74), 0, 0x2B, 0xff62, Some("Exec");
+    Help = "help", (0x07, 0x75), 138, 0x2F, 0xff6a, Some("Help");
+    Menu = "menu", (0x07, 0x76), 139, 0x00, 0x0000, Some("☰");
+    Select = "select", (0x07, 0x77), 353, 0x29, 0xff60, Some("Select");
+    Stop = "stop", (0x07, 0x78), 128, 0x00, 0x0000, Some("Stop");
+    Again = "again", (0x07, 0x79), 129, 0x00, 0xff66, Some("↷");
+    Undo = "undo", (0x07, 0x7A), 131, 0x00, 0xff65, Some("↶");
+    Cut = "cut", (0x07, 0x7B), 137, 0x00, 0x1008ff58, Some("✂");
+    Copy = "copy", (0x07, 0x7C), 133, 0x00, 0x1008ff57, Some("Copy");
+    Paste = "paste", (0x07, 0x7D), 135, 0x00, 0x1008ff6d, Some("Paste");
+    Find = "find", (0x07, 0x7E), 136, 0x00, 0xff68, Some("Find");
+    Mute = "mute", (0x07, 0x7F), 113, 0xAD, 0x1008ff12, Some("🔇");
+    VolumeUp = "volume_up", (0x07, 0x80), 115, 0xAF, 0x1008ff13, Some("🔊");
+    VolumeDown = "volume_down", (0x07, 0x81), 114, 0xAE, 0x1008ff11, Some("🔉");
+    LockingCapsLock = "locking_caps_lock", (0x07, 0x82), 0, 0x00, 0x0000, Some("⇪");
+    LockingNumLock = "locking_num_lock", (0x07, 0x83), 0, 0x00, 0x0000, Some("⇭");
+    LockingScrollLock = "locking_scroll_lock", (0x07, 0x84), 0, 0x00, 0x0000, Some("⇳");
+    KeypadComma = "keypad_comma", (0x07, 0x85), 121, 0x6C, 0xffac, None;
+    KeypadEqualAs400 = "keypad_equal_as400", (0x07, 0x86), 0, 0x00, 0x0000, None;
+    Ro = "ro", (0x07, 0x87), 89, 0xC1, 0xff24, None;
+    KatakanaHiragana = "katakana_hiragana", (0x07, 0x88), 93, 0x00, 0xff27, Some("かな");
+    Yen = "yen", (0x07, 0x89), 124, 0x00, 0x0000, None;
+    Henkan = "henkan", (0x07, 0x8A), 92, 0x1C, 0xff23, Some("変換");
+    Muhenkan = "muhenkan", (0x07, 0x8B), 94, 0x1D, 0xff22, Some("無変換");
+    KeypadJpComma = "keypad_jp_comma", (0x07, 0x8C), 95, 0x00, 0x0000, None;
+    International7 = "international_7", (0x07, 0x8D), 0, 0x00, 0x0000, None;
+    International8 = "international_8", (0x07, 0x8E), 0, 0x00, 0x0000, None;
+    International9 = "international_9", (0x07, 0x8F), 0, 0x00, 0x0000, None;
+    Hangul = "hangul", (0x07, 0x90), 122, 0x15, 0xff31, Some("한/영");
+    Hanja = "hanja", (0x07, 0x91), 123, 0x19, 0xff34, Some("漢字");
+    Katakana = "katakana", (0x07, 0x92), 90, 0x00, 0xff26, Some("カナ");
+    Hiragana = "hiragana", (0x07, 0x93), 91, 0x00, 0xff25, Some("ひらがな");
+    ZenkakuHankaku = "zenkaku_hankaku", (0x07, 0x94), 85, 0x00, 0xff2a, Some("半/全");
+    Lang6 = "lang_6", (0x07, 0x95), 0, 0x00, 0x0000, None;
+    Lang7 = "lang_7", (0x07, 0x96), 0, 0x00, 0x0000, None;
+    Lang8 = "lang_8", (0x07, 0x97), 0, 0x00, 0x0000, None;
+    Lang9 = "lang_9", (0x07, 0x98), 0, 0x00, 0x0000, None;
+    AltErase = "alt_erase", (0x07, 0x99), 222, 0x00, 0x0000, None;
+    SysReq = "sys_req", (0x07, 0x9A), 0, 0x00, 0xff15, Some("SysRq");
+    Cancel = "cancel", (0x07, 0x9B), 223, 0x00, 0xff69, Some("Cancel");
+    Clear = "clear", (0x07, 0x9C), 355, 0x0C, 0xff0b, Some("⌧");
+    Prior = "prior", (0x07, 0x9D), 0, 0x00, 0x0000, None;
+    Return = "return", (0x07, 0x9E), 0, 0x00, 0x0000, None;
+    Separator = "separator", (0x07, 0x9F), 0, 0x00, 0x0000, None;
+    Out = "out", (0x07, 0xA0), 0, 0x00, 0x0000, None;
+    Oper = "oper", (0x07, 0xA1), 0, 0x00, 0x0000, None;
+    ClearAgain = "clear_again", (0x07, 0xA2), 0, 0x00, 0x0000, None;
+    CrSel = "cr_sel", (0x07, 0xA3), 0, 0xF7, 0x0000, None;
+    ExSel = "ex_sel", (0x07, 0xA4), 0, 0xF8, 0x0000, None;
+    Keypad00 = "keypad_00", (0x07, 0xB0), 0, 0x00, 0x0000, None;
+    Keypad000 = "keypad_000", (0x07, 0xB1), 0, 0x00, 0x0000, None;
+    ThousandsSeparator = "thousands_separator", (0x07, 0xB2), 0, 0x00, 0x0000, None;
+    DecimalSeparator = "decimal_separator", (0x07, 0xB3), 0, 0x00, 0x0000, None;
+    CurrencyUnit = "currency_unit", (0x07, 0xB4), 0, 0x00, 0x0000, None;
+    CurrencySubunit = "currency_subunit", (0x07, 0xB5), 0, 0x00, 0x0000, None;
+    KeypadLeftParen = "keypad_left_paren", (0x07, 0xB6), 179, 0x00, 0x0000, None;
+    KeypadRightParen = "keypad_right_paren", (0x07, 0xB7), 180, 0x00, 0x0000, None;
+    KeypadLeftBrace = "keypad_left_brace", (0x07, 0xB8), 0, 0x00, 0x0000, None;
+    KeypadRightBrace = "keypad_right_brace", (0x07, 0xB9), 0, 0x00, 0x0000, None;
+    KeypadTab = "keypad_tab", (0x07, 0xBA), 0, 0x00, 0xff89, None;
+    KeypadBackspace = "keypad_backspace", (0x07, 0xBB), 0, 0x00, 0x0000, None;
+    KeypadA = "keypad_a", (0x07, 0xBC), 0, 0x00, 0x0000, None;
+    KeypadB = "keypad_b", (0x07, 0xBD), 0, 0x00, 0x0000, None;
+    KeypadC = "keypad_c", (0x07, 0xBE), 0, 0x00, 0x0000, None;
+    KeypadD = "keypad_d", (0x07, 0xBF), 0, 0x00, 0x0000, None;
+    KeypadE = "keypad_e", (0x07, 0xC0), 0, 0x00, 0x0000, None;
+    KeypadF = "keypad_f", (0x07, 0xC1), 0, 0x00, 0x0000, None;
+    KeypadXor = "keypad_xor", (0x07, 0xC2), 0, 0x00, 0x0000, None;
+    KeypadCaret = "keypad_caret", (0x07, 0xC3), 0, 0x00, 0x0000, None;
+    KeypadPercent = "keypad_percent", (0x07, 0xC4), 0, 0x00, 0x0000, None;
+    KeypadLess = "keypad_less", (0x07, 0xC5), 0, 0x00, 0x0000, None;
+    KeypadGreater = "keypad_greater", (0x07, 0xC6), 0, 0x00, 0x0000, None;
+    KeypadAmpersand = "keypad_ampersand", (0x07, 0xC7), 0, 0x00, 0x0000, None;
+    KeypadDoubleAmpersand = "keypad_double_ampersand", (0x07, 0xC8), 0, 0x00, 0x0000, None;
+    KeypadBar = "keypad_bar", (0x07, 0xC9), 0, 0x00, 0x0000, None;
+    KeypadDoubleBar = "keypad_double_bar", (0x07, 0xCA), 0, 0x00, 0x0000, None;
+    KeypadColon = "keypad_colon", (0x07, 0xCB), 0, 0x00, 0x0000, None;
+    KeypadHash = "keypad_hash", (0x07, 0xCC), 0, 0x00, 0x0000, None;
+    KeypadSpace = "keypad_space", (0x07, 0xCD), 0, 0x00, 0xff80, None;
+    KeypadAt = "keypad_at", (0x07, 0xCE), 0, 0x00, 0x0000, None;
+    KeypadBang = "keypad_bang", (0x07, 0xCF), 0, 0x00, 0x0000, None;
+    KeypadMemoryStore = "keypad_memory_store", (0x07, 0xD0), 0, 0x00, 0x0000, None;
+    KeypadMemoryRecall = "keypad_memory_recall", (0x07, 0xD1), 0, 0x00, 0x0000, None;
+    KeypadMemoryClear = "keypad_memory_clear", (0x07, 0xD2), 0, 0x00, 0x0000, None;
+    KeypadMemoryAdd = "keypad_memory_add", (0x07, 0xD3), 0, 0x00, 0x0000, None;
+    KeypadMemorySubtract = "keypad_memory_subtract", (0x07, 0xD4), 0, 0x00, 0x0000, None;
+    KeypadMemoryMultiply = "keypad_memory_multiply", (0x07, 0xD5), 0, 0x00, 0x0000, None;
+    KeypadMemoryDivide = "keypad_memory_divide", (0x07, 0xD6), 0, 0x00, 0x0000, None;
+    KeypadPlusMinus = "keypad_plus_minus", (0x07, 0xD7), 118, 0x00, 0x0000, None;
+    KeypadClear = "keypad_clear", (0x07, 0xD8), 0, 0x00, 0x0000, None;
+    KeypadClearEntry = "keypad_clear_entry", (0x07, 0xD9), 0, 0x00, 0x0000, None;
+    KeypadBinary = "keypad_binary", (0x07, 0xDA), 0, 0x00, 0x0000, None;
+    KeypadOctal = "keypad_octal", (0x07, 0xDB), 0, 0x00, 0x0000, None;
+    KeypadDecimalBase = "keypad_decimal_base", (0x07, 0xDC), 0, 0x00, 0x0000, None;
+    KeypadHexadecimal = "keypad_hexadecimal", (0x07, 0xDD), 0, 0x00, 0x0000, None;
+    LeftControl = "left_control", (0x07, 0xE0), 29, 0xA2, 0xffe3, Some("⌃");
+    LeftShift = "left_shift", (0x07, 0xE1), 42, 0xA0, 0xffe1, Some("⇧");
+    LeftAlt = "left_alt", (0x07, 0xE2), 56, 0xA4, 0xffe9, Some("⎇");
+    LeftMeta = "left_meta", (0x07, 0xE3), 125, 0x5B, 0xffeb, Some("❖");
+    RightControl = "right_control", (0x07, 0xE4), 97, 0xA3, 0xffe4, Some("⌃");
+    RightShift = "right_shift", (0x07, 0xE5), 54, 0xA1, 0xffe2, Some("⇧");
+    RightAlt = "right_alt", (0x07, 0xE6), 100, 0xA5, 0xffea, Some("⎇");
+    RightMeta = "right_meta", (0x07, 0xE7), 126, 0x5C, 0xffec, Some("❖");
+
+    // Right alt on layouts that use it to type extra characters (ISO_Level3_Shift)
+    AltGr = "alt_gr", (0x00, 0x00), 0, 0x00, 0xfe03, Some("AltGr");
+    // Whichever key the layout uses to start compose sequences (Multi_key)
+    Compose = "compose", (0x00, 0x00), 0, 0x00, 0xff20, Some("⎄");
+
+    // Generic desktop (0x01) and consumer (0x0C) pages
+    Sleep = "sleep", (0x01, 0x82), 142, 0x5F, 0x1008ff2f, Some("⏾");
+    MediaNextTrack = "media_next_track", (0x0C, 0xB5), 163, 0xB0, 0x1008ff17, Some("⏭");
+    MediaPreviousTrack = "media_previous_track", (0x0C, 0xB6), 165, 0xB1, 0x1008ff16, Some("⏮");
+    MediaStop = "media_stop", (0x0C, 0xB7), 166, 0xB2, 0x1008ff15, Some("⏹");
+    MediaPlayPause = "media_play_pause", (0x0C, 0xCD), 164, 0xB3, 0x1008ff14, Some("⏯");
+    LaunchMediaSelect = "launch_media_select", (0x0C, 0x183), 226, 0xB5, 0x1008ff32, Some("♫");
+    LaunchMail = "launch_mail", (0x0C, 0x18A), 155, 0xB4, 0x1008ff19, Some("✉");
+    LaunchApp2 = "launch_app2", (0x0C, 0x192), 140, 0xB7, 0x1008ff1d, Some("🖩");
+    LaunchApp1 = "launch_app1", (0x0C, 0x194), 157, 0xB6, 0x1008ff33, Some("🖥");
+    BrowserSearch = "browser_search", (0x0C, 0x221), 217, 0xAA, 0x1008ff1b, Some("🔍");
+    BrowserHome = "browser_home", (0x0C, 0x223), 172, 0xAC, 0x1008ff18, Some("⌂");
+    BrowserBack = "browser_back", (0x0C, 0x224), 158, 0xA6, 0x1008ff26, Some("⇦");
+    BrowserForward = "browser_forward", (0x0C, 0x225), 159, 0xA7, 0x1008ff27, Some("⇨");
+    BrowserStop = "browser_stop", (0x0C, 0x226), 0, 0xA9, 0x1008ff28, Some("⨯");
+    BrowserRefresh = "browser_refresh", (0x0C, 0x227), 173, 0xA8, 0x1008ff29, Some("⟳");
+    BrowserFavorites = "browser_favorites", (0x0C, 0x22A), 156, 0xAB, 0x1008ff30, Some("★");
+    Wake = "wake", (0x01, 0x83), 143, 0x00, 0x1008ff2b, Some("Wake");
+    BrightnessUp = "brightness_up", (0x0C, 0x6F), 225, 0x00, 0x1008ff02, Some("🔆");
+    BrightnessDown = "brightness_down", (0x0C, 0x70), 224, 0x00, 0x1008ff03, Some("🔅");
+    KeyboardBacklightUp = "keyboard_backlight_up", (0x0C, 0x79), 230, 0x00, 0x1008ff05, Some("⌨+");
+    KeyboardBacklightDown = "keyboard_backlight_down", (0x0C, 0x7A), 229, 0x00, 0x1008ff06, Some("⌨−");
+    KeyboardBacklightToggle = "keyboard_backlight_toggle", (0x0C, 0x7C), 228, 0x00, 0x1008ff04, Some("⌨");
+    MediaPlay = "media_play", (0x0C, 0xB0), 200, 0xFA, 0x0000, Some("▶");
+    MediaPause = "media_pause", (0x0C, 0xB1), 201, 0x00, 0x1008ff31, Some("⏸");
+    MediaRecord = "media_record", (0x0C, 0xB2), 167, 0x00, 0x1008ff1c, Some("⏺");
+    MediaFastForward = "media_fast_forward", (0x0C, 0xB3), 208, 0x00, 0x1008ff97, Some("⏩");
+    MediaRewind = "media_rewind", (0x0C, 0xB4), 168, 0x00, 0x1008ff3e, Some("⏪");
+    Eject = "eject", (0x0C, 0xB8), 161, 0x00, 0x1008ff2c, Some("⏏");
+    VoiceCommand = "voice_command", (0x0C, 0xCF), 582, 0x00, 0x0000, Some("🎤");
+    LaunchBrowser = "launch_browser", (0x0C, 0x196), 150, 0x00, 0x1008ff2e, Some("🌐");
+    LaunchCalendar = "launch_calendar", (0x0C, 0x18E), 397, 0x00, 0x1008ff20, Some("📅");
+    ScreenLock = "screen_lock", (0x0C, 0x19E), 152, 0x00, 0x1008ff2d, Some("🔒");
+    LaunchControlPanel = "launch_control_panel", (0x0C, 0x19F), 579, 0x00, 0x0000, Some("⚙");
+    LaunchFiles = "launch_files", (0x0C, 0x1B4), 144, 0x00, 0x1008ff5d, Some("🗀");
+    LaunchScreenSaver = "launch_screen_saver", (0x0C, 0x1B1), 581, 0x00, 0x0000, Some("Saver");
+    LaunchAssistant = "launch_assistant", (0x0C, 0x1CB), 583, 0x00, 0x0000, Some("Assistant");
+    New = "new", (0x0C, 0x201), 181, 0x00, 0x1008ff68, Some("New");
+    Open = "open", (0x0C, 0x202), 134, 0x00, 0x1008ff6b, Some("Open");
+    Close = "close", (0x0C, 0x203), 206, 0x00, 0x1008ff56, Some("Close");
+    Save = "save", (0x0C, 0x207), 234, 0x00, 0x1008ff77, Some("Save");
+    Print = "print", (0x0C, 0x208), 210, 0x00, 0x0000, Some("🖶");
+    ZoomIn = "zoom_in", (0x0C, 0x22D), 418, 0x00, 0x1008ff8b, Some("⊕");
+    ZoomOut = "zoom_out", (0x0C, 0x22E), 419, 0x00, 0x1008ff8c, Some("⊖");
+    Redo = "redo", (0x0C, 0x279), 182, 0x00, 0x0000, Some("↷");
+}
+
+fn nonzero<T: Default + PartialEq>(code: T) -> Option<T> {
+    if code == T::default() {
+        None
+    } else {
+        Some(code)
+    }
+}
+
+/// Inverts one of the `Key::to_*` mappings.
+fn reverse<T: Eq + std::hash::Hash>(map: impl Fn(Key) -> Option<T>) -> HashMap<T, Key> {
+    Key::ALL
+        .iter()
+        .filter_map(|&key| map(key).map(|code| (code, key)))
+        .collect()
+}
+
+static BY_NAME: LazyLock<HashMap<&'static str, Key>> =
+    LazyLock::new(|| reverse(|key| Some(key.name())));
+#[cfg(any(target_os = "linux", test))]
+static BY_EVDEV: LazyLock<HashMap<u16, Key>> = LazyLock::new(|| reverse(Key::to_evdev));
+#[cfg(any(target_os = "windows", test))]
+static BY_VK: LazyLock<HashMap<u16, Key>> = LazyLock::new(|| reverse(Key::to_vk));
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Key> {
+        BY_NAME.get(name).cloned()
+    }
+
+    #[cfg(any(target_os = "linux", test))]
+    pub fn from_evdev(code: u16) -> Option<Key> {
+        BY_EVDEV.get(&code).cloned()
+    }
+
+    /// Maps a Windows virtual key code. The left/right specific codes are
+    /// expected for modifiers, as reported by low level hooks. `VK_RETURN` is
+    /// shared by both enter keys, the caller tells them apart by the extended flag.
+    #[cfg(any(target_os = "windows", test))]
+    pub fn from_vk(vk: u16) -> Option<Key> {
+        BY_VK.get(&vk).cloned()
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+
+        Key::from_name(&name)
+            .ok_or_else(|| de::Error::custom(format_args!("unknown key `{}`", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::hash::Hash;
+
+    /// Asserts `from(to(key)) == key` for every key `to` has a code for, and
+    /// that no two keys share a code.
+    fn round_trips<T: Copy + Eq + Hash + fmt::Debug>(
+        to: impl Fn(Key) -> Option<T>,
+        from: impl Fn(T) -> Option<Key>,
+    ) -> usize {
+        let mut seen = HashMap::new();
+
+        for &key in Key::ALL {
+            if let Some(code) = to(key) {
+                if let Some(other) = seen.insert(code, key) {
+                    panic!("{:?} and {:?} both have {:?}", other, key, code);
+                }
+
+                assert_eq!(from(code), Some(key), "{:?} by {:?}", key, code);
+            }
+        }
+
+        seen.len()
+    }
+
+    #[test]
+    fn names_round_trip() {
+        assert_eq!(round_trips(|key| Some(key.name()), Key::from_name), Key::ALL.len());
+    }
+
+    #[test]
+    fn hid_usages_are_unique() {
+        let by_usage = reverse(Key::hid_usage);
+        round_trips(Key::hid_usage, |usage| by_usage.get(&usage).copied());
+    }
+
+    #[test]
+    fn evdev_codes_round_trip() {
+        round_trips(Key::to_evdev, Key::from_evdev);
+    }
+
+    #[test]
+    fn vk_codes_round_trip() {
+        round_trips(Key::to_vk, Key::from_vk);
+    }
+
+    #[test]
+    fn keysyms_are_unique() {
+        let by_keysym = reverse(Key::to_keysym);
+        round_trips(Key::to_keysym, |keysym| by_keysym.get(&keysym).copied());
+    }
+
+    #[test]
+    fn serde_round_trips() {
+        for &key in Key::ALL {
+            let json = serde_json::to_string(&key).unwrap();
+            assert_eq!(json, format!("\"{}\"", key.name()));
+            assert_eq!(serde_json::from_str::<Key>(&json).unwrap(), key);
+        }
+
+        assert!(serde_json::from_str::<Key>("\"left_shoe\"").is_err());
+    }
+
+    #[test]
+    fn names_are_snake_case() {
+        for &key in Key::ALL {
+            let name = key.name();
+            let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_';
+
+            assert!(name.chars().all(valid), "{}", name);
+            assert!(!name.starts_with('_') && !name.ends_with('_') && !name.contains("__"));
+        }
+    }
+
+    #[test]
+    fn keyboard_page_is_complete() {
+        let listed: HashSet<u16> = Key::ALL
+            .iter()
+            .filter_map(|key| key.hid_usage())
+            .filter(|(page, _)| *page == 0x07)
+            .map(|(_, usage)| usage)
+            .collect();
+
+        // Everything from `a` to right meta that the HID tables don't reserve.
+        let reserved = |usage: u16| matches!(usage, 0xA5..=0xAF | 0xDE..=0xDF);
+
+        for usage in (0x04..=0xE7).filter(|usage| !reserved(*usage)) {
+            assert!(listed.contains(&usage), "keyboard usage {:#04x}", usage);
+        }
+
+        assert!(listed.iter().all(|usage| !reserved(*usage)));
+    }
+
+    #[test]
+    fn modifiers_have_sides_and_glyphs() {
+        for (left, right) in &[
+            (Key::LeftShift, Key::RightShift),
+            (Key::LeftControl, Key::RightControl),
+            (Key::LeftAlt, Key::RightAlt),
+            (Key::LeftMeta, Key::RightMeta),
+        ] {
+            assert_ne!(left.to_evdev(), right.to_evdev());
+            assert_ne!(left.to_vk(), right.to_vk());
+            assert_eq!(left.glyph(), right.glyph());
+            assert!(left.glyph().is_some());
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Input events and the platform independent parts of processing them.
+//!
+//! Each platform module only produces, and uses, part of what is here.
+
+mod clock;
+mod compose;
+mod device;
+mod gamepad;
+mod key;
+mod modifiers;
+mod mouse;
+mod utf16;
+
+pub use self::clock::Timestamp;
+pub use self::compose::{Composed, Composer};
+pub use self::device::DeviceId;
+#[cfg(target_os = "linux")]
+pub use self::gamepad::DPad;
+pub use self::gamepad::{Axis, AxisFilter, ButtonLabels, GamepadButton};
+pub use self::key::Key;
+pub use self::modifiers::{Lock, Locks, ModifierState, Transition};
+pub use self::mouse::{scroll_glyph, MouseButton};
+pub use self::utf16::{Utf16Decoder, SURROGATE_TIMEOUT};
+
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    Key { key: Key, key_state: KeyState },
+    Char(char),
+    /// A dead key was pressed, carrying the spacing form of its accent.
+    DeadKey(char),
+    /// One UTF-16 unit of typed text, as Windows gives it. The two halves of
+    /// a surrogate pair can arrive in separate events.
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    Utf16(u16),
+    /// A key press on Windows, as its virtual key and scan code, for the
+    /// processor to type in the layout of the window it went to.
+    #[cfg(target_os = "windows")]
+    Untranslated { vk: u32, scan: u32 },
+    /// A key event on Linux, as its evdev code and value, for the processor
+    /// to type in the keyboard layout. The value is 0 for a release, 1 for a
+    /// press and 2 for autorepeat.
+    #[cfg(target_os = "linux")]
+    Untranslated { code: u16, value: i32 },
+    MouseButton {
+        button: MouseButton,
+        key_state: KeyState,
+    },
+    /// Wheel movement in notches, positive is up and right.
+    Scroll { dx: f32, dy: f32 },
+    /// Pointer position relative to the top left corner of the screen.
+    MouseMove { x: i32, y: i32 },
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    GamepadButton {
+        button: GamepadButton,
+        key_state: KeyState,
+    },
+    /// Stick position from -1 to 1, positive is right and down, or trigger
+    /// position from 0 to 1. Raw, without a dead zone.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    GamepadAxis { axis: Axis, value: f32 },
+}
+
+impl Event {
+    /// Snake case name of the variant, as used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::Key { .. } => "key",
+            Event::Char(_) => "char",
+            Event::DeadKey(_) => "dead_key",
+            Event::Utf16(_) => "utf16",
+            Event::Untranslated { .. } => "untranslated",
+            Event::MouseButton { .. } => "mouse_button",
+            Event::Scroll { .. } => "scroll",
+            Event::MouseMove { .. } => "mouse_move",
+            Event::GamepadButton { .. } => "gamepad_button",
+            Event::GamepadAxis { .. } => "gamepad_axis",
+        }
+    }
+}
+
+/// An event as handed over by the platform's hook.
+#[derive(Debug, Clone)]
+pub struct Input {
+    pub event: Event,
+    /// `None` where the platform doesn't say which device the event came from.
+    pub device: Option<Arc<DeviceId>>,
+    /// Generated by software, like a macro tool or remote desktop, rather than typed.
+    pub injected: bool,
+    /// When the source says the event happened.
+    pub time: Timestamp,
+    /// The window that had focus at the time, as `get_fg_window` gives it.
+    pub window: usize,
+}
@@ -0,0 +1,324 @@
+//! Tracking of held modifiers and lock key toggles.
+//!
+//! Modifiers are kept per side, so clients can tell right alt from left alt
+//! and AltGr from both. Lock keys are tracked by whether their lock is engaged
+//! rather than whether the key is held, platforms seed that state at startup
+//! since it can't be observed from key events alone.
+
+use super::{Key, KeyState};
+
+/// Held modifiers, left and right kept apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_control: bool,
+    pub right_control: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub alt_gr: bool,
+    pub left_meta: bool,
+    pub right_meta: bool,
+}
+
+impl Modifiers {
+    fn get_mut(&mut self, key: Key) -> Option<&mut bool> {
+        match key {
+            Key::LeftShift => Some(&mut self.left_shift),
+            Key::RightShift => Some(&mut self.right_shift),
+            Key::LeftControl => Some(&mut self.left_control),
+            Key::RightControl => Some(&mut self.right_control),
+            Key::LeftAlt => Some(&mut self.left_alt),
+            Key::RightAlt => Some(&mut self.right_alt),
+            Key::AltGr => Some(&mut self.alt_gr),
+            Key::LeftMeta => Some(&mut self.left_meta),
+            Key::RightMeta => Some(&mut self.right_meta),
+            _ => None,
+        }
+    }
+
+    /// Held modifiers, in the order they're shown in chords.
+    pub fn keys(&self) -> Vec<Key> {
+        let order = [
+            (self.left_control, Key::LeftControl),
+            (self.right_control, Key::RightControl),
+            (self.left_alt, Key::LeftAlt),
+            (self.right_alt, Key::RightAlt),
+            (self.alt_gr, Key::AltGr),
+            (self.left_shift, Key::LeftShift),
+            (self.right_shift, Key::RightShift),
+            (self.left_meta, Key::LeftMeta),
+            (self.right_meta, Key::RightMeta),
+        ];
+
+        order
+            .iter()
+            .filter(|(held, _)| *held)
+            .map(|(_, key)| *key)
+            .collect()
+    }
+
+    /// Prefixes `glyph` with the held modifiers, as in `⌃+Click`.
+    pub fn chord(&self, glyph: &str) -> String {
+        let mut parts: Vec<&str> = self.keys().into_iter().filter_map(Key::glyph).collect();
+        // Both sides of a modifier held are shown once.
+        parts.dedup();
+        parts.push(glyph);
+        parts.join("+")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum Lock {
+    CapsLock,
+    NumLock,
+    ScrollLock,
+}
+
+impl Lock {
+    pub fn from_key(key: Key) -> Option<Lock> {
+        match key {
+            Key::CapsLock => Some(Lock::CapsLock),
+            Key::NumLock => Some(Lock::NumLock),
+            Key::ScrollLock => Some(Lock::ScrollLock),
+            _ => None,
+        }
+    }
+
+    pub fn key(self) -> Key {
+        match self {
+            Lock::CapsLock => Key::CapsLock,
+            Lock::NumLock => Key::NumLock,
+            Lock::ScrollLock => Key::ScrollLock,
+        }
+    }
+}
+
+/// Which locks are engaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Locks {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Locks {
+    fn get_mut(&mut self, lock: Lock) -> &mut bool {
+        match lock {
+            Lock::CapsLock => &mut self.caps_lock,
+            Lock::NumLock => &mut self.num_lock,
+            Lock::ScrollLock => &mut self.scroll_lock,
+        }
+    }
+}
+
+/// What a key event meant, given the keys held before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Pressed,
+    /// Autorepeat of a held modifier or lock key.
+    Repeated,
+    Released,
+    /// A lock key was pressed, the lock is now engaged or not.
+    Toggled(Lock, bool),
+}
+
+#[derive(Debug, Default)]
+pub struct ModifierState {
+    pub held: Modifiers,
+    pub locks: Locks,
+    /// Lock keys currently held down, to ignore their autorepeat.
+    lock_keys: Locks,
+}
+
+impl ModifierState {
+    pub fn new(locks: Locks) -> Self {
+        Self {
+            locks,
+            ..Self::default()
+        }
+    }
+
+    pub fn update(&mut self, key: Key, key_state: KeyState) -> Transition {
+        let pressed = key_state == KeyState::Pressed;
+
+        if let Some(held) = self.held.get_mut(key) {
+            let was_held = *held;
+            *held = pressed;
+
+            return match (was_held, pressed) {
+                (true, true) => Transition::Repeated,
+                (_, true) => Transition::Pressed,
+                (_, false) => Transition::Released,
+            };
+        }
+
+        if let Some(lock) = Lock::from_key(key) {
+            let held = self.lock_keys.get_mut(lock);
+            let was_held = *held;
+            *held = pressed;
+
+            return match (was_held, pressed) {
+                (true, true) => Transition::Repeated,
+                (_, true) => {
+                    let engaged = self.locks.get_mut(lock);
+                    *engaged = !*engaged;
+
+                    Transition::Toggled(lock, *engaged)
+                }
+                (_, false) => Transition::Released,
+            };
+        }
+
+        if pressed {
+            Transition::Pressed
+        } else {
+            Transition::Released
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use KeyState::{Pressed, Released};
+
+    const SIDES: &[(Key, Key)] = &[
+        (Key::LeftShift, Key::RightShift),
+        (Key::LeftControl, Key::RightControl),
+        (Key::LeftAlt, Key::RightAlt),
+        (Key::LeftMeta, Key::RightMeta),
+    ];
+
+    #[test]
+    fn sides_are_kept_apart() {
+        for &(left, right) in SIDES {
+            let mut state = ModifierState::default();
+
+            assert_eq!(state.update(left, Pressed), Transition::Pressed);
+            assert_eq!(state.held.keys(), [left]);
+            assert_eq!(state.update(right, Pressed), Transition::Pressed);
+            assert_eq!(state.held.keys(), [left, right]);
+
+            assert_eq!(state.update(left, Released), Transition::Released);
+            assert_eq!(state.held.keys(), [right]);
+            assert_eq!(state.update(right, Released), Transition::Released);
+            assert_eq!(state.held, Modifiers::default());
+        }
+    }
+
+    #[test]
+    fn modifier_autorepeat_is_a_repeat() {
+        let mut state = ModifierState::default();
+
+        assert_eq!(state.update(Key::LeftShift, Pressed), Transition::Pressed);
+        assert_eq!(state.update(Key::LeftShift, Pressed), Transition::Repeated);
+        assert_eq!(state.update(Key::LeftShift, Pressed), Transition::Repeated);
+        assert_eq!(state.update(Key::LeftShift, Released), Transition::Released);
+        assert_eq!(state.update(Key::LeftShift, Pressed), Transition::Pressed);
+
+        // Other keys don't repeat as such, they type again.
+        assert_eq!(state.update(Key::A, Pressed), Transition::Pressed);
+        assert_eq!(state.update(Key::A, Pressed), Transition::Pressed);
+        assert_eq!(state.update(Key::A, Released), Transition::Released);
+    }
+
+    #[test]
+    fn caps_lock_toggles_once_per_press() {
+        let mut state = ModifierState::default();
+
+        assert_eq!(
+            state.update(Key::CapsLock, Pressed),
+            Transition::Toggled(Lock::CapsLock, true)
+        );
+        assert_eq!(state.update(Key::CapsLock, Pressed), Transition::Repeated);
+        assert_eq!(state.update(Key::CapsLock, Pressed), Transition::Repeated);
+        assert!(state.locks.caps_lock);
+        assert_eq!(state.update(Key::CapsLock, Released), Transition::Released);
+        assert!(state.locks.caps_lock);
+
+        assert_eq!(
+            state.update(Key::CapsLock, Pressed),
+            Transition::Toggled(Lock::CapsLock, false)
+        );
+        assert_eq!(state.update(Key::CapsLock, Released), Transition::Released);
+        assert_eq!(state.locks, Locks::default());
+        assert_eq!(state.held, Modifiers::default());
+    }
+
+    #[test]
+    fn locks_start_as_seeded() {
+        let locks = Locks {
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: true,
+        };
+        let mut state = ModifierState::new(locks);
+
+        assert_eq!(
+            state.update(Key::NumLock, Pressed),
+            Transition::Toggled(Lock::NumLock, false)
+        );
+        assert_eq!(
+            state.update(Key::ScrollLock, Pressed),
+            Transition::Toggled(Lock::ScrollLock, false)
+        );
+        assert_eq!(
+            state.update(Key::CapsLock, Pressed),
+            Transition::Toggled(Lock::CapsLock, true)
+        );
+
+        for lock in [Lock::CapsLock, Lock::NumLock, Lock::ScrollLock] {
+            assert_eq!(Lock::from_key(lock.key()), Some(lock));
+        }
+    }
+
+    #[test]
+    fn alt_gr_is_its_own_modifier() {
+        let mut state = ModifierState::default();
+
+        assert_eq!(state.update(Key::AltGr, Pressed), Transition::Pressed);
+        assert!(state.held.alt_gr);
+        assert!(!state.held.right_alt);
+        assert_eq!(state.held.keys(), [Key::AltGr]);
+        assert_eq!(state.held.chord("Click"), "AltGr+Click");
+
+        assert_eq!(state.update(Key::AltGr, Released), Transition::Released);
+        assert_eq!(state.held, Modifiers::default());
+    }
+
+    #[test]
+    fn chords_show_each_modifier_once() {
+        let mut state = ModifierState::default();
+
+        assert_eq!(state.held.chord("Click"), "Click");
+
+        for key in [
+            Key::LeftShift,
+            Key::RightControl,
+            Key::LeftControl,
+            Key::RightShift,
+        ] {
+            state.update(key, Pressed);
+        }
+
+        // In the order of `Modifiers::keys`, both sides shown as one.
+        assert_eq!(
+            state.held.keys(),
+            [
+                Key::LeftControl,
+                Key::RightControl,
+                Key::LeftShift,
+                Key::RightShift
+            ]
+        );
+        assert_eq!(state.held.chord("Click"), "⌃+⇧+Click");
+
+        state.update(Key::LeftMeta, Pressed);
+        state.update(Key::LeftAlt, Pressed);
+        assert_eq!(state.held.chord("🖱↑"), "⌃+⎇+⇧+❖+🖱↑");
+    }
+}
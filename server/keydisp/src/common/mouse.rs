@@ -0,0 +1,38 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    /// The first side button, back in browsers.
+    Back,
+    /// The second side button, forward in browsers.
+    Forward,
+}
+
+impl MouseButton {
+    pub fn glyph(self) -> &'static str {
+        match self {
+            MouseButton::Left => "Click",
+            MouseButton::Right => "Right Click",
+            MouseButton::Middle => "Middle Click",
+            MouseButton::Back => "Back Click",
+            MouseButton::Forward => "Forward Click",
+        }
+    }
+}
+
+/// What the overlay shows for a scroll, by its main direction.
+pub fn scroll_glyph(dx: f32, dy: f32) -> &'static str {
+    if dy.abs() >= dx.abs() {
+        if dy > 0.0 {
+            "Scroll ↑"
+        } else {
+            "Scroll ↓"
+        }
+    } else if dx > 0.0 {
+        "Scroll →"
+    } else {
+        "Scroll ←"
+    }
+}
@@ -0,0 +1,168 @@
+//! Decoding of UTF-16 as it trickles in from key events.
+//!
+//! Characters outside the basic multilingual plane arrive as surrogate pairs,
+//! and the two halves don't always arrive in the same event: `VK_PACKET`
+//! injects one unit per key event. A high surrogate is held until the next
+//! unit is pushed, or reported as unpaired once it has waited too long.
+
+use std::char;
+use std::time::{Duration, Instant};
+
+/// How long a high surrogate waits for its low half.
+pub const SURROGATE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A surrogate without its other half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnpairedSurrogate(pub u16);
+
+pub type Decoded = Result<char, UnpairedSurrogate>;
+
+#[derive(Debug)]
+pub struct Utf16Decoder {
+    high: Option<(u16, Instant)>,
+    timeout: Duration,
+}
+
+impl Utf16Decoder {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            high: None,
+            timeout,
+        }
+    }
+
+    /// Decodes one more unit, yielding up to two results: an unpaired high
+    /// surrogate it interrupts, and whatever it completes.
+    pub fn push(&mut self, unit: u16, now: Instant) -> Vec<Decoded> {
+        let mut decoded: Vec<Decoded> = self.flush_expired(now).into_iter().collect();
+
+        match (self.high.take(), unit) {
+            (Some((high, _)), 0xDC00..=0xDFFF) => {
+                let c = 0x1_0000 + ((u32::from(high) - 0xD800) << 10 | (u32::from(unit) - 0xDC00));
+                decoded.push(char::from_u32(c).ok_or(UnpairedSurrogate(high)));
+            }
+            (high, _) => {
+                if let Some((high, _)) = high {
+                    decoded.push(Err(UnpairedSurrogate(high)));
+                }
+
+                match unit {
+                    0xD800..=0xDBFF => self.high = Some((unit, now)),
+                    _ => {
+                        decoded.push(char::from_u32(u32::from(unit)).ok_or(UnpairedSurrogate(unit)))
+                    }
+                }
+            }
+        }
+
+        decoded
+    }
+
+    /// When a held high surrogate is given up on, unless its low half comes.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.high.map(|(_, since)| since + self.timeout)
+    }
+
+    /// Gives up on a high surrogate that has waited longer than the timeout.
+    pub fn flush_expired(&mut self, now: Instant) -> Option<Decoded> {
+        match self.high {
+            Some((high, since)) if now.duration_since(since) >= self.timeout => {
+                self.high = None;
+                Some(Err(UnpairedSurrogate(high)))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+
+    /// What the standard library makes of `units` as a whole.
+    fn expected(units: &[u16]) -> Vec<Decoded> {
+        char::decode_utf16(units.iter().cloned())
+            .map(|decoded| decoded.map_err(|e| UnpairedSurrogate(e.unpaired_surrogate())))
+            .collect()
+    }
+
+    /// Pushes `units` at the given times, then waits out a held surrogate.
+    fn decode(units: impl IntoIterator<Item = (u16, Instant)>) -> Vec<Decoded> {
+        let mut decoder = Utf16Decoder::new(SURROGATE_TIMEOUT);
+        let mut decoded = Vec::new();
+
+        for (unit, now) in units {
+            decoded.extend(decoder.push(unit, now));
+        }
+
+        if let Some(deadline) = decoder.deadline() {
+            decoded.extend(decoder.flush_expired(deadline));
+        }
+
+        assert_eq!(decoder.deadline(), None);
+        decoded
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_like_std(units in prop::collection::vec(any::<u16>(), 0..64)) {
+            let now = Instant::now();
+            prop_assert_eq!(decode(units.iter().map(|&unit| (unit, now))), expected(&units));
+        }
+
+        #[test]
+        fn decodes_surrogate_heavy_input(
+            units in prop::collection::vec(
+                prop_oneof![0xD7F0..=0xE010u16, any::<u16>()],
+                0..64,
+            ),
+        ) {
+            let now = Instant::now();
+            prop_assert_eq!(decode(units.iter().map(|&unit| (unit, now))), expected(&units));
+        }
+
+        #[test]
+        fn pairs_within_the_timeout(text in ".*", gaps in prop::collection::vec(0..500u64, 64)) {
+            let mut now = Instant::now();
+            let units = text.encode_utf16().zip(gaps.iter().cycle()).map(|(unit, gap)| {
+                now += Duration::from_millis(*gap);
+                (unit, now)
+            });
+            let decoded: Result<String, _> = decode(units).into_iter().collect();
+
+            prop_assert_eq!(decoded, Ok(text));
+        }
+    }
+
+    #[test]
+    fn high_surrogate_expires() {
+        let start = Instant::now();
+        let mut decoder = Utf16Decoder::new(SURROGATE_TIMEOUT);
+
+        assert_eq!(decoder.push(0xD83D, start), []);
+        assert_eq!(decoder.deadline(), Some(start + SURROGATE_TIMEOUT));
+        assert_eq!(decoder.flush_expired(start + SURROGATE_TIMEOUT / 2), None);
+        assert_eq!(
+            decoder.flush_expired(start + SURROGATE_TIMEOUT),
+            Some(Err(UnpairedSurrogate(0xD83D)))
+        );
+        assert_eq!(decoder.deadline(), None);
+    }
+
+    #[test]
+    fn late_low_surrogate_is_unpaired() {
+        let start = Instant::now();
+        let mut decoder = Utf16Decoder::new(SURROGATE_TIMEOUT);
+
+        decoder.push(0xD83D, start);
+        assert_eq!(
+            decoder.push(0xDE00, start + SURROGATE_TIMEOUT),
+            [
+                Err(UnpairedSurrogate(0xD83D)),
+                Err(UnpairedSurrogate(0xDE00))
+            ]
+        );
+    }
+}
@@ -0,0 +1,573 @@
+use crate::common::{ButtonLabels, DeviceId};
+use crate::render::Color;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const DEFAULT_CONFIG_FILE: &str = "keydisp.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    /// Only display input going to the selected window.
+    Window,
+    /// Display all input, regardless of which window has focus.
+    Everything,
+}
+
+/// What to do with input generated by software, see `Input::injected`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectedPolicy {
+    /// Display it like typed input.
+    Show,
+    /// Display it, set apart from typed input.
+    Mark,
+    /// Don't display it.
+    Hide,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Shared secrets clients must present, no tokens disables authentication.
+    pub tokens: Vec<String>,
+    /// Accepted `Origin` headers, an empty list accepts any origin.
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key, PKCS#8 or RSA.
+    pub key: PathBuf,
+    /// Create a self-signed certificate if neither `cert` nor `key` exists.
+    pub generate_self_signed: bool,
+    /// Names and addresses the generated certificate is valid for.
+    pub hostnames: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::from("keydisp.crt"),
+            key: PathBuf::from("keydisp.key"),
+            generate_self_signed: true,
+            hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GamepadConfig {
+    /// Which printed labels to show for the face and shoulder buttons.
+    pub labels: ButtonLabels,
+    /// Stick and trigger positions closer to rest than this are reported as 0.
+    pub deadzone: f32,
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        Self {
+            labels: ButtonLabels::Xbox,
+            deadzone: 0.15,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JsonLinesConfig {
+    /// A file or named pipe to write to, standard output if not set.
+    pub path: Option<PathBuf>,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ObsConfig {
+    /// Address of obs-websocket, version 5 or later.
+    pub url: String,
+    /// As set under Tools, WebSocket Server Settings, if authentication is on.
+    pub password: Option<String>,
+    /// Name of the text source, GDI+ or FreeType 2, to show keystrokes in.
+    pub source: String,
+    /// Show the source while typing and hide it once the keystrokes fade.
+    pub toggle_visibility: bool,
+    /// Scene the source is toggled in, the current program scene if not set.
+    pub scene: Option<String>,
+    /// Grouping and lifetime of keystrokes, the looks are the text source's.
+    pub theme: ThemeConfig,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+impl Default for ObsConfig {
+    fn default() -> Self {
+        Self {
+            url: "ws://127.0.0.1:4455".to_string(),
+            password: None,
+            source: "keydisp".to_string(),
+            toggle_visibility: false,
+            scene: None,
+            theme: ThemeConfig::default(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OscConfig {
+    /// Where to send messages, as `host:port`.
+    pub targets: Vec<String>,
+    pub addresses: OscAddresses,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+/// Address patterns per event type, where `{field}` is replaced by that
+/// field of the event as sent to JSON clients. Empty ones aren't sent.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OscAddresses {
+    pub key: String,
+    pub release: String,
+    pub char: String,
+    pub pending: String,
+    pub lock: String,
+    pub mouse_button: String,
+    pub scroll: String,
+    pub mouse_move: String,
+    pub gamepad_button: String,
+    pub gamepad_axis: String,
+}
+
+impl Default for OscAddresses {
+    fn default() -> Self {
+        Self {
+            key: "/keydisp/key/down".to_string(),
+            release: "/keydisp/key/up".to_string(),
+            char: "/keydisp/char".to_string(),
+            pending: "/keydisp/pending".to_string(),
+            lock: "/keydisp/lock".to_string(),
+            mouse_button: "/keydisp/mouse/button".to_string(),
+            scroll: "/keydisp/mouse/scroll".to_string(),
+            mouse_move: "/keydisp/mouse/move".to_string(),
+            gamepad_button: "/keydisp/gamepad/button".to_string(),
+            gamepad_axis: "/keydisp/gamepad/axis".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    /// The broker as `host:port`, spoken to over plain TCP.
+    pub broker: String,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Quality of service for everything published, 0 to 2.
+    pub qos: u8,
+    /// Where every event is published as JSON, nothing is when empty.
+    pub events_topic: String,
+    /// Where `typing` and `idle` are published, and `offline` by the broker
+    /// when the connection is lost. Nothing is when empty.
+    pub state_topic: String,
+    /// Whether the broker keeps the last state for new subscribers.
+    pub retain_state: bool,
+    /// How long after the last keystroke `idle` is published.
+    pub idle_after_ms: u64,
+    /// Seconds between pings when nothing else is sent.
+    pub keep_alive: u16,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            broker: "127.0.0.1:1883".to_string(),
+            client_id: "keydisp".to_string(),
+            username: None,
+            password: None,
+            qos: 0,
+            events_topic: "keydisp/events".to_string(),
+            state_topic: "keydisp/state".to_string(),
+            retain_state: true,
+            idle_after_ms: 2000,
+            keep_alive: 30,
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// What a webhook is sent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookPayload {
+    /// Every event, as sent to JSON clients.
+    #[default]
+    Events,
+    /// A summary per stretch of typing, with the shortcuts used.
+    Sessions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// Where batches are posted, `http://` or `https://`.
+    pub url: String,
+    /// Signs each body with HMAC-SHA256 when present.
+    pub secret: Option<String>,
+    pub payload: WebhookPayload,
+    /// How long events are collected before they're posted together.
+    pub batch_ms: u64,
+    /// Batches are posted early once they're this big.
+    pub max_batch: usize,
+    /// How long without keyboard input ends a typing session.
+    pub session_gap_ms: u64,
+    /// Tries per batch before it's given up on.
+    pub max_attempts: u32,
+    pub timeout_ms: u64,
+    /// PEM certificates to trust for `https://`, the system's when absent.
+    pub ca_file: Option<PathBuf>,
+    /// Only these event types, all types if empty.
+    pub types: Vec<String>,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            secret: None,
+            payload: WebhookPayload::default(),
+            batch_ms: 1000,
+            max_batch: 500,
+            session_gap_ms: 5000,
+            max_attempts: 5,
+            timeout_ms: 10_000,
+            ca_file: None,
+            types: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// How the overlay is drawn without a browser, `style.css` by default.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ThemeConfig {
+    /// A TrueType font, the bundled DejaVu Sans Mono when not set or for
+    /// glyphs it doesn't have.
+    pub font: Option<PathBuf>,
+    /// In pixels.
+    pub font_size: f32,
+    pub color: Color,
+    /// Behind everything, transparent by default.
+    pub background: Color,
+    /// Drawn behind each group of keystrokes when set.
+    pub bubble: Option<Color>,
+    /// A pause this long starts a new group.
+    pub group_ms: u64,
+    /// Groups longer than this many characters are closed.
+    pub group_chars: usize,
+    /// How long a group is shown, from its first keystroke.
+    pub lifetime_ms: u64,
+    /// The end of the lifetime spent fading out.
+    pub fade_ms: u64,
+}
+
+impl Default for ThemeConfig {
+    fn default() -> Self {
+        Self {
+            font: None,
+            font_size: 48.0,
+            color: Color::rgba(0, 0, 0, 255),
+            background: Color::TRANSPARENT,
+            bubble: None,
+            group_ms: 250,
+            group_chars: 6,
+            lifetime_ms: 5000,
+            fade_ms: 500,
+        }
+    }
+}
+
+/// How rendered frames are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RenderFormat {
+    /// RGBA pixels, frame after frame, as ffmpeg's `rawvideo`.
+    #[default]
+    Raw,
+    /// One PNG file per frame.
+    Png,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RenderConfig {
+    pub format: RenderFormat,
+    /// For `raw` a file or named pipe, for `png` the directory to fill.
+    pub path: Option<PathBuf>,
+    /// A program and its arguments that gets `raw` frames on its standard
+    /// input, standard output is used if neither this nor `path` is set.
+    pub command: Vec<String>,
+    pub width: usize,
+    pub height: usize,
+    /// Frames per second.
+    pub fps: u32,
+    pub theme: ThemeConfig,
+    /// Only input from these devices, all devices if empty.
+    pub devices: Vec<String>,
+}
+
+impl Default for RenderConfig {
+    fn default() -> Self {
+        Self {
+            format: RenderFormat::default(),
+            path: None,
+            command: Vec::new(),
+            width: 1280,
+            height: 720,
+            fps: 30,
+            theme: ThemeConfig::default(),
+            devices: Vec::new(),
+        }
+    }
+}
+
+/// How `keydisp export` draws captured keystrokes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExportConfig {
+    pub width: usize,
+    pub height: usize,
+    /// Frames per second, identical frames in a row are merged.
+    pub fps: u32,
+    pub theme: ThemeConfig,
+    /// The colours GIFs are limited to, taken from the theme if empty.
+    pub palette: Vec<Color>,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 240,
+            fps: 25,
+            theme: ThemeConfig::default(),
+            palette: Vec::new(),
+        }
+    }
+}
+
+/// Somewhere outputs are sent besides websocket clients, see `sink`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    JsonLines(JsonLinesConfig),
+    Obs(ObsConfig),
+    Osc(OscConfig),
+    Mqtt(MqttConfig),
+    Webhook(WebhookConfig),
+    Render(RenderConfig),
+}
+
+/// The XKB layout characters are typed in on Linux, as given to `setxkbmap`.
+/// When `layout` isn't set it is taken from `XKB_DEFAULT_LAYOUT`, or from the
+/// system's keyboard configuration. Windows follows the focused window's layout.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    pub layout: Option<String>,
+    pub variant: Option<String>,
+    pub options: Option<String>,
+}
+
+/// Which devices to display input from, as patterns described in `common::device`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DevicesConfig {
+    /// Only these devices, an empty list includes every device.
+    pub include: Vec<String>,
+    /// Never these devices, even when included.
+    pub exclude: Vec<String>,
+}
+
+impl DevicesConfig {
+    /// Input from unknown devices is always allowed, there is nothing to match.
+    pub fn allows(&self, device: Option<&DeviceId>) -> bool {
+        match device {
+            Some(device) => {
+                (self.include.is_empty() || device.matches_any(&self.include))
+                    && !device.matches_any(&self.exclude)
+            }
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bind: String,
+    /// Further connections are turned away with 503 Service Unavailable.
+    pub max_clients: usize,
+    pub filter_mode: FilterMode,
+    pub auth: AuthConfig,
+    /// Serve `wss://` instead of `ws://` when present.
+    pub tls: Option<TlsConfig>,
+    /// Send pointer movement to clients, off by default as it is chatty.
+    pub mouse_move: bool,
+    pub injected: InjectedPolicy,
+    pub gamepad: GamepadConfig,
+    pub devices: DevicesConfig,
+    /// Read when input capture starts.
+    pub keyboard: KeyboardConfig,
+    /// Serve websocket clients on `bind`, off leaves only the sinks.
+    pub websocket: bool,
+    /// Also serve `GET /metrics` here, which works with `websocket` off.
+    pub metrics_addr: Option<String>,
+    pub sinks: Vec<SinkConfig>,
+    pub export: ExportConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:2945".to_string(),
+            max_clients: 32,
+            filter_mode: FilterMode::Window,
+            auth: AuthConfig::default(),
+            tls: None,
+            mouse_move: false,
+            injected: InjectedPolicy::Show,
+            gamepad: GamepadConfig::default(),
+            devices: DevicesConfig::default(),
+            keyboard: KeyboardConfig::default(),
+            websocket: true,
+            metrics_addr: None,
+            sinks: Vec::new(),
+            export: ExportConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read config: {}", e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl Config {
+    /// `$KEYDISP_CONFIG` if set, otherwise `keydisp.toml` in the working directory.
+    pub fn path() -> PathBuf {
+        env::var_os("KEYDISP_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE))
+    }
+
+    /// Reads the settings file, a missing file gives the default settings.
+    pub fn load() -> Result<Config, ConfigError> {
+        match fs::read_to_string(Self::path()) {
+            Ok(contents) => toml::from_str(&contents).map_err(ConfigError::Parse),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(ConfigError::Io(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str, vendor: u16, product: u16) -> DeviceId {
+        DeviceId {
+            name: name.to_string(),
+            vendor,
+            product,
+            phys: String::new(),
+        }
+    }
+
+    fn patterns(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn no_device_lists_allow_everything() {
+        let devices = DevicesConfig::default();
+
+        assert!(devices.allows(Some(&device("Keyboard", 0x046d, 0xc52b))));
+        assert!(devices.allows(None));
+    }
+
+    #[test]
+    fn only_included_devices_are_allowed() {
+        let devices = DevicesConfig {
+            include: patterns(&["046d:c52b", "player two"]),
+            exclude: vec![],
+        };
+
+        assert!(devices.allows(Some(&device("Keyboard", 0x046d, 0xc52b))));
+        assert!(devices.allows(Some(&device("Player Two Pad", 0x045e, 0x028e))));
+        assert!(!devices.allows(Some(&device("Macro Pad", 0x1209, 0x0001))));
+        // Nothing to match, as on Windows.
+        assert!(devices.allows(None));
+    }
+
+    #[test]
+    fn excluded_devices_are_left_out_even_when_included() {
+        let devices = DevicesConfig {
+            include: patterns(&["keyboard"]),
+            exclude: patterns(&["1209:0001"]),
+        };
+
+        assert!(devices.allows(Some(&device("Keyboard", 0x046d, 0xc52b))));
+        assert!(!devices.allows(Some(&device("Macro Keyboard", 0x1209, 0x0001))));
+
+        let devices = DevicesConfig {
+            include: vec![],
+            exclude: patterns(&["macro"]),
+        };
+
+        assert!(devices.allows(Some(&device("Keyboard", 0x046d, 0xc52b))));
+        assert!(!devices.allows(Some(&device("Macro Pad", 0x1209, 0x0001))));
+    }
+
+    #[test]
+    fn device_lists_are_read() {
+        let config: Config = toml::from_str(
+            r#"
+            [devices]
+            include = ["046d:c52b"]
+            exclude = ["Macro Pad"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.devices.include, ["046d:c52b"]);
+        assert_eq!(config.devices.exclude, ["Macro Pad"]);
+    }
+}
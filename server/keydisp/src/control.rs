@@ -0,0 +1,300 @@
+//! Local control endpoint for scripting a running instance.
+//!
+//! Listens on a Unix socket under `$XDG_RUNTIME_DIR` and speaks line based
+//! JSON: each line from the client is one request, answered by one line.
+//! Access is governed by the file mode of the socket and its directory, so
+//! only the user running keydisp can connect.
+//!
+//! ```text
+//! {"command": "select_window", "window": 4242}
+//! {"command": "pause"}
+//! {"command": "resume"}
+//! {"command": "set_filter_mode", "mode": "everything"}
+//! {"command": "reload_config"}
+//! {"command": "stats"}
+//! ```
+
+use crate::config::{Config, FilterMode};
+use crate::state::{SharedState, Stats};
+
+use std::env;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    /// Select the window to display input from, `null` clears the selection.
+    SelectWindow { window: Option<usize> },
+    Pause,
+    Resume,
+    SetFilterMode { mode: FilterMode },
+    ReloadConfig,
+    Stats,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Response {
+    Ok,
+    Stats { stats: Stats },
+    Error { message: String },
+}
+
+pub fn socket_path() -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join("keydisp")
+        .join("control.sock")
+}
+
+/// Binds the control socket and serves it on the runtime it is called from.
+pub fn spawn(state: SharedState) -> io::Result<PathBuf> {
+    let path = socket_path();
+    spawn_at(&path, state)?;
+    Ok(path)
+}
+
+fn spawn_at(path: &Path, state: SharedState) -> io::Result<()> {
+    let dir = path.parent().expect("socket path to have a parent.");
+
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    fs::set_permissions(dir, Permissions::from_mode(0o700))?;
+
+    // A socket file left behind by an instance that didn't shut down cleanly
+    // refuses connections, one that accepts them belongs to a running instance.
+    if path.exists() {
+        if StdUnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another keydisp instance is running",
+            ));
+        }
+
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve(stream, &state).await {
+                            eprintln!("Control connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Control socket error: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn serve(stream: UnixStream, state: &SharedState) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle(request, state),
+            Err(e) => Response::Error {
+                message: e.to_string(),
+            },
+        };
+
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+
+    Ok(())
+}
+
+fn handle(request: Request, state: &SharedState) -> Response {
+    let mut state = state.lock().expect("state lock to not be poisoned.");
+
+    match request {
+        Request::SelectWindow { window } => state.select_window(window),
+        Request::Pause => state.set_paused(true),
+        Request::Resume => state.set_paused(false),
+        Request::SetFilterMode { mode } => state.set_filter_mode(mode),
+        Request::ReloadConfig => match Config::load() {
+            Ok(config) => state.reload(config),
+            Err(e) => {
+                return Response::Error {
+                    message: e.to_string(),
+                }
+            }
+        },
+        Request::Stats => return Response::Stats { stats: state.stats },
+    }
+
+    Response::Ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::State;
+
+    use std::os::unix::fs::FileTypeExt;
+
+    use tempfile::TempDir;
+    use tokio::io::Lines;
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+
+    struct Client {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(path: &Path) -> Self {
+            let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+
+            Self {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn request(&mut self, line: &str) -> serde_json::Value {
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.writer.write_all(b"\n").await.unwrap();
+
+            let response = self.lines.next_line().await.unwrap().unwrap();
+            serde_json::from_str(&response).unwrap()
+        }
+    }
+
+    fn serve_in(dir: &TempDir) -> (PathBuf, SharedState) {
+        let path = dir.path().join("keydisp").join("control.sock");
+        let state = State::new(Config::default()).shared();
+        spawn_at(&path, state.clone()).unwrap();
+        (path, state)
+    }
+
+    #[tokio::test]
+    async fn commands_change_state() {
+        let dir = TempDir::new().unwrap();
+        let (path, state) = serve_in(&dir);
+        let mut client = Client::connect(&path).await;
+
+        let ok = serde_json::json!({"status": "ok"});
+
+        assert_eq!(client.request(r#"{"command": "pause"}"#).await, ok);
+        assert!(state.lock().unwrap().paused);
+
+        assert_eq!(client.request(r#"{"command": "resume"}"#).await, ok);
+        assert!(!state.lock().unwrap().paused);
+
+        let select = r#"{"command": "select_window", "window": 4242}"#;
+        assert_eq!(client.request(select).await, ok);
+        assert_eq!(state.lock().unwrap().input_window, Some(4242));
+
+        let clear = r#"{"command": "select_window", "window": null}"#;
+        assert_eq!(client.request(clear).await, ok);
+        assert_eq!(state.lock().unwrap().input_window, None);
+
+        let mode = r#"{"command": "set_filter_mode", "mode": "everything"}"#;
+        assert_eq!(client.request(mode).await, ok);
+        assert_eq!(state.lock().unwrap().filter_mode, FilterMode::Everything);
+    }
+
+    #[tokio::test]
+    async fn stats_are_reported() {
+        let dir = TempDir::new().unwrap();
+        let (path, state) = serve_in(&dir);
+        state.lock().unwrap().stats.events = 12;
+        state.lock().unwrap().stats.sent = 7;
+
+        let mut client = Client::connect(&path).await;
+        let response = client.request(r#"{"command": "stats"}"#).await;
+
+        assert_eq!(response["status"], "stats");
+        assert_eq!(response["stats"]["events"], 12);
+        assert_eq!(response["stats"]["sent"], 7);
+        assert_eq!(response["stats"]["clients"], 0);
+    }
+
+    #[tokio::test]
+    async fn bad_requests_get_errors_and_keep_the_connection() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = serve_in(&dir);
+        let mut client = Client::connect(&path).await;
+
+        for line in &[
+            "not json",
+            r#"{"command": "explode"}"#,
+            r#"{"command": "set_filter_mode", "mode": "sideways"}"#,
+        ] {
+            let response = client.request(line).await;
+            assert_eq!(response["status"], "error", "{}", line);
+            assert!(response["message"].is_string());
+        }
+
+        let response = client.request(r#"{"command": "pause"}"#).await;
+        assert_eq!(response["status"], "ok");
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_can_connect() {
+        let dir = TempDir::new().unwrap();
+        let (path, _) = serve_in(&dir);
+
+        let socket = fs::metadata(&path).unwrap();
+        assert!(socket.file_type().is_socket());
+        assert_eq!(socket.permissions().mode() & 0o777, 0o600);
+
+        let parent = fs::metadata(path.parent().unwrap()).unwrap();
+        assert_eq!(parent.permissions().mode() & 0o777, 0o700);
+    }
+
+    #[tokio::test]
+    async fn loosened_directories_are_tightened() {
+        let dir = TempDir::new().unwrap();
+        let control_dir = dir.path().join("keydisp");
+        fs::create_dir(&control_dir).unwrap();
+        fs::set_permissions(&control_dir, Permissions::from_mode(0o755)).unwrap();
+
+        serve_in(&dir);
+
+        let parent = fs::metadata(&control_dir).unwrap();
+        assert_eq!(parent.permissions().mode() & 0o777, 0o700);
+    }
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced_and_live_ones_kept() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keydisp").join("control.sock");
+        fs::create_dir(path.parent().unwrap()).unwrap();
+
+        // Bound and closed again, like after a crash.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        spawn_at(&path, State::new(Config::default()).shared()).unwrap();
+
+        let mut client = Client::connect(&path).await;
+        let response = client.request(r#"{"command": "pause"}"#).await;
+        assert_eq!(response["status"], "ok");
+
+        let second = spawn_at(&path, State::new(Config::default()).shared());
+        assert_eq!(second.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,1105 @@
+//! A service on the D-Bus session bus, for desktop integration on Linux.
+//!
+//! keydisp owns `org.keydisp` and serves the `org.keydisp` interface on
+//! `/org/keydisp`:
+//!
+//! ```text
+//! SelectWindow(t window)       0 clears the selection
+//! Pause()
+//! Resume()
+//! GetStatus() -> a{sv}         paused, filter_mode, window, events, sent, clients
+//!
+//! signal KeyEvent(s type, s glyph, s json)
+//! signal Paused(b paused)
+//! signal TargetChanged(t window)
+//! signal FilterModeChanged(s mode)
+//! ```
+//!
+//! The few messages needed are spoken directly over the bus socket. The state
+//! tells of every change, so the signals go out whichever way keydisp was
+//! told, be it here, through the control socket or with F10. The service comes
+//! back whenever the bus does.
+
+use crate::config::FilterMode;
+use crate::metrics;
+use crate::output::{Format, Tagged};
+use crate::server::percent_decode;
+use crate::state::SharedState;
+
+use std::env;
+use std::io::{self, Error, ErrorKind};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixStream as StdUnixStream};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::sleep;
+
+const NAME: &str = "org.keydisp";
+const PATH: &str = "/org/keydisp";
+const INTERFACE: &str = "org.keydisp";
+
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+const PEER: &str = "org.freedesktop.DBus.Peer";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.keydisp">
+    <method name="SelectWindow"><arg name="window" type="t" direction="in"/></method>
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <method name="GetStatus"><arg name="status" type="a{sv}" direction="out"/></method>
+    <signal name="KeyEvent">
+      <arg name="type" type="s"/>
+      <arg name="glyph" type="s"/>
+      <arg name="json" type="s"/>
+    </signal>
+    <signal name="Paused"><arg name="paused" type="b"/></signal>
+    <signal name="TargetChanged"><arg name="window" type="t"/></signal>
+    <signal name="FilterModeChanged"><arg name="mode" type="s"/></signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml" type="s" direction="out"/></method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+// Message types.
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+// Header fields.
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+const NAME_DO_NOT_QUEUE: u32 = 0x4;
+const NAME_PRIMARY_OWNER: u32 = 1;
+
+/// Messages larger than this are refused, as the bus itself does.
+const MAX_MESSAGE: usize = 128 << 20;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Marshals values, aligned as if written at the start of a message. Bodies
+/// start 8 byte aligned, so they are marshaled on their own.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn align(&mut self, n: usize) {
+        self.buf.resize(self.buf.len().next_multiple_of(n), 0);
+    }
+
+    fn byte(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u32(u32::from(value));
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.align(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.align(8);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Strings and object paths.
+    fn string(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    fn signature(&mut self, value: &str) {
+        self.buf.push(value.len() as u8);
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.push(0);
+    }
+
+    /// An array of elements aligned to `align`, written by `elements`.
+    fn array(&mut self, align: usize, elements: impl FnOnce(&mut Writer)) {
+        self.u32(0);
+        let len_at = self.buf.len() - 4;
+        self.align(align);
+
+        let start = self.buf.len();
+        elements(self);
+        let len = (self.buf.len() - start) as u32;
+
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    fn variant(&mut self, value: &Variant) {
+        match value {
+            Variant::Bool(b) => {
+                self.signature("b");
+                self.bool(*b);
+            }
+            Variant::U64(n) => {
+                self.signature("t");
+                self.u64(*n);
+            }
+            Variant::Str(s) => {
+                self.signature("s");
+                self.string(s);
+            }
+        }
+    }
+}
+
+enum Variant {
+    Bool(bool),
+    U64(u64),
+    Str(String),
+}
+
+/// Unmarshals values in either byte order.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn align(&mut self, n: usize) {
+        self.pos = self.pos.next_multiple_of(n);
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated message"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.align(4);
+        let bytes = self.take(4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+
+        if self.big_endian {
+            Ok(u32::from_be_bytes(bytes))
+        } else {
+            Ok(u32::from_le_bytes(bytes))
+        }
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.align(8);
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+
+        if self.big_endian {
+            Ok(u64::from_be_bytes(bytes))
+        } else {
+            Ok(u64::from_le_bytes(bytes))
+        }
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+
+    fn signature(&mut self) -> io::Result<String> {
+        let len = usize::from(self.byte()?);
+        let bytes = self.take(len + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+#[derive(Default)]
+struct Message {
+    kind: u8,
+    flags: u8,
+    serial: u32,
+    path: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    error_name: Option<String>,
+    reply_serial: Option<u32>,
+    destination: Option<String>,
+    sender: Option<String>,
+    signature: String,
+    body: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Message {
+    fn method_call(destination: &str, path: &str, interface: &str, member: &str) -> Message {
+        Message {
+            kind: METHOD_CALL,
+            path: Some(path.to_string()),
+            interface: Some(interface.to_string()),
+            member: Some(member.to_string()),
+            destination: Some(destination.to_string()),
+            ..Message::default()
+        }
+    }
+
+    fn signal(member: &str, signature: &str, body: Writer) -> Message {
+        Message {
+            kind: SIGNAL,
+            path: Some(PATH.to_string()),
+            interface: Some(INTERFACE.to_string()),
+            member: Some(member.to_string()),
+            signature: signature.to_string(),
+            body: body.buf,
+            ..Message::default()
+        }
+    }
+
+    fn reply(&self, signature: &str, body: Writer) -> Message {
+        Message {
+            kind: METHOD_RETURN,
+            reply_serial: Some(self.serial),
+            destination: self.sender.clone(),
+            signature: signature.to_string(),
+            body: body.buf,
+            ..Message::default()
+        }
+    }
+
+    fn error(&self, name: &str, text: &str) -> Message {
+        let mut body = Writer::default();
+        body.string(text);
+
+        Message {
+            kind: ERROR,
+            error_name: Some(name.to_string()),
+            ..self.reply("s", body)
+        }
+    }
+
+    fn with_args(mut self, signature: &str, body: Writer) -> Message {
+        self.signature = signature.to_string();
+        self.body = body.buf;
+        self
+    }
+
+    fn args(&self) -> Reader<'_> {
+        Reader {
+            buf: &self.body,
+            pos: 0,
+            big_endian: self.big_endian,
+        }
+    }
+
+    fn encode(&self, serial: u32) -> Vec<u8> {
+        let mut w = Writer::default();
+
+        w.byte(b'l');
+        w.byte(self.kind);
+        w.byte(self.flags);
+        w.byte(1);
+        w.u32(self.body.len() as u32);
+        w.u32(serial);
+
+        w.array(8, |w| {
+            let strings = [
+                (FIELD_PATH, "o", &self.path),
+                (FIELD_INTERFACE, "s", &self.interface),
+                (FIELD_MEMBER, "s", &self.member),
+                (FIELD_ERROR_NAME, "s", &self.error_name),
+                (FIELD_DESTINATION, "s", &self.destination),
+            ];
+
+            for (code, signature, value) in strings.iter() {
+                if let Some(value) = value {
+                    w.align(8);
+                    w.byte(*code);
+                    w.signature(signature);
+                    w.string(value);
+                }
+            }
+
+            if let Some(reply_serial) = self.reply_serial {
+                w.align(8);
+                w.byte(FIELD_REPLY_SERIAL);
+                w.signature("u");
+                w.u32(reply_serial);
+            }
+
+            if !self.signature.is_empty() {
+                w.align(8);
+                w.byte(FIELD_SIGNATURE);
+                w.signature("g");
+                w.signature(&self.signature);
+            }
+        });
+
+        w.align(8);
+        w.buf.extend_from_slice(&self.body);
+        w.buf
+    }
+
+    /// The length of the message starting `buf`, once its fixed part is there.
+    fn len(buf: &[u8]) -> io::Result<Option<usize>> {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+
+        let mut r = Reader {
+            buf,
+            pos: 4,
+            big_endian: buf[0] == b'B',
+        };
+        let body = r.u32()? as usize;
+        r.u32()?;
+        let fields = r.u32()? as usize;
+        let len = (16 + fields).next_multiple_of(8) + body;
+
+        if len > MAX_MESSAGE {
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
+
+        Ok(Some(len))
+    }
+
+    fn decode(buf: &[u8]) -> io::Result<Message> {
+        let mut r = Reader {
+            buf,
+            pos: 0,
+            big_endian: match buf[0] {
+                b'l' => false,
+                b'B' => true,
+                _ => return Err(Error::new(ErrorKind::InvalidData, "unknown byte order")),
+            },
+        };
+
+        let mut message = Message {
+            big_endian: r.big_endian,
+            ..Message::default()
+        };
+
+        r.byte()?;
+        message.kind = r.byte()?;
+        message.flags = r.byte()?;
+        r.byte()?;
+        let body_len = r.u32()? as usize;
+        message.serial = r.u32()?;
+
+        let fields_len = r.u32()? as usize;
+        let fields_end = r.pos + fields_len;
+
+        while r.pos < fields_end {
+            r.align(8);
+            let code = r.byte()?;
+
+            match (code, r.signature()?.as_str()) {
+                (FIELD_REPLY_SERIAL, "u") => message.reply_serial = Some(r.u32()?),
+                (FIELD_SIGNATURE, "g") => message.signature = r.signature()?,
+                (_, "s") | (_, "o") => {
+                    let value = Some(r.string()?);
+
+                    match code {
+                        FIELD_PATH => message.path = value,
+                        FIELD_INTERFACE => message.interface = value,
+                        FIELD_MEMBER => message.member = value,
+                        FIELD_ERROR_NAME => message.error_name = value,
+                        FIELD_DESTINATION => message.destination = value,
+                        FIELD_SENDER => message.sender = value,
+                        _ => (),
+                    }
+                }
+                (_, "u") => {
+                    r.u32()?;
+                }
+                (_, "g") => {
+                    r.signature()?;
+                }
+                (code, signature) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("header field {} of type {}", code, signature),
+                    ))
+                }
+            }
+        }
+
+        r.align(8);
+        message.body = r.take(body_len)?.to_vec();
+
+        Ok(message)
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    /// Received bytes not yet making up a whole message.
+    received: Vec<u8>,
+    next_serial: u32,
+}
+
+impl Connection {
+    /// Connects to the first of the `;` separated `addresses` that works, and
+    /// authenticates as the user running keydisp.
+    async fn open(addresses: &str) -> io::Result<Connection> {
+        let mut last_error = Error::new(ErrorKind::NotFound, "no usable bus address");
+
+        for address in addresses.split(';') {
+            match connect(address) {
+                Ok(stream) => {
+                    let mut connection = Connection {
+                        stream,
+                        received: Vec::new(),
+                        next_serial: 1,
+                    };
+
+                    connection.authenticate().await?;
+                    return Ok(connection);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    async fn authenticate(&mut self) -> io::Result<()> {
+        let uid: String = unsafe { libc::getuid() }
+            .to_string()
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        self.stream
+            .write_all(format!("\0AUTH EXTERNAL {}\r\n", uid).as_bytes())
+            .await?;
+
+        // The bus says nothing more until it is told to begin.
+        let mut line = Vec::new();
+
+        while !line.ends_with(b"\r\n") {
+            if self.stream.read_buf(&mut line).await? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "the bus went away"));
+            }
+        }
+
+        if !line.starts_with(b"OK ") {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("the bus refused: {}", String::from_utf8_lossy(&line).trim()),
+            ));
+        }
+
+        self.stream.write_all(b"BEGIN\r\n").await
+    }
+
+    async fn send(&mut self, message: &Message) -> io::Result<u32> {
+        let serial = self.next_serial;
+        self.next_serial = self.next_serial.checked_add(1).unwrap_or(1);
+
+        self.stream.write_all(&message.encode(serial)).await?;
+        Ok(serial)
+    }
+
+    /// The next message, safe to cancel as partial messages are kept.
+    async fn next(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(len) = Message::len(&self.received)? {
+                if self.received.len() >= len {
+                    let message = Message::decode(&self.received[..len]);
+                    self.received.drain(..len);
+                    return message;
+                }
+            }
+
+            if self.stream.read_buf(&mut self.received).await? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "the bus went away"));
+            }
+        }
+    }
+
+    /// Calls a method of the bus and waits for its reply, while nothing else
+    /// is going on yet.
+    async fn call(&mut self, message: Message) -> io::Result<Message> {
+        let serial = self.send(&message).await?;
+
+        loop {
+            let reply = self.next().await?;
+
+            if reply.reply_serial != Some(serial) {
+                continue;
+            }
+
+            if reply.kind == ERROR {
+                let text = reply.args().string().unwrap_or_default();
+                return Err(Error::other(format!(
+                    "{}: {}",
+                    reply.error_name.unwrap_or_default(),
+                    text
+                )));
+            }
+
+            return Ok(reply);
+        }
+    }
+}
+
+/// Connects to a `unix:` address, with its `path` or `abstract` key.
+fn connect(address: &str) -> io::Result<UnixStream> {
+    let params = address
+        .strip_prefix("unix:")
+        .ok_or_else(|| Error::new(ErrorKind::Unsupported, "only unix addresses are supported"))?;
+
+    for param in params.split(',') {
+        let stream = match param.split_once('=') {
+            Some(("path", path)) => StdUnixStream::connect(percent_decode(path))?,
+            Some(("abstract", name)) => {
+                let addr = SocketAddr::from_abstract_name(percent_decode(name))?;
+                StdUnixStream::connect_addr(&addr)?
+            }
+            _ => continue,
+        };
+
+        stream.set_nonblocking(true)?;
+        return UnixStream::from_std(stream);
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        "address without a socket",
+    ))
+}
+
+/// What was last signalled, to tell actual changes.
+struct Signalled {
+    paused: bool,
+    window: Option<usize>,
+    filter_mode: FilterMode,
+}
+
+/// Owns the name on the session bus and serves it, reconnecting whenever
+/// needed.
+pub async fn run(state: SharedState, events: broadcast::Receiver<Tagged>) {
+    match env::var("DBUS_SESSION_BUS_ADDRESS") {
+        Ok(addresses) => run_on(&addresses, state, events).await,
+        Err(_) => eprintln!("D-Bus service unavailable: no session bus"),
+    }
+}
+
+async fn run_on(addresses: &str, state: SharedState, mut events: broadcast::Receiver<Tagged>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match register(addresses).await {
+            Ok(connection) => {
+                eprintln!("D-Bus service running as {}", NAME);
+                backoff = MIN_BACKOFF;
+
+                match serve(connection, &state, &mut events).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        metrics::WRITE_ERRORS.inc(&["dbus"]);
+                        eprintln!("Lost the D-Bus connection: {}", e);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::AddrInUse => {
+                eprintln!("D-Bus service unavailable: {}", e);
+                return;
+            }
+            Err(e) => eprintln!("Could not register on D-Bus: {}", e),
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        events = events.resubscribe();
+    }
+}
+
+async fn register(addresses: &str) -> io::Result<Connection> {
+    let mut connection = Connection::open(addresses).await?;
+
+    connection
+        .call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello"))
+        .await?;
+
+    let mut args = Writer::default();
+    args.string(NAME);
+    args.u32(NAME_DO_NOT_QUEUE);
+
+    let reply = connection
+        .call(
+            Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "RequestName").with_args("su", args),
+        )
+        .await?;
+
+    if reply.args().u32()? != NAME_PRIMARY_OWNER {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("{} is owned by another instance", NAME),
+        ));
+    }
+
+    Ok(connection)
+}
+
+/// Runs until the connection fails, or keydisp stops broadcasting. Messages
+/// that can't be decoded fail it too, as the stream can't be trusted after.
+async fn serve(
+    mut connection: Connection,
+    state: &SharedState,
+    events: &mut broadcast::Receiver<Tagged>,
+) -> io::Result<()> {
+    let (mut signalled, mut changed) = {
+        let state = state.lock().expect("state lock to not be poisoned.");
+        let signalled = Signalled {
+            paused: state.paused,
+            window: state.input_window,
+            filter_mode: state.filter_mode,
+        };
+
+        (signalled, state.watch())
+    };
+
+    loop {
+        tokio::select! {
+            message = connection.next() => {
+                let message = message?;
+
+                if message.kind == METHOD_CALL {
+                    if let Some(reply) = handle(&message, state) {
+                        if message.flags & NO_REPLY_EXPECTED == 0 {
+                            connection.send(&reply).await?;
+                        }
+                    }
+                }
+            }
+            event = events.recv() => match event {
+                Ok(tagged) => {
+                    connection.send(&key_event(&tagged)).await?;
+                }
+                Err(RecvError::Lagged(skipped)) => metrics::DROPPED_EVENTS.add(&["dbus"], skipped),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            // The state outlives this, so the sender can't go away.
+            Ok(()) = changed.changed() => {
+                for signal in changes(state, &mut signalled) {
+                    connection.send(&signal).await?;
+                }
+            }
+        }
+    }
+}
+
+/// Answers a method call, `None` for calls that aren't meant for keydisp.
+fn handle(call: &Message, state: &SharedState) -> Option<Message> {
+    if call.path.as_deref() != Some(PATH) {
+        return Some(call.error(
+            "org.freedesktop.DBus.Error.UnknownObject",
+            &format!("no object at {}", call.path.as_deref().unwrap_or_default()),
+        ));
+    }
+
+    let member = call.member.as_deref()?;
+    let mut body = Writer::default();
+
+    let signature = match (call.interface.as_deref(), member) {
+        (Some(INTERFACE) | None, "SelectWindow") => {
+            let window = match (call.signature.as_str(), call.args().u64()) {
+                ("t", Ok(window)) => window,
+                _ => {
+                    return Some(call.error(
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        "expected a window id of type t",
+                    ))
+                }
+            };
+
+            let mut state = state.lock().expect("state lock to not be poisoned.");
+            state.select_window(match window {
+                0 => None,
+                window => Some(window as usize),
+            });
+            ""
+        }
+        (Some(INTERFACE) | None, "Pause") => {
+            state.lock().expect("state lock to not be poisoned.").set_paused(true);
+            ""
+        }
+        (Some(INTERFACE) | None, "Resume") => {
+            state.lock().expect("state lock to not be poisoned.").set_paused(false);
+            ""
+        }
+        (Some(INTERFACE) | None, "GetStatus") => {
+            let state = state.lock().expect("state lock to not be poisoned.");
+            let status = [
+                ("paused", Variant::Bool(state.paused)),
+                ("filter_mode", Variant::Str(mode_name(state.filter_mode))),
+                (
+                    "window",
+                    Variant::U64(state.input_window.unwrap_or(0) as u64),
+                ),
+                ("events", Variant::U64(state.stats.events)),
+                ("sent", Variant::U64(state.stats.sent)),
+                ("clients", Variant::U64(state.stats.clients as u64)),
+            ];
+
+            body.array(8, |w| {
+                for (key, value) in status.iter() {
+                    w.align(8);
+                    w.string(key);
+                    w.variant(value);
+                }
+            });
+            "a{sv}"
+        }
+        (Some(INTROSPECTABLE), "Introspect") => {
+            body.string(INTROSPECTION);
+            "s"
+        }
+        (Some(PEER), "Ping") => "",
+        (interface, member) => {
+            return Some(call.error(
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                &format!("no method {}.{}", interface.unwrap_or(INTERFACE), member),
+            ))
+        }
+    };
+
+    Some(call.reply(signature, body))
+}
+
+/// The event as sent to JSON clients, with its type and glyph picked out.
+fn key_event(tagged: &Tagged) -> Message {
+    let json = Format::Json.encode(tagged).unwrap_or_default();
+    let kind = serde_json::to_value(&tagged.output).unwrap_or_default();
+    let mut body = Writer::default();
+
+    body.string(kind["type"].as_str().unwrap_or_default());
+    body.string(&tagged.output.glyph().unwrap_or_default());
+    body.string(&json);
+
+    Message::signal("KeyEvent", "sss", body)
+}
+
+/// Signals for what changed since last time.
+fn changes(state: &SharedState, signalled: &mut Signalled) -> Vec<Message> {
+    let state = state.lock().expect("state lock to not be poisoned.");
+    let mut signals = Vec::new();
+
+    if state.paused != signalled.paused {
+        let mut body = Writer::default();
+        body.bool(state.paused);
+        signals.push(Message::signal("Paused", "b", body));
+        signalled.paused = state.paused;
+    }
+
+    if state.input_window != signalled.window {
+        let mut body = Writer::default();
+        body.u64(state.input_window.unwrap_or(0) as u64);
+        signals.push(Message::signal("TargetChanged", "t", body));
+        signalled.window = state.input_window;
+    }
+
+    if state.filter_mode != signalled.filter_mode {
+        let mut body = Writer::default();
+        body.string(&mode_name(state.filter_mode));
+        signals.push(Message::signal("FilterModeChanged", "s", body));
+        signalled.filter_mode = state.filter_mode;
+    }
+
+    signals
+}
+
+/// As spelled in the config.
+fn mode_name(mode: FilterMode) -> String {
+    serde_json::to_value(mode)
+        .ok()
+        .and_then(|mode| mode.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Timestamp;
+    use crate::config::Config;
+    use crate::output::Output;
+    use crate::state::State;
+
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+
+    use tempfile::TempDir;
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+
+    const CONFIG: &str = r#"<!DOCTYPE busconfig PUBLIC "-//freedesktop//DTD D-Bus Bus Configuration 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/busconfig.dtd">
+<busconfig>
+  <type>session</type>
+  <listen>unix:path=BUS</listen>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>
+"#;
+
+    /// A private bus listening in `dir`, stopped when dropped.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start(dir: &Path) -> Bus {
+            let config = dir.join("bus.conf");
+            let socket = dir.join("bus");
+            let _ = fs::remove_file(&socket);
+            fs::write(&config, CONFIG.replace("BUS", socket.to_str().unwrap())).unwrap();
+
+            let mut daemon = Command::new("dbus-daemon")
+                .arg(format!("--config-file={}", config.display()))
+                .args(["--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("dbus-daemon to be installed");
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.as_mut().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+
+            Bus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    fn serve_on(
+        bus: &Bus,
+        state: &SharedState,
+        events: &broadcast::Sender<Tagged>,
+    ) -> JoinHandle<()> {
+        let address = bus.address.clone();
+        let state = state.clone();
+        let events = events.subscribe();
+
+        tokio::spawn(async move { run_on(&address, state, events).await })
+    }
+
+    /// Another client on the bus, listening to keydisp's signals.
+    async fn client(bus: &Bus) -> Connection {
+        let mut connection = Connection::open(&bus.address).await.unwrap();
+
+        connection
+            .call(Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "Hello"))
+            .await
+            .unwrap();
+
+        let mut rule = Writer::default();
+        rule.string("type='signal',interface='org.keydisp'");
+        connection
+            .call(
+                Message::method_call(BUS_NAME, BUS_PATH, BUS_NAME, "AddMatch").with_args("s", rule),
+            )
+            .await
+            .unwrap();
+
+        // keydisp may not have registered yet.
+        for _ in 0..50 {
+            if connection
+                .call(Message::method_call(NAME, PATH, PEER, "Ping"))
+                .await
+                .is_ok()
+            {
+                return connection;
+            }
+
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        panic!("{} never showed up on the bus", NAME);
+    }
+
+    async fn call(
+        connection: &mut Connection,
+        member: &str,
+        signature: &str,
+        args: Writer,
+    ) -> Message {
+        let call = Message::method_call(NAME, PATH, INTERFACE, member).with_args(signature, args);
+        connection.call(call).await.unwrap()
+    }
+
+    /// The next signal of keydisp's, waiting up to a second for it.
+    async fn signal(connection: &mut Connection) -> Message {
+        timeout(Duration::from_secs(1), async {
+            loop {
+                let message = connection.next().await.unwrap();
+
+                if message.kind == SIGNAL && message.interface.as_deref() == Some(INTERFACE) {
+                    return message;
+                }
+            }
+        })
+        .await
+        .expect("a signal")
+    }
+
+    fn window(window: u64) -> Writer {
+        let mut args = Writer::default();
+        args.u64(window);
+        args
+    }
+
+    #[tokio::test]
+    async fn serves_the_interface() {
+        let dir = TempDir::new().unwrap();
+        let bus = Bus::start(dir.path());
+        let state = State::new(Config::default()).shared();
+        let (events, _) = broadcast::channel(16);
+        let service = serve_on(&bus, &state, &events);
+        let mut client = client(&bus).await;
+
+        call(&mut client, "SelectWindow", "t", window(42)).await;
+        assert_eq!(state.lock().unwrap().input_window, Some(42));
+
+        let changed = signal(&mut client).await;
+        assert_eq!(changed.member.as_deref(), Some("TargetChanged"));
+        assert_eq!(changed.args().u64().unwrap(), 42);
+
+        call(&mut client, "Pause", "", Writer::default()).await;
+
+        let paused = signal(&mut client).await;
+        assert_eq!(paused.member.as_deref(), Some("Paused"));
+        assert_eq!(paused.args().u32().unwrap(), 1);
+
+        let status = call(&mut client, "GetStatus", "", Writer::default()).await;
+        assert_eq!(status.signature, "a{sv}");
+
+        let mut args = status.args();
+        args.u32().unwrap();
+        args.align(8);
+        assert_eq!(args.string().unwrap(), "paused");
+        assert_eq!(args.signature().unwrap(), "b");
+        assert_eq!(args.u32().unwrap(), 1);
+
+        // As the control socket would.
+        state.lock().unwrap().set_paused(false);
+
+        let resumed = signal(&mut client).await;
+        assert_eq!(resumed.member.as_deref(), Some("Paused"));
+        assert_eq!(resumed.args().u32().unwrap(), 0);
+
+        state
+            .lock()
+            .unwrap()
+            .set_filter_mode(FilterMode::Everything);
+
+        let mode = signal(&mut client).await;
+        assert_eq!(mode.member.as_deref(), Some("FilterModeChanged"));
+        assert_eq!(mode.args().string().unwrap(), "everything");
+
+        events
+            .send(Tagged {
+                output: Output::Char { text: 'a' },
+                device: None,
+                injected: false,
+                marked: false,
+                time: Timestamp::from_micros(1),
+            })
+            .unwrap();
+
+        let key = signal(&mut client).await;
+        let mut args = key.args();
+        assert_eq!(key.member.as_deref(), Some("KeyEvent"));
+        assert_eq!(args.string().unwrap(), "char");
+        assert_eq!(args.string().unwrap(), "a");
+
+        service.abort();
+    }
+
+    #[tokio::test]
+    async fn comes_back_with_the_bus() {
+        let dir = TempDir::new().unwrap();
+        let bus = Bus::start(dir.path());
+        let state = State::new(Config::default()).shared();
+        let (events, _) = broadcast::channel(16);
+        let service = serve_on(&bus, &state, &events);
+        client(&bus).await;
+
+        drop(bus);
+        let bus = Bus::start(dir.path());
+        let mut client = client(&bus).await;
+
+        call(&mut client, "SelectWindow", "t", window(7)).await;
+        assert_eq!(state.lock().unwrap().input_window, Some(7));
+
+        service.abort();
+    }
+
+    #[test]
+    fn malformed_calls_are_refused() {
+        let state = State::new(Config::default()).shared();
+
+        for signature in ["t", "s"] {
+            let call = Message {
+                serial: 3,
+                sender: Some(":1.1".to_string()),
+                ..Message::method_call(NAME, PATH, INTERFACE, "SelectWindow")
+            }
+            // Too short for the window id.
+            .with_args(signature, Writer::default());
+
+            let reply = handle(&call, &state).unwrap();
+            assert_eq!(reply.kind, ERROR);
+            assert_eq!(reply.reply_serial, Some(3));
+            assert_eq!(
+                reply.error_name.as_deref(),
+                Some("org.freedesktop.DBus.Error.InvalidArgs")
+            );
+        }
+
+        assert_eq!(state.lock().unwrap().input_window, None);
+    }
+}
@@ -0,0 +1,332 @@
+//! `keydisp export <capture> <output> [from] [to]`, draws captured input as
+//! an animated GIF or APNG, for documentation.
+//!
+//! The capture is what a `json_lines` sink wrote, one JSON object per line.
+//! `from` and `to` are seconds since the first event in it, by default from
+//! that event until everything shown has faded out. Frames are drawn as the
+//! overlay would have shown them at the configured rate, and frames that
+//! don't differ from the one before are merged into it.
+
+use crate::common::{Key, Timestamp};
+use crate::config::{Config, InjectedPolicy, ThemeConfig};
+use crate::output;
+use crate::render::{self, ApngEncoder, GifEncoder, Overlay, Renderer, Scene};
+
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+const USAGE: &str = "Usage: keydisp export <capture.jsonl> <output.gif|output.png> [from] [to]";
+
+enum Encoder {
+    Gif(GifEncoder),
+    Apng(ApngEncoder),
+}
+
+impl Encoder {
+    fn for_path(path: &Path, config: &Config) -> Option<Self> {
+        let export = &config.export;
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "gif" => {
+                let palette = if export.palette.is_empty() {
+                    render::palette(&export.theme)
+                } else {
+                    export.palette.clone()
+                };
+
+                Some(Encoder::Gif(GifEncoder::new(
+                    export.width,
+                    export.height,
+                    &palette,
+                )))
+            }
+            "png" | "apng" => Some(Encoder::Apng(ApngEncoder::new(export.width, export.height))),
+            _ => None,
+        }
+    }
+
+    fn add(&mut self, frame: &render::Frame, delay_ms: u32) {
+        match self {
+            Encoder::Gif(encoder) => encoder.add(frame, delay_ms),
+            Encoder::Apng(encoder) => encoder.add(frame, delay_ms),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Encoder::Gif(encoder) => encoder.finish(),
+            Encoder::Apng(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// What the overlay displays for a captured event, as `Output::glyph` did.
+fn glyph(event: &Value) -> Option<String> {
+    match event["type"].as_str()? {
+        "key" | "mouse_button" | "scroll" | "gamepad_button" => {
+            event["glyph"].as_str().map(str::to_string)
+        }
+        "char" => event["text"].as_str().map(str::to_string),
+        "lock" => {
+            let glyph = Key::from_name(event["lock"].as_str()?)?.glyph()?;
+            let on = event["on"].as_bool()?;
+            Some(format!("{} {}", glyph, if on { "on" } else { "off" }))
+        }
+        _ => None,
+    }
+}
+
+/// Displayed events in the order they happened, with their time.
+fn read_capture(path: &Path, injected: InjectedPolicy) -> Result<Vec<(u64, String)>, String> {
+    let capture = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    let mut events = Vec::new();
+
+    for (number, line) in capture.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let event: Value = serde_json::from_str(line)
+            .map_err(|e| format!("{} line {}: {}", path.display(), number + 1, e))?;
+        let time = match event["time"].as_u64() {
+            Some(time) => time,
+            None => continue,
+        };
+        let injected = match event["injected"].as_bool() {
+            Some(true) => injected,
+            _ => InjectedPolicy::Show,
+        };
+
+        match (glyph(&event), injected) {
+            (Some(_), InjectedPolicy::Hide) | (None, _) => (),
+            (Some(glyph), InjectedPolicy::Mark) => events.push((time, output::mark(&glyph))),
+            (Some(glyph), InjectedPolicy::Show) => events.push((time, glyph)),
+        }
+    }
+
+    // Sinks write in the order events were processed, which input from
+    // several devices doesn't always happen in.
+    events.sort_by_key(|(time, _)| *time);
+    Ok(events)
+}
+
+/// The scenes shown from `start` to `end`, drawn `fps` times a second, each
+/// with how long it is shown in milliseconds. Frames that don't differ from
+/// the one before are merged into it.
+fn scenes(
+    events: &[(u64, String)],
+    theme: &ThemeConfig,
+    fps: u32,
+    start: u64,
+    end: u64,
+) -> Vec<(Scene, u32)> {
+    let fps = u64::from(fps.max(1));
+    let frames = ((end - start) * fps).div_ceil(1_000_000);
+    // When frame `n` starts, in milliseconds, rounded the same way for every
+    // frame so merged frames add up to the whole length.
+    let at_ms = |n: u64| (n * 1000 + fps / 2) / fps;
+
+    let mut overlay = Overlay::new(theme);
+    let mut upcoming = events.iter().peekable();
+    // The scene last drawn and the frame it was first shown on.
+    let mut shown: Option<(Scene, u64)> = None;
+    let mut scenes = Vec::new();
+
+    for n in 0..=frames {
+        // One past the last frame only flushes the scene before it.
+        let scene = if n < frames {
+            let time = start + n * 1_000_000 / fps;
+
+            // Including what happened before `from`, still shown at the start.
+            while let Some((event_time, text)) = upcoming.next_if(|(at, _)| *at <= time) {
+                overlay.push(text, Timestamp::from_micros(*event_time));
+            }
+
+            Some(overlay.scene(Timestamp::from_micros(time)))
+        } else {
+            None
+        };
+
+        if let Some((ref shown_scene, since)) = shown {
+            if scene.as_ref() == Some(shown_scene) {
+                continue;
+            }
+
+            scenes.push((shown_scene.clone(), (at_ms(n) - at_ms(since)) as u32));
+        }
+
+        shown = scene.map(|scene| (scene, n));
+    }
+
+    scenes
+}
+
+/// Microseconds from `arg`, given in seconds.
+fn micros(arg: Option<&String>) -> Result<Option<u64>, String> {
+    let arg = match arg {
+        Some(arg) => arg,
+        None => return Ok(None),
+    };
+
+    match arg.parse::<f64>() {
+        Ok(seconds) if seconds >= 0.0 => Ok(Some((seconds * 1_000_000.0) as u64)),
+        _ => Err(format!("Invalid time {}, expected seconds", arg)),
+    }
+}
+
+pub fn run(config: &Config, args: &[String]) {
+    let (capture, output) = match args {
+        [capture, output, ..] if args.len() <= 4 => (Path::new(capture), Path::new(output)),
+        _ => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+
+    let (from, to) = match (micros(args.get(2)), micros(args.get(3))) {
+        (Ok(from), Ok(to)) => (from.unwrap_or(0), to),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let mut encoder = match Encoder::for_path(output, config) {
+        Some(encoder) => encoder,
+        None => {
+            println!(
+                "Can't tell the format of {}, expected .gif, .png or .apng",
+                output.display()
+            );
+            return;
+        }
+    };
+
+    let events = match read_capture(capture, config.injected) {
+        Ok(events) => events,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let (first, last) = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => (first.0, last.0),
+        _ => {
+            println!("Nothing in {} is displayed", capture.display());
+            return;
+        }
+    };
+
+    let export = &config.export;
+    let start = first + from;
+    let end = first + to.unwrap_or(last - first + export.theme.lifetime_ms * 1000);
+
+    if end <= start {
+        println!("Nothing to export, the range ends before it starts");
+        return;
+    }
+
+    let mut renderer = match Renderer::new(&export.theme, export.width, export.height) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            let font = export.theme.font.clone().unwrap_or_default();
+            println!("Could not load font {}: {}", font.display(), e);
+            return;
+        }
+    };
+
+    let scenes = scenes(&events, &export.theme, export.fps, start, end);
+
+    for (scene, delay_ms) in &scenes {
+        encoder.add(renderer.draw(scene), *delay_ms);
+    }
+
+    let image = encoder.finish();
+
+    match fs::write(output, &image) {
+        Ok(()) => println!(
+            "Wrote {} frames, {:.2} seconds, {} bytes to {}",
+            scenes.len(),
+            (end - start) as f64 / 1_000_000.0,
+            image.len(),
+            output.display()
+        ),
+        Err(e) => println!("Could not write {}: {}", output.display(), e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000;
+
+    /// Lines that appear at once and stay for a second.
+    fn theme() -> ThemeConfig {
+        ThemeConfig {
+            group_ms: 100,
+            lifetime_ms: 1000,
+            fade_ms: 0,
+            ..ThemeConfig::default()
+        }
+    }
+
+    fn events(events: &[(u64, &str)]) -> Vec<(u64, String)> {
+        events
+            .iter()
+            .map(|(time, text)| (*time, text.to_string()))
+            .collect()
+    }
+
+    fn lines(lines: &[&str]) -> Scene {
+        lines.iter().map(|line| (line.to_string(), 255)).collect()
+    }
+
+    #[test]
+    fn frames_that_look_the_same_are_merged() {
+        let events = events(&[(SECOND, "a"), (SECOND + SECOND / 2, "b")]);
+
+        assert_eq!(
+            scenes(&events, &theme(), 10, SECOND, 3 * SECOND),
+            vec![
+                (lines(&["a"]), 500),
+                (lines(&["a", "b"]), 500),
+                (lines(&["b"]), 500),
+                (lines(&[]), 500),
+            ]
+        );
+    }
+
+    #[test]
+    fn earlier_events_are_still_shown() {
+        let events = events(&[(SECOND, "a")]);
+        let (from, to) = (SECOND + SECOND / 4, SECOND + 3 * SECOND / 4);
+
+        assert_eq!(
+            scenes(&events, &theme(), 10, from, to),
+            vec![(lines(&["a"]), 500)]
+        );
+    }
+
+    #[test]
+    fn delays_add_up_to_the_whole_length() {
+        let theme = ThemeConfig {
+            fade_ms: 400,
+            ..theme()
+        };
+        let events = events(&[(0, "a"), (SECOND / 3, "b"), (SECOND, "c")]);
+        let scenes = scenes(&events, &theme, 30, 0, 5 * SECOND / 2);
+
+        assert_eq!(scenes.iter().map(|(_, delay)| delay).sum::<u32>(), 2500);
+        assert!(scenes.windows(2).all(|pair| pair[0].0 != pair[1].0));
+        // Fading takes a frame each step of the way.
+        assert!(scenes
+            .iter()
+            .any(|(scene, delay)| scene[0].1 < 255 && *delay <= 34));
+    }
+}
@@ -0,0 +1,376 @@
+//! Raw access to the input devices under `/dev/input`.
+//!
+//! Reading them requires membership of the `input` group, or root.
+
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+
+pub use libc::{input_absinfo, input_event};
+
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_LED: u16 = 0x11;
+
+pub const REL_HWHEEL: u16 = 0x06;
+pub const REL_WHEEL: u16 = 0x08;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+pub const BTN_SIDE: u16 = 0x113;
+pub const BTN_EXTRA: u16 = 0x114;
+
+/// Devices created in software, through uinput.
+pub const BUS_VIRTUAL: u16 = 0x06;
+
+pub const LED_NUML: usize = 0x00;
+pub const LED_CAPSL: usize = 0x01;
+pub const LED_SCROLLL: usize = 0x02;
+
+pub const INPUT_DIR: &str = "/dev/input";
+
+const EVENT_SIZE: usize = mem::size_of::<input_event>();
+/// Enough for `KEY_MAX`.
+const KEY_BYTES: usize = 0x300 / 8;
+const READ_EVENTS: usize = 64;
+
+/// `_IOC(_IOC_READ, 'E', nr, len)` from `linux/input.h`.
+fn eviocg(nr: u64, len: usize) -> u64 {
+    (2 << 30) | ((len as u64) << 16) | ((b'E' as u64) << 8) | nr
+}
+
+/// `EVIOCSCLOCKID`, `_IOW('E', 0xa0, int)`.
+const EVIOCSCLOCKID: u64 = (1 << 30) | (4 << 16) | ((b'E' as u64) << 8) | 0xa0;
+
+pub struct Device {
+    file: File,
+    pub path: PathBuf,
+    pub name: String,
+    /// `BUS_*`, how the device is connected.
+    pub bus: u16,
+    pub vendor: u16,
+    pub product: u16,
+    /// Physical path, where the device is plugged in.
+    pub phys: String,
+    /// The clock events are stamped with.
+    clock: libc::clockid_t,
+    /// Bitmask of the supported `EV_*` event types.
+    event_types: u32,
+    /// Bitmask of the `KEY_*` and `BTN_*` codes the device has.
+    keys: [u8; KEY_BYTES],
+}
+
+impl Device {
+    pub fn open(path: &Path) -> io::Result<Device> {
+        let file = File::open(path)?;
+        let mut name = [0u8; 256];
+        let mut phys = [0u8; 256];
+        // Bus type, vendor, product and version.
+        let mut id = [0u16; 4];
+        let mut event_types = 0u32;
+        let mut keys = [0u8; KEY_BYTES];
+        let fd = file.as_raw_fd();
+
+        // EVIOCGNAME, EVIOCGID, EVIOCGBIT(0) and EVIOCGBIT(EV_KEY): the device
+        // name, its ids, its event types and its keys.
+        unsafe {
+            if libc::ioctl(fd, eviocg(0x06, name.len()) as _, name.as_mut_ptr()) < 0
+                || libc::ioctl(fd, eviocg(0x02, 8) as _, id.as_mut_ptr()) < 0
+                || libc::ioctl(fd, eviocg(0x20, 4) as _, &mut event_types) < 0
+                || libc::ioctl(fd, eviocg(0x21, KEY_BYTES) as _, keys.as_mut_ptr()) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        // EVIOCGPHYS, virtual devices have none.
+        unsafe {
+            libc::ioctl(fd, eviocg(0x07, phys.len()) as _, phys.as_mut_ptr());
+        }
+
+        // Events are stamped with the wall clock unless asked otherwise,
+        // which jumps when the time is set.
+        let monotonic: libc::c_int = libc::CLOCK_MONOTONIC;
+        let clock = if unsafe { libc::ioctl(fd, EVIOCSCLOCKID as _, &monotonic) } == 0 {
+            libc::CLOCK_MONOTONIC
+        } else {
+            libc::CLOCK_REALTIME
+        };
+
+        Ok(Device {
+            file,
+            path: path.to_path_buf(),
+            name: c_string(&name),
+            bus: id[0],
+            vendor: id[1],
+            product: id[2],
+            phys: c_string(&phys),
+            clock,
+            event_types,
+            keys,
+        })
+    }
+
+    pub fn supports(&self, event_type: u16) -> bool {
+        self.event_types & (1 << event_type) != 0
+    }
+
+    pub fn has_key(&self, code: u16) -> bool {
+        let code = usize::from(code);
+        code < KEY_BYTES * 8 && self.keys[code / 8] & (1 << (code % 8)) != 0
+    }
+
+    /// Range and current value of an absolute axis (`ABS_*`).
+    pub fn abs_info(&self, code: u16) -> io::Result<input_absinfo> {
+        let mut info: input_absinfo = unsafe { mem::zeroed() };
+
+        // EVIOCGABS
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                eviocg(0x40 + u64::from(code), mem::size_of::<input_absinfo>()) as _,
+                &mut info,
+            )
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(info)
+    }
+
+    /// Whether the LED (`LED_*`) is lit, keyboards light them for engaged locks.
+    pub fn led(&self, led: usize) -> bool {
+        let mut leds = [0u8; 8];
+
+        // EVIOCGLED
+        let ret = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), eviocg(0x19, leds.len()) as _, leds.as_mut_ptr())
+        };
+
+        ret >= 0 && leds[led / 8] & (1 << (led % 8)) != 0
+    }
+
+    /// How long ago the event happened, by the timestamp the kernel gave it.
+    pub fn age(&self, event: &input_event) -> Duration {
+        let mut now: libc::timespec = unsafe { mem::zeroed() };
+
+        unsafe {
+            libc::clock_gettime(self.clock, &mut now);
+        }
+
+        let now = Duration::new(now.tv_sec as u64, now.tv_nsec as u32);
+        let then = Duration::new(event.time.tv_sec as u64, event.time.tv_usec as u32 * 1000);
+
+        now.saturating_sub(then)
+    }
+
+    /// Reads the events that are ready, blocking until there is at least one.
+    pub fn read(&mut self) -> io::Result<Vec<input_event>> {
+        let mut buf = [0u8; EVENT_SIZE * READ_EVENTS];
+        let n = self.file.read(&mut buf)?;
+
+        // The kernel only hands out whole events.
+        Ok(buf[..n]
+            .chunks_exact(EVENT_SIZE)
+            .map(|chunk| unsafe { ptr::read_unaligned(chunk.as_ptr() as *const input_event) })
+            .collect())
+    }
+}
+
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Tells when devices are added to a directory, through inotify.
+pub struct Watcher {
+    file: File,
+    dir: PathBuf,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path) -> io::Result<Watcher> {
+        let path = CString::new(dir.as_os_str().to_os_string().into_vec())?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        // udev creates device nodes before giving them their group, so
+        // changed attributes count as an addition too.
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), libc::IN_CREATE | libc::IN_ATTRIB) }
+            < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Watcher {
+            file,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The event devices added or changed since the last call, without blocking.
+    pub fn read(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            let n = match self.file.read(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            let mut rest = &buf[..n];
+
+            // Each event is followed by the name, padded with nuls.
+            while rest.len() >= mem::size_of::<libc::inotify_event>() {
+                let event =
+                    unsafe { ptr::read_unaligned(rest.as_ptr() as *const libc::inotify_event) };
+                let end =
+                    (mem::size_of::<libc::inotify_event>() + event.len as usize).min(rest.len());
+                let name = &rest[mem::size_of::<libc::inotify_event>()..end];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+
+                if name.starts_with(b"event") {
+                    let path = self.dir.join(OsStr::from_bytes(name));
+
+                    if !paths.contains(&path) {
+                        paths.push(path);
+                    }
+                }
+
+                rest = &rest[end..];
+            }
+        }
+
+        Ok(paths)
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+fn c_string(buf: &[u8]) -> String {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Opens every event device that can be read, skipping the others.
+pub fn devices() -> io::Result<Vec<Device>> {
+    let mut devices = Vec::new();
+
+    for entry in fs::read_dir(INPUT_DIR)? {
+        let path = entry?.path();
+        let is_event_device = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with("event"));
+
+        if is_event_device {
+            match Device::open(&path) {
+                Ok(device) => devices.push(device),
+                Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(devices)
+}
+
+/// Waits until at least one device, or watcher, has events, returning the
+/// indices of the readable ones and those that went away.
+pub fn poll(files: &[RawFd]) -> io::Result<Vec<usize>> {
+    let mut fds: Vec<libc::pollfd> = files
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+
+    loop {
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) };
+
+        if ret >= 0 {
+            break;
+        }
+
+        let e = io::Error::last_os_error();
+
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    Ok(fds
+        .iter()
+        .enumerate()
+        .filter(|(_, fd)| fd.revents != 0)
+        .map(|(i, _)| i)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watcher_sees_event_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap();
+
+        assert_eq!(watcher.read().unwrap(), Vec::<PathBuf>::new());
+
+        File::create(dir.path().join("event7")).unwrap();
+        File::create(dir.path().join("mouse0")).unwrap();
+        File::create(dir.path().join("event12")).unwrap();
+
+        assert_eq!(
+            watcher.read().unwrap(),
+            [dir.path().join("event7"), dir.path().join("event12")]
+        );
+
+        // As udev does once the node has its group.
+        let mut permissions = fs::metadata(dir.path().join("event7"))
+            .unwrap()
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(dir.path().join("event7"), permissions).unwrap();
+
+        assert_eq!(watcher.read().unwrap(), [dir.path().join("event7")]);
+        assert_eq!(watcher.read().unwrap(), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn poll_reports_ready_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut watcher = Watcher::new(dir.path()).unwrap();
+        let (idle, _writer) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        File::create(dir.path().join("event0")).unwrap();
+
+        assert_eq!(poll(&[idle.as_raw_fd(), watcher.as_raw_fd()]).unwrap(), [1]);
+        watcher.read().unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Gamepads and joysticks, as exposed by the kernel's evdev drivers.
+//!
+//! Only depends on the device for axis ranges, so a recorded event stream
+//! can be replayed through `Gamepad::handle` with ranges given up front.
+
+use super::evdev::{self, input_event, Device};
+
+use crate::common::{Axis, DPad, Event, GamepadButton, KeyState};
+
+use std::collections::HashMap;
+
+const BTN_JOYSTICK: u16 = 0x120;
+const BTN_SOUTH: u16 = 0x130;
+
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+/// Axes of standard pads, triggers come as Z/RZ or as brake/gas.
+const AXES: &[(u16, Axis)] = &[
+    (0x00, Axis::LeftX),
+    (0x01, Axis::LeftY),
+    (0x02, Axis::LeftTrigger),
+    (0x03, Axis::RightX),
+    (0x04, Axis::RightY),
+    (0x05, Axis::RightTrigger),
+    (0x09, Axis::RightTrigger),
+    (0x0a, Axis::LeftTrigger),
+];
+
+pub fn is_gamepad(device: &Device) -> bool {
+    device.supports(evdev::EV_KEY) && (device.has_key(BTN_SOUTH) || device.has_key(BTN_JOYSTICK))
+}
+
+fn button(code: u16) -> Option<GamepadButton> {
+    let button = match code {
+        0x130 => GamepadButton::South,
+        0x131 => GamepadButton::East,
+        0x133 => GamepadButton::North,
+        0x134 => GamepadButton::West,
+        0x136 => GamepadButton::LeftBumper,
+        0x137 => GamepadButton::RightBumper,
+        0x138 => GamepadButton::LeftTrigger,
+        0x139 => GamepadButton::RightTrigger,
+        0x13a => GamepadButton::Select,
+        0x13b => GamepadButton::Start,
+        0x13c => GamepadButton::Mode,
+        0x13d => GamepadButton::LeftStick,
+        0x13e => GamepadButton::RightStick,
+        0x220 => GamepadButton::DPadUp,
+        0x221 => GamepadButton::DPadDown,
+        0x222 => GamepadButton::DPadLeft,
+        0x223 => GamepadButton::DPadRight,
+        // BTN_TRIGGER up to BTN_DEAD of joysticks, and BTN_C and BTN_Z of
+        // pads with six face buttons, all counted from BTN_TRIGGER so no two
+        // share a number.
+        0x120..=0x13f => GamepadButton::Other((code - BTN_JOYSTICK + 1) as u8),
+        _ => return None,
+    };
+
+    Some(button)
+}
+
+pub struct Gamepad {
+    /// Minimum and maximum of each axis.
+    ranges: HashMap<u16, (i32, i32)>,
+    dpad: DPad,
+}
+
+impl Gamepad {
+    pub fn new(device: &Device) -> Self {
+        let ranges = AXES
+            .iter()
+            .filter_map(|&(code, _)| {
+                let info = device.abs_info(code).ok()?;
+                Some((code, (info.minimum, info.maximum)))
+            })
+            .collect();
+
+        Self::with_ranges(ranges)
+    }
+
+    pub fn with_ranges(ranges: HashMap<u16, (i32, i32)>) -> Self {
+        Self {
+            ranges,
+            dpad: DPad::new(),
+        }
+    }
+
+    /// The events an evdev event amounts to, none for those that aren't gamepad input.
+    pub fn handle(&mut self, event: &input_event) -> Vec<Event> {
+        match event.type_ {
+            evdev::EV_KEY => match button(event.code) {
+                Some(button) => vec![Event::GamepadButton {
+                    button,
+                    key_state: if event.value == 0 {
+                        KeyState::Released
+                    } else {
+                        KeyState::Pressed
+                    },
+                }],
+                None => vec![],
+            },
+            evdev::EV_ABS => {
+                let dpad = match event.code {
+                    ABS_HAT0X => self.dpad.set_x(event.value),
+                    ABS_HAT0Y => self.dpad.set_y(event.value),
+                    _ => return self.axis(event.code, event.value).into_iter().collect(),
+                };
+
+                dpad.into_iter()
+                    .map(|(button, key_state)| Event::GamepadButton { button, key_state })
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn axis(&self, code: u16, value: i32) -> Option<Event> {
+        let (_, axis) = AXES.iter().find(|(axis_code, _)| *axis_code == code)?;
+        let &(min, max) = self.ranges.get(&code)?;
+
+        if max <= min {
+            return None;
+        }
+
+        let position = (value - min) as f32 / (max - min) as f32;
+
+        let value = if axis.is_trigger() {
+            position
+        } else {
+            position * 2.0 - 1.0
+        };
+
+        Some(Event::GamepadAxis {
+            axis: *axis,
+            value: value.clamp(-1.0, 1.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::AxisFilter;
+
+    const EV_SYN: u16 = 0x00;
+    const EV_MSC: u16 = 0x04;
+
+    /// An Xbox pad as xpad reports it, triggers on Z and RZ.
+    fn xbox() -> Gamepad {
+        let stick = (-32768, 32767);
+        let ranges = [
+            (0x00, stick),
+            (0x01, stick),
+            (0x02, (0, 1023)),
+            (0x03, stick),
+            (0x04, stick),
+            (0x05, (0, 1023)),
+        ];
+
+        Gamepad::with_ranges(ranges.iter().cloned().collect())
+    }
+
+    fn event(type_: u16, code: u16, value: i32) -> input_event {
+        input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        }
+    }
+
+    /// Replays `(type, code, value)` triples, describing what came out.
+    fn replay(gamepad: &mut Gamepad, stream: &[(u16, u16, i32)]) -> Vec<String> {
+        stream
+            .iter()
+            .flat_map(|&(type_, code, value)| gamepad.handle(&event(type_, code, value)))
+            .map(|event| match event {
+                Event::GamepadButton { button, key_state } => {
+                    format!("{:?} {:?}", button, key_state)
+                }
+                Event::GamepadAxis { axis, value } => format!("{:?} {:.2}", axis, value),
+                event => panic!("not gamepad input: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recorded_xbox_stream() {
+        // Pressing A, rolling the D-pad from up to left, half a pull of the
+        // right trigger and the left stick pushed to the left.
+        let stream = [
+            (EV_MSC, 0x04, 0x90001),
+            (evdev::EV_KEY, 0x130, 1),
+            (EV_SYN, 0, 0),
+            (evdev::EV_ABS, ABS_HAT0Y, -1),
+            (EV_SYN, 0, 0),
+            (evdev::EV_ABS, ABS_HAT0X, -1),
+            (evdev::EV_ABS, ABS_HAT0Y, 0),
+            (EV_SYN, 0, 0),
+            (evdev::EV_ABS, ABS_HAT0X, 0),
+            (evdev::EV_KEY, 0x130, 0),
+            (EV_SYN, 0, 0),
+            (evdev::EV_ABS, 0x05, 512),
+            (evdev::EV_ABS, 0x00, -32768),
+            (evdev::EV_ABS, 0x01, -1),
+            (EV_SYN, 0, 0),
+        ];
+
+        assert_eq!(
+            replay(&mut xbox(), &stream),
+            [
+                "South Pressed",
+                "DPadUp Pressed",
+                "DPadLeft Pressed",
+                "DPadUp Released",
+                "DPadLeft Released",
+                "South Released",
+                "RightTrigger 0.50",
+                "LeftX -1.00",
+                "LeftY -0.00",
+            ]
+        );
+    }
+
+    #[test]
+    fn recorded_joystick_stream() {
+        // A flight stick: trigger and thumb buttons, a hat, and axes the
+        // device gave no range for.
+        let mut gamepad = Gamepad::with_ranges(HashMap::new());
+        let stream = [
+            (evdev::EV_KEY, BTN_JOYSTICK, 1),
+            (evdev::EV_KEY, BTN_JOYSTICK + 1, 1),
+            (evdev::EV_ABS, ABS_HAT0X, 1),
+            (evdev::EV_ABS, ABS_HAT0X, -1),
+            (evdev::EV_ABS, 0x00, 200),
+            (evdev::EV_KEY, BTN_JOYSTICK, 0),
+        ];
+
+        assert_eq!(
+            replay(&mut gamepad, &stream),
+            [
+                "Other(1) Pressed",
+                "Other(2) Pressed",
+                "DPadRight Pressed",
+                "DPadRight Released",
+                "DPadLeft Pressed",
+                "Other(1) Released",
+            ]
+        );
+    }
+
+    #[test]
+    fn buttons_are_told_apart() {
+        let codes = (BTN_JOYSTICK..=0x13f).chain(0x220..=0x223);
+        let buttons: Vec<GamepadButton> = codes.filter_map(button).collect();
+
+        assert_eq!(buttons.len(), 0x20 + 4);
+
+        for (i, button) in buttons.iter().enumerate() {
+            assert!(!buttons[..i].contains(button), "{:?} twice", button);
+        }
+
+        assert_eq!(button(0x132), Some(GamepadButton::Other(19)));
+        assert_eq!(button(BTN_JOYSTICK + 2), Some(GamepadButton::Other(3)));
+    }
+
+    #[test]
+    fn recorded_stick_drift_within_dead_zone() {
+        let mut gamepad = xbox();
+        let mut filter = AxisFilter::default();
+        // Resting noise, then a push to the right and back.
+        let stream = [
+            (evdev::EV_ABS, 0x00, 1200),
+            (evdev::EV_ABS, 0x00, -900),
+            (evdev::EV_ABS, 0x00, 1500),
+            (evdev::EV_ABS, 0x00, 32767),
+            (evdev::EV_ABS, 0x00, 31800),
+            (evdev::EV_ABS, 0x00, 300),
+        ];
+
+        let reported: Vec<String> = stream
+            .iter()
+            .flat_map(|&(type_, code, value)| gamepad.handle(&event(type_, code, value)))
+            .filter_map(|event| match event {
+                Event::GamepadAxis { axis, value } => filter.update(axis, value, 0.1),
+                _ => None,
+            })
+            .map(|value| format!("{:.2}", value))
+            .collect();
+
+        // Slightly off full stays unreported, not to flicker.
+        assert_eq!(reported, ["1.00", "0.00"]);
+    }
+}
@@ -0,0 +1,670 @@
+//! Characters typed by keys, in the active keyboard layout.
+//!
+//! evdev reports physical keys only, the layout lives in the display server.
+//! Layouts are compiled by xkbcommon, loaded at runtime so it isn't needed
+//! to build, from the same rules the display server uses. The layout is the
+//! configured one, otherwise the one in the `XKB_DEFAULT_*` variables, or the
+//! system's console and X11 layout. Without xkbcommon, characters are
+//! produced as on a US keyboard. Dead keys are reported as the spacing accent they stand for, see
+//! `Composer`, which also makes pressing them twice type the accent.
+
+use crate::common::{Key, KeyState, Locks, ModifierState};
+use crate::config::KeyboardConfig;
+
+use std::env;
+use std::ffi::CString;
+use std::fs;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::sync::LazyLock;
+
+const XKB_KEY_UP: c_int = 0;
+const XKB_KEY_DOWN: c_int = 1;
+const XKB_STATE_MODS_EFFECTIVE: c_int = 1 << 3;
+
+/// xkb numbers keys 8 above evdev, a leftover of X11's minimum keycode.
+const EVDEV_OFFSET: u32 = 8;
+
+/// Spacing accents of the dead keysyms, starting at `dead_grave`.
+const DEAD_KEYSYMS: (u32, &[char]) = (
+    0xfe50,
+    &[
+        '`', '´', '^', '~', '¯', '˘', '˙', '¨', '˚', '˝', 'ˇ', '¸', '˛',
+    ],
+);
+
+/// Where Debian's keyboard-configuration and systemd's localectl keep the
+/// system layout, both as shell variables.
+const SYSTEM_LAYOUT_FILES: &[&str] = &["/etc/default/keyboard", "/etc/vconsole.conf"];
+
+/// Modifiers that make a key a shortcut rather than type.
+const SHORTCUT_MODS: &[&[u8]] = &[b"Control\0", b"Mod1\0", b"Mod4\0"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Typed {
+    Char(char),
+    /// A dead key, as its spacing accent.
+    DeadKey(char),
+}
+
+/// Names of an XKB layout, as in `setxkbmap`. Unset names are left to
+/// xkbcommon's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Layout {
+    pub layout: Option<String>,
+    pub variant: Option<String>,
+    pub options: Option<String>,
+}
+
+impl Layout {
+    pub fn configured(config: &KeyboardConfig) -> Self {
+        if config.layout.is_some() {
+            return Layout {
+                layout: config.layout.clone(),
+                variant: config.variant.clone(),
+                options: config.options.clone(),
+            };
+        }
+
+        // Left to xkbcommon, which reads the variables itself.
+        if env::var_os("XKB_DEFAULT_LAYOUT").is_some() {
+            return Layout::default();
+        }
+
+        SYSTEM_LAYOUT_FILES
+            .iter()
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .map(|text| Layout::parse_system(&text))
+            .find(|layout| layout.layout.is_some())
+            .unwrap_or_default()
+    }
+
+    /// Reads `XKBLAYOUT="no"` and the like.
+    fn parse_system(text: &str) -> Self {
+        let mut layout = Layout::default();
+
+        for line in text.lines() {
+            let (name, value) = match line.trim().split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            let value = value.trim_matches(|c| c == '"' || c == '\'');
+            let value = Some(value.to_string()).filter(|value| !value.is_empty());
+
+            match name {
+                "XKBLAYOUT" => layout.layout = value,
+                "XKBVARIANT" => layout.variant = value,
+                "XKBOPTIONS" => layout.options = value,
+                _ => (),
+            }
+        }
+
+        layout
+    }
+}
+
+#[repr(C)]
+struct RuleNames {
+    rules: *const c_char,
+    model: *const c_char,
+    layout: *const c_char,
+    variant: *const c_char,
+    options: *const c_char,
+}
+
+type Object = *mut c_void;
+
+/// The few xkbcommon functions used.
+struct Library {
+    context_new: unsafe extern "C" fn(c_int) -> Object,
+    context_unref: unsafe extern "C" fn(Object),
+    keymap_new_from_names: unsafe extern "C" fn(Object, *const RuleNames, c_int) -> Object,
+    keymap_unref: unsafe extern "C" fn(Object),
+    keymap_mod_get_index: unsafe extern "C" fn(Object, *const c_char) -> u32,
+    state_new: unsafe extern "C" fn(Object) -> Object,
+    state_unref: unsafe extern "C" fn(Object),
+    state_update_key: unsafe extern "C" fn(Object, u32, c_int) -> c_int,
+    state_update_mask: unsafe extern "C" fn(Object, u32, u32, u32, u32, u32, u32) -> c_int,
+    state_key_get_one_sym: unsafe extern "C" fn(Object, u32) -> u32,
+    state_key_get_utf32: unsafe extern "C" fn(Object, u32) -> u32,
+    state_mod_name_is_active: unsafe extern "C" fn(Object, *const c_char, c_int) -> c_int,
+}
+
+/// Loaded once, and never unloaded.
+static LIBRARY: LazyLock<Option<Library>> = LazyLock::new(|| unsafe { Library::load() });
+
+impl Library {
+    unsafe fn load() -> Option<Self> {
+        let handle = libc::dlopen(
+            b"libxkbcommon.so.0\0".as_ptr() as *const c_char,
+            libc::RTLD_NOW,
+        );
+
+        if handle.is_null() {
+            return None;
+        }
+
+        macro_rules! symbol {
+            ($name:literal) => {{
+                let symbol = libc::dlsym(handle, $name.as_ptr() as *const c_char);
+
+                if symbol.is_null() {
+                    return None;
+                }
+
+                #[allow(clippy::missing_transmute_annotations)]
+                std::mem::transmute(symbol)
+            }};
+        }
+
+        Some(Library {
+            context_new: symbol!(b"xkb_context_new\0"),
+            context_unref: symbol!(b"xkb_context_unref\0"),
+            keymap_new_from_names: symbol!(b"xkb_keymap_new_from_names\0"),
+            keymap_unref: symbol!(b"xkb_keymap_unref\0"),
+            keymap_mod_get_index: symbol!(b"xkb_keymap_mod_get_index\0"),
+            state_new: symbol!(b"xkb_state_new\0"),
+            state_unref: symbol!(b"xkb_state_unref\0"),
+            state_update_key: symbol!(b"xkb_state_update_key\0"),
+            state_update_mask: symbol!(b"xkb_state_update_mask\0"),
+            state_key_get_one_sym: symbol!(b"xkb_state_key_get_one_sym\0"),
+            state_key_get_utf32: symbol!(b"xkb_state_key_get_utf32\0"),
+            state_mod_name_is_active: symbol!(b"xkb_state_mod_name_is_active\0"),
+        })
+    }
+}
+
+/// A compiled layout and the state of its modifiers, groups and locks.
+struct Xkb {
+    library: &'static Library,
+    context: Object,
+    keymap: Object,
+    state: Object,
+    /// Right alt is ISO_Level3_Shift rather than an alt key.
+    alt_gr: bool,
+}
+
+impl Xkb {
+    fn new(layout: &Layout, locks: Locks) -> Option<Self> {
+        let library = LIBRARY.as_ref()?;
+        let name = |name: &Option<String>| {
+            name.as_deref()
+                .filter(|name| !name.is_empty())
+                .and_then(|name| CString::new(name).ok())
+        };
+        let names = [
+            name(&layout.layout),
+            name(&layout.variant),
+            name(&layout.options),
+        ];
+        let [layout, variant, options] = names
+            .each_ref()
+            .map(|name| name.as_ref().map_or(ptr::null(), |name| name.as_ptr()));
+        let rule_names = RuleNames {
+            rules: ptr::null(),
+            model: ptr::null(),
+            layout,
+            variant,
+            options,
+        };
+
+        unsafe {
+            let context = (library.context_new)(0);
+
+            if context.is_null() {
+                return None;
+            }
+
+            let keymap = (library.keymap_new_from_names)(context, &rule_names, 0);
+            let state = if keymap.is_null() {
+                ptr::null_mut()
+            } else {
+                (library.state_new)(keymap)
+            };
+            // Each unref accepts null.
+            let mut xkb = Xkb {
+                library,
+                context,
+                keymap,
+                state,
+                alt_gr: false,
+            };
+
+            if state.is_null() {
+                return None;
+            }
+
+            if let (Some(right_alt), Some(level3_shift)) =
+                (Key::RightAlt.to_evdev(), Key::AltGr.to_keysym())
+            {
+                let keycode = u32::from(right_alt) + EVDEV_OFFSET;
+                xkb.alt_gr = (library.state_key_get_one_sym)(state, keycode) == level3_shift;
+            }
+
+            // Locks engaged before startup can't be seen in key events.
+            let locked = [(locks.caps_lock, b"Lock\0"), (locks.num_lock, b"Mod2\0")]
+                .iter()
+                .filter(|(on, _)| *on)
+                .map(|(_, name)| {
+                    (library.keymap_mod_get_index)(keymap, name.as_ptr() as *const c_char)
+                })
+                .filter(|&index| index < 32)
+                .fold(0, |mask, index| mask | 1 << index);
+            (library.state_update_mask)(state, 0, 0, locked, 0, 0, 0);
+
+            Some(xkb)
+        }
+    }
+
+    fn typed(&mut self, key: Key, key_state: KeyState, repeat: bool) -> Option<Typed> {
+        let keycode = u32::from(key.to_evdev()?) + EVDEV_OFFSET;
+        let library = self.library;
+
+        unsafe {
+            // Asked before the key itself changes the state, as xkbcommon
+            // recommends, so caps lock doesn't apply to its own press.
+            let shortcut = SHORTCUT_MODS.iter().any(|name| {
+                let active = library.state_mod_name_is_active;
+                active(
+                    self.state,
+                    name.as_ptr() as *const c_char,
+                    XKB_STATE_MODS_EFFECTIVE,
+                ) > 0
+            });
+            let keysym = (library.state_key_get_one_sym)(self.state, keycode);
+            let utf32 = (library.state_key_get_utf32)(self.state, keycode);
+
+            // Autorepeat doesn't change the state.
+            if !repeat {
+                let direction = match key_state {
+                    KeyState::Pressed => XKB_KEY_DOWN,
+                    KeyState::Released => XKB_KEY_UP,
+                };
+                (library.state_update_key)(self.state, keycode, direction);
+            }
+
+            if key_state == KeyState::Released || shortcut {
+                return None;
+            }
+
+            let (first, accents) = DEAD_KEYSYMS;
+
+            match keysym
+                .checked_sub(first)
+                .and_then(|index| accents.get(index as usize))
+            {
+                Some(_) if repeat => None,
+                Some(&accent) => Some(Typed::DeadKey(accent)),
+                None => std::char::from_u32(utf32)
+                    .filter(|&c| c != '\0')
+                    .map(Typed::Char),
+            }
+        }
+    }
+}
+
+// The objects are owned, not shared with another thread, so handing them
+// over to the processing thread is safe.
+unsafe impl Send for Xkb {}
+
+impl Drop for Xkb {
+    fn drop(&mut self) {
+        unsafe {
+            (self.library.state_unref)(self.state);
+            (self.library.keymap_unref)(self.keymap);
+            (self.library.context_unref)(self.context);
+        }
+    }
+}
+
+/// Turns key events into what they type.
+pub struct Keymap {
+    xkb: Option<Xkb>,
+    /// Tracked for the US layout, which xkbcommon does itself.
+    modifiers: ModifierState,
+}
+
+impl Keymap {
+    pub fn new(layout: &Layout, locks: Locks) -> Self {
+        Self {
+            xkb: Xkb::new(layout, locks),
+            modifiers: ModifierState::new(locks),
+        }
+    }
+
+    /// Whether the layout is read by xkbcommon rather than assumed.
+    pub fn is_xkb(&self) -> bool {
+        self.xkb.is_some()
+    }
+
+    /// The key the layout makes of `key`, which is AltGr for right alt on
+    /// layouts that type extra characters with it.
+    pub fn key(&self, key: Key) -> Key {
+        match key {
+            Key::RightAlt if self.xkb.as_ref().is_some_and(|xkb| xkb.alt_gr) => Key::AltGr,
+            key => key,
+        }
+    }
+
+    /// What pressing, or releasing, `key` types, with `repeat` set for the
+    /// presses of autorepeat.
+    pub fn typed(&mut self, key: Key, key_state: KeyState, repeat: bool) -> Option<Typed> {
+        self.modifiers.update(key, key_state);
+
+        if let Some(xkb) = self.xkb.as_mut() {
+            return xkb.typed(key, key_state, repeat);
+        }
+
+        let held = self.modifiers.held;
+
+        // Shortcuts don't type.
+        if key_state == KeyState::Released
+            || held.left_control
+            || held.right_control
+            || held.left_alt
+            || held.right_alt
+            || held.left_meta
+            || held.right_meta
+        {
+            return None;
+        }
+
+        let locks = self.modifiers.locks;
+        let shift = held.left_shift || held.right_shift;
+
+        to_char(key, shift, locks.caps_lock, locks.num_lock).map(Typed::Char)
+    }
+}
+
+/// The character a key types, unshifted and shifted.
+fn chars(key: Key) -> Option<(char, char)> {
+    let chars = match key {
+        Key::Digit1 => ('1', '!'),
+        Key::Digit2 => ('2', '@'),
+        Key::Digit3 => ('3', '#'),
+        Key::Digit4 => ('4', '$'),
+        Key::Digit5 => ('5', '%'),
+        Key::Digit6 => ('6', '^'),
+        Key::Digit7 => ('7', '&'),
+        Key::Digit8 => ('8', '*'),
+        Key::Digit9 => ('9', '('),
+        Key::Digit0 => ('0', ')'),
+        Key::Minus => ('-', '_'),
+        Key::Equal => ('=', '+'),
+        Key::LeftBracket => ('[', '{'),
+        Key::RightBracket => (']', '}'),
+        Key::Backslash => ('\\', '|'),
+        Key::Semicolon => (';', ':'),
+        Key::Apostrophe => ('\'', '"'),
+        Key::Grave => ('`', '~'),
+        Key::Comma => (',', '<'),
+        Key::Period => ('.', '>'),
+        Key::Slash => ('/', '?'),
+        Key::Space => (' ', ' '),
+        Key::Enter | Key::KeypadEnter => ('\r', '\r'),
+        Key::Tab => ('\t', '\t'),
+        Key::Backspace => ('\x08', '\x08'),
+        Key::Escape => ('\x1b', '\x1b'),
+        Key::KeypadDivide => ('/', '/'),
+        Key::KeypadMultiply => ('*', '*'),
+        Key::KeypadMinus => ('-', '-'),
+        Key::KeypadPlus => ('+', '+'),
+        _ => return None,
+    };
+
+    Some(chars)
+}
+
+fn keypad_char(key: Key) -> Option<char> {
+    let c = match key {
+        Key::Keypad0 => '0',
+        Key::Keypad1 => '1',
+        Key::Keypad2 => '2',
+        Key::Keypad3 => '3',
+        Key::Keypad4 => '4',
+        Key::Keypad5 => '5',
+        Key::Keypad6 => '6',
+        Key::Keypad7 => '7',
+        Key::Keypad8 => '8',
+        Key::Keypad9 => '9',
+        Key::KeypadDecimal => '.',
+        _ => return None,
+    };
+
+    Some(c)
+}
+
+fn to_char(key: Key, shift: bool, caps_lock: bool, num_lock: bool) -> Option<char> {
+    // Letter keys are named by their letter.
+    let name = key.name();
+
+    if name.len() == 1 && name.as_bytes()[0].is_ascii_lowercase() {
+        let c = name.as_bytes()[0] as char;
+
+        return Some(if shift != caps_lock {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        });
+    }
+
+    if num_lock {
+        if let Some(c) = keypad_char(key) {
+            return Some(c);
+        }
+    }
+
+    chars(key).map(|(plain, shifted)| if shift { shifted } else { plain })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::KeyState::{Pressed, Released};
+
+    #[test]
+    fn system_layout() {
+        let text = "# KEYBOARD CONFIGURATION FILE\n\
+                    XKBMODEL=\"pc105\"\n\
+                    XKBLAYOUT=\"no\"\n\
+                    XKBVARIANT=\"\"\n\
+                    XKBOPTIONS='compose:ralt'\n";
+
+        assert_eq!(
+            Layout::parse_system(text),
+            Layout {
+                layout: Some("no".to_string()),
+                variant: None,
+                options: Some("compose:ralt".to_string()),
+            }
+        );
+        assert_eq!(Layout::parse_system("KEYMAP=us\n"), Layout::default());
+    }
+
+    #[test]
+    fn configured_layout_comes_first() {
+        let config = KeyboardConfig {
+            layout: Some("de".to_string()),
+            variant: Some("nodeadkeys".to_string()),
+            options: None,
+        };
+        let layout = Layout::configured(&config);
+
+        assert_eq!(layout.layout.as_deref(), Some("de"));
+        assert_eq!(layout.variant.as_deref(), Some("nodeadkeys"));
+
+        let mut keymap = match Keymap::new(&layout, Locks::default()).xkb {
+            Some(xkb) => xkb,
+            None => return,
+        };
+
+        // Without dead keys, the acute accent is typed as is.
+        assert_eq!(
+            keymap.typed(Key::Equal, Pressed, false),
+            Some(Typed::Char('´'))
+        );
+    }
+
+    fn layout(layout: &str, locks: Locks) -> Option<Keymap> {
+        let layout = Layout {
+            layout: Some(layout.to_string()),
+            ..Layout::default()
+        };
+        let keymap = Keymap::new(&layout, locks);
+
+        if !keymap.is_xkb() {
+            eprintln!("xkbcommon is not installed, skipping");
+            return None;
+        }
+
+        Some(keymap)
+    }
+
+    fn us() -> Keymap {
+        Keymap {
+            xkb: None,
+            modifiers: ModifierState::new(Locks::default()),
+        }
+    }
+
+    /// What pressing `keys` in order, then releasing them, types.
+    fn chord(keymap: &mut Keymap, keys: &[Key]) -> Vec<Typed> {
+        let typed = keys
+            .iter()
+            .filter_map(|&key| keymap.typed(key, Pressed, false))
+            .collect();
+
+        for &key in keys.iter().rev() {
+            assert_eq!(keymap.typed(key, Released, false), None);
+        }
+
+        typed
+    }
+
+    #[test]
+    fn us_fallback() {
+        let mut keymap = us();
+
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('a')]);
+        assert_eq!(
+            chord(&mut keymap, &[Key::LeftShift, Key::Digit2]),
+            [Typed::Char('@')]
+        );
+        assert_eq!(chord(&mut keymap, &[Key::LeftControl, Key::A]), []);
+        assert_eq!(chord(&mut keymap, &[Key::Keypad1]), []);
+
+        chord(&mut keymap, &[Key::CapsLock]);
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('A')]);
+        assert_eq!(
+            chord(&mut keymap, &[Key::LeftShift, Key::A]),
+            [Typed::Char('a')]
+        );
+    }
+
+    #[test]
+    fn xkb_us() {
+        let mut keymap = match layout("us", Locks::default()) {
+            Some(keymap) => keymap,
+            None => return,
+        };
+
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('a')]);
+        assert_eq!(
+            chord(&mut keymap, &[Key::LeftShift, Key::Digit2]),
+            [Typed::Char('@')]
+        );
+        assert_eq!(chord(&mut keymap, &[Key::Enter]), [Typed::Char('\r')]);
+        assert_eq!(chord(&mut keymap, &[Key::LeftControl, Key::A]), []);
+        assert_eq!(chord(&mut keymap, &[Key::RightAlt, Key::A]), []);
+        assert_eq!(chord(&mut keymap, &[Key::LeftMeta, Key::A]), []);
+        assert_eq!(chord(&mut keymap, &[Key::Equal]), [Typed::Char('=')]);
+
+        chord(&mut keymap, &[Key::CapsLock]);
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('A')]);
+        chord(&mut keymap, &[Key::CapsLock]);
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('a')]);
+    }
+
+    #[test]
+    fn xkb_locks_are_seeded() {
+        let locks = Locks {
+            caps_lock: true,
+            num_lock: true,
+            scroll_lock: false,
+        };
+        let mut keymap = match layout("us", locks) {
+            Some(keymap) => keymap,
+            None => return,
+        };
+
+        assert_eq!(chord(&mut keymap, &[Key::A]), [Typed::Char('A')]);
+        assert_eq!(chord(&mut keymap, &[Key::Keypad1]), [Typed::Char('1')]);
+    }
+
+    #[test]
+    fn xkb_layout_is_used() {
+        let mut keymap = match layout("de", Locks::default()) {
+            Some(keymap) => keymap,
+            None => return,
+        };
+
+        assert_eq!(chord(&mut keymap, &[Key::Y]), [Typed::Char('z')]);
+        assert_eq!(chord(&mut keymap, &[Key::Minus]), [Typed::Char('ß')]);
+        assert_eq!(chord(&mut keymap, &[Key::Equal]), [Typed::DeadKey('´')]);
+        assert_eq!(
+            chord(&mut keymap, &[Key::LeftShift, Key::Equal]),
+            [Typed::DeadKey('`')]
+        );
+    }
+
+    #[test]
+    fn xkb_dead_keys_behind_alt_gr() {
+        let mut keymap = match layout("no", Locks::default()) {
+            Some(keymap) => keymap,
+            None => return,
+        };
+
+        // Right alt is AltGr here, so it types rather than being a shortcut.
+        assert_eq!(
+            chord(&mut keymap, &[Key::RightAlt, Key::Equal]),
+            [Typed::DeadKey('´')]
+        );
+        assert_eq!(
+            chord(&mut keymap, &[Key::RightAlt, Key::Digit2]),
+            [Typed::Char('@')]
+        );
+        assert_eq!(chord(&mut keymap, &[Key::Equal]), [Typed::Char('\\')]);
+    }
+
+    #[test]
+    fn xkb_dead_keys_dont_repeat() {
+        let mut keymap = match layout("de", Locks::default()) {
+            Some(keymap) => keymap,
+            None => return,
+        };
+
+        assert_eq!(
+            keymap.typed(Key::Equal, Pressed, false),
+            Some(Typed::DeadKey('´'))
+        );
+        assert_eq!(keymap.typed(Key::Equal, Pressed, true), None);
+        assert_eq!(keymap.typed(Key::Equal, Released, false), None);
+        assert_eq!(keymap.typed(Key::A, Pressed, false), Some(Typed::Char('a')));
+        assert_eq!(keymap.typed(Key::A, Pressed, true), Some(Typed::Char('a')));
+    }
+
+    #[test]
+    fn right_alt_is_alt_gr_where_it_types() {
+        assert_eq!(us().key(Key::RightAlt), Key::RightAlt);
+
+        if let Some(keymap) = layout("us", Locks::default()) {
+            assert_eq!(keymap.key(Key::RightAlt), Key::RightAlt);
+        }
+
+        if let Some(keymap) = layout("no", Locks::default()) {
+            assert_eq!(keymap.key(Key::RightAlt), Key::AltGr);
+            assert_eq!(keymap.key(Key::LeftAlt), Key::LeftAlt);
+        }
+    }
+}
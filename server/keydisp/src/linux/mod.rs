@@ -0,0 +1,333 @@
+//! Input capture on Linux, read straight from the evdev devices.
+//!
+//! This sees input regardless of display server, but also without its
+//! notion of windows. Which window has focus is asked of the compositor or X
+//! server separately, see `wayland` and `x11`, elsewhere every event counts
+//! as going to the same window. Pointer movement is only known as relative
+//! motion, so `MouseMove` events are only produced on X11, which is asked
+//! where the pointer is. Devices plugged in later are noticed through inotify.
+//! Key events are handed over as evdev reports them, what they type is
+//! looked up by the processor, see `translate`.
+
+mod evdev;
+mod gamepad;
+mod keymap;
+pub mod synthetic;
+mod wayland;
+mod x11;
+
+use crate::common::{DeviceId, Event, Input, Key, KeyState, Locks, MouseButton, Timestamp};
+
+pub use self::keymap::{Keymap, Layout};
+
+use self::evdev::{input_event, Device};
+use self::gamepad::Gamepad;
+use self::keymap::Typed;
+use self::x11::Pointer;
+
+use std::env;
+use std::io::{self, Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 0 when focus isn't tracked, see the module documentation.
+pub fn get_fg_window() -> usize {
+    match wayland::focused() {
+        0 => x11::focused(),
+        window => window,
+    }
+}
+
+/// A position on the screen relative to the top left corner of `window`,
+/// if there is a way to tell.
+pub fn window_position(window: usize, x: i32, y: i32) -> Option<(i32, i32)> {
+    if x11::is_tracking() {
+        x11::window_position(window, x, y)
+    } else {
+        None
+    }
+}
+
+/// Follows focus on the compositor, or the X server outside Wayland sessions.
+/// Fails when neither tells which window is focused.
+pub fn track_focus() -> io::Result<()> {
+    // Under Xwayland, X11 only knows about part of the windows.
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        return wayland::track_focus();
+    }
+
+    x11::track_focus()
+}
+
+/// Reads which locks are engaged from the keyboard LEDs.
+pub fn get_lock_state() -> Locks {
+    let devices = evdev::devices().unwrap_or_default();
+    let lit = |led| {
+        devices
+            .iter()
+            .filter(|device| device.supports(evdev::EV_LED))
+            .any(|device| device.led(led))
+    };
+
+    Locks {
+        caps_lock: lit(evdev::LED_CAPSL),
+        num_lock: lit(evdev::LED_NUML),
+        scroll_lock: lit(evdev::LED_SCROLLL),
+    }
+}
+
+/// What the key event `code` and `value` typed, in `keymap`'s layout. Every
+/// key event goes through here, releases included, for the layout to follow
+/// the modifiers and locks.
+pub fn translate(keymap: &mut Keymap, code: u16, value: i32) -> Vec<Event> {
+    let key_state = if value == 0 {
+        KeyState::Released
+    } else {
+        KeyState::Pressed
+    };
+
+    match Key::from_evdev(code).and_then(|key| keymap.typed(key, key_state, value == 2)) {
+        Some(Typed::Char(c)) => vec![Event::Char(c)],
+        Some(Typed::DeadKey(accent)) => vec![Event::DeadKey(accent)],
+        None => vec![],
+    }
+}
+
+struct Source {
+    device: Device,
+    id: Arc<DeviceId>,
+    /// Virtual devices are how tools like ydotool type on Linux.
+    injected: bool,
+    gamepad: Option<Gamepad>,
+}
+
+pub struct Hook {
+    callback: Box<dyn FnMut(Input)>,
+    /// The device whose events are being handled.
+    device: Option<Arc<DeviceId>>,
+    injected: bool,
+    /// When the event being handled happened.
+    time: Timestamp,
+}
+
+impl Source {
+    /// Devices without keys, buttons or wheels have nothing to display.
+    fn new(device: Device) -> Option<Source> {
+        if !device.supports(evdev::EV_KEY) && !device.supports(evdev::EV_REL) {
+            return None;
+        }
+
+        let source = Source {
+            id: Arc::new(DeviceId {
+                name: device.name.clone(),
+                vendor: device.vendor,
+                product: device.product,
+                phys: device.phys.clone(),
+            }),
+            injected: device.bus == evdev::BUS_VIRTUAL,
+            gamepad: if gamepad::is_gamepad(&device) {
+                Some(Gamepad::new(&device))
+            } else {
+                None
+            },
+            device,
+        };
+
+        eprintln!(
+            "Reading input from {} ({})",
+            source.id,
+            source.device.path.display()
+        );
+        Some(source)
+    }
+}
+
+/// Opens devices the watcher saw added, unless they are already read.
+fn plugged(sources: &mut Vec<Source>, paths: Vec<PathBuf>) {
+    for path in paths {
+        if sources.iter().any(|source| source.device.path == path) {
+            continue;
+        }
+
+        // Until udev has given a new node its group, it can't be opened,
+        // and it is tried again when that changes.
+        if let Some(source) = Device::open(&path).ok().and_then(Source::new) {
+            sources.push(source);
+        }
+    }
+}
+
+impl Hook {
+    pub fn run_forever(callback: impl FnMut(Input) + 'static) -> Result<(), Error> {
+        // Watched first, so nothing plugged in meanwhile is missed.
+        let mut watcher = match evdev::Watcher::new(Path::new(evdev::INPUT_DIR)) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                eprintln!("Not watching for new input devices: {}", e);
+                None
+            }
+        };
+        let mut pointer = if x11::is_tracking() {
+            Pointer::new()
+                .map_err(|e| eprintln!("Not following the pointer: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let mut sources: Vec<Source> = evdev::devices()?
+            .into_iter()
+            .filter_map(Source::new)
+            .collect();
+
+        if sources.is_empty() {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "no readable input devices, add the user to the `input` group",
+            ));
+        }
+
+        let mut hook = Hook {
+            callback: Box::new(callback),
+            device: None,
+            injected: false,
+            time: Timestamp::now(),
+        };
+
+        // Without a watcher, capture stops once every device is gone.
+        while !sources.is_empty() || watcher.is_some() {
+            let mut fds: Vec<RawFd> = sources
+                .iter()
+                .map(|source| source.device.as_raw_fd())
+                .collect();
+            let mut push = |fd| {
+                fds.push(fd);
+                fds.len() - 1
+            };
+            let watcher_index = watcher.as_ref().map(|watcher| push(watcher.as_raw_fd()));
+            let pointer_index = pointer.as_ref().map(|pointer| push(pointer.as_raw_fd()));
+            let ready = evdev::poll(&fds)?;
+
+            // In reverse, so removing a device doesn't shift the later
+            // indices. The others come last, so devices added don't either.
+            for &index in ready.iter().rev() {
+                if Some(index) == pointer_index {
+                    match pointer.as_mut().map(Pointer::read) {
+                        Some(Ok(Some((x, y)))) => {
+                            hook.device = None;
+                            hook.injected = false;
+                            hook.time = Timestamp::now();
+                            hook.emit(Event::MouseMove { x, y });
+                        }
+                        Some(Err(e)) => {
+                            eprintln!("Stopped following the pointer: {}", e);
+                            pointer = None;
+                        }
+                        _ => (),
+                    }
+
+                    continue;
+                }
+
+                if Some(index) == watcher_index {
+                    if let Some(watching) = watcher.as_mut() {
+                        match watching.read() {
+                            Ok(paths) => plugged(&mut sources, paths),
+                            Err(e) => {
+                                eprintln!("Stopped watching for new input devices: {}", e);
+                                watcher = None;
+                            }
+                        }
+                    }
+
+                    continue;
+                }
+
+                let source = &mut sources[index];
+
+                match source.device.read() {
+                    Ok(events) => {
+                        hook.device = Some(source.id.clone());
+                        hook.injected = source.injected;
+
+                        for event in &events {
+                            hook.time = Timestamp::ago(source.device.age(event));
+                            hook.handle(event, source.gamepad.as_mut());
+                        }
+                    }
+                    Err(e) => {
+                        let source = sources.remove(index);
+                        eprintln!("Stopped reading {}: {}", source.device.name, e);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, event: &input_event, gamepad: Option<&mut Gamepad>) {
+        if let Some(gamepad) = gamepad {
+            let events = gamepad.handle(event);
+
+            if !events.is_empty() {
+                events.into_iter().for_each(|event| self.emit(event));
+                return;
+            }
+        }
+
+        // 0 is a release, 1 a press and 2 autorepeat.
+        let key_state = if event.value == 0 {
+            KeyState::Released
+        } else {
+            KeyState::Pressed
+        };
+
+        match event.type_ {
+            evdev::EV_KEY => {
+                let button = match event.code {
+                    evdev::BTN_LEFT => Some(MouseButton::Left),
+                    evdev::BTN_RIGHT => Some(MouseButton::Right),
+                    evdev::BTN_MIDDLE => Some(MouseButton::Middle),
+                    evdev::BTN_SIDE => Some(MouseButton::Back),
+                    evdev::BTN_EXTRA => Some(MouseButton::Forward),
+                    _ => None,
+                };
+
+                if let Some(button) = button {
+                    self.emit(Event::MouseButton { button, key_state });
+                } else if let Some(key) = Key::from_evdev(event.code) {
+                    self.emit(Event::Key { key, key_state });
+                    self.emit(Event::Untranslated {
+                        code: event.code,
+                        value: event.value,
+                    });
+                }
+            }
+            // Wheels also report in 1/120 notches, those are ignored so a
+            // scroll isn't counted twice.
+            evdev::EV_REL => match event.code {
+                evdev::REL_WHEEL => self.emit(Event::Scroll {
+                    dx: 0.0,
+                    dy: event.value as f32,
+                }),
+                evdev::REL_HWHEEL => self.emit(Event::Scroll {
+                    dx: event.value as f32,
+                    dy: 0.0,
+                }),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn emit(&mut self, event: Event) {
+        (self.callback)(Input {
+            event,
+            device: self.device.clone(),
+            injected: self.injected,
+            time: self.time,
+            window: get_fg_window(),
+        });
+    }
+}
@@ -0,0 +1,76 @@
+//! Made up typing, to measure keydisp itself rather than a keyboard.
+//!
+//! Produces what the evdev source would for a keyboard typing the alphabet
+//! over and over: each keystroke is a press and a release, each followed by
+//! its evdev code for the processor to translate, stamped when they are
+//! handed over.
+
+use crate::common::{Event, Input, Key, KeyState, Timestamp};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+const LETTERS: [Key; 26] = [
+    Key::A,
+    Key::B,
+    Key::C,
+    Key::D,
+    Key::E,
+    Key::F,
+    Key::G,
+    Key::H,
+    Key::I,
+    Key::J,
+    Key::K,
+    Key::L,
+    Key::M,
+    Key::N,
+    Key::O,
+    Key::P,
+    Key::Q,
+    Key::R,
+    Key::S,
+    Key::T,
+    Key::U,
+    Key::V,
+    Key::W,
+    Key::X,
+    Key::Y,
+    Key::Z,
+];
+
+/// Types `keystrokes` keys, one every `interval`, on the calling thread.
+pub fn run(keystrokes: usize, interval: Duration, mut callback: impl FnMut(Input)) {
+    let start = Instant::now();
+
+    for (i, &key) in LETTERS.iter().cycle().take(keystrokes).enumerate() {
+        // Paced from the start, so time spent in the callback isn't added.
+        if let Some(wait) = (start + interval * i as u32).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+
+        let code = key.to_evdev().expect("letters to have evdev codes.");
+        let events = [
+            Event::Key {
+                key,
+                key_state: KeyState::Pressed,
+            },
+            Event::Untranslated { code, value: 1 },
+            Event::Key {
+                key,
+                key_state: KeyState::Released,
+            },
+            Event::Untranslated { code, value: 0 },
+        ];
+
+        for &event in &events {
+            callback(Input {
+                event,
+                device: None,
+                injected: false,
+                time: Timestamp::now(),
+                window: super::get_fg_window(),
+            });
+        }
+    }
+}
@@ -0,0 +1,591 @@
+//! The focused window on Wayland compositors based on wlroots, like sway.
+//!
+//! Wayland doesn't let clients ask for the focused window, but compositors
+//! that offer `zwlr_foreign_toplevel_manager_v1` announce every toplevel and
+//! whether it is activated. The few messages needed are spoken directly over
+//! the compositor's socket. Toplevels are numbered from 1 as they appear, so
+//! a selected window that closes is never mistaken for a later one.
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+const MANAGER_INTERFACE: &str = "zwlr_foreign_toplevel_manager_v1";
+const MANAGER_VERSION: u32 = 3;
+
+const DISPLAY_ID: u32 = 1;
+const REGISTRY_ID: u32 = 2;
+const SYNC_ID: u32 = 3;
+const MANAGER_ID: u32 = 4;
+
+// Requests.
+const DISPLAY_SYNC: u16 = 0;
+const DISPLAY_GET_REGISTRY: u16 = 1;
+const REGISTRY_BIND: u16 = 0;
+const TOPLEVEL_DESTROY: u16 = 7;
+
+// Events.
+const DISPLAY_ERROR: u16 = 0;
+const REGISTRY_GLOBAL: u16 = 0;
+const CALLBACK_DONE: u16 = 0;
+const MANAGER_TOPLEVEL: u16 = 0;
+const MANAGER_FINISHED: u16 = 1;
+const TOPLEVEL_STATE: u16 = 4;
+const TOPLEVEL_DONE: u16 = 5;
+const TOPLEVEL_CLOSED: u16 = 6;
+
+const STATE_ACTIVATED: u32 = 2;
+
+/// The focused toplevel, 0 when unknown.
+static FOCUSED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn focused() -> usize {
+    FOCUSED.load(Ordering::Relaxed)
+}
+
+enum Arg<'a> {
+    Uint(u32),
+    Str(&'a str),
+}
+
+struct Message {
+    object: u32,
+    opcode: u16,
+    body: Vec<u8>,
+}
+
+/// Reads the arguments of a message, in order.
+struct Args<'a> {
+    body: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    fn uint(&mut self) -> io::Result<u32> {
+        let (word, rest) = split(self.body, 4)?;
+        self.body = rest;
+        Ok(u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+    }
+
+    /// Arrays and strings, the latter with their terminating NUL.
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.uint()? as usize;
+        let (bytes, rest) = split(self.body, padded(len))?;
+        self.body = rest;
+        Ok(&bytes[..len])
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let bytes = self.bytes()?;
+        let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+fn split(body: &[u8], len: usize) -> io::Result<(&[u8], &[u8])> {
+    if body.len() < len {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated message"));
+    }
+
+    Ok(body.split_at(len))
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+struct Connection {
+    stream: UnixStream,
+}
+
+impl Connection {
+    /// `$WAYLAND_DISPLAY`, relative to `$XDG_RUNTIME_DIR` unless absolute.
+    fn open() -> io::Result<Connection> {
+        let display = env::var_os("WAYLAND_DISPLAY")
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "not a Wayland session"))?;
+        let mut path = PathBuf::from(display);
+
+        if path.is_relative() {
+            let runtime_dir = env::var_os("XDG_RUNTIME_DIR")
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "XDG_RUNTIME_DIR is not set"))?;
+            path = PathBuf::from(runtime_dir).join(path);
+        }
+
+        Ok(Connection {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    fn send(&mut self, object: u32, opcode: u16, args: &[Arg]) -> io::Result<()> {
+        let mut body = Vec::new();
+
+        for arg in args {
+            match arg {
+                Arg::Uint(value) => body.extend_from_slice(&value.to_ne_bytes()),
+                Arg::Str(s) => {
+                    let len = s.len() + 1;
+                    body.extend_from_slice(&(len as u32).to_ne_bytes());
+                    body.extend_from_slice(s.as_bytes());
+                    body.resize(body.len() + padded(len) - s.len(), 0);
+                }
+            }
+        }
+
+        let size = (8 + body.len()) as u32;
+        let mut message = Vec::with_capacity(size as usize);
+        message.extend_from_slice(&object.to_ne_bytes());
+        message.extend_from_slice(&(size << 16 | u32::from(opcode)).to_ne_bytes());
+        message.extend_from_slice(&body);
+
+        self.stream.write_all(&message)
+    }
+
+    fn recv(&mut self) -> io::Result<Message> {
+        let mut header = [0u8; 8];
+        self.stream.read_exact(&mut header).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => Error::new(e.kind(), "the compositor went away"),
+            _ => e,
+        })?;
+
+        let object = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+        let word = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+        let size = (word >> 16) as usize;
+
+        if size < 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "message smaller than its header"));
+        }
+
+        let mut body = vec![0; size - 8];
+        self.stream.read_exact(&mut body)?;
+
+        let message = Message {
+            object,
+            opcode: word as u16,
+            body,
+        };
+
+        if message.object == DISPLAY_ID && message.opcode == DISPLAY_ERROR {
+            let mut args = message.args();
+            let (_, code) = (args.uint()?, args.uint()?);
+            let text = args.string()?;
+            return Err(Error::other(format!("compositor error {}: {}", code, text)));
+        }
+
+        Ok(message)
+    }
+}
+
+impl Message {
+    fn args(&self) -> Args<'_> {
+        Args { body: &self.body }
+    }
+}
+
+/// A toplevel as announced so far, changes apply on its `done` event.
+struct Toplevel {
+    window: usize,
+    activated: bool,
+}
+
+struct Tracker {
+    connection: Connection,
+    toplevels: HashMap<u32, Toplevel>,
+    next_window: usize,
+}
+
+impl Tracker {
+    /// Binds the toplevel manager, if the compositor has one.
+    fn new(mut connection: Connection) -> io::Result<Tracker> {
+        connection.send(DISPLAY_ID, DISPLAY_GET_REGISTRY, &[Arg::Uint(REGISTRY_ID)])?;
+        connection.send(DISPLAY_ID, DISPLAY_SYNC, &[Arg::Uint(SYNC_ID)])?;
+
+        // Globals are all announced by the time the sync is done.
+        let mut manager = None;
+
+        loop {
+            let message = connection.recv()?;
+            let mut args = message.args();
+
+            match (message.object, message.opcode) {
+                (REGISTRY_ID, REGISTRY_GLOBAL) => {
+                    let name = args.uint()?;
+
+                    if args.string()? == MANAGER_INTERFACE {
+                        manager = Some((name, args.uint()?.min(MANAGER_VERSION)));
+                    }
+                }
+                (SYNC_ID, CALLBACK_DONE) => break,
+                _ => (),
+            }
+        }
+
+        let (name, version) = manager.ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                format!("the compositor doesn't offer {}", MANAGER_INTERFACE),
+            )
+        })?;
+
+        connection.send(
+            REGISTRY_ID,
+            REGISTRY_BIND,
+            &[
+                Arg::Uint(name),
+                Arg::Str(MANAGER_INTERFACE),
+                Arg::Uint(version),
+                Arg::Uint(MANAGER_ID),
+            ],
+        )?;
+
+        Ok(Tracker {
+            connection,
+            toplevels: HashMap::new(),
+            next_window: 1,
+        })
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        loop {
+            let message = self.connection.recv()?;
+            let mut args = message.args();
+
+            match (message.object, message.opcode) {
+                (MANAGER_ID, MANAGER_TOPLEVEL) => {
+                    let toplevel = Toplevel {
+                        window: self.next_window,
+                        activated: false,
+                    };
+
+                    self.next_window += 1;
+                    self.toplevels.insert(args.uint()?, toplevel);
+                }
+                (MANAGER_ID, MANAGER_FINISHED) => {
+                    return Err(Error::other("compositor stopped reporting windows"));
+                }
+                (id, opcode) => {
+                    let toplevel = match self.toplevels.get_mut(&id) {
+                        Some(toplevel) => toplevel,
+                        None => continue,
+                    };
+
+                    match opcode {
+                        TOPLEVEL_STATE => {
+                            let states = args.bytes()?;
+
+                            toplevel.activated = states.chunks_exact(4).any(|state| {
+                                u32::from_ne_bytes([state[0], state[1], state[2], state[3]])
+                                    == STATE_ACTIVATED
+                            })
+                        }
+                        TOPLEVEL_DONE if toplevel.activated => {
+                            FOCUSED.store(toplevel.window, Ordering::Relaxed);
+                        }
+                        // Another toplevel may already have taken over.
+                        TOPLEVEL_DONE => {
+                            let _ = FOCUSED.compare_exchange(
+                                toplevel.window,
+                                0,
+                                Ordering::Relaxed,
+                                Ordering::Relaxed,
+                            );
+                        }
+                        TOPLEVEL_CLOSED => {
+                            let _ = FOCUSED.compare_exchange(
+                                toplevel.window,
+                                0,
+                                Ordering::Relaxed,
+                                Ordering::Relaxed,
+                            );
+                            self.toplevels.remove(&id);
+                            self.connection.send(id, TOPLEVEL_DESTROY, &[])?;
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the compositor and follows focus on a thread of its own.
+/// Fails when this isn't a Wayland session, or the compositor doesn't
+/// report its windows.
+pub fn track_focus() -> io::Result<()> {
+    let tracker = Tracker::new(Connection::open()?)?;
+
+    thread::Builder::new()
+        .name("wayland".to_string())
+        .spawn(move || {
+            if let Err(e) = tracker.run() {
+                FOCUSED.store(0, Ordering::Relaxed);
+                eprintln!("Lost track of the focused window: {}", e);
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+
+    use tempfile::TempDir;
+
+    const TOPLEVEL_A: u32 = 0xff00_0000;
+    const TOPLEVEL_B: u32 = 0xff00_0001;
+
+    /// The compositor's end of the socket, it speaks the same wire format.
+    struct Compositor {
+        connection: Connection,
+    }
+
+    impl Compositor {
+        fn pair() -> (Connection, Compositor) {
+            let (client, compositor) = UnixStream::pair().unwrap();
+            let compositor = Compositor {
+                connection: Connection { stream: compositor },
+            };
+
+            (Connection { stream: client }, compositor)
+        }
+
+        /// The next request, as object, opcode and arguments.
+        fn request(&mut self) -> (u32, u16, Vec<u8>) {
+            let mut header = [0u8; 8];
+            self.connection.stream.read_exact(&mut header).unwrap();
+            let word = u32::from_ne_bytes([header[4], header[5], header[6], header[7]]);
+            let mut body = vec![0; (word >> 16) as usize - 8];
+            self.connection.stream.read_exact(&mut body).unwrap();
+
+            let object = u32::from_ne_bytes([header[0], header[1], header[2], header[3]]);
+            (object, word as u16, body)
+        }
+
+        fn send(&mut self, object: u32, opcode: u16, args: &[Arg]) {
+            self.connection.send(object, opcode, args).unwrap();
+        }
+
+        fn global(&mut self, name: u32, interface: &str, version: u32) {
+            let args = [Arg::Uint(name), Arg::Str(interface), Arg::Uint(version)];
+            self.send(REGISTRY_ID, REGISTRY_GLOBAL, &args);
+        }
+
+        /// Answers the registry and sync requests with `globals`.
+        fn announce(&mut self, globals: &[(u32, &str, u32)]) {
+            assert_eq!(self.request().1, DISPLAY_GET_REGISTRY);
+            assert_eq!(self.request().1, DISPLAY_SYNC);
+
+            for &(name, interface, version) in globals {
+                self.global(name, interface, version);
+            }
+
+            self.send(SYNC_ID, CALLBACK_DONE, &[Arg::Uint(0)]);
+        }
+
+        /// States go as an array of words, its length in bytes first.
+        fn state(&mut self, toplevel: u32, activated: bool) {
+            if activated {
+                let args = [Arg::Uint(4), Arg::Uint(STATE_ACTIVATED)];
+                self.send(toplevel, TOPLEVEL_STATE, &args);
+            } else {
+                self.send(toplevel, TOPLEVEL_STATE, &[Arg::Uint(0)]);
+            }
+
+            self.send(toplevel, TOPLEVEL_DONE, &[]);
+        }
+
+        /// Waits for the client to destroy `toplevel`, by when it has
+        /// handled everything sent before.
+        fn close(&mut self, toplevel: u32) {
+            self.send(toplevel, TOPLEVEL_CLOSED, &[]);
+            assert_eq!(self.request(), (toplevel, TOPLEVEL_DESTROY, vec![]));
+        }
+    }
+
+    #[test]
+    fn focus_follows_the_activated_toplevel() {
+        let (client, mut compositor) = Compositor::pair();
+
+        let compositor = thread::spawn(move || {
+            compositor.announce(&[
+                (1, "wl_compositor", 4),
+                (9, MANAGER_INTERFACE, 2),
+                (12, "wl_seat", 7),
+            ]);
+
+            let (object, opcode, body) = compositor.request();
+            let mut args = Args { body: &body };
+            assert_eq!((object, opcode), (REGISTRY_ID, REGISTRY_BIND));
+            assert_eq!(args.uint().unwrap(), 9);
+            assert_eq!(args.string().unwrap(), MANAGER_INTERFACE);
+            assert_eq!(args.uint().unwrap(), 2);
+            assert_eq!(args.uint().unwrap(), MANAGER_ID);
+
+            compositor.send(MANAGER_ID, MANAGER_TOPLEVEL, &[Arg::Uint(TOPLEVEL_A)]);
+            compositor.state(TOPLEVEL_A, true);
+            compositor.send(MANAGER_ID, MANAGER_TOPLEVEL, &[Arg::Uint(TOPLEVEL_B)]);
+            compositor.state(TOPLEVEL_B, false);
+            // B is activated before A hears it lost focus.
+            compositor.state(TOPLEVEL_B, true);
+            compositor.state(TOPLEVEL_A, false);
+            // Toplevels the client doesn't know are ignored.
+            compositor.state(0xff00_0009, true);
+
+            compositor.close(TOPLEVEL_A);
+            assert_eq!(focused(), 2);
+            compositor.close(TOPLEVEL_B);
+            assert_eq!(focused(), 0);
+        });
+
+        let tracker = Tracker::new(client).unwrap();
+
+        // Until the compositor hangs up.
+        let e = tracker.run().err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        compositor.join().unwrap();
+    }
+
+    #[test]
+    fn compositor_without_toplevel_manager() {
+        let (client, mut compositor) = Compositor::pair();
+
+        let compositor = thread::spawn(move || {
+            compositor.announce(&[(1, "wl_compositor", 4), (2, "wl_shm", 1)]);
+        });
+
+        let e = Tracker::new(client).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        compositor.join().unwrap();
+    }
+
+    #[test]
+    fn compositor_errors() {
+        let (client, mut compositor) = Compositor::pair();
+
+        let compositor = thread::spawn(move || {
+            // Both the registry and the sync, or hanging up could fail the
+            // client's sync before it reads the error.
+            compositor.request();
+            compositor.request();
+            let args = [
+                Arg::Uint(REGISTRY_ID),
+                Arg::Uint(1),
+                Arg::Str("invalid method"),
+            ];
+            compositor.send(DISPLAY_ID, DISPLAY_ERROR, &args);
+        });
+
+        let e = Tracker::new(client).err().unwrap();
+        assert_eq!(e.to_string(), "compositor error 1: invalid method");
+        compositor.join().unwrap();
+    }
+
+    /// Headless sway with a runtime dir of its own, stopped when dropped.
+    struct Sway {
+        process: Child,
+        runtime_dir: TempDir,
+        display: PathBuf,
+    }
+
+    impl Sway {
+        fn start() -> Sway {
+            let runtime_dir = TempDir::new().unwrap();
+            let config = runtime_dir.path().join("config");
+            fs::write(&config, "").unwrap();
+
+            let process = Command::new("sway")
+                .arg("--config")
+                .arg(&config)
+                .env("XDG_RUNTIME_DIR", runtime_dir.path())
+                .env("WLR_BACKENDS", "headless")
+                .env("WLR_HEADLESS_OUTPUTS", "1")
+                .env("WLR_LIBINPUT_NO_DEVICES", "1")
+                .env("WLR_RENDERER", "pixman")
+                .env_remove("WAYLAND_DISPLAY")
+                .env_remove("DISPLAY")
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("sway to be installed");
+
+            let mut display = None;
+            wait_for("sway to listen", || {
+                display = fs::read_dir(runtime_dir.path())
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .find(|path| {
+                        let name = path.file_name().unwrap().to_string_lossy();
+                        name.starts_with("wayland-") && !name.ends_with(".lock")
+                    });
+                display.is_some()
+            });
+
+            Sway {
+                process,
+                runtime_dir,
+                display: display.unwrap(),
+            }
+        }
+
+        /// A window of its own, closed when killed.
+        fn window(&self) -> Child {
+            Command::new("foot")
+                .env("XDG_RUNTIME_DIR", self.runtime_dir.path())
+                .env("WAYLAND_DISPLAY", &self.display)
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("foot to be installed")
+        }
+    }
+
+    impl Drop for Sway {
+        fn drop(&mut self) {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Needs sway and the foot terminal, run with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn focus_follows_headless_sway() {
+        let sway = Sway::start();
+        let connection = Connection {
+            stream: UnixStream::connect(&sway.display).unwrap(),
+        };
+        let tracker = Tracker::new(connection).unwrap();
+        let tracker = thread::spawn(move || tracker.run());
+
+        let mut first = sway.window();
+        wait_for("the first window to be focused", || focused() == 1);
+        let mut second = sway.window();
+        wait_for("the second window to be focused", || focused() == 2);
+
+        // Sway focuses what is left.
+        second.kill().unwrap();
+        second.wait().unwrap();
+        wait_for("focus to go back to the first window", || focused() == 1);
+
+        first.kill().unwrap();
+        first.wait().unwrap();
+        wait_for("focus to be unknown", || focused() == 0);
+
+        drop(sway);
+        assert!(tracker.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,899 @@
+//! The focused window and the pointer position on X11.
+//!
+//! Window managers following EWMH keep the focused window in the root
+//! window's `_NET_ACTIVE_WINDOW` property, and say when it changes. evdev
+//! only knows relative pointer motion, so XInput 2 raw motion events tell
+//! when the pointer moved and the server is asked where it went. The few
+//! requests needed are spoken directly over the display's socket.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::thread;
+
+const X_TCP_PORT: u16 = 6000;
+const AUTH_NAME: &str = "MIT-MAGIC-COOKIE-1";
+
+// Xauthority address families.
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+// Requests.
+const CHANGE_WINDOW_ATTRIBUTES: u8 = 2;
+const INTERN_ATOM: u8 = 16;
+const GET_PROPERTY: u8 = 20;
+const QUERY_POINTER: u8 = 38;
+const TRANSLATE_COORDINATES: u8 = 40;
+const QUERY_EXTENSION: u8 = 98;
+const XI_SELECT_EVENTS: u8 = 46;
+const XI_QUERY_VERSION: u8 = 47;
+
+// Messages from the server, by their first byte.
+const ERROR: u8 = 0;
+const REPLY: u8 = 1;
+const PROPERTY_NOTIFY: u8 = 28;
+const GENERIC_EVENT: u8 = 35;
+
+const CW_EVENT_MASK: u32 = 1 << 11;
+const PROPERTY_CHANGE_MASK: u32 = 1 << 22;
+const XI_ALL_MASTER_DEVICES: u16 = 1;
+const XI_RAW_MOTION: u16 = 17;
+
+const XINPUT: &str = "XInputExtension";
+const ACTIVE_WINDOW: &str = "_NET_ACTIVE_WINDOW";
+
+/// The focused window, 0 when unknown.
+static FOCUSED: AtomicUsize = AtomicUsize::new(0);
+/// Whether focus is followed here, rather than on Wayland.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+/// For translating pointer positions, opened when first needed.
+static TRANSLATOR: LazyLock<Mutex<Option<Connection>>> =
+    LazyLock::new(|| Mutex::new(Connection::open().ok()));
+
+pub fn focused() -> usize {
+    FOCUSED.load(Ordering::Relaxed)
+}
+
+pub fn is_tracking() -> bool {
+    TRACKING.load(Ordering::Relaxed)
+}
+
+/// A position on the screen relative to the top left corner of `window`.
+pub fn window_position(window: usize, x: i32, y: i32) -> Option<(i32, i32)> {
+    let mut translator = TRANSLATOR.lock().ok()?;
+    let connection = translator.as_mut()?;
+    let root = connection.root;
+
+    match connection.translate_coordinates(root, window as u32, x, y) {
+        Ok(position) => Some(position),
+        // Closed windows are an X error, the connection is still good.
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(_) => {
+            *translator = None;
+            None
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn i16_at(bytes: &[u8], at: usize) -> i16 {
+    u16_at(bytes, at) as i16
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Where `$DISPLAY` is, as `[host]:display[.screen]`.
+#[derive(Debug, PartialEq, Eq)]
+struct Display {
+    host: Option<String>,
+    number: u16,
+}
+
+impl Display {
+    fn parse(display: &str) -> Option<Display> {
+        let (host, rest) = display.rsplit_once(':')?;
+        let number = rest.split('.').next()?.parse().ok()?;
+        let host = match host {
+            "" | "unix" => None,
+            host => Some(host.to_string()),
+        };
+
+        Some(Display { host, number })
+    }
+}
+
+/// The `MIT-MAGIC-COOKIE-1` for a local display from an Xauthority file,
+/// entries of which are big endian, length prefixed fields.
+fn find_cookie(xauthority: &[u8], hostname: &str, display: u16) -> Option<Vec<u8>> {
+    let mut rest = xauthority;
+    let field = |rest: &mut &[u8]| -> Option<Vec<u8>> {
+        let len = usize::from(u16::from_be_bytes([*rest.first()?, *rest.get(1)?]));
+        let value = rest.get(2..2 + len)?.to_vec();
+        *rest = &rest[2 + len..];
+        Some(value)
+    };
+
+    while rest.len() >= 2 {
+        let family = u16::from_be_bytes([rest[0], rest[1]]);
+        rest = &rest[2..];
+        let (address, number, name, data) = (
+            field(&mut rest)?,
+            field(&mut rest)?,
+            field(&mut rest)?,
+            field(&mut rest)?,
+        );
+
+        let local = match family {
+            FAMILY_LOCAL => address == hostname.as_bytes(),
+            FAMILY_WILD => true,
+            _ => false,
+        };
+        let this_display = number.is_empty() || number == display.to_string().as_bytes();
+
+        if local && this_display && name == AUTH_NAME.as_bytes() {
+            return Some(data);
+        }
+    }
+
+    None
+}
+
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+
+    if unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) } < 0 {
+        return String::new();
+    }
+
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+fn cookie(display: &Display) -> Option<Vec<u8>> {
+    let path = env::var_os("XAUTHORITY")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".Xauthority")))?;
+
+    find_cookie(&fs::read(path).ok()?, &hostname(), display.number)
+}
+
+enum Stream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Unix(stream) => stream.as_raw_fd(),
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+struct Connection {
+    stream: Stream,
+    /// Read but not yet taken apart into messages.
+    buffer: Vec<u8>,
+    /// Events that arrived while waiting for a reply.
+    events: VecDeque<Vec<u8>>,
+    /// Of the last request sent.
+    sequence: u16,
+    root: u32,
+}
+
+impl Connection {
+    /// `$DISPLAY`, authenticated with the cookie in `$XAUTHORITY`.
+    fn open() -> io::Result<Connection> {
+        let display = env::var("DISPLAY")
+            .map_err(|_| Error::new(ErrorKind::NotFound, "not an X11 session"))?;
+        let display = Display::parse(&display)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid DISPLAY"))?;
+        let stream = match &display.host {
+            None => {
+                let path = format!("/tmp/.X11-unix/X{}", display.number);
+                Stream::Unix(UnixStream::connect(path)?)
+            }
+            Some(host) => Stream::Tcp(TcpStream::connect((
+                host.as_str(),
+                X_TCP_PORT + display.number,
+            ))?),
+        };
+
+        Connection::setup(stream, cookie(&display))
+    }
+
+    fn setup(mut stream: Stream, cookie: Option<Vec<u8>>) -> io::Result<Connection> {
+        let (name, data) = match &cookie {
+            Some(cookie) => (AUTH_NAME.as_bytes(), cookie.as_slice()),
+            None => (&[][..], &[][..]),
+        };
+
+        // Little endian, protocol version 11.0.
+        let mut request = vec![b'l', 0, 11, 0, 0, 0];
+        request.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        request.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        request.extend_from_slice(&[0, 0]);
+        request.extend_from_slice(name);
+        request.resize(12 + padded(name.len()), 0);
+        request.extend_from_slice(data);
+        request.resize(12 + padded(name.len()) + padded(data.len()), 0);
+        stream.write_all(&request)?;
+
+        let mut header = [0u8; 8];
+        stream.read_exact(&mut header)?;
+        let mut setup = vec![0; usize::from(u16_at(&header, 6)) * 4];
+        stream.read_exact(&mut setup)?;
+
+        if header[0] != 1 {
+            let len = usize::from(header[1]).min(setup.len());
+            let reason = String::from_utf8_lossy(&setup[..len]);
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("the X server refused the connection: {}", reason.trim()),
+            ));
+        }
+
+        // The first screen's root window follows the vendor and formats.
+        let vendor_len = usize::from(u16_at(&setup, 16));
+        let formats = usize::from(setup[21]);
+        let screen = 32 + padded(vendor_len) + 8 * formats;
+
+        if setup.len() < screen + 4 {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated X setup"));
+        }
+
+        Ok(Connection {
+            stream,
+            buffer: Vec::new(),
+            events: VecDeque::new(),
+            sequence: 0,
+            root: u32_at(&setup, screen),
+        })
+    }
+
+    fn send(&mut self, opcode: u8, data: u8, body: &[u8]) -> io::Result<u16> {
+        let len = 4 + padded(body.len());
+        let mut request = Vec::with_capacity(len);
+        request.extend_from_slice(&[opcode, data]);
+        request.extend_from_slice(&((len / 4) as u16).to_le_bytes());
+        request.extend_from_slice(body);
+        request.resize(len, 0);
+        self.stream.write_all(&request)?;
+
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(self.sequence)
+    }
+
+    /// The length of the first message in the buffer, once all of it is there.
+    fn complete(&self) -> Option<usize> {
+        if self.buffer.len() < 32 {
+            return None;
+        }
+
+        let len = match self.buffer[0] & 0x7f {
+            REPLY | GENERIC_EVENT => 32 + 4 * u32_at(&self.buffer, 4) as usize,
+            _ => 32,
+        };
+
+        Some(len).filter(|&len| self.buffer.len() >= len)
+    }
+
+    /// Reads once, which only blocks when nothing has arrived.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 4096];
+        let n = self.stream.read(&mut chunk)?;
+
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "the X server went away",
+            ));
+        }
+
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+
+    fn take_message(&mut self) -> Option<Vec<u8>> {
+        let len = self.complete()?;
+        Some(self.buffer.drain(..len).collect())
+    }
+
+    fn round_trip(&mut self, opcode: u8, data: u8, body: &[u8]) -> io::Result<Vec<u8>> {
+        let sequence = self.send(opcode, data, body)?;
+
+        loop {
+            let message = match self.take_message() {
+                Some(message) => message,
+                None => {
+                    self.fill()?;
+                    continue;
+                }
+            };
+
+            match message[0] & 0x7f {
+                ERROR if u16_at(&message, 2) == sequence => {
+                    // BadWindow, for one that has been destroyed.
+                    let kind = if message[1] == 3 {
+                        ErrorKind::NotFound
+                    } else {
+                        ErrorKind::Other
+                    };
+
+                    return Err(Error::new(
+                        kind,
+                        format!("X error {} for request {}", message[1], opcode),
+                    ));
+                }
+                REPLY if u16_at(&message, 2) == sequence => return Ok(message),
+                ERROR | REPLY => (),
+                _ => self.events.push_back(message),
+            }
+        }
+    }
+
+    /// The next event, waiting for one if none has arrived.
+    fn event(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            match self.take_message() {
+                Some(message) if message[0] & 0x7f > REPLY => return Ok(message),
+                Some(_) => (),
+                None => self.fill()?,
+            }
+        }
+    }
+
+    /// Events already read, without waiting.
+    fn pending_events(&mut self) -> Vec<Vec<u8>> {
+        let mut events: Vec<Vec<u8>> = self.events.drain(..).collect();
+
+        while let Some(message) = self.take_message() {
+            if message[0] & 0x7f > REPLY {
+                events.push(message);
+            }
+        }
+
+        events
+    }
+
+    fn intern_atom(&mut self, name: &str) -> io::Result<u32> {
+        let mut body = (name.len() as u16).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(name.as_bytes());
+
+        let reply = self.round_trip(INTERN_ATOM, 0, &body)?;
+        Ok(u32_at(&reply, 8))
+    }
+
+    /// The first 32 bit value of a window property, if it is set.
+    fn property(&mut self, window: u32, property: u32) -> io::Result<Option<u32>> {
+        let mut body = Vec::with_capacity(20);
+
+        // Any type, from offset 0, a single value.
+        for value in &[window, property, 0, 0, 1] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+
+        let reply = self.round_trip(GET_PROPERTY, 0, &body)?;
+        let (format, values) = (reply[1], u32_at(&reply, 16));
+
+        if format != 32 || values == 0 || reply.len() < 36 {
+            return Ok(None);
+        }
+
+        Ok(Some(u32_at(&reply, 32)))
+    }
+
+    fn select_property_changes(&mut self, window: u32) -> io::Result<()> {
+        let mut body = Vec::with_capacity(12);
+
+        for value in &[window, CW_EVENT_MASK, PROPERTY_CHANGE_MASK] {
+            body.extend_from_slice(&value.to_le_bytes());
+        }
+
+        self.send(CHANGE_WINDOW_ATTRIBUTES, 0, &body)?;
+        Ok(())
+    }
+
+    /// The major opcode of an extension, if the server has it.
+    fn extension(&mut self, name: &str) -> io::Result<Option<u8>> {
+        let mut body = (name.len() as u16).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(name.as_bytes());
+
+        let reply = self.round_trip(QUERY_EXTENSION, 0, &body)?;
+        Ok(Some(reply[9]).filter(|_| reply[8] != 0))
+    }
+
+    /// Where the pointer is on the screen.
+    fn query_pointer(&mut self) -> io::Result<(i32, i32)> {
+        let root = self.root;
+        let reply = self.round_trip(QUERY_POINTER, 0, &root.to_le_bytes())?;
+
+        Ok((i32::from(i16_at(&reply, 16)), i32::from(i16_at(&reply, 18))))
+    }
+
+    fn translate_coordinates(
+        &mut self,
+        from: u32,
+        to: u32,
+        x: i32,
+        y: i32,
+    ) -> io::Result<(i32, i32)> {
+        let mut body = Vec::with_capacity(12);
+        body.extend_from_slice(&from.to_le_bytes());
+        body.extend_from_slice(&to.to_le_bytes());
+        body.extend_from_slice(&(x as i16).to_le_bytes());
+        body.extend_from_slice(&(y as i16).to_le_bytes());
+
+        let reply = self.round_trip(TRANSLATE_COORDINATES, 0, &body)?;
+        Ok((i32::from(i16_at(&reply, 12)), i32::from(i16_at(&reply, 14))))
+    }
+}
+
+/// Follows `_NET_ACTIVE_WINDOW`.
+struct Tracker {
+    connection: Connection,
+    active_window: u32,
+}
+
+impl Tracker {
+    fn new(mut connection: Connection) -> io::Result<Tracker> {
+        let active_window = connection.intern_atom(ACTIVE_WINDOW)?;
+        let root = connection.root;
+
+        connection.select_property_changes(root)?;
+
+        let mut tracker = Tracker {
+            connection,
+            active_window,
+        };
+
+        match tracker.focused()? {
+            Some(_) => Ok(tracker),
+            None => Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("the window manager doesn't set {}", ACTIVE_WINDOW),
+            )),
+        }
+    }
+
+    /// Reads the property and publishes it.
+    fn focused(&mut self) -> io::Result<Option<u32>> {
+        let root = self.connection.root;
+        let window = self.connection.property(root, self.active_window)?;
+
+        FOCUSED.store(window.unwrap_or(0) as usize, Ordering::Relaxed);
+        Ok(window)
+    }
+
+    fn run(mut self) -> io::Result<()> {
+        loop {
+            let event = self.connection.event()?;
+
+            if event[0] & 0x7f == PROPERTY_NOTIFY && u32_at(&event, 8) == self.active_window {
+                self.focused()?;
+            }
+        }
+    }
+}
+
+/// Connects to the X server and follows focus on a thread of its own.
+/// Fails when this isn't an X11 session, or the window manager doesn't
+/// say which window is active.
+pub fn track_focus() -> io::Result<()> {
+    let tracker = Tracker::new(Connection::open()?)?;
+
+    thread::Builder::new()
+        .name("x11".to_string())
+        .spawn(move || {
+            if let Err(e) = tracker.run() {
+                TRACKING.store(false, Ordering::Relaxed);
+                FOCUSED.store(0, Ordering::Relaxed);
+                eprintln!("Lost track of the focused window: {}", e);
+            }
+        })?;
+
+    TRACKING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Tells when the pointer moves, read along with the input devices.
+pub struct Pointer {
+    connection: Connection,
+    xinput: u8,
+}
+
+impl Pointer {
+    pub fn new() -> io::Result<Pointer> {
+        Pointer::with(Connection::open()?)
+    }
+
+    fn with(mut connection: Connection) -> io::Result<Pointer> {
+        let xinput = connection
+            .extension(XINPUT)?
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "the X server lacks XInput"))?;
+
+        // XInput 2.0 has to be asked for before its requests are accepted.
+        let mut version = 2u16.to_le_bytes().to_vec();
+        version.extend_from_slice(&0u16.to_le_bytes());
+        let reply = connection.round_trip(xinput, XI_QUERY_VERSION, &version)?;
+
+        if u16_at(&reply, 8) < 2 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "the X server lacks XInput 2",
+            ));
+        }
+
+        // Raw events are only delivered to the root window.
+        let mut body = connection.root.to_le_bytes().to_vec();
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(&XI_ALL_MASTER_DEVICES.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&(1u32 << XI_RAW_MOTION).to_le_bytes());
+        connection.send(xinput, XI_SELECT_EVENTS, &body)?;
+
+        Ok(Pointer { connection, xinput })
+    }
+
+    /// Where the pointer moved to, if it did, once the connection is
+    /// readable. Several motion events are answered with one position.
+    pub fn read(&mut self) -> io::Result<Option<(i32, i32)>> {
+        // Events that came along with an earlier reply are handled first.
+        if self.connection.events.is_empty() && self.connection.complete().is_none() {
+            self.connection.fill()?;
+        }
+
+        let xinput = self.xinput;
+        let moved = self.connection.pending_events().iter().any(|event| {
+            event[0] & 0x7f == GENERIC_EVENT
+                && event[1] == xinput
+                && u16_at(event, 8) == XI_RAW_MOTION
+        });
+
+        if !moved {
+            return Ok(None);
+        }
+
+        self.connection.query_pointer().map(Some)
+    }
+}
+
+impl AsRawFd for Pointer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u32 = 0x3c5;
+
+    /// A reply to request `sequence`, with `fields` from byte 8 on.
+    fn reply(sequence: u16, data: u8, fields: &[u8]) -> Vec<u8> {
+        let mut fields = fields.to_vec();
+        fields.resize(padded(fields.len()).max(24), 0);
+
+        let mut reply = vec![REPLY, data];
+        reply.extend_from_slice(&sequence.to_le_bytes());
+        reply.extend_from_slice(&(((fields.len() - 24) / 4) as u32).to_le_bytes());
+        reply.extend_from_slice(&fields);
+        reply
+    }
+
+    fn error(sequence: u16, code: u8) -> Vec<u8> {
+        let mut error = vec![ERROR, code];
+        error.extend_from_slice(&sequence.to_le_bytes());
+        error.resize(32, 0);
+        error
+    }
+
+    fn property_notify(atom: u32) -> Vec<u8> {
+        let mut event = vec![PROPERTY_NOTIFY, 0, 0, 0];
+        event.extend_from_slice(&ROOT.to_le_bytes());
+        event.extend_from_slice(&atom.to_le_bytes());
+        event.resize(32, 0);
+        event
+    }
+
+    fn raw_motion(xinput: u8) -> Vec<u8> {
+        // Followed by a few words of valuators.
+        let mut event = vec![GENERIC_EVENT, xinput, 0, 0, 2, 0, 0, 0];
+        event.extend_from_slice(&XI_RAW_MOTION.to_le_bytes());
+        event.resize(40, 0);
+        event
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect()
+    }
+
+    /// Plays the X server: accepts the setup, then expects the requests in
+    /// `script` in order, writing what is given for each.
+    fn serve(script: Vec<(u8, Vec<Vec<u8>>)>) -> (Connection, thread::JoinHandle<()>) {
+        let (client, mut server) = UnixStream::pair().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut request = [0u8; 12];
+            server.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[b'l', 0, 11, 0]);
+
+            // Release, id base and mask, motion buffer, vendor length,
+            // maximum request length, one screen and one format.
+            let mut setup = words(&[0, 0x0400_0000, 0x001f_ffff, 0]);
+            setup.extend_from_slice(&[4, 0, 0xff, 0xff, 1, 1, 0, 0, 32, 32, 8, 255, 0, 0, 0, 0]);
+            setup.extend_from_slice(b"Mock");
+            setup.extend_from_slice(&[24, 32, 32, 0, 0, 0, 0, 0]);
+            setup.extend_from_slice(&ROOT.to_le_bytes());
+            setup.resize(setup.len() + 36, 0);
+
+            let mut header = vec![1, 0, 11, 0, 0, 0];
+            header.extend_from_slice(&((setup.len() / 4) as u16).to_le_bytes());
+            server.write_all(&header).unwrap();
+            server.write_all(&setup).unwrap();
+
+            for (opcode, responses) in script {
+                let mut header = [0u8; 4];
+                server.read_exact(&mut header).unwrap();
+                let mut body = vec![0; usize::from(u16_at(&header, 2)) * 4 - 4];
+                server.read_exact(&mut body).unwrap();
+                assert_eq!(header[0], opcode);
+
+                server.write_all(&responses.concat()).unwrap();
+            }
+        });
+
+        let connection = Connection::setup(Stream::Unix(client), None).unwrap();
+        (connection, server)
+    }
+
+    #[test]
+    fn displays() {
+        let display = |host: Option<&str>, number| Display {
+            host: host.map(str::to_string),
+            number,
+        };
+
+        assert_eq!(Display::parse(":0"), Some(display(None, 0)));
+        assert_eq!(Display::parse(":1.0"), Some(display(None, 1)));
+        assert_eq!(Display::parse("unix:2"), Some(display(None, 2)));
+        assert_eq!(
+            Display::parse("remote:10.0"),
+            Some(display(Some("remote"), 10))
+        );
+        assert_eq!(Display::parse("wayland-0"), None);
+    }
+
+    #[test]
+    fn cookies() {
+        let entry = |family: u16, address: &str, number: &str, data: &[u8]| {
+            let mut entry = family.to_be_bytes().to_vec();
+
+            for field in &[
+                address.as_bytes(),
+                number.as_bytes(),
+                AUTH_NAME.as_bytes(),
+                data,
+            ] {
+                entry.extend_from_slice(&(field.len() as u16).to_be_bytes());
+                entry.extend_from_slice(field);
+            }
+
+            entry
+        };
+        let file = [
+            entry(FAMILY_LOCAL, "otherhost", "0", b"other"),
+            entry(FAMILY_LOCAL, "thishost", "1", b"display 1"),
+            entry(FAMILY_WILD, "", "0", b"wild"),
+            entry(FAMILY_LOCAL, "thishost", "0", b"too late"),
+        ]
+        .concat();
+
+        assert_eq!(
+            find_cookie(&file, "thishost", 0).as_deref(),
+            Some(&b"wild"[..])
+        );
+        assert_eq!(
+            find_cookie(&file, "thishost", 1).as_deref(),
+            Some(&b"display 1"[..])
+        );
+        assert_eq!(find_cookie(&file, "otherhost", 2), None);
+        assert_eq!(find_cookie(&file[..file.len() - 3], "nohost", 5), None);
+    }
+
+    #[test]
+    fn refused_setup() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let reason = b"No protocol specified\n\0\0";
+        let mut refusal = vec![0, 22, 11, 0, 0, 0];
+        refusal.extend_from_slice(&((reason.len() / 4) as u16).to_le_bytes());
+        refusal.extend_from_slice(reason);
+        server.write_all(&refusal).unwrap();
+
+        let e = Connection::setup(Stream::Unix(client), Some(b"cookie".to_vec()))
+            .err()
+            .unwrap();
+
+        assert_eq!(e.kind(), ErrorKind::PermissionDenied);
+        assert!(e.to_string().ends_with("No protocol specified"), "{}", e);
+
+        let mut request = [0u8; 24];
+        server.read_exact(&mut request).unwrap();
+        assert_eq!(u16_at(&request, 6), AUTH_NAME.len() as u16);
+        assert_eq!(u16_at(&request, 8), 6);
+        assert_eq!(&request[12..24], &AUTH_NAME.as_bytes()[..12]);
+    }
+
+    #[test]
+    fn focus_follows_the_active_window() {
+        const ATOM: u32 = 0x1a5;
+        let active = |sequence, window| reply(sequence, 32, &words(&[0x21, 0, 1, 0, 0, 0, window]));
+
+        let (connection, server) = serve(vec![
+            (INTERN_ATOM, vec![reply(1, 0, &words(&[ATOM]))]),
+            (CHANGE_WINDOW_ATTRIBUTES, vec![]),
+            // Events arriving ahead of a reply are kept for later.
+            (
+                GET_PROPERTY,
+                vec![
+                    property_notify(ATOM + 1),
+                    active(3, 0x0140_0003),
+                    property_notify(ATOM),
+                ],
+            ),
+            (GET_PROPERTY, vec![active(4, 0x0160_0007)]),
+        ]);
+
+        let tracker = Tracker::new(connection).unwrap();
+        assert_eq!(focused(), 0x0140_0003);
+
+        // Until the server hangs up.
+        let e = tracker.run().err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(focused(), 0x0160_0007);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn window_manager_without_active_window() {
+        let (connection, server) = serve(vec![
+            (INTERN_ATOM, vec![reply(1, 0, &words(&[0x1a5]))]),
+            (CHANGE_WINDOW_ATTRIBUTES, vec![]),
+            (GET_PROPERTY, vec![reply(3, 0, &[])]),
+        ]);
+
+        let e = Tracker::new(connection).err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::Unsupported);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn pointer_motion() {
+        const XINPUT_OPCODE: u8 = 131;
+        let position = |sequence, x: i16, y: i16| {
+            let mut fields = words(&[ROOT, 0]);
+            fields.extend_from_slice(&x.to_le_bytes());
+            fields.extend_from_slice(&y.to_le_bytes());
+            reply(sequence, 1, &fields)
+        };
+
+        let (connection, server) = serve(vec![
+            (
+                QUERY_EXTENSION,
+                vec![reply(1, 0, &[1, XINPUT_OPCODE, 0, 0])],
+            ),
+            (XINPUT_OPCODE, vec![reply(2, 0, &[2, 0, 0, 0])]),
+            // Two motions at once, answered with one position.
+            (
+                XINPUT_OPCODE,
+                vec![raw_motion(XINPUT_OPCODE), raw_motion(XINPUT_OPCODE)],
+            ),
+            (
+                QUERY_POINTER,
+                vec![position(4, 640, -12), property_notify(1)],
+            ),
+        ]);
+
+        let mut pointer = Pointer::with(connection).unwrap();
+
+        assert_eq!(pointer.read().unwrap(), Some((640, -12)));
+        // Anything else isn't a move.
+        assert_eq!(pointer.read().unwrap(), None);
+
+        server.join().unwrap();
+        assert_eq!(
+            pointer.read().err().unwrap().kind(),
+            ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn old_xinput() {
+        let (connection, server) = serve(vec![
+            (QUERY_EXTENSION, vec![reply(1, 0, &[1, 131, 0, 0])]),
+            (131, vec![reply(2, 0, &[1, 0, 5, 0])]),
+        ]);
+
+        assert_eq!(
+            Pointer::with(connection).err().unwrap().kind(),
+            ErrorKind::Unsupported
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn translated_positions() {
+        let (mut connection, server) = serve(vec![
+            (
+                TRANSLATE_COORDINATES,
+                vec![reply(1, 1, &[0, 0, 0, 0, 0x88, 0xff, 20, 0])],
+            ),
+            (TRANSLATE_COORDINATES, vec![error(2, 3)]),
+        ]);
+
+        assert_eq!(
+            connection.translate_coordinates(ROOT, 7, 10, 50).unwrap(),
+            (-120, 20)
+        );
+        assert_eq!(
+            connection
+                .translate_coordinates(ROOT, 8, 10, 50)
+                .err()
+                .unwrap()
+                .kind(),
+            ErrorKind::NotFound
+        );
+        server.join().unwrap();
+    }
+}
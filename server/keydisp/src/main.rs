@@ -1,163 +1,220 @@
-#![feature(drain_filter)]
-
 #[macro_use]
-extern crate lazy_static;
-
-extern crate scancode;
-extern crate tungstenite;
-extern crate winapi;
+extern crate serde_derive;
 
+mod auth;
+mod bench;
 mod common;
-
+mod config;
+#[cfg(unix)]
+mod control;
+#[cfg(target_os = "linux")]
+mod dbus;
+mod export;
+mod metrics;
+mod output;
+mod process;
+mod render;
+mod server;
+mod sink;
+mod state;
+mod tls;
+
+#[cfg(target_os = "linux")]
+mod linux;
 #[cfg(target_os = "windows")]
 mod windows;
 
+#[cfg(target_os = "linux")]
+use linux::Hook;
 #[cfg(target_os = "windows")]
-use windows::{get_fg_window, Hook};
+use windows::Hook;
 
-use common::{Event, KeyState};
+use common::Timestamp;
+use config::Config;
+use output::Tagged;
+use process::Processor;
+use state::State;
+use tls::Tls;
 
-use std::net::TcpListener;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use scancode::Scancode;
-use tungstenite::server::accept;
-
-const SET_INPUT_WINDOW_KEY: Scancode = Scancode::F10;
-
-fn modifier_index(key: Scancode) -> Option<usize> {
-    match key {
-        Scancode::LeftShift => Some(0),
-        Scancode::RightShift => Some(0),
-        Scancode::LeftControl => Some(1),
-        Scancode::RightControl => Some(1),
-        Scancode::LeftAlt => Some(2),
-        Scancode::RightAlt => Some(2),
-        Scancode::CapsLock => Some(3),
-        _ => None,
-    }
-}
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+use tokio::sync::broadcast;
 
-fn get_send_char(key: Scancode) -> Option<char> {
-    match key {
-        Scancode::LeftShift => Some('⇧'),
-        Scancode::RightShift => Some('⇧'),
-        Scancode::LeftControl => Some('⌃'),
-        Scancode::RightControl => Some('⌃'),
-        Scancode::LeftAlt => Some('⎇'),
-        Scancode::RightAlt => Some('⎇'),
-        Scancode::CapsLock => Some('⇪'),
-        Scancode::Escape => Some('⎋'),
-        Scancode::Tab => Some('⇥'),
-        Scancode::Space => Some('␣'),
-        Scancode::Enter => Some('⏎'),
-        Scancode::Backspace => Some('⌫'),
-        Scancode::Delete => Some('⌦'),
-        Scancode::Home => Some('⇱'),
-        Scancode::End => Some('⇲'),
-        Scancode::PageUp => Some('⇞'),
-        Scancode::PageDown => Some('⇟'),
-        Scancode::Up => Some('↑'),
-        Scancode::Down => Some('↓'),
-        Scancode::Left => Some('←'),
-        Scancode::Right => Some('→'),
-        _ => None,
-    }
-}
+/// Events a client may fall behind by before it starts missing some.
+const EVENT_BUFFER: usize = 256;
+/// Wait before capturing input again once it stopped.
+const HOOK_RESTART_DELAY: Duration = Duration::from_secs(5);
 
 fn main() {
     // TODO:
     // * Multiple target windows?
-    // * Settings file
     // * Small gui for window? Or windows service?
 
-    let mut input_window = None;
-    let mut modifier_state = [KeyState::Released; 4]; // Shift, ctrl, alt, capslock
-
-    let (tx, rx) = channel::<char>();
-
-    let server = TcpListener::bind("127.0.0.1:2945").unwrap();
-
-    println!("Websocket server running: {:?}", server);
-
-    let clients: Arc<Mutex<Vec<tungstenite::protocol::WebSocket<_>>>> =
-        Arc::new(Mutex::new(vec![]));
-
-    let cx1 = clients.clone();
-    std::thread::spawn(move || {
-        while let Ok(c) = rx.recv() {
-            match cx1.lock() {
-                Ok(mut xs) => {
-                    xs.drain_filter(|client| {
-                        match client.write_message(tungstenite::Message::Text(c.to_string())) {
-                            Err(_) => true,
-                            Ok(()) => false,
-                        }
-                    }).for_each(|_| {});
-                }
-                Err(_) => {
-                    println!("Poisoned lock");
-                    panic!();
-                }
-            };
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+
+    if command.as_deref() == Some("bench") {
+        bench::run(args.next().as_deref());
+        return;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}, using defaults", e);
+            Config::default()
         }
-    });
+    };
+
+    if command.as_deref() == Some("export") {
+        export::run(&config, &args.collect::<Vec<_>>());
+        return;
+    }
+
+    if config.websocket && !auth::bind_allowed(&config.auth, &config.bind) {
+        eprintln!(
+            "Refusing to listen on {} without auth tokens configured, keystrokes would be exposed to the network",
+            config.bind
+        );
+        return;
+    }
+
+    // Counters of input per device tell when someone is typing.
+    if let Some(ref addr) = config.metrics_addr {
+        if !auth::bind_allowed(&config.auth, addr) {
+            eprintln!(
+                "Refusing to serve metrics on {} without auth tokens configured, typing activity would be exposed to the network",
+                addr
+            );
+            return;
+        }
+    }
+
+    let serves_http = config.websocket || config.metrics_addr.is_some();
 
-    let cx2 = clients.clone();
-    std::thread::spawn(move || {
-        for stream in server.incoming() {
-            let mut websocket = accept(stream.unwrap()).unwrap();
-
-            match cx2.lock() {
-                Ok(mut xs) => xs.push(websocket),
-                Err(_) => {
-                    println!("Poisoned lock");
-                    panic!();
-                }
+    let tls = match config.tls {
+        Some(ref tls_config) if serves_http => match Tls::load(tls_config) {
+            Ok(tls) => {
+                eprintln!("TLS certificate SHA-256 fingerprint: {}", tls.fingerprint);
+                Some(tls)
+            }
+            Err(e) => {
+                eprintln!("Could not set up TLS: {}", e);
+                return;
+            }
+        },
+        _ => None,
+    };
+
+    Timestamp::start();
+
+    // The hook needs the main thread, networking runs on the runtime's workers.
+    let runtime = Runtime::new().expect("tokio runtime to start.");
+    let _guard = runtime.enter();
+
+    let listener = if config.websocket {
+        Some(runtime.block_on(TcpListener::bind(&config.bind)).unwrap())
+    } else {
+        None
+    };
+
+    let metrics_listener = config.metrics_addr.as_ref().and_then(|addr| {
+        match runtime.block_on(TcpListener::bind(addr)) {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("Could not serve metrics on {}: {}", addr, e);
+                None
             }
         }
     });
 
-    Hook::run_forever(move |event| {
-        let fg_window = get_fg_window();
+    let sinks = config.sinks.clone();
+    let mut state = State::new(config);
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Err(e) = linux::track_focus() {
+            eprintln!(
+                "Can't tell which window has focus ({}), displaying input from every window",
+                e
+            );
+            state.set_focus_unknown();
+        }
+    }
 
-        if let Event::Key {
-            scancode,
-            key_state,
-        } = event
-        {
-            if scancode == SET_INPUT_WINDOW_KEY && key_state == KeyState::Pressed {
-                input_window = Some(fg_window);
-            }
+    let state = state.shared();
+
+    let (events, _) = broadcast::channel::<Tagged>(EVENT_BUFFER);
+
+    #[cfg(unix)]
+    let control_socket = match control::spawn(state.clone()) {
+        Ok(path) => {
+            eprintln!("Control socket listening on {}", path.display());
+            Some(path)
+        }
+        Err(e) => {
+            eprintln!("Control socket unavailable: {}", e);
+            None
         }
+    };
+
+    #[cfg(target_os = "linux")]
+    runtime.spawn(dbus::run(state.clone(), events.subscribe()));
+
+    if let Some(listener) = listener {
+        eprintln!("Websocket server running: {:?}", listener);
+        runtime.spawn(server::run(listener, tls.clone(), events.clone(), state.clone()));
+    }
+
+    if let Some(listener) = metrics_listener {
+        eprintln!("Metrics served on {:?}", listener);
+        runtime.spawn(server::run_metrics(listener, tls, state.clone()));
+    }
 
-        if Some(fg_window) == input_window {
-            let maybe_char = match event {
-                Event::Char(c) if !(c.is_control() || c.is_whitespace()) => Some(c),
-                Event::Char(_) => None,
-                Event::Key {
-                    scancode,
-                    key_state,
-                } => if let Some(idx) = modifier_index(scancode) {
-                    let prev_char = modifier_state[idx];
-                    modifier_state[idx] = key_state;
-
-                    if prev_char == KeyState::Released {
-                        get_send_char(scancode)
-                    } else {
-                        None
-                    }
-                } else if key_state == KeyState::Pressed {
-                    get_send_char(scancode)
-                } else {
-                    None
-                },
-            };
-
-            if let Some(send_char) = maybe_char {
-                tx.send(send_char).expect("channel to be open.");
+    for sink in sinks {
+        sink::spawn(sink, &events);
+    }
+
+    let queue = match Processor::new(state, events).spawn() {
+        Ok(queue) => queue,
+        Err(e) => {
+            eprintln!("Could not start processing: {}", e);
+            return;
+        }
+    };
+
+    // Capture stops on Linux once every device is gone, if new ones can't be
+    // watched for, and is started again. Only failing to start at all is fatal.
+    let mut restarts = 0;
+
+    loop {
+        let queue = queue.clone();
+        let captured = Hook::run_forever(move |input| {
+            // Only fails once processing has stopped.
+            let _ = queue.send(input);
+        });
+
+        match captured {
+            Err(e) if restarts == 0 => {
+                eprintln!("Could not capture input: {}", e);
+                break;
             }
+            Err(e) => eprintln!("Could not capture input: {}", e),
+            Ok(()) => eprintln!("Input capture stopped"),
         }
-    }).unwrap();
+
+        thread::sleep(HOOK_RESTART_DELAY);
+        restarts += 1;
+        metrics::HOOK_RESTARTS.inc(&[]);
+    }
+
+    #[cfg(unix)]
+    {
+        if let Some(path) = control_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
 }
@@ -0,0 +1,200 @@
+//! Counters for unattended setups, served as `/metrics` in the Prometheus
+//! text format by the websocket server.
+//!
+//! Counters are updated where things happen and read when scraped, so they
+//! only ever go up and survive config reloads. The number of clients is
+//! taken from the shared state instead.
+
+use crate::common::Timestamp;
+use crate::state::SharedState;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+/// Upper bounds of the latency buckets, in microseconds.
+const LATENCY_BUCKETS: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+];
+
+pub static INPUT_EVENTS: LazyLock<Counter> = LazyLock::new(|| {
+    Counter::new(
+        "keydisp_input_events_total",
+        "Input events captured, per device and event type.",
+        &["source", "type"],
+    )
+});
+
+pub static OUTPUTS_SENT: LazyLock<Counter> = LazyLock::new(|| {
+    Counter::new(
+        "keydisp_outputs_sent_total",
+        "Outputs broadcast to clients and sinks, per type.",
+        &["type"],
+    )
+});
+
+pub static DROPPED_EVENTS: LazyLock<Counter> = LazyLock::new(|| {
+    Counter::new(
+        "keydisp_dropped_events_total",
+        "Events skipped by outputs that couldn't keep up.",
+        &["output"],
+    )
+});
+
+pub static WRITE_ERRORS: LazyLock<Counter> = LazyLock::new(|| {
+    Counter::new(
+        "keydisp_write_errors_total",
+        "Failed writes to clients and sinks.",
+        &["output"],
+    )
+});
+
+pub static HOOK_RESTARTS: LazyLock<Counter> = LazyLock::new(|| {
+    Counter::new(
+        "keydisp_hook_restarts_total",
+        "Times input capture stopped and was started again.",
+        &[],
+    )
+});
+
+pub static PROCESSING_LATENCY: LazyLock<Histogram> = LazyLock::new(Histogram::default);
+
+/// A counter per combination of label values.
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl Counter {
+    fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, labels: &[&str]) {
+        self.add(labels, 1);
+    }
+
+    pub fn add(&self, labels: &[&str], n: u64) {
+        let labels = labels.iter().map(|label| label.to_string()).collect();
+        let mut values = self.values.lock().expect("metrics lock to not be poisoned.");
+
+        *values.entry(labels).or_insert(0) += n;
+    }
+
+    fn render(&self, out: &mut String) {
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} counter", self.name);
+
+        let values = self.values.lock().expect("metrics lock to not be poisoned.");
+
+        // Counters without labels are there from the start.
+        if self.labels.is_empty() && values.is_empty() {
+            let _ = writeln!(out, "{} 0", self.name);
+        }
+
+        for (labels, value) in values.iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, label_set(self.labels, labels), value);
+        }
+    }
+}
+
+/// Time from input happening until its outputs were broadcast.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    /// Records the time since `time`.
+    pub fn observe_since(&self, time: Timestamp) {
+        let micros = Timestamp::now().as_micros().saturating_sub(time.as_micros());
+
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            if micros <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let name = "keydisp_processing_latency_seconds";
+
+        let _ = writeln!(out, "# HELP {} Time from input happening until it was processed.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS.iter()) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                *bound as f64 / 1e6,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// `{name="value",...}`, with quotes, backslashes and newlines escaped.
+fn label_set(names: &[&str], values: &[String]) -> String {
+    if names.is_empty() {
+        return String::new();
+    }
+
+    let pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// Every metric, in the text exposition format.
+pub fn render(state: &SharedState) -> String {
+    let clients = state
+        .lock()
+        .expect("state lock to not be poisoned.")
+        .stats
+        .clients;
+    let mut out = String::new();
+
+    INPUT_EVENTS.render(&mut out);
+    OUTPUTS_SENT.render(&mut out);
+
+    let _ = writeln!(out, "# HELP keydisp_clients Connected websocket clients.");
+    let _ = writeln!(out, "# TYPE keydisp_clients gauge");
+    let _ = writeln!(out, "keydisp_clients {}", clients);
+
+    DROPPED_EVENTS.render(&mut out);
+    WRITE_ERRORS.render(&mut out);
+    HOOK_RESTARTS.render(&mut out);
+    PROCESSING_LATENCY.render(&mut out);
+
+    out
+}
@@ -0,0 +1,200 @@
+//! Processed events, as broadcast to every connected client.
+
+use crate::common::{Axis, DeviceId, GamepadButton, Key, Lock, MouseButton, Timestamp};
+
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Output {
+    /// A key shown by its glyph rather than the character it types.
+    Key { key: Key, glyph: &'static str },
+    /// A key was let go, sent for every key so clients can tell hold times.
+    Release { key: Key },
+    /// A character produced by typing.
+    Char { text: char },
+    /// A dead key or compose sequence is being typed, an empty `text` means
+    /// it was abandoned. Completed sequences are sent as `Char`.
+    Pending { text: String },
+    /// A lock key was pressed, engaging or releasing its lock.
+    Lock { lock: Lock, on: bool },
+    /// A mouse button was pressed, with the modifiers held at the time.
+    MouseButton {
+        button: MouseButton,
+        modifiers: Vec<Key>,
+        glyph: String,
+    },
+    /// The wheel moved, in notches, positive is up and right.
+    Scroll {
+        dx: f32,
+        dy: f32,
+        modifiers: Vec<Key>,
+        glyph: String,
+    },
+    /// Pointer position relative to the selected window, or the focused one
+    /// when none is, only sent when `mouse_move` is enabled.
+    MouseMove { x: i32, y: i32 },
+    /// A gamepad button was pressed, `glyph` is its label in the configured set.
+    GamepadButton {
+        button: GamepadButton,
+        glyph: String,
+    },
+    /// A stick or trigger moved, with the dead zone applied.
+    GamepadAxis { axis: Axis, value: f32 },
+}
+
+impl Output {
+    /// The `type` sent to JSON clients.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Output::Key { .. } => "key",
+            Output::Release { .. } => "release",
+            Output::Char { .. } => "char",
+            Output::Pending { .. } => "pending",
+            Output::Lock { .. } => "lock",
+            Output::MouseButton { .. } => "mouse_button",
+            Output::Scroll { .. } => "scroll",
+            Output::MouseMove { .. } => "mouse_move",
+            Output::GamepadButton { .. } => "gamepad_button",
+            Output::GamepadAxis { .. } => "gamepad_axis",
+        }
+    }
+
+    /// What the overlay displays for this event. Pending sequences have
+    /// nothing to display, the overlay can't take back what it has shown.
+    pub fn glyph(&self) -> Option<String> {
+        match self {
+            Output::Key { glyph, .. } => Some(glyph.to_string()),
+            Output::Char { text } => Some(text.to_string()),
+            Output::Release { .. } => None,
+            Output::Pending { .. } => None,
+            Output::MouseButton { glyph, .. } => Some(glyph.clone()),
+            Output::Scroll { glyph, .. } => Some(glyph.clone()),
+            Output::MouseMove { .. } => None,
+            Output::GamepadButton { glyph, .. } => Some(glyph.clone()),
+            Output::GamepadAxis { .. } => None,
+            Output::Lock { lock, on } => Some(format!(
+                "{} {}",
+                lock.key().glyph().expect("lock keys to have glyphs."),
+                if *on { "on" } else { "off" }
+            )),
+        }
+    }
+}
+
+/// An output along with the device whose input caused it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tagged {
+    #[serde(flatten)]
+    pub output: Output,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<Arc<DeviceId>>,
+    #[serde(skip_serializing_if = "is_false")]
+    pub injected: bool,
+    /// When the input happened, in microseconds since keydisp started.
+    pub time: Timestamp,
+    /// Set apart from typed input in the text format, by the `mark` policy.
+    #[serde(skip)]
+    pub marked: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// Underlines each character of marked glyphs.
+const MARK: char = '\u{332}';
+
+impl Tagged {
+    /// Whether a client subscribed to `devices` gets this, no subscriptions
+    /// means every device. Untagged outputs, like pointer motion, can't be
+    /// told apart by device and only go to clients without subscriptions.
+    pub fn is_for(&self, devices: &[String]) -> bool {
+        match self.device {
+            Some(ref device) => devices.is_empty() || device.matches_any(devices),
+            None => devices.is_empty(),
+        }
+    }
+}
+
+/// `glyph` set apart from typed input, each character underlined.
+pub fn mark(glyph: &str) -> String {
+    glyph
+        .chars()
+        .flat_map(|c| std::iter::once(c).chain(std::iter::once(MARK)))
+        .collect()
+}
+
+/// The wire format a client asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Bare glyphs, one per message, as read by `index.html`.
+    Text,
+    /// One JSON object per message.
+    Json,
+}
+
+impl Format {
+    pub const JSON_PROTOCOL: &'static str = "keydisp.json";
+
+    /// The message to send for `tagged`, if the format has one for it.
+    pub fn encode(self, tagged: &Tagged) -> Option<String> {
+        match self {
+            Format::Text if tagged.marked => tagged.output.glyph().map(|glyph| mark(&glyph)),
+            Format::Text => tagged.output.glyph(),
+            Format::Json => Some(serde_json::to_string(tagged).expect("output to serialize.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagged(device: Option<&str>) -> Tagged {
+        Tagged {
+            output: Output::Char { text: 'k' },
+            device: device.map(|name| {
+                Arc::new(DeviceId {
+                    name: name.to_string(),
+                    vendor: 0x046d,
+                    product: 0xc52b,
+                    phys: String::new(),
+                })
+            }),
+            injected: false,
+            time: Timestamp::from_micros(1),
+            marked: false,
+        }
+    }
+
+    fn subscribed(patterns: &[&str]) -> Vec<String> {
+        patterns.iter().map(|pattern| pattern.to_string()).collect()
+    }
+
+    #[test]
+    fn clients_without_subscriptions_get_everything() {
+        assert!(tagged(Some("Player One")).is_for(&[]));
+        assert!(tagged(None).is_for(&[]));
+    }
+
+    #[test]
+    fn subscribed_clients_only_get_their_devices() {
+        let devices = subscribed(&["player one", "1209:0001"]);
+
+        assert!(tagged(Some("Player One Keyboard")).is_for(&devices));
+        assert!(!tagged(Some("Player Two Keyboard")).is_for(&devices));
+        assert!(tagged(Some("Player One")).is_for(&subscribed(&["046d:c52b"])));
+    }
+
+    #[test]
+    fn untagged_outputs_skip_subscribed_clients() {
+        assert!(!tagged(None).is_for(&subscribed(&["Player One"])));
+    }
+
+    #[test]
+    fn marks_underline_every_character() {
+        assert_eq!(mark("⇧+a"), "⇧\u{332}+\u{332}a\u{332}");
+        assert_eq!(mark(""), "");
+    }
+}
@@ -0,0 +1,613 @@
+//! Turning input into outputs, on a thread of its own.
+//!
+//! Windows silently removes low level hooks that take longer than
+//! `LowLevelHooksTimeout` to return, so hooks only stamp events and queue
+//! them. Everything that can take a while, like waiting for the state lock,
+//! happens here instead.
+
+#[cfg(target_os = "linux")]
+use crate::linux::{get_lock_state, translate, window_position, Keymap, Layout};
+#[cfg(target_os = "windows")]
+use crate::windows::{get_lock_state, translate, window_position};
+
+use crate::common::{
+    scroll_glyph, AxisFilter, Composed, Composer, DeviceId, Event, Input, Key, KeyState,
+    ModifierState, Transition, Utf16Decoder, SURROGATE_TIMEOUT,
+};
+use crate::config::InjectedPolicy;
+use crate::metrics;
+use crate::output::{Output, Tagged};
+use crate::state::SharedState;
+
+use std::char::REPLACEMENT_CHARACTER;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use tokio::sync::broadcast;
+
+const SET_INPUT_WINDOW_KEY: Key = Key::F10;
+
+pub struct Processor {
+    state: SharedState,
+    events: broadcast::Sender<Tagged>,
+    modifiers: ModifierState,
+    composer: Composer,
+    decoder: Utf16Decoder,
+    /// The input that left a high surrogate waiting in `decoder`.
+    surrogate: Option<Input>,
+    /// Each pad's sticks, apart from the other pads'.
+    axes: HashMap<Option<Arc<DeviceId>>, AxisFilter>,
+    /// The configured layout, which evdev's key codes are typed in.
+    #[cfg(target_os = "linux")]
+    keymap: Keymap,
+}
+
+impl Processor {
+    pub fn new(state: SharedState, events: broadcast::Sender<Tagged>) -> Self {
+        let locks = get_lock_state();
+
+        #[cfg(target_os = "linux")]
+        let keymap = {
+            let st = state.lock().expect("state lock to not be poisoned.");
+            let keymap = Keymap::new(&Layout::configured(&st.config.keyboard), locks);
+
+            if !keymap.is_xkb() {
+                eprintln!("xkbcommon is not installed, typing as on a US keyboard");
+            }
+
+            keymap
+        };
+
+        Self {
+            state,
+            events,
+            modifiers: ModifierState::new(locks),
+            composer: Composer::new(),
+            decoder: Utf16Decoder::new(SURROGATE_TIMEOUT),
+            surrogate: None,
+            axes: HashMap::new(),
+            #[cfg(target_os = "linux")]
+            keymap,
+        }
+    }
+
+    /// Starts processing on a new thread, which stops once every sender of
+    /// the returned queue is dropped. Sending never blocks, or takes a lock.
+    pub fn spawn(self) -> io::Result<Sender<Input>> {
+        let (queue, inputs) = mpsc::channel();
+
+        thread::Builder::new()
+            .name("processing".to_string())
+            .spawn(move || self.run(inputs))?;
+
+        Ok(queue)
+    }
+
+    fn run(mut self, inputs: Receiver<Input>) {
+        loop {
+            // A high surrogate is given up on once it has waited too long,
+            // rather than when the next input happens to arrive.
+            let received = match self.decoder.deadline() {
+                Some(deadline) => {
+                    inputs.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                }
+                None => inputs.recv().map_err(RecvTimeoutError::from),
+            };
+
+            match received {
+                Ok(input) => self.handle(input),
+                Err(RecvTimeoutError::Timeout) => self.flush_surrogate(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn flush_surrogate(&mut self) {
+        let expired = match self.decoder.deadline() {
+            Some(deadline) => self.decoder.flush_expired(deadline),
+            None => None,
+        };
+
+        if let (Some(_), Some(input)) = (expired, self.surrogate.take()) {
+            self.handle(Input {
+                event: Event::Char(REPLACEMENT_CHARACTER),
+                ..input
+            });
+        }
+    }
+
+    fn handle(&mut self, input: Input) {
+        // evdev only knows right alt, whether it is AltGr is up to the layout.
+        #[cfg(target_os = "linux")]
+        let event = match input.event {
+            Event::Key { key, key_state } => Event::Key {
+                key: self.keymap.key(key),
+                key_state,
+            },
+            event => event,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let event = input.event;
+        let fg_window = input.window;
+        let mut st = self.state.lock().expect("state lock to not be poisoned.");
+
+        st.stats.events += 1;
+
+        let source = input.device.as_ref().map_or("hook", |device| device.name.as_str());
+        metrics::INPUT_EVENTS.inc(&[source, event.kind()]);
+
+        // Excluded devices, like a macro pad, don't take part at all.
+        if !st.config.devices.allows(input.device.as_deref()) {
+            return;
+        }
+
+        // Tracked even while not displaying, so the lock state and pending
+        // sequences stay in sync with the focused application.
+        let transition = match event {
+            Event::Key { key, key_state } => {
+                if key == SET_INPUT_WINDOW_KEY && key_state == KeyState::Pressed {
+                    st.select_window(Some(fg_window));
+                }
+
+                Some(self.modifiers.update(key, key_state))
+            }
+            _ => None,
+        };
+
+        // Hooks only say which key was pressed, the text it types is looked
+        // up here rather than on the hook thread.
+        let typed = match event {
+            #[cfg(target_os = "windows")]
+            Event::Untranslated { vk, scan } => translate(vk, scan, fg_window, &self.modifiers),
+            #[cfg(target_os = "linux")]
+            Event::Untranslated { code, value } => translate(&mut self.keymap, code, value),
+            event => vec![event],
+        };
+
+        let mut composed = Vec::new();
+
+        for event in typed {
+            composed.extend(match (event, transition) {
+                (Event::Char(c), _) => self.composer.char(c),
+                (Event::DeadKey(accent), _) => self.composer.dead_key(accent),
+                (Event::Utf16(unit), _) => {
+                    let decoded = self.decoder.push(unit, input.time.to_instant());
+                    self.surrogate = self.decoder.deadline().map(|_| input.clone());
+
+                    let composer = &mut self.composer;
+
+                    decoded
+                        .into_iter()
+                        .flat_map(|decoded| composer.char(decoded.unwrap_or(REPLACEMENT_CHARACTER)))
+                        .collect()
+                }
+                (
+                    Event::Key {
+                        key: Key::Compose, ..
+                    },
+                    Some(Transition::Pressed),
+                ) => self.composer.compose_key(),
+                _ => vec![],
+            });
+        }
+
+        let hidden = input.injected && st.config.injected == InjectedPolicy::Hide;
+
+        if st.should_display(fg_window) && !hidden {
+            let held = self.modifiers.held;
+
+            let event_output = match (event, transition) {
+                (Event::Key { key, .. }, Some(Transition::Pressed)) => {
+                    key.glyph().map(|glyph| Output::Key { key, glyph })
+                }
+                (Event::Key { key, .. }, Some(Transition::Released)) => {
+                    Some(Output::Release { key })
+                }
+                (_, Some(Transition::Toggled(lock, on))) => Some(Output::Lock { lock, on }),
+                (
+                    Event::MouseButton {
+                        button,
+                        key_state: KeyState::Pressed,
+                    },
+                    _,
+                ) => Some(Output::MouseButton {
+                    button,
+                    modifiers: held.keys(),
+                    glyph: held.chord(button.glyph()),
+                }),
+                (Event::Scroll { dx, dy }, _) => Some(Output::Scroll {
+                    dx,
+                    dy,
+                    modifiers: held.keys(),
+                    glyph: held.chord(scroll_glyph(dx, dy)),
+                }),
+                // Relative to the selected window, or the focused one.
+                (Event::MouseMove { x, y }, _) if st.config.mouse_move => {
+                    window_position(st.input_window.unwrap_or(fg_window), x, y)
+                        .map(|(x, y)| Output::MouseMove { x, y })
+                }
+                (
+                    Event::GamepadButton {
+                        button,
+                        key_state: KeyState::Pressed,
+                    },
+                    _,
+                ) => Some(Output::GamepadButton {
+                    button,
+                    glyph: button.label(st.config.gamepad.labels),
+                }),
+                (Event::GamepadAxis { axis, value }, _) => self
+                    .axes
+                    .entry(input.device.clone())
+                    .or_default()
+                    .update(axis, value, st.config.gamepad.deadzone)
+                    .map(|value| Output::GamepadAxis { axis, value }),
+                _ => None,
+            };
+
+            let char_outputs = composed.into_iter().filter_map(|composed| match composed {
+                Composed::Char(c) if !(c.is_control() || c.is_whitespace()) => {
+                    Some(Output::Char { text: c })
+                }
+                Composed::Char(_) => None,
+                Composed::Pending(text) => Some(Output::Pending { text }),
+                Composed::Cancelled => Some(Output::Pending {
+                    text: String::new(),
+                }),
+            });
+
+            for output in event_output.into_iter().chain(char_outputs) {
+                st.stats.sent += 1;
+                metrics::OUTPUTS_SENT.inc(&[output.kind()]);
+
+                // Only fails when no client is connected.
+                let _ = self.events.send(Tagged {
+                    output,
+                    device: input.device.clone(),
+                    injected: input.injected,
+                    marked: input.injected && st.config.injected == InjectedPolicy::Mark,
+                    time: input.time,
+                });
+            }
+        }
+
+        metrics::PROCESSING_LATENCY.observe_since(input.time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::{Axis, MouseButton, Timestamp};
+    use crate::config::{Config, FilterMode};
+    use crate::output::Format;
+    use crate::state::State;
+
+    use std::time::Duration;
+
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn start() -> (Sender<Input>, broadcast::Receiver<Tagged>) {
+        start_filtering(FilterMode::Everything)
+    }
+
+    fn start_filtering(filter_mode: FilterMode) -> (Sender<Input>, broadcast::Receiver<Tagged>) {
+        let (processor, receiver) = processor(Config {
+            filter_mode,
+            ..Config::default()
+        });
+
+        (processor.spawn().unwrap(), receiver)
+    }
+
+    fn processor(config: Config) -> (Processor, broadcast::Receiver<Tagged>) {
+        let (events, receiver) = broadcast::channel(16);
+
+        (
+            Processor::new(State::new(config).shared(), events),
+            receiver,
+        )
+    }
+
+    fn send(queue: &Sender<Input>, event: Event) {
+        send_to(queue, 0, event);
+    }
+
+    /// As a macro tool or remote desktop would.
+    fn inject(queue: &Sender<Input>, event: Event) {
+        let input = Input {
+            event,
+            device: None,
+            injected: true,
+            time: Timestamp::now(),
+            window: 0,
+        };
+
+        queue.send(input).unwrap();
+    }
+
+    fn start_injected(injected: InjectedPolicy) -> (Sender<Input>, broadcast::Receiver<Tagged>) {
+        let (processor, receiver) = processor(Config {
+            filter_mode: FilterMode::Everything,
+            injected,
+            ..Config::default()
+        });
+
+        (processor.spawn().unwrap(), receiver)
+    }
+
+    /// As the hook would while `window` has focus.
+    fn send_to(queue: &Sender<Input>, window: usize, event: Event) {
+        let input = Input {
+            event,
+            device: None,
+            injected: false,
+            time: Timestamp::now(),
+            window,
+        };
+
+        queue.send(input).unwrap();
+    }
+
+    /// The next output, waiting up to a second for it.
+    fn next(receiver: &mut broadcast::Receiver<Tagged>) -> Option<Tagged> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+
+        while Instant::now() < deadline {
+            match receiver.try_recv() {
+                Ok(tagged) => return Some(tagged),
+                Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(5)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        None
+    }
+
+    /// The next character output, skipping the others.
+    fn next_char(receiver: &mut broadcast::Receiver<Tagged>) -> Option<char> {
+        loop {
+            if let Output::Char { text } = next(receiver)?.output {
+                return Some(text);
+            }
+        }
+    }
+
+    #[test]
+    fn surrogate_pairs_are_decoded() {
+        let (queue, mut receiver) = start();
+
+        send(&queue, Event::Utf16(0xD83D));
+        send(&queue, Event::Utf16(0xDE00));
+        send(&queue, Event::Utf16(u16::from(b'a')));
+
+        assert_eq!(next_char(&mut receiver), Some('😀'));
+        assert_eq!(next_char(&mut receiver), Some('a'));
+    }
+
+    #[test]
+    fn unpaired_surrogate_is_flushed_on_time() {
+        let (queue, mut receiver) = start();
+        let sent = Instant::now();
+
+        // Nothing follows, it has to be given up on without another event.
+        send(&queue, Event::Utf16(0xD83D));
+
+        assert_eq!(next_char(&mut receiver), Some(REPLACEMENT_CHARACTER));
+        assert!(sent.elapsed() >= SURROGATE_TIMEOUT - Duration::from_millis(10));
+        drop(queue);
+    }
+
+    #[test]
+    fn focus_is_as_stamped_by_the_hook() {
+        let (queue, mut receiver) = start_filtering(FilterMode::Window);
+        let press = |key| Event::Key {
+            key,
+            key_state: KeyState::Pressed,
+        };
+
+        send_to(&queue, 7, press(SET_INPUT_WINDOW_KEY));
+        send_to(&queue, 7, Event::Char('a'));
+        // Typed into another window, whichever has focus by now.
+        send_to(&queue, 9, Event::Char('b'));
+        send_to(&queue, 7, Event::Char('c'));
+
+        assert_eq!(next_char(&mut receiver), Some('a'));
+        assert_eq!(next_char(&mut receiver), Some('c'));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keys_are_typed_in_the_configured_layout() {
+        let mut config = Config {
+            filter_mode: FilterMode::Everything,
+            ..Config::default()
+        };
+        config.keyboard.layout = Some("de".to_string());
+
+        let (processor, mut receiver) = processor(config);
+        // Without xkbcommon, as on a US keyboard.
+        let (z, quote) = if processor.keymap.is_xkb() {
+            ('z', '"')
+        } else {
+            ('y', '@')
+        };
+        let queue = processor.spawn().unwrap();
+        let key = |key: Key, value| {
+            let key_state = if value == 0 {
+                KeyState::Released
+            } else {
+                KeyState::Pressed
+            };
+            let code = key.to_evdev().unwrap();
+
+            send(&queue, Event::Key { key, key_state });
+            send(&queue, Event::Untranslated { code, value });
+        };
+
+        key(Key::Y, 1);
+        key(Key::Y, 2);
+        key(Key::Y, 0);
+        key(Key::LeftShift, 1);
+        key(Key::LeftShift, 2);
+        key(Key::Digit2, 1);
+        key(Key::Digit2, 0);
+        key(Key::LeftShift, 0);
+
+        assert_eq!(next_char(&mut receiver), Some(z));
+        assert_eq!(next_char(&mut receiver), Some(z));
+        assert_eq!(next_char(&mut receiver), Some(quote));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn right_alt_is_alt_gr_where_it_types() {
+        let mut config = Config {
+            filter_mode: FilterMode::Everything,
+            ..Config::default()
+        };
+        config.keyboard.layout = Some("no".to_string());
+
+        let (processor, mut receiver) = processor(config);
+        let alt_gr = if processor.keymap.is_xkb() {
+            Key::AltGr
+        } else {
+            Key::RightAlt
+        };
+        let queue = processor.spawn().unwrap();
+
+        send(
+            &queue,
+            Event::Key {
+                key: Key::RightAlt,
+                key_state: KeyState::Pressed,
+            },
+        );
+        send(
+            &queue,
+            Event::MouseButton {
+                button: MouseButton::Left,
+                key_state: KeyState::Pressed,
+            },
+        );
+
+        match next(&mut receiver).map(|tagged| tagged.output) {
+            Some(Output::Key { key, .. }) => assert_eq!(key, alt_gr),
+            other => panic!("{:?}", other),
+        }
+        match next(&mut receiver).map(|tagged| tagged.output) {
+            Some(Output::MouseButton { modifiers, .. }) => assert_eq!(modifiers, [alt_gr]),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn each_pad_has_its_own_sticks() {
+        let (queue, mut receiver) = start();
+        let pad = |name: &str| {
+            Some(Arc::new(DeviceId {
+                name: name.to_string(),
+                vendor: 0x045e,
+                product: 0x028e,
+                phys: String::new(),
+            }))
+        };
+        let (one, two) = (pad("player one"), pad("player two"));
+        let send_from = |device: &Option<Arc<DeviceId>>, event| {
+            let input = Input {
+                event,
+                device: device.clone(),
+                injected: false,
+                time: Timestamp::now(),
+                window: 0,
+            };
+
+            queue.send(input).unwrap();
+        };
+        let left_x = |value| Event::GamepadAxis {
+            axis: Axis::LeftX,
+            value,
+        };
+
+        // Each pad's resting stick is no news, and neither is a small move
+        // after a big one, whatever the other pad did meanwhile.
+        send_from(&one, left_x(1.0));
+        send_from(&two, left_x(0.0));
+        send_from(&one, left_x(0.99));
+        send_from(&two, left_x(-1.0));
+        send_from(&one, left_x(0.0));
+        send_from(&two, left_x(-0.99));
+        send(&queue, Event::Char('.'));
+
+        let mut reported = Vec::new();
+
+        while let Some(tagged) = next(&mut receiver) {
+            match tagged.output {
+                Output::GamepadAxis { value, .. } => {
+                    reported.push((tagged.device.unwrap().name.clone(), value))
+                }
+                _ => break,
+            }
+        }
+
+        assert_eq!(
+            reported,
+            [
+                ("player one".to_string(), 1.0),
+                ("player two".to_string(), -1.0),
+                ("player one".to_string(), 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn injected_input_is_shown_as_typed() {
+        let (queue, mut receiver) = start_injected(InjectedPolicy::Show);
+
+        inject(&queue, Event::Char('a'));
+
+        let tagged = next(&mut receiver).unwrap();
+        assert!(tagged.injected);
+        assert!(!tagged.marked);
+        assert_eq!(Format::Text.encode(&tagged).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn injected_input_is_hidden() {
+        let (queue, mut receiver) = start_injected(InjectedPolicy::Hide);
+        let shift = |key_state| Event::Key {
+            key: Key::LeftShift,
+            key_state,
+        };
+
+        inject(&queue, shift(KeyState::Pressed));
+        inject(&queue, Event::Char('A'));
+        inject(&queue, shift(KeyState::Released));
+        send(&queue, Event::Char('b'));
+
+        let tagged = next(&mut receiver).unwrap();
+        assert!(!tagged.injected);
+        assert!(matches!(tagged.output, Output::Char { text: 'b' }));
+    }
+
+    #[test]
+    fn injected_input_is_marked() {
+        let (queue, mut receiver) = start_injected(InjectedPolicy::Mark);
+
+        inject(&queue, Event::Char('a'));
+        send(&queue, Event::Char('b'));
+
+        let injected = next(&mut receiver).unwrap();
+        assert!(injected.injected && injected.marked);
+        assert_eq!(Format::Text.encode(&injected).as_deref(), Some("a\u{332}"));
+
+        let typed = next(&mut receiver).unwrap();
+        assert!(!typed.injected && !typed.marked);
+        assert_eq!(Format::Text.encode(&typed).as_deref(), Some("b"));
+    }
+}
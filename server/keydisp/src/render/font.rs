@@ -0,0 +1,745 @@
+//! Just enough of TrueType to draw keystrokes: character mapping, advances
+//! and `glyf` outlines, simple and composite, rasterised with coverage based
+//! anti-aliasing. Hinting instructions are ignored, text is drawn large
+//! enough to not need them.
+
+use super::raster::Rasterizer;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::{fs, io};
+
+/// DejaVu Sans Mono, what browsers fall back to for `monospace` on most
+/// Linux systems when `style.css` can't load Ubuntu Mono.
+const BUNDLED: &[u8] = include_bytes!("../../fonts/DejaVuSansMono.ttf");
+
+/// Composite glyphs nested deeper than this are taken to be broken.
+const MAX_COMPONENT_DEPTH: u32 = 8;
+
+#[derive(Debug)]
+pub enum FontError {
+    Io(io::Error),
+    /// A table is missing or points outside the file.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FontError::Io(e) => write!(f, "{}", e),
+            FontError::Malformed(e) => write!(f, "not a usable TrueType font, {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for FontError {
+    fn from(e: io::Error) -> Self {
+        FontError::Io(e)
+    }
+}
+
+/// A glyph rasterised at some size, as coverage from 0 to 255.
+pub struct Bitmap {
+    /// Offset of the top left corner from the pen position on the baseline.
+    pub left: i32,
+    pub top: i32,
+    pub width: usize,
+    pub height: usize,
+    pub coverage: Vec<u8>,
+    /// How far the pen moves after this glyph, in pixels.
+    pub advance: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Point {
+    x: f32,
+    y: f32,
+    on_curve: bool,
+}
+
+pub struct Font {
+    data: Cow<'static, [u8]>,
+    units_per_em: f32,
+    ascender: f32,
+    descender: f32,
+    line_gap: f32,
+    long_loca: bool,
+    glyph_count: u16,
+    metric_count: u16,
+    cmap: usize,
+    loca: usize,
+    glyf: usize,
+    hmtx: usize,
+}
+
+impl Font {
+    pub fn bundled() -> Self {
+        Self::parse(Cow::Borrowed(BUNDLED)).expect("bundled font to parse.")
+    }
+
+    pub fn open(path: &Path) -> Result<Self, FontError> {
+        Self::parse(Cow::Owned(fs::read(path)?))
+    }
+
+    fn parse(data: Cow<'static, [u8]>) -> Result<Self, FontError> {
+        let table = |tag: &[u8; 4]| -> Result<usize, FontError> {
+            let count = read_u16(&data, 4).ok_or(FontError::Malformed("no table directory"))?;
+
+            (0..usize::from(count))
+                .map(|i| 12 + 16 * i)
+                .find(|&record| data.get(record..record + 4) == Some(&tag[..]))
+                .and_then(|record| read_u32(&data, record + 8))
+                .map(|offset| offset as usize)
+                .filter(|&offset| offset < data.len())
+                .ok_or(FontError::Malformed("a required table is missing"))
+        };
+
+        let head = table(b"head")?;
+        let hhea = table(b"hhea")?;
+        let maxp = table(b"maxp")?;
+        let cmap = table(b"cmap")?;
+        let short = |offset| read_u16(&data, offset).ok_or(FontError::Malformed("truncated table"));
+
+        let mut font = Font {
+            units_per_em: f32::from(short(head + 18)?.max(16)),
+            ascender: f32::from(short(hhea + 4)? as i16),
+            descender: f32::from(short(hhea + 6)? as i16),
+            line_gap: f32::from(short(hhea + 8)? as i16),
+            long_loca: short(head + 50)? != 0,
+            glyph_count: short(maxp + 4)?,
+            metric_count: short(hhea + 34)?.max(1),
+            cmap: 0,
+            loca: table(b"loca")?,
+            glyf: table(b"glyf")?,
+            hmtx: table(b"hmtx")?,
+            data: Cow::Borrowed(&[]),
+        };
+
+        font.cmap = Self::find_cmap(&data, cmap).ok_or(FontError::Malformed("no Unicode cmap"))?;
+        font.data = data;
+        Ok(font)
+    }
+
+    /// The best Unicode subtable: full repertoire, then the BMP.
+    fn find_cmap(data: &[u8], cmap: usize) -> Option<usize> {
+        let count = read_u16(data, cmap + 2)?;
+        let mut best = None;
+
+        for i in 0..usize::from(count) {
+            let record = cmap + 4 + 8 * i;
+            let platform = read_u16(data, record)?;
+            let encoding = read_u16(data, record + 2)?;
+            let offset = cmap + read_u32(data, record + 4)? as usize;
+            let format = read_u16(data, offset)?;
+
+            match (platform, encoding, format) {
+                (0, _, 12) | (3, 10, 12) => return Some(offset),
+                (0, _, 4) | (3, 1, 4) => best = Some(offset),
+                _ => (),
+            }
+        }
+
+        best
+    }
+
+    /// The glyph for `c`, 0 being the font's missing glyph box.
+    fn glyph_index(&self, c: char) -> u16 {
+        let data = &self.data[..];
+        let c = c as u32;
+
+        let found = match read_u16(data, self.cmap) {
+            Some(4) => self.lookup_format4(c),
+            Some(12) => self.lookup_format12(c),
+            _ => None,
+        };
+
+        found.filter(|&glyph| glyph < self.glyph_count).unwrap_or(0)
+    }
+
+    fn lookup_format4(&self, c: u32) -> Option<u16> {
+        let data = &self.data[..];
+        let table = self.cmap;
+
+        if c > 0xffff {
+            return None;
+        }
+
+        let segments = usize::from(read_u16(data, table + 6)? / 2);
+        let ends = table + 14;
+        let starts = ends + 2 * segments + 2;
+        let deltas = starts + 2 * segments;
+        let range_offsets = deltas + 2 * segments;
+
+        for i in 0..segments {
+            if u32::from(read_u16(data, ends + 2 * i)?) < c {
+                continue;
+            }
+
+            let start = u32::from(read_u16(data, starts + 2 * i)?);
+
+            if start > c {
+                return None;
+            }
+
+            let delta = read_u16(data, deltas + 2 * i)?;
+            let range_offset = usize::from(read_u16(data, range_offsets + 2 * i)?);
+
+            if range_offset == 0 {
+                return Some((c as u16).wrapping_add(delta));
+            }
+
+            let at = range_offsets + 2 * i + range_offset + 2 * (c - start) as usize;
+
+            return match read_u16(data, at)? {
+                0 => None,
+                glyph => Some(glyph.wrapping_add(delta)),
+            };
+        }
+
+        None
+    }
+
+    fn lookup_format12(&self, c: u32) -> Option<u16> {
+        let data = &self.data[..];
+        let groups = read_u32(data, self.cmap + 12)? as usize;
+
+        for i in 0..groups {
+            let group = self.cmap + 16 + 12 * i;
+            let start = read_u32(data, group)?;
+            let end = read_u32(data, group + 4)?;
+
+            if (start..=end).contains(&c) {
+                return Some((read_u32(data, group + 8)? + c - start) as u16);
+            }
+        }
+
+        None
+    }
+
+    pub fn has_glyph(&self, c: char) -> bool {
+        self.glyph_index(c) != 0
+    }
+
+    fn advance(&self, glyph: u16) -> f32 {
+        let index = glyph.min(self.metric_count - 1);
+
+        read_u16(&self.data, self.hmtx + 4 * usize::from(index))
+            .map(f32::from)
+            .unwrap_or(0.0)
+    }
+
+    fn glyph_range(&self, glyph: u16) -> Option<(usize, usize)> {
+        let data = &self.data[..];
+        let glyph = usize::from(glyph);
+
+        let (start, end) = if self.long_loca {
+            (
+                read_u32(data, self.loca + 4 * glyph)? as usize,
+                read_u32(data, self.loca + 4 * glyph + 4)? as usize,
+            )
+        } else {
+            (
+                2 * usize::from(read_u16(data, self.loca + 2 * glyph)?),
+                2 * usize::from(read_u16(data, self.loca + 2 * glyph + 2)?),
+            )
+        };
+
+        if end <= start {
+            return None;
+        }
+
+        Some((self.glyf + start, self.glyf + end))
+    }
+
+    /// Contours of `glyph` in font units, y up.
+    fn outline(&self, glyph: u16, depth: u32, contours: &mut Vec<Vec<Point>>) -> Option<()> {
+        let (start, end) = self.glyph_range(glyph)?;
+        let data = self.data.get(start..end)?;
+        let contour_count = read_u16(data, 0)? as i16;
+
+        if contour_count >= 0 {
+            Self::simple_outline(data, contour_count as usize, contours)
+        } else if depth < MAX_COMPONENT_DEPTH {
+            self.composite_outline(data, depth, contours)
+        } else {
+            None
+        }
+    }
+
+    fn simple_outline(
+        data: &[u8],
+        contour_count: usize,
+        contours: &mut Vec<Vec<Point>>,
+    ) -> Option<()> {
+        let mut ends = Vec::with_capacity(contour_count);
+
+        for i in 0..contour_count {
+            ends.push(usize::from(read_u16(data, 10 + 2 * i)?));
+        }
+
+        let point_count = ends.last().map_or(0, |end| end + 1);
+        let instructions = usize::from(read_u16(data, 10 + 2 * contour_count)?);
+        let mut at = 12 + 2 * contour_count + instructions;
+
+        let mut flags = Vec::with_capacity(point_count);
+
+        while flags.len() < point_count {
+            let flag = *data.get(at)?;
+            at += 1;
+            flags.push(flag);
+
+            if flag & 8 != 0 {
+                let repeat = *data.get(at)?;
+                at += 1;
+                flags.extend(std::iter::repeat_n(flag, usize::from(repeat)));
+            }
+        }
+
+        flags.truncate(point_count);
+
+        // Coordinates are deltas, each axis stored after the other.
+        let mut read_axis = |short: u8, same_or_positive: u8| -> Option<Vec<f32>> {
+            let mut value = 0i32;
+            let mut values = Vec::with_capacity(point_count);
+
+            for flag in &flags {
+                if flag & short != 0 {
+                    let delta = i32::from(*data.get(at)?);
+                    at += 1;
+                    value += if flag & same_or_positive != 0 {
+                        delta
+                    } else {
+                        -delta
+                    };
+                } else if flag & same_or_positive == 0 {
+                    value += i32::from(read_u16(data, at)? as i16);
+                    at += 2;
+                }
+
+                values.push(value as f32);
+            }
+
+            Some(values)
+        };
+
+        let xs = read_axis(0x02, 0x10)?;
+        let ys = read_axis(0x04, 0x20)?;
+
+        let mut first = 0;
+
+        for end in ends {
+            if end < first || end >= point_count {
+                return None;
+            }
+
+            contours.push(
+                (first..=end)
+                    .map(|i| Point {
+                        x: xs[i],
+                        y: ys[i],
+                        on_curve: flags[i] & 1 != 0,
+                    })
+                    .collect(),
+            );
+            first = end + 1;
+        }
+
+        Some(())
+    }
+
+    fn composite_outline(
+        &self,
+        data: &[u8],
+        depth: u32,
+        contours: &mut Vec<Vec<Point>>,
+    ) -> Option<()> {
+        const ARGS_ARE_WORDS: u16 = 0x0001;
+        const ARGS_ARE_OFFSETS: u16 = 0x0002;
+        const SCALE: u16 = 0x0008;
+        const MORE_COMPONENTS: u16 = 0x0020;
+        const X_AND_Y_SCALE: u16 = 0x0040;
+        const TWO_BY_TWO: u16 = 0x0080;
+
+        let mut at = 10;
+
+        loop {
+            let flags = read_u16(data, at)?;
+            let glyph = read_u16(data, at + 2)?;
+            at += 4;
+
+            let (dx, dy) = if flags & ARGS_ARE_WORDS != 0 {
+                at += 4;
+                (
+                    f32::from(read_u16(data, at - 4)? as i16),
+                    f32::from(read_u16(data, at - 2)? as i16),
+                )
+            } else {
+                at += 2;
+                (
+                    f32::from(*data.get(at - 2)? as i8),
+                    f32::from(*data.get(at - 1)? as i8),
+                )
+            };
+
+            let fixed = |at: usize| read_u16(data, at).map(|v| f32::from(v as i16) / 16384.0);
+            let (mut a, mut b, mut c, mut d) = (1.0, 0.0, 0.0, 1.0);
+
+            if flags & SCALE != 0 {
+                a = fixed(at)?;
+                d = a;
+                at += 2;
+            } else if flags & X_AND_Y_SCALE != 0 {
+                a = fixed(at)?;
+                d = fixed(at + 2)?;
+                at += 4;
+            } else if flags & TWO_BY_TWO != 0 {
+                a = fixed(at)?;
+                b = fixed(at + 2)?;
+                c = fixed(at + 4)?;
+                d = fixed(at + 6)?;
+                at += 8;
+            }
+
+            // Matching points instead of offsets is rare enough to leave out.
+            if flags & ARGS_ARE_OFFSETS != 0 {
+                let mut component = Vec::new();
+                self.outline(glyph, depth + 1, &mut component);
+
+                contours.extend(component.into_iter().map(|contour| {
+                    contour
+                        .into_iter()
+                        .map(|p| Point {
+                            x: a * p.x + c * p.y + dx,
+                            y: b * p.x + d * p.y + dy,
+                            on_curve: p.on_curve,
+                        })
+                        .collect()
+                }));
+            }
+
+            if flags & MORE_COMPONENTS == 0 {
+                return Some(());
+            }
+        }
+    }
+
+    /// Draws `c` at `size` pixels per em.
+    pub fn rasterize(&self, c: char, size: f32) -> Bitmap {
+        let glyph = self.glyph_index(c);
+        let scale = size / self.units_per_em;
+        let advance = self.advance(glyph) * scale;
+
+        let mut contours = Vec::new();
+        self.outline(glyph, 0, &mut contours);
+
+        let points = contours.iter().flatten();
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+
+        for p in points {
+            min_x = min_x.min(p.x * scale);
+            max_x = max_x.max(p.x * scale);
+            min_y = min_y.min(-p.y * scale);
+            max_y = max_y.max(-p.y * scale);
+        }
+
+        if min_x > max_x {
+            return Bitmap {
+                left: 0,
+                top: 0,
+                width: 0,
+                height: 0,
+                coverage: Vec::new(),
+                advance,
+            };
+        }
+
+        let left = min_x.floor() as i32;
+        let top = min_y.floor() as i32;
+        let width = (max_x.ceil() as i32 - left + 1) as usize;
+        let height = (max_y.ceil() as i32 - top + 1) as usize;
+
+        let mut rasterizer = Rasterizer::new(width, height);
+        let to_pixels = |p: &Point| (p.x * scale - left as f32, -p.y * scale - top as f32);
+
+        for contour in &contours {
+            trace(contour, |from, control, to| {
+                let from = to_pixels(&from);
+                let to = to_pixels(&to);
+
+                match control {
+                    Some(control) => rasterizer.quad(from, to_pixels(&control), to),
+                    None => rasterizer.line(from, to),
+                }
+            });
+        }
+
+        Bitmap {
+            left,
+            top,
+            width,
+            height,
+            coverage: rasterizer.coverage(),
+            advance,
+        }
+    }
+
+    /// Pixels from the top of a line to its baseline, and the line height,
+    /// as CSS lays out `line-height: normal`.
+    pub fn line_metrics(&self, size: f32) -> (f32, f32) {
+        let scale = size / self.units_per_em;
+        let height = (self.ascender - self.descender + self.line_gap) * scale;
+
+        (self.line_gap * scale / 2.0 + self.ascender * scale, height)
+    }
+}
+
+/// Calls `segment` for each line and quadratic curve of a contour. Two
+/// control points in a row have an implied point on the curve between them.
+fn trace(contour: &[Point], mut segment: impl FnMut(Point, Option<Point>, Point)) {
+    let midpoint = |a: Point, b: Point| Point {
+        x: (a.x + b.x) / 2.0,
+        y: (a.y + b.y) / 2.0,
+        on_curve: true,
+    };
+
+    let start = match contour.iter().position(|p| p.on_curve) {
+        Some(i) => contour[i],
+        None if contour.len() >= 2 => midpoint(contour[0], contour[1]),
+        None => return,
+    };
+    let offset = contour.iter().position(|p| p.on_curve).unwrap_or(0);
+
+    let mut pen = start;
+    let mut control: Option<Point> = None;
+
+    for i in 1..=contour.len() {
+        let p = contour[(offset + i) % contour.len()];
+
+        match (p.on_curve, control) {
+            (true, None) => {
+                segment(pen, None, p);
+                pen = p;
+            }
+            (true, Some(c)) => {
+                segment(pen, Some(c), p);
+                pen = p;
+                control = None;
+            }
+            (false, None) => control = Some(p),
+            (false, Some(c)) => {
+                let mid = midpoint(c, p);
+                segment(pen, Some(c), mid);
+                pen = mid;
+                control = Some(p);
+            }
+        }
+    }
+
+    match control {
+        Some(c) => segment(pen, Some(c), start),
+        None if pen.x != start.x || pen.y != start.y => segment(pen, None, start),
+        None => (),
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    data.get(at..at + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Rasterised glyphs at one size, each from the first font that has it.
+pub struct GlyphCache {
+    fonts: Vec<Font>,
+    size: f32,
+    glyphs: HashMap<char, Bitmap>,
+}
+
+impl GlyphCache {
+    pub fn new(fonts: Vec<Font>, size: f32) -> Self {
+        assert!(!fonts.is_empty(), "glyphs need a font.");
+
+        Self {
+            fonts,
+            size,
+            glyphs: HashMap::new(),
+        }
+    }
+
+    /// As laid out by the first font.
+    pub fn line_metrics(&self) -> (f32, f32) {
+        self.fonts[0].line_metrics(self.size)
+    }
+
+    pub fn get(&mut self, c: char) -> &Bitmap {
+        let fonts = &self.fonts;
+        let size = self.size;
+
+        self.glyphs.entry(c).or_insert_with(|| {
+            let font = fonts
+                .iter()
+                .find(|font| font.has_glyph(c))
+                .unwrap_or(&fonts[0]);
+            font.rasterize(c, size)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: f32 = 64.0;
+
+    /// Pixels covered, as the rasteriser has it.
+    fn covered(bitmap: &Bitmap) -> f32 {
+        bitmap.coverage.iter().map(|&c| f32::from(c)).sum::<f32>() / 255.0
+    }
+
+    /// The exact area inside the outline of `c`, in pixels. Each quadratic
+    /// curve adds two thirds of the triangle its control point makes.
+    fn outline_area(font: &Font, c: char) -> f32 {
+        let scale = SIZE / font.units_per_em;
+        let mut contours = Vec::new();
+        font.outline(font.glyph_index(c), 0, &mut contours);
+
+        let cross = |a: Point, b: Point| a.x * b.y - a.y * b.x;
+        let mut area = 0.0;
+
+        for contour in &contours {
+            trace(contour, |from, control, to| {
+                area += cross(from, to) / 2.0;
+
+                if let Some(control) = control {
+                    let relative = |p: Point| Point {
+                        x: p.x - from.x,
+                        y: p.y - from.y,
+                        on_curve: true,
+                    };
+                    area += cross(relative(control), relative(to)) / 3.0;
+                }
+            });
+        }
+
+        area.abs() * scale * scale
+    }
+
+    #[test]
+    fn bundled_font_metrics() {
+        let font = Font::bundled();
+
+        // DejaVu Sans Mono: 2048 units per em, 1901 up and 483 down.
+        assert_eq!(font.line_metrics(2048.0), (1901.0, 2384.0));
+
+        for c in ['i', 'M', '⇧', ' '] {
+            assert_eq!(font.rasterize(c, 2048.0).advance, 1233.0);
+        }
+    }
+
+    #[test]
+    fn maps_characters_to_glyphs() {
+        let font = Font::bundled();
+
+        for c in ['a', 'Z', '⇧', '⌫', '→', 'é'] {
+            assert!(font.has_glyph(c), "{:?}", c);
+        }
+
+        assert_ne!(font.glyph_index('a'), font.glyph_index('b'));
+        assert!(!font.has_glyph('\u{10fffd}'));
+        assert!(!font.has_glyph('\u{e000}'));
+    }
+
+    #[test]
+    fn coverage_adds_up_to_the_outline() {
+        let font = Font::bundled();
+
+        for c in ['█', 'l', 'o', 'e', '@', '⇧', 'é'] {
+            let bitmap = font.rasterize(c, SIZE);
+            let expected = outline_area(&font, c);
+
+            assert_eq!(bitmap.coverage.len(), bitmap.width * bitmap.height);
+            assert!(
+                (covered(&bitmap) - expected).abs() < expected * 0.01,
+                "{:?} covers {} pixels, its outline {}",
+                c,
+                covered(&bitmap),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn solid_glyphs_are_solid_inside() {
+        let bitmap = Font::bundled().rasterize('█', SIZE);
+
+        // Edges fall between pixels, everything else is fully covered.
+        for y in 1..bitmap.height - 2 {
+            for x in 1..bitmap.width - 2 {
+                assert_eq!(
+                    bitmap.coverage[y * bitmap.width + x],
+                    255,
+                    "at {}, {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn composite_glyphs_combine_their_components() {
+        let font = Font::bundled();
+        let e = font.rasterize('e', SIZE);
+        let accented = font.rasterize('é', SIZE);
+        let accent = font.rasterize('´', SIZE);
+
+        assert!(accented.top < e.top);
+        assert_eq!(
+            accented.top + accented.height as i32,
+            e.top + e.height as i32
+        );
+        assert!((covered(&accented) - covered(&e) - covered(&accent)).abs() < 1.0);
+    }
+
+    #[test]
+    fn blank_and_missing_glyphs() {
+        let font = Font::bundled();
+
+        let space = font.rasterize(' ', SIZE);
+        assert_eq!((space.width, space.height), (0, 0));
+        assert!(space.advance > 0.0);
+
+        // The missing glyph box, rather than nothing.
+        assert!(covered(&font.rasterize('\u{10fffd}', SIZE)) > 0.0);
+
+        let mut cache = GlyphCache::new(vec![Font::bundled()], SIZE);
+        assert_eq!(cache.get('a').coverage, font.rasterize('a', SIZE).coverage);
+    }
+
+    #[test]
+    fn broken_fonts_are_refused_or_drawn_blank() {
+        assert!(matches!(
+            Font::parse(Cow::Borrowed(b"")),
+            Err(FontError::Malformed(_))
+        ));
+        assert!(matches!(
+            Font::parse(Cow::Borrowed(&BUNDLED[..12])),
+            Err(FontError::Malformed(_))
+        ));
+
+        // Tables may start inside what is left and run past its end.
+        for len in (BUNDLED.len() / 8..BUNDLED.len()).step_by(BUNDLED.len() / 8) {
+            if let Ok(font) = Font::parse(Cow::Owned(BUNDLED[..len].to_vec())) {
+                for c in ['a', 'é', '⇧', '\u{10fffd}'] {
+                    font.rasterize(c, SIZE);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,360 @@
+//! Animated GIF encoding.
+//!
+//! GIF has at most 256 colours and pixels are either opaque or not, so
+//! frames are mapped to a palette and pixels less than half covered are
+//! left transparent. Every frame replaces the whole image.
+
+use super::raster::Frame;
+use super::Color;
+
+use std::collections::HashMap;
+
+/// Codes are at most 12 bits, the table starts over once it is full.
+const MAX_CODES: u16 = 4096;
+
+/// LZW codes packed least significant bit first, in blocks of up to 255.
+struct Codes {
+    out: Vec<u8>,
+    block: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl Codes {
+    fn push(&mut self, code: u16, bits: u32) {
+        self.buffer |= u32::from(code) << self.count;
+        self.count += bits;
+
+        while self.count >= 8 {
+            self.byte(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn byte(&mut self, byte: u8) {
+        self.block.push(byte);
+
+        if self.block.len() == 255 {
+            self.flush_block();
+        }
+    }
+
+    fn flush_block(&mut self) {
+        if !self.block.is_empty() {
+            self.out.push(self.block.len() as u8);
+            self.out.append(&mut self.block);
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.byte(self.buffer as u8);
+        }
+
+        self.flush_block();
+        self.out.push(0);
+        self.out
+    }
+}
+
+fn lzw(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    let mut codes = Codes {
+        out: vec![min_code_size],
+        block: Vec::with_capacity(255),
+        buffer: 0,
+        count: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut bits = u32::from(min_code_size) + 1;
+
+    codes.push(clear, bits);
+
+    let mut current = match indices.first() {
+        Some(index) => u16::from(*index),
+        None => {
+            codes.push(end, bits);
+            return codes.finish();
+        }
+    };
+
+    for &index in &indices[1..] {
+        if let Some(&code) = table.get(&(current, index)) {
+            current = code;
+            continue;
+        }
+
+        codes.push(current, bits);
+        bits = widened(bits, next);
+
+        if next < MAX_CODES {
+            table.insert((current, index), next);
+            next += 1;
+        } else {
+            codes.push(clear, bits);
+            table.clear();
+            next = end + 1;
+            bits = u32::from(min_code_size) + 1;
+        }
+
+        current = u16::from(index);
+    }
+
+    codes.push(current, bits);
+    codes.push(end, widened(bits, next));
+    codes.finish()
+}
+
+/// Decoders widen codes once the next one to be added doesn't fit, which
+/// they know after each code they read, the end code included.
+fn widened(bits: u32, next: u16) -> u32 {
+    if u32::from(next) >= 1 << bits && bits < 12 {
+        bits + 1
+    } else {
+        bits
+    }
+}
+
+/// Builds an animated GIF frame by frame.
+pub struct GifEncoder {
+    out: Vec<u8>,
+    palette: Vec<Color>,
+    /// The palette index pixels with little alpha get.
+    transparent: u8,
+    nearest: HashMap<[u8; 3], u8>,
+    /// Delays are whole hundredths, the rounding is carried over.
+    elapsed_ms: u64,
+    written_cs: u64,
+}
+
+impl GifEncoder {
+    /// `palette` is cut to 255 colours, the last index is transparent.
+    pub fn new(width: usize, height: usize, palette: &[Color]) -> Self {
+        let mut palette: Vec<Color> = palette.iter().take(255).copied().collect();
+
+        if palette.is_empty() {
+            palette.push(Color::rgba(0, 0, 0, 255));
+        }
+
+        let transparent = palette.len() as u8;
+        palette.push(Color::TRANSPARENT);
+
+        // Colour tables have a power of two entries.
+        let table_bits = (palette.len() as u32).next_power_of_two().trailing_zeros().max(1);
+
+        let mut out = b"GIF89a".to_vec();
+        out.extend_from_slice(&(width as u16).to_le_bytes());
+        out.extend_from_slice(&(height as u16).to_le_bytes());
+        out.push(0x80 | ((table_bits as u8 - 1) << 4) | (table_bits as u8 - 1));
+        out.push(transparent);
+        out.push(0);
+
+        for i in 0..1 << table_bits {
+            let color = palette.get(i).copied().unwrap_or(Color::TRANSPARENT);
+            out.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+
+        // Loop forever.
+        out.extend_from_slice(b"\x21\xff\x0bNETSCAPE2.0\x03\x01\x00\x00\x00");
+
+        Self {
+            out,
+            palette,
+            transparent,
+            nearest: HashMap::new(),
+            elapsed_ms: 0,
+            written_cs: 0,
+        }
+    }
+
+    fn index(&mut self, pixel: &[u8]) -> u8 {
+        if pixel[3] < 128 {
+            return self.transparent;
+        }
+
+        let palette = &self.palette[..self.transparent as usize];
+
+        *self.nearest.entry([pixel[0], pixel[1], pixel[2]]).or_insert_with(|| {
+            let distance = |color: &Color| {
+                let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+                d(color.r, pixel[0]) + d(color.g, pixel[1]) + d(color.b, pixel[2])
+            };
+
+            (0..palette.len())
+                .min_by_key(|&i| distance(&palette[i]))
+                .unwrap_or(0) as u8
+        })
+    }
+
+    /// Adds `frame`, shown for `delay_ms`.
+    pub fn add(&mut self, frame: &Frame, delay_ms: u32) {
+        self.elapsed_ms += u64::from(delay_ms);
+        let until_cs = (self.elapsed_ms + 5) / 10;
+        let delay_cs = (until_cs - self.written_cs).min(u64::from(u16::MAX)) as u16;
+        self.written_cs = until_cs;
+
+        // Graphic control: cleared to transparent before the next frame.
+        self.out.extend_from_slice(&[0x21, 0xf9, 0x04, 0x09]);
+        self.out.extend_from_slice(&delay_cs.to_le_bytes());
+        self.out.extend_from_slice(&[self.transparent, 0]);
+
+        self.out.push(0x2c);
+        self.out.extend_from_slice(&[0, 0, 0, 0]);
+        self.out.extend_from_slice(&(frame.width as u16).to_le_bytes());
+        self.out.extend_from_slice(&(frame.height as u16).to_le_bytes());
+        self.out.push(0);
+
+        let indices: Vec<u8> = frame
+            .pixels
+            .chunks_exact(4)
+            .map(|pixel| self.index(pixel))
+            .collect();
+        let min_code_size = (self.palette.len() as u32)
+            .next_power_of_two()
+            .trailing_zeros()
+            .max(2) as u8;
+
+        self.out.extend(lzw(&indices, min_code_size));
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.out.push(0x3b);
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use proptest::prelude::*;
+    use weezl::decode::Decoder;
+    use weezl::BitOrder;
+
+    /// What another decoder makes of `lzw`'s output, sub-blocks and all.
+    fn decode(encoded: &[u8]) -> Vec<u8> {
+        let min_code_size = encoded[0];
+        let mut data = Vec::new();
+        let mut at = 1;
+
+        while encoded[at] != 0 {
+            let len = usize::from(encoded[at]);
+            assert!(len <= 255);
+            data.extend_from_slice(&encoded[at + 1..at + 1 + len]);
+            at += 1 + len;
+        }
+
+        assert_eq!(at, encoded.len() - 1, "data after the terminator");
+        Decoder::new(BitOrder::Lsb, min_code_size)
+            .decode(&data)
+            .unwrap()
+    }
+
+    /// Frame delays in hundredths, from each graphic control extension.
+    fn delays(gif: &[u8]) -> Vec<u16> {
+        gif.windows(6)
+            .filter(|w| w[..4] == [0x21, 0xf9, 0x04, 0x09])
+            .map(|w| u16::from_le_bytes([w[4], w[5]]))
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn lzw_round_trips(indices in prop::collection::vec(0..4u8, 0..5000)) {
+            prop_assert_eq!(decode(&lzw(&indices, 2)), indices);
+        }
+
+        #[test]
+        fn lzw_round_trips_every_colour(indices in prop::collection::vec(any::<u8>(), 0..5000)) {
+            prop_assert_eq!(decode(&lzw(&indices, 8)), indices);
+        }
+    }
+
+    #[test]
+    fn lzw_starts_over_once_the_table_is_full() {
+        // Noise fills the table many times over, long runs make long codes.
+        let mut state = 0x1234_5678_u32;
+        let mut indices: Vec<u8> = (0..100_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state % 16) as u8
+            })
+            .collect();
+        indices.extend(std::iter::repeat_n(3, 100_000));
+
+        assert_eq!(decode(&lzw(&indices, 4)), indices);
+        assert_eq!(decode(&lzw(&[], 2)), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn pixels_map_to_the_nearest_colour() {
+        let palette = [
+            Color::rgba(0, 0, 0, 255),
+            Color::rgba(255, 255, 255, 255),
+            Color::rgba(200, 0, 0, 255),
+        ];
+        let mut encoder = GifEncoder::new(1, 1, &palette);
+
+        assert_eq!(encoder.index(&[10, 20, 10, 255]), 0);
+        assert_eq!(encoder.index(&[230, 240, 250, 128]), 1);
+        assert_eq!(encoder.index(&[160, 40, 30, 255]), 2);
+        // Less than half covered is left out.
+        assert_eq!(encoder.index(&[255, 255, 255, 127]), 3);
+        assert_eq!(encoder.transparent, 3);
+    }
+
+    #[test]
+    fn palettes_leave_room_for_transparency() {
+        let many: Vec<Color> = (0..=255).map(|i| Color::rgba(i as u8, 0, 0, 255)).collect();
+        let encoder = GifEncoder::new(1, 1, &many);
+        assert_eq!(encoder.transparent, 255);
+        // A global colour table of 256 entries.
+        assert_eq!(encoder.out[10] & 0x07, 7);
+
+        let encoder = GifEncoder::new(1, 1, &[]);
+        assert_eq!(
+            encoder.palette,
+            vec![Color::rgba(0, 0, 0, 255), Color::TRANSPARENT]
+        );
+    }
+
+    #[test]
+    fn frames_keep_their_indices_and_delays() {
+        let palette = [Color::rgba(0, 0, 0, 255), Color::rgba(255, 255, 255, 255)];
+        let mut encoder = GifEncoder::new(2, 2, &palette);
+        let frame = Frame {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 0, 250, 250, 250, 200,
+            ],
+        };
+
+        for _ in 0..3 {
+            encoder.add(&frame, 33);
+        }
+
+        let gif = encoder.finish();
+        assert_eq!(gif.last(), Some(&0x3b));
+        // Hundredths don't fit 33ms, the rounding carries over.
+        assert_eq!(delays(&gif), vec![3, 4, 3]);
+
+        let image = gif
+            .windows(10)
+            .position(|w| w == [0x2c, 0, 0, 0, 0, 2, 0, 2, 0, 0])
+            .unwrap();
+        let data = &gif[image + 10..];
+        let end = data
+            .windows(2)
+            .position(|w| w[0] == 0 && w[1] == 0x21)
+            .unwrap()
+            + 1;
+        assert_eq!(decode(&data[..end]), vec![0, 1, 2, 1]);
+    }
+}
@@ -0,0 +1,446 @@
+//! Drawing the overlay without a browser, into RGBA frames.
+//!
+//! Keystrokes are grouped the way `index.html` groups them: a new line
+//! starts after a pause or once the current one is long enough, and each
+//! line disappears a while after it started, fading out on the way. Lines
+//! stack up from the bottom left, in the font, size and colour `style.css`
+//! gives them, on a transparent background.
+
+mod font;
+mod gif;
+mod png;
+mod raster;
+
+pub use self::font::{Font, FontError};
+pub use self::gif::GifEncoder;
+pub use self::png::ApngEncoder;
+pub use self::raster::Frame;
+
+use self::font::GlyphCache;
+use crate::common::Timestamp;
+use crate::config::ThemeConfig;
+
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::fmt;
+
+/// Left of the lines, `body`'s default margin.
+const MARGIN_LEFT: f32 = 8.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const TRANSPARENT: Color = Color::rgba(0, 0, 0, 0);
+
+    pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    /// Part of the way from this colour to `other`, `amount` out of 255.
+    fn mix(self, other: Color, amount: u8) -> Self {
+        let mix = |a: u8, b: u8| {
+            ((u32::from(a) * u32::from(255 - amount) + u32::from(b) * u32::from(amount)) / 255) as u8
+        };
+
+        Color::rgba(
+            mix(self.r, other.r),
+            mix(self.g, other.g),
+            mix(self.b, other.b),
+            mix(self.a, other.a),
+        )
+    }
+
+    /// This colour with its alpha multiplied by `opacity` out of 255.
+    fn faded(self, opacity: u8) -> Self {
+        Self {
+            a: (u32::from(self.a) * u32::from(opacity) / 255) as u8,
+            ..self
+        }
+    }
+}
+
+/// `#rgb`, `#rrggbb` or `#rrggbbaa`, as in CSS.
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("{} is not a colour, expected #rrggbb or #rrggbbaa", s);
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        let digits: Vec<u8> = match hex.len() {
+            3 => hex
+                .chars()
+                .map(|c| c.to_digit(16).unwrap_or(0) as u8 * 17)
+                .collect(),
+            6 | 8 => (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0))
+                .collect(),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Color::rgba(
+            digits[0],
+            digits[1],
+            digits[2],
+            digits.get(3).copied().unwrap_or(255),
+        ))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{:02x}{:02x}{:02x}{:02x}",
+            self.r, self.g, self.b, self.a
+        )
+    }
+}
+
+/// Lines on screen, and their opacity out of 255, oldest first.
+pub type Scene = Vec<(String, u8)>;
+
+struct Group {
+    text: String,
+    start: Timestamp,
+}
+
+/// Keystrokes grouped into lines as they arrive.
+pub struct Overlay {
+    theme: ThemeConfig,
+    groups: VecDeque<Group>,
+    last_event: Option<Timestamp>,
+}
+
+impl Overlay {
+    pub fn new(theme: &ThemeConfig) -> Self {
+        Self {
+            theme: theme.clone(),
+            groups: VecDeque::new(),
+            last_event: None,
+        }
+    }
+
+    /// Adds `text` as displayed, typed at `time`.
+    pub fn push(&mut self, text: &str, time: Timestamp) {
+        let paused = match self.last_event {
+            Some(last) => {
+                time.as_micros().saturating_sub(last.as_micros()) > self.theme.group_ms * 1000
+            }
+            None => true,
+        };
+        let full = self
+            .groups
+            .back()
+            .is_none_or(|group| group.text.chars().count() > self.theme.group_chars);
+
+        if paused || full {
+            self.groups.push_back(Group {
+                text: String::new(),
+                start: time,
+            });
+        }
+
+        if let Some(group) = self.groups.back_mut() {
+            group.text.push_str(text);
+        }
+
+        self.last_event = Some(time);
+    }
+
+    /// What is shown at `time`, forgetting lines that are gone by then.
+    pub fn scene(&mut self, time: Timestamp) -> Scene {
+        let lifetime = self.theme.lifetime_ms * 1000;
+        let fade = (self.theme.fade_ms * 1000).min(lifetime);
+
+        let age = |group: &Group| time.as_micros().saturating_sub(group.start.as_micros());
+
+        while self
+            .groups
+            .front()
+            .is_some_and(|group| age(group) >= lifetime)
+        {
+            self.groups.pop_front();
+        }
+
+        self.groups
+            .iter()
+            .filter(|group| group.start <= time)
+            .map(|group| {
+                let left = lifetime - age(group);
+                let opacity = if left < fade {
+                    (left * 255 / fade) as u8
+                } else {
+                    255
+                };
+
+                (group.text.clone(), opacity)
+            })
+            .collect()
+    }
+}
+
+/// Draws scenes into a frame of a fixed size.
+pub struct Renderer {
+    theme: ThemeConfig,
+    glyphs: GlyphCache,
+    frame: Frame,
+}
+
+impl Renderer {
+    /// Uses the theme's font, the bundled font where it has no glyph.
+    pub fn new(theme: &ThemeConfig, width: usize, height: usize) -> Result<Self, FontError> {
+        let mut fonts = Vec::new();
+
+        if let Some(ref path) = theme.font {
+            fonts.push(Font::open(path)?);
+        }
+
+        fonts.push(Font::bundled());
+
+        Ok(Self {
+            theme: theme.clone(),
+            glyphs: GlyphCache::new(fonts, theme.font_size),
+            frame: Frame::new(width, height),
+        })
+    }
+
+    pub fn draw(&mut self, scene: &[(String, u8)]) -> &Frame {
+        self.frame.clear(self.theme.background);
+
+        let (baseline, line_height) = self.glyphs.line_metrics();
+        // `ul` has a margin of 1em below it.
+        let bottom = self.frame.height as f32 - self.theme.font_size;
+        let padding = if self.theme.bubble.is_some() {
+            self.theme.font_size / 4.0
+        } else {
+            0.0
+        };
+
+        for (i, (text, opacity)) in scene.iter().enumerate() {
+            let top = bottom - (scene.len() - i) as f32 * line_height;
+
+            if top + line_height < 0.0 {
+                continue;
+            }
+
+            if let Some(bubble) = self.theme.bubble {
+                let width = self.text_width(text) + 2.0 * padding;
+                let inset = line_height / 16.0;
+
+                self.frame.rounded_rect(
+                    MARGIN_LEFT,
+                    top + inset,
+                    width,
+                    line_height - 2.0 * inset,
+                    padding,
+                    bubble.faded(*opacity),
+                );
+            }
+
+            let color = self.theme.color.faded(*opacity);
+            self.draw_text(text, MARGIN_LEFT + padding, top + baseline, color);
+        }
+
+        &self.frame
+    }
+
+    fn text_width(&mut self, text: &str) -> f32 {
+        text.chars()
+            .filter(|c| !is_combining(*c))
+            .map(|c| self.glyphs.get(c).advance)
+            .sum()
+    }
+
+    fn draw_text(&mut self, text: &str, x: f32, baseline: f32, color: Color) {
+        let mut pen = x;
+        let mut previous = x;
+
+        for c in text.chars() {
+            // Marks like the underline of marked input go on the character before.
+            let origin = if is_combining(c) { previous } else { pen };
+            let glyph = self.glyphs.get(c);
+            let left = origin.round() as i32 + glyph.left;
+            let top = baseline.round() as i32 + glyph.top;
+
+            for y in 0..glyph.height {
+                for x in 0..glyph.width {
+                    let coverage = glyph.coverage[y * glyph.width + x];
+
+                    if coverage > 0 {
+                        self.frame
+                            .blend(left + x as i32, top + y as i32, color, coverage);
+                    }
+                }
+            }
+
+            if !is_combining(c) {
+                previous = pen;
+                pen += glyph.advance;
+            }
+        }
+    }
+}
+
+fn is_combining(c: char) -> bool {
+    matches!(c, '\u{300}'..='\u{36f}' | '\u{20d0}'..='\u{20ff}')
+}
+
+/// The frame as a PNG image with alpha.
+pub fn encode_png(frame: &Frame) -> Vec<u8> {
+    png::encode(frame)
+}
+
+/// Colours a theme draws with, for formats with few colours: the text,
+/// bubble and background colours, and steps between each two of them for
+/// anti-aliased edges and fading.
+pub fn palette(theme: &ThemeConfig) -> Vec<Color> {
+    const STEPS: u8 = 24;
+
+    let opaque = |color: Color| Color { a: 255, ..color };
+    let mut colors = vec![opaque(theme.color)];
+
+    if let Some(bubble) = theme.bubble {
+        colors.push(opaque(bubble));
+    }
+
+    // Transparent backgrounds have no colour to blend with.
+    if theme.background.a > 0 {
+        colors.push(opaque(theme.background));
+    }
+
+    let mut palette = Vec::new();
+
+    for (i, from) in colors.iter().enumerate() {
+        palette.push(*from);
+
+        for to in &colors[i + 1..] {
+            for step in 1..STEPS {
+                palette.push(from.mix(*to, (u32::from(step) * 255 / u32::from(STEPS)) as u8));
+            }
+        }
+    }
+
+    palette.dedup();
+    palette
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+
+    /// Renders `scene` and compares it with `testdata/render/<name>.png`.
+    /// `KEYDISP_BLESS=1` writes what was rendered instead, after a change
+    /// to drawing was checked by eye.
+    fn golden(name: &str, theme: &ThemeConfig, width: usize, height: usize, scene: &[(&str, u8)]) {
+        let scene: Scene = scene
+            .iter()
+            .map(|(text, opacity)| (text.to_string(), *opacity))
+            .collect();
+        let mut renderer = Renderer::new(theme, width, height).unwrap();
+        let frame = renderer.draw(&scene);
+
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "testdata", "render", name]
+            .iter()
+            .collect::<PathBuf>()
+            .with_extension("png");
+
+        if env::var_os("KEYDISP_BLESS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut encoder =
+                ::png::Encoder::new(File::create(&path).unwrap(), width as u32, height as u32);
+            encoder.set_color(::png::ColorType::Rgba);
+            encoder.set_depth(::png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .unwrap()
+                .write_image_data(&frame.pixels)
+                .unwrap();
+            return;
+        }
+
+        let mut reader = ::png::Decoder::new(File::open(&path).unwrap())
+            .read_info()
+            .unwrap();
+        let mut expected = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut expected).unwrap();
+
+        assert_eq!(
+            (info.width as usize, info.height as usize),
+            (width, height),
+            "{}",
+            name
+        );
+        assert_eq!(info.color_type, ::png::ColorType::Rgba, "{}", name);
+
+        let differing = frame
+            .pixels
+            .chunks_exact(4)
+            .zip(expected.chunks_exact(4))
+            .filter(|(drawn, expected)| drawn != expected)
+            .count();
+        assert_eq!(
+            differing,
+            0,
+            "{} pixels differ from {}",
+            differing,
+            path.display()
+        );
+    }
+
+    #[test]
+    fn golden_lines() {
+        golden(
+            "lines",
+            &ThemeConfig::default(),
+            400,
+            200,
+            &[("hello", 96), ("⌃C ⇧→ é", 255)],
+        );
+    }
+
+    #[test]
+    fn golden_bubbles() {
+        let theme = ThemeConfig {
+            font_size: 32.0,
+            color: Color::rgba(255, 255, 255, 255),
+            background: Color::rgba(0, 255, 0, 255),
+            bubble: Some(Color::rgba(32, 32, 48, 200)),
+            ..ThemeConfig::default()
+        };
+
+        // Marked input is underlined with a combining mark.
+        golden(
+            "bubbles",
+            &theme,
+            320,
+            160,
+            &[("⌘Tab", 255), ("a\u{332}b\u{332}", 160)],
+        );
+    }
+
+    #[test]
+    fn golden_overflow() {
+        let lines: Vec<String> = (1..=6).map(|i| format!("line {}", i)).collect();
+        let scene: Vec<(&str, u8)> = lines.iter().map(|line| (line.as_str(), 255)).collect();
+
+        // Older lines go off the top.
+        golden("overflow", &ThemeConfig::default(), 240, 120, &scene);
+    }
+}
@@ -0,0 +1,480 @@
+//! PNG encoding of RGBA frames.
+//!
+//! Rows are filtered with `Up` and compressed with LZ77 and the fixed
+//! Huffman codes of deflate. Overlay frames are mostly empty and repeat
+//! from row to row, which this handles well without dynamic codes.
+
+use super::raster::Frame;
+
+use std::convert::TryFrom;
+use std::sync::LazyLock;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Longest match deflate can express, and the window it can look back in.
+const MAX_MATCH: usize = 258;
+const MIN_MATCH: usize = 3;
+const WINDOW: usize = 32 * 1024;
+const HASH_BITS: u32 = 15;
+/// Earlier positions tried per match, more compresses better but slower.
+const MAX_CHAIN: usize = 32;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+static CRC_TABLE: LazyLock<[u32; 256]> = LazyLock::new(|| {
+    let mut table = [0; 256];
+
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+
+        *entry = c;
+    }
+
+    table
+});
+
+pub fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc = CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    // Sums stay below overflow for this many bytes between reductions.
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
+
+/// Deflate's bit stream, least significant bit first.
+struct Bits {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl Bits {
+    fn push(&mut self, value: u32, bits: u32) {
+        self.buffer |= u64::from(value) << self.count;
+        self.count += bits;
+
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes go most significant bit first.
+    fn push_code(&mut self, code: u32, bits: u32) {
+        self.push(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn literal(&mut self, value: u16) {
+        match value {
+            0..=143 => self.push_code(0x30 + u32::from(value), 8),
+            144..=255 => self.push_code(0x190 + u32::from(value) - 144, 9),
+            256..=279 => self.push_code(u32::from(value) - 256, 7),
+            _ => self.push_code(0xc0 + u32::from(value) - 280, 8),
+        }
+    }
+
+    fn copy(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASES
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .unwrap_or(0);
+        self.literal(257 + code as u16);
+        self.push(
+            (length - usize::from(LENGTH_BASES[code])) as u32,
+            u32::from(LENGTH_EXTRA[code]),
+        );
+
+        let code = DISTANCE_BASES
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .unwrap_or(0);
+        self.push_code(code as u32, 5);
+        self.push(
+            (distance - usize::from(DISTANCE_BASES[code])) as u32,
+            u32::from(DISTANCE_EXTRA[code]),
+        );
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+
+        self.out
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from(data[0]) << 16 | u32::from(data[1]) << 8 | u32::from(data[2]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Makes the position `at` findable by later matches.
+fn insert(data: &[u8], head: &mut [usize], previous: &mut [usize], at: usize) {
+    if at + MIN_MATCH <= data.len() {
+        let h = hash(&data[at..]);
+        previous[at % WINDOW] = head[h];
+        head[h] = at;
+    }
+}
+
+/// A zlib stream of `data` in a single fixed Huffman block.
+pub fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits {
+        out: vec![0x78, 0x01],
+        buffer: 0,
+        count: 0,
+    };
+
+    // Final block, fixed codes.
+    bits.push(0b011, 3);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; WINDOW];
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut best = (0, 0);
+
+        if i + MIN_MATCH <= data.len() {
+            let limit = (data.len() - i).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[i..])];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && i - candidate < WINDOW && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[i..i + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+
+                if length > best.0 {
+                    best = (length, i - candidate);
+
+                    if length == limit {
+                        break;
+                    }
+                }
+
+                let next = previous[candidate % WINDOW];
+
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            bits.copy(best.0, best.1);
+
+            for at in i..i + best.0 {
+                insert(data, &mut head, &mut previous, at);
+            }
+
+            i += best.0;
+        } else {
+            bits.literal(u16::from(data[i]));
+            insert(data, &mut head, &mut previous, i);
+            i += 1;
+        }
+    }
+
+    bits.literal(256);
+
+    let mut out = bits.finish();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn push_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc32(&[kind, data]).to_be_bytes());
+}
+
+/// The `IHDR` chunk data for 8 bit RGBA.
+pub fn header(width: usize, height: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    header
+}
+
+/// Compressed image data of `frame`, each row filtered as the difference
+/// to the one above.
+pub fn image_data(frame: &Frame) -> Vec<u8> {
+    let stride = frame.width * 4;
+    let mut filtered = Vec::with_capacity((stride + 1) * frame.height);
+
+    for y in 0..frame.height {
+        let row = &frame.pixels[y * stride..(y + 1) * stride];
+        filtered.push(2);
+
+        if y == 0 {
+            filtered.extend_from_slice(row);
+        } else {
+            let above = &frame.pixels[(y - 1) * stride..y * stride];
+            filtered.extend(row.iter().zip(above).map(|(a, b)| a.wrapping_sub(*b)));
+        }
+    }
+
+    zlib(&filtered)
+}
+
+pub fn encode(frame: &Frame) -> Vec<u8> {
+    let mut out = SIGNATURE.to_vec();
+
+    push_chunk(&mut out, b"IHDR", &header(frame.width, frame.height));
+    push_chunk(&mut out, b"IDAT", &image_data(frame));
+    push_chunk(&mut out, b"IEND", &[]);
+
+    out
+}
+
+/// Builds an animated PNG frame by frame. The first frame is also the
+/// still image viewers without animation support show.
+pub struct ApngEncoder {
+    width: usize,
+    height: usize,
+    chunks: Vec<u8>,
+    frames: u32,
+    sequence: u32,
+}
+
+impl ApngEncoder {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            chunks: Vec::new(),
+            frames: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Adds `frame`, shown for `delay_ms`.
+    pub fn add(&mut self, frame: &Frame, delay_ms: u32) {
+        let mut control = Vec::with_capacity(26);
+        control.extend_from_slice(&self.sequence.to_be_bytes());
+        control.extend_from_slice(&(frame.width as u32).to_be_bytes());
+        control.extend_from_slice(&(frame.height as u32).to_be_bytes());
+        control.extend_from_slice(&[0; 8]);
+        // Thousandths of a second, hundredths for delays too long for those.
+        let (delay, unit) = match u16::try_from(delay_ms) {
+            Ok(delay) => (delay, 1000u16),
+            Err(_) => ((delay_ms / 10).min(u32::from(u16::MAX)) as u16, 100),
+        };
+        control.extend_from_slice(&delay.to_be_bytes());
+        control.extend_from_slice(&unit.to_be_bytes());
+        // Left as is after, replacing what was there before.
+        control.extend_from_slice(&[0, 0]);
+
+        push_chunk(&mut self.chunks, b"fcTL", &control);
+        self.sequence += 1;
+
+        let data = image_data(frame);
+
+        if self.frames == 0 {
+            push_chunk(&mut self.chunks, b"IDAT", &data);
+        } else {
+            let mut frame_data = self.sequence.to_be_bytes().to_vec();
+            frame_data.extend_from_slice(&data);
+            push_chunk(&mut self.chunks, b"fdAT", &frame_data);
+            self.sequence += 1;
+        }
+
+        self.frames += 1;
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+
+        push_chunk(&mut out, b"IHDR", &header(self.width, self.height));
+
+        // Frame count, then plays, 0 repeating forever.
+        let mut animation = self.frames.to_be_bytes().to_vec();
+        animation.extend_from_slice(&0u32.to_be_bytes());
+        push_chunk(&mut out, b"acTL", &animation);
+
+        out.extend_from_slice(&self.chunks);
+        push_chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame's delay as a fraction of a second.
+    type Delay = Option<(u16, u16)>;
+
+    /// Pixels as another decoder sees them, with the delay of each frame.
+    fn decode(png: &[u8]) -> Vec<(Vec<u8>, Delay)> {
+        let mut reader = png::Decoder::new(png).read_info().unwrap();
+        let frames = reader
+            .info()
+            .animation_control
+            .map_or(1, |control| control.num_frames);
+        let mut decoded = Vec::new();
+
+        for _ in 0..frames {
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut pixels).unwrap();
+            assert_eq!(
+                (info.color_type, info.bit_depth),
+                (png::ColorType::Rgba, png::BitDepth::Eight)
+            );
+            pixels.truncate(info.buffer_size());
+
+            let delay = reader
+                .info()
+                .frame_control
+                .map(|control| (control.delay_num, control.delay_den));
+            decoded.push((pixels, delay));
+        }
+
+        decoded
+    }
+
+    /// Deterministic noise, which leaves little for LZ77 to find.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn frame(width: usize, height: usize, pixels: Vec<u8>) -> Frame {
+        Frame {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    #[test]
+    fn checksums_match_their_check_values() {
+        assert_eq!(crc32(&[b"123456789"]), 0xcbf4_3926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // Long enough for the sums to be reduced along the way.
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    #[test]
+    fn frames_survive_a_round_trip() {
+        let frames = [
+            // One long run, in copies as long as deflate allows.
+            frame(300, 200, vec![0; 300 * 200 * 4]),
+            frame(64, 64, noise(64 * 64 * 4)),
+            // Repeats at every distance up to the window.
+            frame(
+                256,
+                160,
+                (0..256 * 160 * 4)
+                    .map(|i: usize| (i % 1021 + i / 4099) as u8)
+                    .collect(),
+            ),
+            frame(1, 1, vec![1, 2, 3, 4]),
+        ];
+
+        for frame in &frames {
+            assert_eq!(decode(&encode(frame)), vec![(frame.pixels.clone(), None)]);
+        }
+    }
+
+    #[test]
+    fn matches_reach_back_across_the_window() {
+        // The same block again after nearly a window of noise, and again
+        // after more than a window, where it can't be referred to any more.
+        let block = noise(1000);
+        let mut data = block.clone();
+        data.extend(noise(WINDOW - 2000).iter().map(|b| b ^ 0x55));
+        data.extend_from_slice(&block);
+        data.extend(noise(WINDOW).iter().map(|b| b ^ 0xaa));
+        data.extend_from_slice(&block);
+
+        let len = data.len();
+        let frame = frame(len / 4, 1, data[..len / 4 * 4].to_vec());
+        assert_eq!(decode(&encode(&frame))[0].0, frame.pixels);
+    }
+
+    #[test]
+    fn compresses_empty_frames_well() {
+        let frame = frame(1920, 1080, vec![0; 1920 * 1080 * 4]);
+        assert!(encode(&frame).len() < frame.pixels.len() / 100);
+    }
+
+    #[test]
+    fn animations_have_every_frame_and_delay() {
+        let first = frame(4, 2, noise(32));
+        let second = frame(4, 2, vec![0x80; 32]);
+        let mut encoder = ApngEncoder::new(4, 2);
+
+        encoder.add(&first, 40);
+        encoder.add(&second, 70_000);
+
+        assert_eq!(
+            decode(&encoder.finish()),
+            vec![
+                (first.pixels, Some((40, 1000))),
+                // Too long for thousandths, in hundredths instead.
+                (second.pixels, Some((7000, 100))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Coverage rasterisation and compositing into RGBA frames.
+//!
+//! Outlines are drawn by accumulating the signed area each edge covers in
+//! every pixel, summing along rows gives how much of each pixel is inside.
+//! This is exact for straight edges, curves are flattened first.
+
+use super::Color;
+
+/// Accumulates edges of one outline.
+pub struct Rasterizer {
+    width: usize,
+    height: usize,
+    /// Row after row, so what spills past the end of one row is carried
+    /// into the next when summing. Callers keep edges inside the bounds.
+    area: Vec<f32>,
+}
+
+impl Rasterizer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            area: vec![0.0; width * height + 1],
+        }
+    }
+
+    pub fn line(&mut self, from: (f32, f32), to: (f32, f32)) {
+        if (from.1 - to.1).abs() <= f32::EPSILON {
+            return;
+        }
+
+        let (direction, (x0, y0), (x1, y1)) = if from.1 < to.1 {
+            (1.0, from, to)
+        } else {
+            (-1.0, to, from)
+        };
+
+        let dxdy = (x1 - x0) / (y1 - y0);
+        let mut x = x0;
+
+        if y0 < 0.0 {
+            x -= y0 * dxdy;
+        }
+
+        let first_row = y0.max(0.0) as usize;
+        let end_row = (y1.ceil().max(0.0) as usize).min(self.height);
+
+        for row in first_row..end_row {
+            let line = row * self.width;
+            let dy = (row as f32 + 1.0).min(y1) - (row as f32).max(y0);
+            let next_x = x + dxdy * dy;
+            let d = dy * direction;
+
+            let (left, right) = if x < next_x { (x, next_x) } else { (next_x, x) };
+
+            let left_floor = left.floor();
+            let left_cell = left_floor as usize;
+            let right_ceil = right.ceil();
+            let right_cell = right_ceil as usize;
+
+            if right_cell <= left_cell + 1 {
+                // Within one pixel, the covered area splits at the midpoint.
+                let middle = 0.5 * (left + right) - left_floor;
+                self.area[line + left_cell] += d - d * middle;
+                self.area[line + left_cell + 1] += d * middle;
+            } else {
+                let slope = (right - left).recip();
+                let left_fraction = left - left_floor;
+                let first = 0.5 * slope * (1.0 - left_fraction) * (1.0 - left_fraction);
+                let right_fraction = right - right_ceil + 1.0;
+                let last = 0.5 * slope * right_fraction * right_fraction;
+
+                self.area[line + left_cell] += d * first;
+
+                if right_cell == left_cell + 2 {
+                    self.area[line + left_cell + 1] += d * (1.0 - first - last);
+                } else {
+                    let second = slope * (1.5 - left_fraction);
+                    self.area[line + left_cell + 1] += d * (second - first);
+
+                    for cell in left_cell + 2..right_cell - 1 {
+                        self.area[line + cell] += d * slope;
+                    }
+
+                    let before_last = second + (right_cell - left_cell - 3) as f32 * slope;
+                    self.area[line + right_cell - 1] += d * (1.0 - before_last - last);
+                }
+
+                self.area[line + right_cell] += d * last;
+            }
+
+            x = next_x;
+        }
+    }
+
+    /// A quadratic Bézier curve, flattened into enough lines to not be
+    /// told apart from the curve.
+    pub fn quad(&mut self, from: (f32, f32), control: (f32, f32), to: (f32, f32)) {
+        let dx = from.0 - 2.0 * control.0 + to.0;
+        let dy = from.1 - 2.0 * control.1 + to.1;
+        let deviation = dx * dx + dy * dy;
+
+        if deviation < 0.333 {
+            self.line(from, to);
+            return;
+        }
+
+        let segments = 1 + (3.0 * deviation).sqrt().sqrt().floor() as usize;
+        let mut previous = from;
+
+        for i in 1..=segments {
+            let t = i as f32 / segments as f32;
+            let u = 1.0 - t;
+            let point = (
+                u * u * from.0 + 2.0 * u * t * control.0 + t * t * to.0,
+                u * u * from.1 + 2.0 * u * t * control.1 + t * t * to.1,
+            );
+
+            self.line(previous, point);
+            previous = point;
+        }
+    }
+
+    /// Coverage of each pixel, row by row.
+    pub fn coverage(&self) -> Vec<u8> {
+        let mut sum = 0.0;
+
+        self.area[..self.width * self.height]
+            .iter()
+            .map(|area| {
+                sum += area;
+                (sum.abs().min(1.0) * 255.0 + 0.5) as u8
+            })
+            .collect()
+    }
+}
+
+/// An image with straight, not premultiplied, alpha.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// RGBA, row by row from the top.
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    pub fn clear(&mut self, color: Color) {
+        for pixel in self.pixels.chunks_exact_mut(4) {
+            pixel.copy_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+    }
+
+    /// Draws `color` over the pixel at `x`, `y` with `coverage` out of 255.
+    pub fn blend(&mut self, x: i32, y: i32, color: Color, coverage: u8) {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return;
+        }
+
+        let alpha = u32::from(color.a) * u32::from(coverage) / 255;
+
+        if alpha == 0 {
+            return;
+        }
+
+        let i = (y as usize * self.width + x as usize) * 4;
+        let pixel = &mut self.pixels[i..i + 4];
+        let below = u32::from(pixel[3]) * (255 - alpha) / 255;
+        let out = alpha + below;
+
+        for (channel, source) in pixel[..3].iter_mut().zip(&[color.r, color.g, color.b]) {
+            let blended = u32::from(*source) * alpha + u32::from(*channel) * below;
+            *channel = ((blended + out / 2) / out) as u8;
+        }
+
+        pixel[3] = out as u8;
+    }
+
+    /// Fills a rectangle with rounded corners, anti-aliased along its edges.
+    pub fn rounded_rect(
+        &mut self,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        radius: f32,
+        color: Color,
+    ) {
+        let radius = radius.min(width / 2.0).min(height / 2.0).max(0.0);
+
+        for py in y.floor() as i32..(y + height).ceil() as i32 {
+            for px in x.floor() as i32..(x + width).ceil() as i32 {
+                // Distance outside the shape, from the pixel centre.
+                let cx = px as f32 + 0.5;
+                let cy = py as f32 + 0.5;
+                let qx = (cx - (x + width / 2.0)).abs() - (width / 2.0 - radius);
+                let qy = (cy - (y + height / 2.0)).abs() - (height / 2.0 - radius);
+                let outside = qx.max(0.0).hypot(qy.max(0.0)) + qx.max(qy).min(0.0) - radius;
+                let coverage = (0.5 - outside).clamp(0.0, 1.0);
+
+                self.blend(px, py, color, (coverage * 255.0 + 0.5) as u8);
+            }
+        }
+    }
+}
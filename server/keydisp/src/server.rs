@@ -0,0 +1,813 @@
+//! Accepting and serving websocket clients.
+//!
+//! Every connection gets its own task. The handshake runs under a deadline,
+//! so a port scanner, a stalled client or a browser opening the port as a web
+//! page is answered (or dropped) and logged without affecting anyone else.
+//! Connected clients subscribe to the event broadcast and are pinged, clients
+//! that stop answering or can't keep up with writes are disconnected. Clients
+//! can limit themselves to some devices by repeating the `device` query
+//! parameter, like `?device=046d:c52b&device=Macro%20Pad`. `GET /metrics`
+//! is answered with the counters of `metrics`, under the same access checks,
+//! and can also be served on an address of its own with `run_metrics`.
+
+use crate::auth;
+use crate::config::AuthConfig;
+use crate::metrics;
+use crate::output::{Format, Tagged};
+use crate::state::SharedState;
+use crate::tls::{ServerStream, Tls};
+
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::time::{interval, timeout};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+/// Time a client gets to complete the handshake, and to accept each write after.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+/// Clients that haven't sent anything, pongs included, for this long are dropped.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const MAX_PENDING_HANDSHAKES: usize = 16;
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+type ClientSink = SplitSink<WebSocketStream<ServerStream>, Message>;
+
+#[derive(Debug)]
+enum AcceptError {
+    Io(io::Error),
+    /// The request was answered with a plain HTTP response instead of an upgrade.
+    Http(u16),
+    /// A request for metrics was answered.
+    Metrics,
+}
+
+impl fmt::Display for AcceptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcceptError::Io(e) => write!(f, "{}", e),
+            AcceptError::Http(status) => write!(f, "answered with {} {}", status, reason(*status)),
+            AcceptError::Metrics => write!(f, "answered with metrics"),
+        }
+    }
+}
+
+impl From<io::Error> for AcceptError {
+    fn from(e: io::Error) -> Self {
+        AcceptError::Io(e)
+    }
+}
+
+/// The parts of an HTTP request the server looks at.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, Vec<u8>)>,
+}
+
+impl Request {
+    pub fn parse(buf: &[u8]) -> Option<(Request, usize)> {
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+
+        let len = match parsed.parse(buf) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => return None,
+        };
+
+        let request = Request {
+            method: parsed.method?.to_string(),
+            path: parsed.path?.to_string(),
+            headers: parsed
+                .headers
+                .iter()
+                .map(|header| (header.name.to_string(), header.value.to_vec()))
+                .collect(),
+        };
+
+        Some((request, len))
+    }
+
+    /// Values of every header called `name`.
+    pub fn headers<'r>(&'r self, name: &'r str) -> impl Iterator<Item = &'r [u8]> + 'r {
+        self.headers
+            .iter()
+            .filter(move |(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| &value[..])
+    }
+
+    /// The first query parameter called `name`, still percent-encoded.
+    pub fn query(&self, name: &str) -> Option<&str> {
+        self.query_pairs()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Values of every query parameter called `name`, still percent-encoded.
+    pub fn queries<'r>(&'r self, name: &'r str) -> impl Iterator<Item = &'r str> + 'r {
+        self.query_pairs()
+            .filter(move |(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn query_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        let query = self.path.split_once('?').map_or("", |(_, query)| query);
+
+        query.split('&').filter_map(|pair| pair.split_once('='))
+    }
+
+    /// The path without the query.
+    fn route(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    /// Entries of `Sec-WebSocket-Protocol`, which may be split over several headers.
+    pub fn subprotocols<'r>(&'r self) -> impl Iterator<Item = &'r str> + 'r {
+        self.headers("Sec-WebSocket-Protocol")
+            .filter_map(|value| std::str::from_utf8(value).ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim())
+    }
+
+    fn is_upgrade(&self) -> bool {
+        self.headers("Upgrade")
+            .any(|value| String::from_utf8_lossy(value).to_ascii_lowercase().contains("websocket"))
+    }
+}
+
+/// A connected client's place in the `max_clients` budget, released on drop.
+struct ClientSlot(SharedState);
+
+impl ClientSlot {
+    fn acquire(state: &SharedState) -> Option<ClientSlot> {
+        let mut st = state.lock().expect("state lock to not be poisoned.");
+
+        if st.stats.clients >= st.config.max_clients {
+            return None;
+        }
+
+        st.stats.clients += 1;
+        Some(ClientSlot(state.clone()))
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.0.lock().expect("state lock to not be poisoned.").stats.clients -= 1;
+    }
+}
+
+struct Client {
+    websocket: WebSocketStream<ServerStream>,
+    format: Format,
+    /// Device patterns subscribed to, empty for every device.
+    devices: Vec<String>,
+    _slot: ClientSlot,
+}
+
+pub async fn run(
+    listener: TcpListener,
+    tls: Option<Tls>,
+    events: broadcast::Sender<Tagged>,
+    state: SharedState,
+) {
+    let tls = Arc::new(tls);
+    let pending = Arc::new(Semaphore::new(MAX_PENDING_HANDSHAKES));
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let permit = match pending.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                eprintln!("{}: dropped, too many pending handshakes", peer);
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let events = events.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let handshake = timeout(IO_TIMEOUT, accept(stream, (*tls).as_ref(), &state)).await;
+            drop(permit);
+
+            match handshake {
+                Ok(Ok(client)) => {
+                    eprintln!("{}: connected", peer);
+                    serve(client, events.subscribe(), peer).await;
+                    eprintln!("{}: disconnected", peer);
+                }
+                // Scraped every few seconds, not worth a line each time.
+                Ok(Err(AcceptError::Metrics)) => (),
+                Ok(Err(e)) => eprintln!("{}: {}", peer, e),
+                Err(_) => eprintln!("{}: handshake timed out", peer),
+            }
+        });
+    }
+}
+
+/// Serves nothing but `GET /metrics`, for `metrics_addr`.
+pub async fn run_metrics(listener: TcpListener, tls: Option<Tls>, state: SharedState) {
+    let tls = Arc::new(tls);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Could not accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let tls = tls.clone();
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            match timeout(IO_TIMEOUT, scrape(stream, (*tls).as_ref(), &state)).await {
+                Ok(AcceptError::Metrics) => (),
+                Ok(e) => eprintln!("{}: {}", peer, e),
+                Err(_) => eprintln!("{}: request timed out", peer),
+            }
+        });
+    }
+}
+
+/// Answers a single request on the metrics address, saying how.
+async fn scrape(stream: TcpStream, tls: Option<&Tls>, state: &SharedState) -> AcceptError {
+    let mut stream = match Tls::wrap(tls, stream).await {
+        Ok(stream) => stream,
+        Err(e) => return AcceptError::Io(e),
+    };
+
+    let auth = state
+        .lock()
+        .expect("state lock to not be poisoned.")
+        .config
+        .auth
+        .clone();
+
+    let buf = match read_request(&mut stream).await {
+        Ok(buf) => buf,
+        Err(AcceptError::Http(status)) => return reject(&mut stream, status).await,
+        Err(e) => return e,
+    };
+
+    match Request::parse(&buf) {
+        Some((request, _)) if request.method == "GET" && request.route() == "/metrics" => {
+            answer_metrics(&mut stream, &auth, &request, state).await
+        }
+        Some(_) => reject(&mut stream, 404).await,
+        None => reject(&mut stream, 400).await,
+    }
+}
+
+/// Answers with every metric, under the same access checks as clients.
+async fn answer_metrics(
+    stream: &mut ServerStream,
+    auth: &AuthConfig,
+    request: &Request,
+    state: &SharedState,
+) -> AcceptError {
+    if let Err(status) = auth::check(auth, request) {
+        return reject(stream, status).await;
+    }
+
+    match respond(stream, 200, METRICS_CONTENT_TYPE, &metrics::render(state)).await {
+        Ok(()) => AcceptError::Metrics,
+        Err(e) => AcceptError::Io(e),
+    }
+}
+
+async fn accept(
+    stream: TcpStream,
+    tls: Option<&Tls>,
+    state: &SharedState,
+) -> Result<Client, AcceptError> {
+    stream.set_nodelay(true)?;
+
+    let mut stream = Tls::wrap(tls, stream).await?;
+
+    let auth = state
+        .lock()
+        .expect("state lock to not be poisoned.")
+        .config
+        .auth
+        .clone();
+
+    let buf = match read_request(&mut stream).await {
+        Ok(buf) => buf,
+        Err(AcceptError::Http(status)) => return Err(reject(&mut stream, status).await),
+        Err(e) => return Err(e),
+    };
+
+    let (request, len) = match Request::parse(&buf) {
+        Some(parsed) => parsed,
+        None => return Err(reject(&mut stream, 400).await),
+    };
+
+    if request.method == "GET" && request.route() == "/metrics" {
+        return Err(answer_metrics(&mut stream, &auth, &request, state).await);
+    }
+
+    if request.method != "GET" || !request.is_upgrade() {
+        return Err(reject(&mut stream, 426).await);
+    }
+
+    let token_protocol = match auth::check(&auth, &request) {
+        Ok(protocol) => protocol,
+        Err(status) => return Err(reject(&mut stream, status).await),
+    };
+
+    let key = match request.header("Sec-WebSocket-Key") {
+        Some(key) => derive_accept_key(key),
+        None => return Err(reject(&mut stream, 400).await),
+    };
+
+    let json_protocol = request
+        .subprotocols()
+        .find(|protocol| *protocol == Format::JSON_PROTOCOL)
+        .map(|protocol| protocol.to_string());
+
+    let format = if json_protocol.is_some() || request.query("format") == Some("json") {
+        Format::Json
+    } else {
+        Format::Text
+    };
+
+    let devices = request.queries("device").map(percent_decode).collect();
+
+    let slot = match ClientSlot::acquire(state) {
+        Some(slot) => slot,
+        None => return Err(reject(&mut stream, 503).await),
+    };
+
+    let mut reply = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: {}\r\n",
+        key
+    );
+
+    if let Some(protocol) = json_protocol.or(token_protocol) {
+        reply.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+
+    reply.push_str("\r\n");
+
+    stream.write_all(reply.as_bytes()).await?;
+    stream.flush().await?;
+
+    let websocket =
+        WebSocketStream::from_partially_read(stream, buf[len..].to_vec(), Role::Server, None)
+            .await;
+
+    Ok(Client {
+        websocket,
+        format,
+        devices,
+        _slot: slot,
+    })
+}
+
+async fn serve(client: Client, mut events: broadcast::Receiver<Tagged>, peer: SocketAddr) {
+    let (mut sink, mut incoming) = client.websocket.split();
+    let mut keepalive = interval(PING_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(tagged) => {
+                    let message = match client.format.encode(&tagged) {
+                        Some(text) if tagged.is_for(&client.devices) => Message::Text(text),
+                        _ => continue,
+                    };
+
+                    if let Err(e) = send(&mut sink, message).await {
+                        eprintln!("{}: {}", peer, e);
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics::DROPPED_EVENTS.add(&["websocket"], skipped);
+                    eprintln!("{}: too slow, skipped {} events", peer, skipped)
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = incoming.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => last_seen = Instant::now(),
+            },
+            _ = keepalive.tick() => {
+                if last_seen.elapsed() > CLIENT_TIMEOUT {
+                    eprintln!("{}: stopped answering pings", peer);
+                    break;
+                }
+
+                if let Err(e) = send(&mut sink, Message::Ping(Vec::new())).await {
+                    eprintln!("{}: {}", peer, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+async fn send(sink: &mut ClientSink, message: Message) -> Result<(), String> {
+    let result = match timeout(IO_TIMEOUT, sink.send(message)).await {
+        Ok(Ok(())) => return Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("write timed out".to_string()),
+    };
+
+    metrics::WRITE_ERRORS.inc(&["websocket"]);
+    result
+}
+
+/// Decodes `%XX` escapes and `+` as space, malformed escapes are kept as is.
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads up to and including the blank line ending the request headers.
+async fn read_request(stream: &mut ServerStream) -> Result<Vec<u8>, AcceptError> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        let n = stream.read(&mut chunk).await?;
+
+        if n == 0 {
+            return Err(AcceptError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during handshake",
+            )));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+
+        if buf.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Ok(buf);
+        }
+
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(AcceptError::Http(431));
+        }
+    }
+}
+
+/// Answers with an error instead of upgrading.
+async fn reject(stream: &mut ServerStream, status: u16) -> AcceptError {
+    let body = match status {
+        426 => "keydisp is running, add index.html as a browser source to see keystrokes.\n",
+        _ => "",
+    };
+
+    match respond(stream, status, "text/plain; charset=utf-8", body).await {
+        Ok(()) => AcceptError::Http(status),
+        Err(e) => AcceptError::Io(e),
+    }
+}
+
+/// Answers with a plain HTTP response, the connection is closed once dropped.
+async fn respond(
+    stream: &mut ServerStream,
+    status: u16,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        content_type,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    let _ = stream.shutdown().await;
+
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{DeviceId, Timestamp};
+    use crate::config::Config;
+    use crate::output::Output;
+    use crate::state::State;
+
+    /// A server on a free local port.
+    async fn start(config: Config) -> (SocketAddr, broadcast::Sender<Tagged>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, _) = broadcast::channel(16);
+        let state = State::new(config).shared();
+
+        tokio::spawn(run(listener, None, events.clone(), state));
+        (addr, events)
+    }
+
+    /// Sends `request` and reads until the server closes the connection.
+    async fn exchange(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response).await;
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    fn status(response: &str) -> &str {
+        response.lines().next().unwrap_or_default()
+    }
+
+    async fn connects(addr: SocketAddr, events: &broadcast::Sender<Tagged>) {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let url = format!("ws://{}/", addr);
+        let (mut websocket, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+        // Subscribed once the handshake is answered, but not before.
+        while events.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        events
+            .send(Tagged {
+                output: Output::Char { text: 'k' },
+                device: None,
+                injected: false,
+                time: Timestamp::from_micros(1),
+                marked: false,
+            })
+            .unwrap();
+
+        loop {
+            let message = timeout(IO_TIMEOUT, websocket.next()).await.unwrap();
+
+            match message.unwrap().unwrap() {
+                // Keepalive starts right away.
+                Message::Ping(_) => continue,
+                message => {
+                    assert_eq!(message, Message::Text("k".to_string()));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plain_http_gets_upgrade_required() {
+        let (addr, events) = start(Config::default()).await;
+
+        let response = exchange(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 426 Upgrade Required");
+        assert!(response.ends_with("add index.html as a browser source to see keystrokes.\n"));
+
+        let post = b"POST / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        assert_eq!(
+            status(&exchange(addr, post).await),
+            "HTTP/1.1 426 Upgrade Required"
+        );
+
+        connects(addr, &events).await;
+    }
+
+    #[tokio::test]
+    async fn upgrades_without_a_key_are_bad_requests() {
+        let (addr, events) = start(Config::default()).await;
+
+        let request = b"GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        assert_eq!(
+            status(&exchange(addr, request).await),
+            "HTTP/1.1 400 Bad Request"
+        );
+
+        connects(addr, &events).await;
+    }
+
+    #[tokio::test]
+    async fn garbage_is_a_bad_request() {
+        let (addr, events) = start(Config::default()).await;
+
+        let response = exchange(addr, b"\x16\x03\x01\x02\x00\x01\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 400 Bad Request");
+
+        // Closed before finishing the request.
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HT").await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut response = Vec::new();
+        let read = timeout(IO_TIMEOUT / 2, stream.read_to_end(&mut response)).await;
+        assert!(read.is_ok() && response.is_empty());
+
+        connects(addr, &events).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_refused() {
+        let (addr, events) = start(Config::default()).await;
+
+        let mut request = b"GET / HTTP/1.1\r\n".to_vec();
+
+        while request.len() <= MAX_REQUEST_SIZE {
+            request.extend_from_slice(b"X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n");
+        }
+
+        let response = exchange(addr, &request).await;
+        assert_eq!(
+            status(&response),
+            "HTTP/1.1 431 Request Header Fields Too Large"
+        );
+
+        connects(addr, &events).await;
+    }
+
+    #[tokio::test]
+    async fn slow_handshakes_time_out() {
+        let (addr, events) = start(Config::default()).await;
+
+        let started = Instant::now();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: loc")
+            .await
+            .unwrap();
+
+        // Others get through while the slow client holds its connection.
+        connects(addr, &events).await;
+
+        let mut response = Vec::new();
+        let read = timeout(IO_TIMEOUT * 2, stream.read_to_end(&mut response)).await;
+
+        assert!(read.is_ok(), "the connection was not closed");
+        assert!(response.is_empty());
+        assert!(started.elapsed() >= IO_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_limit_are_turned_away() {
+        let (addr, _) = start(Config {
+            max_clients: 1,
+            ..Config::default()
+        })
+        .await;
+
+        let url = format!("ws://{}/", addr);
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let _first = tokio_tungstenite::client_async(url.clone(), stream)
+            .await
+            .unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let second = tokio_tungstenite::client_async(url, stream).await;
+        assert!(second.is_err());
+    }
+
+    #[tokio::test]
+    async fn metrics_on_their_own_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Config {
+            websocket: false,
+            auth: AuthConfig {
+                tokens: vec!["secret".to_string()],
+                ..AuthConfig::default()
+            },
+            ..Config::default()
+        };
+
+        tokio::spawn(run_metrics(listener, None, State::new(config).shared()));
+
+        let response = exchange(addr, b"GET /metrics?token=secret HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 200 OK");
+        assert!(response.contains("\nkeydisp_hook_restarts_total "));
+
+        let response = exchange(addr, b"GET /metrics HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 401 Unauthorized");
+
+        // Nothing else is served there, websocket clients included.
+        let response = exchange(addr, b"GET /?token=secret HTTP/1.1\r\n\r\n").await;
+        assert_eq!(status(&response), "HTTP/1.1 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn clients_get_the_devices_they_subscribe_to() {
+        let (addr, events) = start(Config::default()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let url = format!("ws://{}/?device=Player%20One&device=1209:0001", addr);
+        let (mut websocket, _) = tokio_tungstenite::client_async(url, stream).await.unwrap();
+
+        while events.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let from = |name: Option<&str>, text| Tagged {
+            output: Output::Char { text },
+            device: name.map(|name| {
+                Arc::new(DeviceId {
+                    name: name.to_string(),
+                    vendor: 0x046d,
+                    product: 0xc52b,
+                    phys: String::new(),
+                })
+            }),
+            injected: false,
+            time: Timestamp::from_micros(1),
+            marked: false,
+        };
+
+        for tagged in [
+            from(Some("Player One Keyboard"), 'a'),
+            from(Some("Player Two Keyboard"), 'b'),
+            from(None, 'c'),
+            from(Some("Player one"), 'd'),
+        ] {
+            events.send(tagged).unwrap();
+        }
+
+        let mut received = Vec::new();
+
+        while received.len() < 2 {
+            let message = timeout(IO_TIMEOUT, websocket.next()).await.unwrap();
+
+            match message.unwrap().unwrap() {
+                Message::Ping(_) => continue,
+                Message::Text(text) => received.push(text),
+                message => panic!("{:?}", message),
+            }
+        }
+
+        assert_eq!(received, ["a", "d"]);
+    }
+}
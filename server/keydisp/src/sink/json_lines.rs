@@ -0,0 +1,249 @@
+//! A sink writing one JSON object per line, as sent to JSON websocket clients.
+//!
+//! Writes to standard output, or to a file or named pipe. A pipe is opened
+//! once a reader is there, and opened again when the reader goes away, so
+//! scripts reading it can come and go.
+
+use crate::config::JsonLinesConfig;
+use crate::metrics;
+use crate::output::{Format, Tagged};
+
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::sleep;
+
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+pub async fn run(config: JsonLinesConfig, mut events: broadcast::Receiver<Tagged>) {
+    loop {
+        let output: Pin<Box<dyn AsyncWrite + Send>> = match config.path {
+            Some(ref path) => {
+                // Waits for a reader when `path` is a named pipe.
+                let opened = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .await;
+
+                match opened {
+                    Ok(file) => Box::pin(file),
+                    Err(e) => {
+                        eprintln!("Could not open {}: {}", path.display(), e);
+                        sleep(REOPEN_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            None => Box::pin(tokio::io::stdout()),
+        };
+
+        match write(&config, output, &mut events).await {
+            Ok(()) => return,
+            Err(e) => {
+                let path = config.path.as_deref().unwrap_or_else(|| "stdout".as_ref());
+                metrics::WRITE_ERRORS.inc(&["json_lines"]);
+                eprintln!("Could not write to {}: {}", path.display(), e);
+            }
+        }
+
+        // Standard output doesn't come back.
+        if config.path.is_none() {
+            return;
+        }
+
+        sleep(REOPEN_DELAY).await;
+        events = events.resubscribe();
+    }
+}
+
+/// Writes until keydisp stops broadcasting, or writing fails. The output is
+/// closed on return, a reader waiting on a pipe mustn't see it open still.
+async fn write(
+    config: &JsonLinesConfig,
+    mut output: Pin<Box<dyn AsyncWrite + Send>>,
+    events: &mut broadcast::Receiver<Tagged>,
+) -> io::Result<()> {
+    loop {
+        let tagged = match events.recv().await {
+            Ok(tagged) => tagged,
+            Err(RecvError::Lagged(skipped)) => {
+                metrics::DROPPED_EVENTS.add(&["json_lines"], skipped);
+                eprintln!("JSON lines output too slow, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+
+        if !tagged.is_for(&config.devices) {
+            continue;
+        }
+
+        if let Some(mut line) = Format::Json.encode(&tagged) {
+            line.push('\n');
+            output.write_all(line.as_bytes()).await?;
+            output.flush().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::Timestamp;
+    use crate::output::Output;
+
+    use std::path::Path;
+
+    use serde_json::Value;
+    use tokio::time::timeout;
+
+    fn char(text: char) -> Tagged {
+        Tagged {
+            output: Output::Char { text },
+            device: None,
+            injected: false,
+            time: Timestamp::from_micros(1),
+            marked: false,
+        }
+    }
+
+    /// The `text` of each line, which must each be a JSON object.
+    fn texts(lines: &str) -> Vec<String> {
+        lines
+            .lines()
+            .map(|line| {
+                let object: Value = serde_json::from_str(line).unwrap();
+                assert_eq!(object["type"], "char");
+                object["text"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    /// Writes `chars` to `path`, until the sink is done.
+    async fn write_all(path: &Path, chars: &[char]) {
+        let config = JsonLinesConfig {
+            path: Some(path.to_path_buf()),
+            devices: Vec::new(),
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        for c in chars {
+            events.send(char(*c)).unwrap();
+        }
+
+        drop(events);
+        timeout(Duration::from_secs(1), sink)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn appends_a_line_per_event() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys.jsonl");
+
+        write_all(&path, &['a', 'b']).await;
+        write_all(&path, &['c']).await;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.ends_with('\n'));
+        assert_eq!(texts(&written), ["a", "b", "c"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn reopens_a_pipe_for_the_next_reader() {
+        use std::ffi::CString;
+        use std::fs::File;
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::ffi::OsStrExt;
+
+        use crate::config::Config;
+        use crate::state::State;
+
+        use tokio::task::spawn_blocking;
+
+        /// Opens the pipe at `path` and reads a line, keeping it open.
+        async fn read_line(path: &Path) -> (String, BufReader<File>) {
+            let path = path.to_path_buf();
+
+            spawn_blocking(move || {
+                let mut reader = BufReader::new(File::open(path).unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                (line, reader)
+            })
+            .await
+            .unwrap()
+        }
+
+        /// Failed writes so far, as served on `/metrics`.
+        fn write_errors() -> String {
+            let state = State::new(Config::default()).shared();
+
+            metrics::render(&state)
+                .lines()
+                .find(|line| line.starts_with(r#"keydisp_write_errors_total{output="json_lines"}"#))
+                .unwrap_or_default()
+                .to_string()
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("keys");
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let config = JsonLinesConfig {
+            path: Some(path.clone()),
+            devices: Vec::new(),
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        events.send(char('a')).unwrap();
+        let (line, reader) = read_line(&path).await;
+        assert_eq!(texts(&line), ["a"]);
+        drop(reader);
+
+        // Lost with the reader, and writing it fails. The next reader would
+        // otherwise get it, if it came before the sink tried.
+        let errors = write_errors();
+        events.send(char('b')).unwrap();
+        timeout(Duration::from_secs(1), async {
+            while write_errors() == errors {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Events before the pipe is open again are skipped, so keep typing
+        // until the next reader gets a line.
+        let mut next = tokio::spawn({
+            let path = path.clone();
+            async move { read_line(&path).await }
+        });
+        let (line, _reader) = loop {
+            events.send(char('c')).unwrap();
+
+            if let Ok(read) = timeout(Duration::from_millis(100), &mut next).await {
+                break read.unwrap();
+            }
+        };
+        assert_eq!(texts(&line), ["c"]);
+
+        drop(events);
+        timeout(Duration::from_secs(1), sink)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
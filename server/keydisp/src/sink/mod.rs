@@ -0,0 +1,32 @@
+//! Places outputs are sent besides websocket clients.
+//!
+//! Each configured sink runs as a task of its own with its own subscription
+//! to the broadcast, so a slow or unreachable sink only misses events itself.
+//! Adding a sink is adding a variant to `SinkConfig` and a module here with
+//! a `run` function taking its config and the subscription.
+
+mod json_lines;
+mod mqtt;
+mod obs;
+mod osc;
+mod render;
+mod webhook;
+
+use crate::config::SinkConfig;
+use crate::output::Tagged;
+
+use tokio::sync::broadcast;
+
+/// Starts the sink on the current runtime.
+pub fn spawn(config: SinkConfig, events: &broadcast::Sender<Tagged>) {
+    let events = events.subscribe();
+
+    match config {
+        SinkConfig::JsonLines(config) => tokio::spawn(json_lines::run(config, events)),
+        SinkConfig::Obs(config) => tokio::spawn(obs::run(config, events)),
+        SinkConfig::Osc(config) => tokio::spawn(osc::run(config, events)),
+        SinkConfig::Mqtt(config) => tokio::spawn(mqtt::run(config, events)),
+        SinkConfig::Webhook(config) => tokio::spawn(webhook::run(config, events)),
+        SinkConfig::Render(config) => tokio::spawn(render::run(config, events)),
+    };
+}
@@ -0,0 +1,715 @@
+//! A sink publishing to an MQTT broker, speaking MQTT 3.1.1 over TCP.
+//!
+//! Every event is published as JSON to the events topic, and whether someone
+//! is typing to the state topic as `typing` or `idle`. The broker is left a
+//! will, so it publishes `offline` there when keydisp goes away. Publishes
+//! with QoS 1 or 2 that weren't acknowledged are sent again after
+//! reconnecting, which is retried with backoff for as long as keydisp runs.
+//! The broker is asked to keep the session for that, which takes a client
+//! id, without one they are given up on instead.
+
+use crate::config::MqttConfig;
+use crate::metrics;
+use crate::output::{Format, Output, Tagged};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, sleep, sleep_until, timeout, Instant};
+
+const PROTOCOL_LEVEL: u8 = 4;
+
+// Packet types, the high nibble of the first byte.
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+
+// Connect flags.
+const CLEAN_SESSION: u8 = 0x02;
+const WILL: u8 = 0x04;
+const WILL_RETAIN: u8 = 0x20;
+const PASSWORD: u8 = 0x40;
+const USERNAME: u8 = 0x80;
+
+/// Set on a publish that may have been received before.
+const DUP: u8 = 0x08;
+
+const STATE_TYPING: &str = "typing";
+const STATE_IDLE: &str = "idle";
+const STATE_OFFLINE: &str = "offline";
+
+/// Publishes the broker may hold on to before acknowledging, beyond which
+/// it is taken to have stopped answering.
+const MAX_UNACKED: usize = 256;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum MqttError {
+    Io(io::Error),
+    /// The broker turned the connection down, with the CONNACK return code.
+    Refused(u8),
+    Protocol(String),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "{}", e),
+            MqttError::Refused(code) => {
+                let reason = match code {
+                    1 => "unsupported protocol version",
+                    2 => "client id rejected",
+                    3 => "server unavailable",
+                    4 => "bad username or password",
+                    5 => "not authorized",
+                    _ => "unknown reason",
+                };
+                write!(f, "refused by the broker: {}", reason)
+            }
+            MqttError::Protocol(e) => write!(f, "unexpected packet: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(e: io::Error) -> Self {
+        MqttError::Io(e)
+    }
+}
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Prefixes `body` with the fixed header, its length as a variable length
+/// integer of 7 bits per byte.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+
+        packet.push(byte | 0x80);
+    }
+
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Whether the broker keeps the session across connections, so publishes
+/// in flight can be sent again. Brokers only do for a client id.
+fn persistent(config: &MqttConfig, qos: u8) -> bool {
+    qos > 0 && !config.client_id.is_empty()
+}
+
+fn connect_packet(config: &MqttConfig, qos: u8) -> Vec<u8> {
+    let mut flags = if persistent(config, qos) {
+        0
+    } else {
+        CLEAN_SESSION
+    };
+    let mut payload = Vec::new();
+
+    push_string(&mut payload, &config.client_id);
+
+    if !config.state_topic.is_empty() {
+        flags |= WILL | qos << 3;
+
+        if config.retain_state {
+            flags |= WILL_RETAIN;
+        }
+
+        push_string(&mut payload, &config.state_topic);
+        push_string(&mut payload, STATE_OFFLINE);
+    }
+
+    if let Some(ref username) = config.username {
+        flags |= USERNAME;
+        push_string(&mut payload, username);
+    }
+
+    if let Some(ref password) = config.password {
+        flags |= PASSWORD;
+        push_string(&mut payload, password);
+    }
+
+    let mut body = Vec::new();
+    push_string(&mut body, "MQTT");
+    body.push(PROTOCOL_LEVEL);
+    body.push(flags);
+    body.extend_from_slice(&config.keep_alive.to_be_bytes());
+    body.extend_from_slice(&payload);
+
+    packet(CONNECT << 4, &body)
+}
+
+/// Packets that carry nothing but a packet id.
+fn id_packet(header: u8, id: u16) -> Vec<u8> {
+    packet(header, &id.to_be_bytes())
+}
+
+fn packet_id(body: &[u8]) -> Result<u16, MqttError> {
+    match body {
+        [high, low, ..] => Ok(u16::from_be_bytes([*high, *low])),
+        _ => Err(MqttError::Protocol("missing packet id".to_string())),
+    }
+}
+
+/// Takes the first complete packet off `buf`, as its header byte and body.
+fn take_packet(buf: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>, MqttError> {
+    let mut len = 0;
+
+    for (i, byte) in buf.iter().enumerate().skip(1).take(4) {
+        len += usize::from(byte & 0x7f) << (7 * (i - 1));
+
+        if byte & 0x80 == 0 {
+            let start = i + 1;
+
+            if buf.len() < start + len {
+                return Ok(None);
+            }
+
+            let header = buf[0];
+            let body = buf[start..start + len].to_vec();
+            buf.drain(..start + len);
+            return Ok(Some((header, body)));
+        }
+    }
+
+    if buf.len() > 4 {
+        return Err(MqttError::Protocol("malformed length".to_string()));
+    }
+
+    Ok(None)
+}
+
+struct Session {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    /// Received bytes not yet making up a whole packet.
+    received: Vec<u8>,
+}
+
+impl Session {
+    async fn connect(config: &MqttConfig, qos: u8) -> Result<Session, MqttError> {
+        let connecting = async {
+            let stream = TcpStream::connect(&config.broker).await?;
+            stream.set_nodelay(true)?;
+
+            let (reader, writer) = stream.into_split();
+            let mut session = Session {
+                reader,
+                writer,
+                received: Vec::new(),
+            };
+
+            session.send(&connect_packet(config, qos)).await?;
+
+            match session.next().await? {
+                (header, body) if header >> 4 == CONNACK && body.len() == 2 => match body[1] {
+                    0 => Ok(session),
+                    code => Err(MqttError::Refused(code)),
+                },
+                (header, _) => Err(MqttError::Protocol(format!(
+                    "packet type {} instead of CONNACK",
+                    header >> 4
+                ))),
+            }
+        };
+
+        match timeout(CONNECT_TIMEOUT, connecting).await {
+            Ok(connected) => connected,
+            Err(_) => Err(MqttError::Protocol("no answer".to_string())),
+        }
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<(), MqttError> {
+        self.writer.write_all(packet).await?;
+        Ok(())
+    }
+
+    /// The next packet, safe to cancel as partial packets are kept.
+    async fn next(&mut self) -> Result<(u8, Vec<u8>), MqttError> {
+        loop {
+            if let Some(packet) = take_packet(&mut self.received)? {
+                return Ok(packet);
+            }
+
+            if self.reader.read_buf(&mut self.received).await? == 0 {
+                return Err(MqttError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection lost",
+                )));
+            }
+        }
+    }
+}
+
+/// What outlives a connection: publishes awaiting acknowledgement and
+/// whether someone is typing.
+struct Publisher {
+    qos: u8,
+    next_id: u16,
+    /// The packet to send again after reconnecting, per packet id: the
+    /// publish, or for QoS 2 once it was received, the release.
+    unacked: BTreeMap<u16, Vec<u8>>,
+    typing: bool,
+}
+
+impl Publisher {
+    async fn publish(
+        &mut self,
+        session: &mut Session,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        let mut body = Vec::new();
+        push_string(&mut body, topic);
+
+        if self.qos > 0 {
+            if self.unacked.len() >= MAX_UNACKED {
+                return Err(MqttError::Protocol(
+                    "the broker stopped acknowledging".to_string(),
+                ));
+            }
+
+            loop {
+                self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+
+                if !self.unacked.contains_key(&self.next_id) {
+                    break;
+                }
+            }
+
+            body.extend_from_slice(&self.next_id.to_be_bytes());
+        }
+
+        body.extend_from_slice(payload);
+
+        let packet = packet(PUBLISH << 4 | self.qos << 1 | u8::from(retain), &body);
+        session.send(&packet).await?;
+
+        if self.qos > 0 {
+            self.unacked.insert(self.next_id, packet);
+        }
+
+        Ok(())
+    }
+
+    async fn publish_state(
+        &mut self,
+        config: &MqttConfig,
+        session: &mut Session,
+    ) -> Result<(), MqttError> {
+        if config.state_topic.is_empty() {
+            return Ok(());
+        }
+
+        let state = if self.typing { STATE_TYPING } else { STATE_IDLE };
+        self.publish(session, &config.state_topic, state.as_bytes(), config.retain_state)
+            .await
+    }
+
+    /// Sends whatever wasn't acknowledged on the previous connection again,
+    /// or forgets about it when the broker started a new session.
+    async fn resend(&mut self, session: &mut Session, persistent: bool) -> Result<(), MqttError> {
+        if !persistent {
+            self.unacked.clear();
+            return Ok(());
+        }
+
+        for packet in self.unacked.values_mut() {
+            if packet[0] >> 4 == PUBLISH {
+                packet[0] |= DUP;
+            }
+
+            session.send(packet).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn acknowledged(
+        &mut self,
+        session: &mut Session,
+        header: u8,
+        body: &[u8],
+    ) -> Result<(), MqttError> {
+        match header >> 4 {
+            PUBACK | PUBCOMP => {
+                self.unacked.remove(&packet_id(body)?);
+            }
+            PUBREC => {
+                let id = packet_id(body)?;
+                let release = id_packet(PUBREL << 4 | 0x02, id);
+
+                session.send(&release).await?;
+                self.unacked.insert(id, release);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+/// Events that count as typing, as opposed to using the mouse or a pad.
+fn is_typing(output: &Output) -> bool {
+    matches!(
+        output,
+        Output::Key { .. }
+            | Output::Release { .. }
+            | Output::Char { .. }
+            | Output::Pending { .. }
+            | Output::Lock { .. }
+    )
+}
+
+/// Keeps publishing, reconnecting to the broker whenever needed.
+pub async fn run(config: MqttConfig, mut events: broadcast::Receiver<Tagged>) {
+    if config.qos > 2 {
+        eprintln!("MQTT QoS {} doesn't exist, using 2", config.qos);
+    }
+
+    let mut publisher = Publisher {
+        qos: config.qos.min(2),
+        next_id: 0,
+        unacked: BTreeMap::new(),
+        typing: false,
+    };
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match Session::connect(&config, publisher.qos).await {
+            Ok(session) => {
+                eprintln!("Connected to MQTT broker at {}", config.broker);
+                backoff = MIN_BACKOFF;
+
+                match serve(&config, session, &mut publisher, &mut events).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        metrics::WRITE_ERRORS.inc(&["mqtt"]);
+                        eprintln!("MQTT: {}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Could not connect to MQTT broker at {}: {}", config.broker, e),
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        events = events.resubscribe();
+    }
+}
+
+/// Runs until the connection fails, or keydisp stops broadcasting.
+async fn serve(
+    config: &MqttConfig,
+    mut session: Session,
+    publisher: &mut Publisher,
+    events: &mut broadcast::Receiver<Tagged>,
+) -> Result<(), MqttError> {
+    publisher
+        .resend(&mut session, persistent(config, publisher.qos))
+        .await?;
+    publisher.publish_state(config, &mut session).await?;
+
+    let idle_after = Duration::from_millis(config.idle_after_ms);
+    let mut idle_at = Instant::now() + idle_after;
+
+    let mut ping = interval(Duration::from_secs(u64::from(config.keep_alive.max(1))));
+    ping.tick().await;
+    let mut awaiting_pong = false;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(tagged) if tagged.is_for(&config.devices) => {
+                    if !config.events_topic.is_empty() {
+                        if let Some(json) = Format::Json.encode(&tagged) {
+                            publisher
+                                .publish(&mut session, &config.events_topic, json.as_bytes(), false)
+                                .await?;
+                        }
+                    }
+
+                    if is_typing(&tagged.output) {
+                        idle_at = Instant::now() + idle_after;
+
+                        if !publisher.typing {
+                            publisher.typing = true;
+                            publisher.publish_state(config, &mut session).await?;
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(skipped)) => metrics::DROPPED_EVENTS.add(&["mqtt"], skipped),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = sleep_until(idle_at), if publisher.typing => {
+                publisher.typing = false;
+                publisher.publish_state(config, &mut session).await?;
+            }
+            _ = ping.tick(), if config.keep_alive > 0 => {
+                if awaiting_pong {
+                    return Err(MqttError::Protocol("the broker stopped answering".to_string()));
+                }
+
+                session.send(&packet(PINGREQ << 4, &[])).await?;
+                awaiting_pong = true;
+            }
+            received = session.next() => {
+                let (header, body) = received?;
+
+                if header >> 4 == PINGRESP {
+                    awaiting_pong = false;
+                }
+
+                publisher.acknowledged(&mut session, header, &body).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::Timestamp;
+
+    use tokio::net::TcpListener;
+
+    const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Publish {
+        dup: bool,
+        retain: bool,
+        topic: String,
+        id: Option<u16>,
+        payload: String,
+    }
+
+    /// Plays the broker for a single connection.
+    struct Broker {
+        stream: TcpStream,
+        received: Vec<u8>,
+    }
+
+    impl Broker {
+        async fn accept(listener: &TcpListener) -> Broker {
+            let (stream, _) = timeout(IO_TIMEOUT, listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+
+            Broker {
+                stream,
+                received: Vec::new(),
+            }
+        }
+
+        async fn packet(&mut self) -> (u8, Vec<u8>) {
+            loop {
+                if let Some(packet) = take_packet(&mut self.received).unwrap() {
+                    return packet;
+                }
+
+                let read = timeout(IO_TIMEOUT, self.stream.read_buf(&mut self.received)).await;
+                assert_ne!(read.unwrap().unwrap(), 0, "connection closed");
+            }
+        }
+
+        async fn send(&mut self, packet: &[u8]) {
+            self.stream.write_all(packet).await.unwrap();
+        }
+
+        /// Accepts the connection, returning its connect flags.
+        async fn connect(&mut self, session_present: bool) -> u8 {
+            let (header, body) = self.packet().await;
+            assert_eq!(header, CONNECT << 4);
+            assert_eq!(&body[..7], b"\0\x04MQTT\x04");
+
+            self.send(&packet(CONNACK << 4, &[u8::from(session_present), 0]))
+                .await;
+            body[7]
+        }
+
+        async fn publish(&mut self) -> Publish {
+            let (header, body) = self.packet().await;
+            assert_eq!(header >> 4, PUBLISH);
+
+            let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+            let (topic, mut rest) = body[2..].split_at(topic_len);
+            let mut id = None;
+
+            if header >> 1 & 3 > 0 {
+                id = Some(u16::from_be_bytes([rest[0], rest[1]]));
+                rest = &rest[2..];
+            }
+
+            Publish {
+                dup: header & DUP != 0,
+                retain: header & 1 != 0,
+                topic: String::from_utf8(topic.to_vec()).unwrap(),
+                id,
+                payload: String::from_utf8(rest.to_vec()).unwrap(),
+            }
+        }
+    }
+
+    fn char(text: char) -> Tagged {
+        Tagged {
+            output: Output::Char { text },
+            device: None,
+            injected: false,
+            time: Timestamp::from_micros(1),
+            marked: false,
+        }
+    }
+
+    #[test]
+    fn variable_length_packets() {
+        let body = vec![7; 321];
+        let mut buf = packet(PUBLISH << 4, &body);
+        assert_eq!(&buf[..3], &[PUBLISH << 4, 0xc1, 0x02]);
+
+        let whole = buf.clone();
+        buf.truncate(200);
+        assert!(take_packet(&mut buf).unwrap().is_none());
+
+        buf = whole;
+        buf.extend_from_slice(&packet(PINGRESP << 4, &[]));
+        assert_eq!(take_packet(&mut buf).unwrap(), Some((PUBLISH << 4, body)));
+        assert_eq!(
+            take_packet(&mut buf).unwrap(),
+            Some((PINGRESP << 4, vec![]))
+        );
+        assert!(buf.is_empty());
+
+        let mut malformed = vec![PUBLISH << 4, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(take_packet(&mut malformed).is_err());
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_publishes_are_sent_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttConfig {
+            broker: listener.local_addr().unwrap().to_string(),
+            username: Some("keydisp".to_string()),
+            password: Some("secret".to_string()),
+            qos: 1,
+            idle_after_ms: 60_000,
+            keep_alive: 0,
+            ..MqttConfig::default()
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        // A session the broker keeps, and a will to say keydisp is gone.
+        let flags = WILL | 1 << 3 | WILL_RETAIN | USERNAME | PASSWORD;
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.connect(false).await, flags);
+
+        let idle = broker.publish().await;
+        assert_eq!(
+            (idle.topic.as_str(), idle.payload.as_str()),
+            ("keydisp/state", "idle")
+        );
+        assert_eq!((idle.id, idle.retain, idle.dup), (Some(1), true, false));
+
+        events.send(char('a')).unwrap();
+
+        let event = broker.publish().await;
+        assert_eq!(event.topic, "keydisp/events");
+        assert_eq!(event.payload, r#"{"type":"char","text":"a","time":1}"#);
+        assert_eq!((event.id, event.retain), (Some(2), false));
+
+        let typing = broker.publish().await;
+        assert_eq!((typing.payload.as_str(), typing.id), ("typing", Some(3)));
+
+        // Only the event is acknowledged before the connection drops.
+        broker.send(&id_packet(PUBACK << 4, 2)).await;
+        drop(broker);
+
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.connect(true).await, flags);
+
+        for &(id, payload) in &[(1, "idle"), (3, "typing")] {
+            let resent = broker.publish().await;
+            assert_eq!((resent.id, resent.dup), (Some(id), true));
+            assert_eq!(resent.payload, payload);
+        }
+
+        let state = broker.publish().await;
+        assert_eq!((state.id, state.dup), (Some(4), false));
+        assert_eq!(state.payload, "typing");
+
+        drop(events);
+        timeout(IO_TIMEOUT, sink).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn clean_sessions_drop_what_was_in_flight() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = MqttConfig {
+            broker: listener.local_addr().unwrap().to_string(),
+            client_id: String::new(),
+            qos: 2,
+            state_topic: String::new(),
+            keep_alive: 0,
+            ..MqttConfig::default()
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.connect(false).await, CLEAN_SESSION);
+
+        events.send(char('a')).unwrap();
+        assert_eq!(broker.publish().await.id, Some(1));
+
+        // Received, the release follows.
+        broker.send(&id_packet(PUBREC << 4, 1)).await;
+        assert_eq!(broker.packet().await, (PUBREL << 4 | 0x02, vec![0, 1]));
+
+        events.send(char('b')).unwrap();
+        assert_eq!(broker.publish().await.id, Some(2));
+        drop(broker);
+
+        let mut broker = Broker::accept(&listener).await;
+        assert_eq!(broker.connect(false).await, CLEAN_SESSION);
+
+        events.send(char('c')).unwrap();
+
+        let publish = broker.publish().await;
+        assert_eq!((publish.id, publish.dup), (Some(3), false));
+        assert!(publish.payload.contains(r#""text":"c""#));
+
+        drop(events);
+        timeout(IO_TIMEOUT, sink).await.unwrap().unwrap();
+    }
+}
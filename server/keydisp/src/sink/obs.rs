@@ -0,0 +1,640 @@
+//! A sink showing keystrokes in a text source of OBS, through obs-websocket 5.
+//!
+//! keydisp connects as a client and sets the text of the configured source
+//! to the recent keystroke groups, one per line, grouped and faded like the
+//! browser overlay does. Optionally the source is shown while typing and
+//! hidden once everything faded. The connection is retried with backoff for
+//! as long as keydisp runs, so OBS can be started and restarted at any time.
+
+use crate::config::{ObsConfig, ThemeConfig};
+use crate::metrics;
+use crate::output::{Format, Tagged};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{interval, sleep, timeout};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+const PROTOCOL: &str = "obswebsocket.json";
+const RPC_VERSION: u64 = 1;
+
+// Message op codes.
+const HELLO: u64 = 0;
+const IDENTIFY: u64 = 1;
+const IDENTIFIED: u64 = 2;
+const REQUEST: u64 = 6;
+const REQUEST_RESPONSE: u64 = 7;
+
+/// Groups shown at once, the oldest are dropped first.
+const MAX_GROUPS: usize = 8;
+
+const FADE_INTERVAL: Duration = Duration::from_millis(100);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+type ObsSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type ObsStream = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+#[derive(Debug)]
+pub enum ObsError {
+    WebSocket(Box<tungstenite::Error>),
+    /// OBS closed the connection, 4009 is a wrong password.
+    Closed(String),
+    Protocol(String),
+    /// A request was answered with a failure.
+    Request(String),
+}
+
+impl fmt::Display for ObsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObsError::WebSocket(e) => write!(f, "{}", e),
+            ObsError::Closed(reason) => write!(f, "closed by OBS: {}", reason),
+            ObsError::Protocol(e) => write!(f, "unexpected message: {}", e),
+            ObsError::Request(e) => write!(f, "request failed: {}", e),
+        }
+    }
+}
+
+impl From<tungstenite::Error> for ObsError {
+    fn from(e: tungstenite::Error) -> Self {
+        ObsError::WebSocket(Box::new(e))
+    }
+}
+
+struct Group {
+    text: String,
+    started: Instant,
+    last: Instant,
+}
+
+/// The keystrokes on display, grouped as typed.
+struct Groups {
+    groups: VecDeque<Group>,
+    /// Keystrokes closer together than this are shown as one group.
+    gap: Duration,
+    /// Characters after which a group is full, and the next keystroke starts one.
+    length: usize,
+    /// Groups fade this long after they started.
+    lifetime: Duration,
+}
+
+impl Groups {
+    fn new(theme: &ThemeConfig) -> Self {
+        Self {
+            groups: VecDeque::new(),
+            gap: Duration::from_millis(theme.group_ms),
+            length: theme.group_chars,
+            lifetime: Duration::from_millis(theme.lifetime_ms),
+        }
+    }
+
+    fn push(&mut self, glyph: &str, now: Instant) {
+        match self.groups.back_mut() {
+            Some(group)
+                if now.duration_since(group.last) <= self.gap
+                    && group.text.chars().count() <= self.length =>
+            {
+                group.text.push_str(glyph);
+                group.last = now;
+            }
+            _ => {
+                self.groups.push_back(Group {
+                    text: glyph.to_string(),
+                    started: now,
+                    last: now,
+                });
+
+                if self.groups.len() > MAX_GROUPS {
+                    self.groups.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Drops faded groups, returning whether any were.
+    fn fade(&mut self, now: Instant) -> bool {
+        let before = self.groups.len();
+        let lifetime = self.lifetime;
+        self.groups.retain(|group| now.duration_since(group.started) < lifetime);
+        self.groups.len() != before
+    }
+
+    fn text(&self) -> String {
+        let lines: Vec<&str> = self.groups.iter().map(|group| group.text.as_str()).collect();
+        lines.join("\n")
+    }
+
+    fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+}
+
+struct Session {
+    sink: ObsSink,
+    stream: ObsStream,
+    next_request: u64,
+}
+
+impl Session {
+    async fn connect(config: &ObsConfig) -> Result<Session, ObsError> {
+        let mut request = config.url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(PROTOCOL));
+
+        let (websocket, _) = match timeout(CONNECT_TIMEOUT, connect_async(request)).await {
+            Ok(connected) => connected?,
+            Err(_) => return Err(ObsError::Protocol("no answer".to_string())),
+        };
+
+        let (sink, stream) = websocket.split();
+        let mut session = Session {
+            sink,
+            stream,
+            next_request: 0,
+        };
+
+        let hello = session.receive(HELLO).await?;
+        let mut identify = json!({
+            "rpcVersion": RPC_VERSION,
+            // No events, only requests are sent.
+            "eventSubscriptions": 0,
+        });
+
+        if let Some(auth) = hello.get("authentication") {
+            let password = config.password.as_deref().ok_or_else(|| {
+                ObsError::Protocol("OBS asks for a password, none is configured".to_string())
+            })?;
+            let challenge = auth["challenge"].as_str().unwrap_or_default();
+            let salt = auth["salt"].as_str().unwrap_or_default();
+
+            identify["authentication"] = json!(authentication(password, salt, challenge));
+        }
+
+        session.send(IDENTIFY, identify).await?;
+        session.receive(IDENTIFIED).await?;
+
+        Ok(session)
+    }
+
+    async fn send(&mut self, op: u64, data: Value) -> Result<(), ObsError> {
+        let message = json!({ "op": op, "d": data }).to_string();
+        self.sink.send(Message::Text(message)).await?;
+        Ok(())
+    }
+
+    /// Sends a request without waiting for the response, returning its id.
+    async fn request(&mut self, request_type: &str, data: Value) -> Result<String, ObsError> {
+        self.next_request += 1;
+        let id = self.next_request.to_string();
+
+        self.send(
+            REQUEST,
+            json!({
+                "requestType": request_type,
+                "requestId": id,
+                "requestData": data,
+            }),
+        )
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Sends a request and waits for its response data.
+    async fn call(&mut self, request_type: &str, data: Value) -> Result<Value, ObsError> {
+        let id = self.request(request_type, data).await?;
+
+        loop {
+            let response = self.receive(REQUEST_RESPONSE).await?;
+
+            if response["requestId"] == id.as_str() {
+                check(&response)?;
+                return Ok(response["responseData"].clone());
+            }
+        }
+    }
+
+    /// The data of the next message with `op`, skipping others.
+    async fn receive(&mut self, op: u64) -> Result<Value, ObsError> {
+        loop {
+            let message = self.next().await?;
+
+            if message["op"] == op {
+                return Ok(message["d"].clone());
+            }
+        }
+    }
+
+    async fn next(&mut self) -> Result<Value, ObsError> {
+        loop {
+            match self.stream.next().await {
+                Some(Ok(Message::Text(text))) => {
+                    return serde_json::from_str(&text)
+                        .map_err(|e| ObsError::Protocol(e.to_string()))
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = frame.map_or_else(String::new, |frame| {
+                        format!("{} {}", u16::from(frame.code), frame.reason)
+                    });
+                    return Err(ObsError::Closed(reason));
+                }
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(ObsError::Closed("connection lost".to_string())),
+            }
+        }
+    }
+}
+
+/// `base64(sha256(base64(sha256(password + salt)) + challenge))`.
+fn authentication(password: &str, salt: &str, challenge: &str) -> String {
+    let secret = base64::encode(Sha256::digest(format!("{}{}", password, salt).as_bytes()));
+    base64::encode(Sha256::digest(format!("{}{}", secret, challenge).as_bytes()))
+}
+
+fn check(response: &Value) -> Result<(), ObsError> {
+    let status = &response["requestStatus"];
+
+    if status["result"] == true {
+        return Ok(());
+    }
+
+    Err(ObsError::Request(format!(
+        "{} ({}: {})",
+        response["requestType"].as_str().unwrap_or("request"),
+        status["code"],
+        status["comment"].as_str().unwrap_or("no reason given")
+    )))
+}
+
+/// Keeps the text source up to date, reconnecting to OBS whenever needed.
+pub async fn run(config: ObsConfig, mut events: broadcast::Receiver<Tagged>) {
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        match Session::connect(&config).await {
+            Ok(session) => {
+                eprintln!("Connected to OBS at {}", config.url);
+                backoff = MIN_BACKOFF;
+
+                match serve(&config, session, &mut events).await {
+                    Ok(()) => return,
+                    Err(e) => {
+                        metrics::WRITE_ERRORS.inc(&["obs"]);
+                        eprintln!("OBS: {}", e);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Could not connect to OBS at {}: {}", config.url, e),
+        }
+
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+        events = events.resubscribe();
+    }
+}
+
+/// Runs until the connection fails, or keydisp stops broadcasting.
+async fn serve(
+    config: &ObsConfig,
+    mut session: Session,
+    events: &mut broadcast::Receiver<Tagged>,
+) -> Result<(), ObsError> {
+    let scene_item = if config.toggle_visibility {
+        let scene = match config.scene {
+            Some(ref scene) => scene.clone(),
+            None => session
+                .call("GetCurrentProgramScene", json!({}))
+                .await?["currentProgramSceneName"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        };
+
+        let item = session
+            .call(
+                "GetSceneItemId",
+                json!({ "sceneName": scene, "sourceName": config.source }),
+            )
+            .await?;
+
+        Some((scene, item["sceneItemId"].clone()))
+    } else {
+        None
+    };
+
+    let mut groups = Groups::new(&config.theme);
+    let mut fade = interval(FADE_INTERVAL);
+    let mut visible = None;
+
+    // Starts out with a clear and hidden source, whatever was left before.
+    let mut changed = true;
+
+    loop {
+        if changed {
+            session
+                .request(
+                    "SetInputSettings",
+                    json!({
+                        "inputName": config.source,
+                        "inputSettings": { "text": groups.text() },
+                    }),
+                )
+                .await?;
+
+            if let Some((ref scene, ref item)) = scene_item {
+                let show = !groups.is_empty();
+
+                if visible != Some(show) {
+                    session
+                        .request(
+                            "SetSceneItemEnabled",
+                            json!({
+                                "sceneName": scene,
+                                "sceneItemId": item,
+                                "sceneItemEnabled": show,
+                            }),
+                        )
+                        .await?;
+                    visible = Some(show);
+                }
+            }
+        }
+
+        changed = tokio::select! {
+            event = events.recv() => match event {
+                Ok(tagged) if tagged.is_for(&config.devices) => {
+                    match Format::Text.encode(&tagged) {
+                        Some(glyph) => {
+                            groups.push(&glyph, Instant::now());
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                Ok(_) => false,
+                Err(RecvError::Lagged(skipped)) => {
+                    metrics::DROPPED_EVENTS.add(&["obs"], skipped);
+                    false
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = fade.tick() => groups.fade(Instant::now()),
+            message = session.next() => {
+                let message = message?;
+
+                // Only requests that don't fit the setup, like a missing
+                // source, fail, so they are reported rather than retried.
+                if message["op"] == REQUEST_RESPONSE {
+                    if let Err(e) = check(&message["d"]) {
+                        eprintln!("OBS: {}", e);
+                    }
+                }
+
+                false
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::Timestamp;
+    use crate::output::Output;
+
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Plays obs-websocket for a single connection.
+    struct Obs {
+        websocket: WebSocketStream<TcpStream>,
+    }
+
+    impl Obs {
+        async fn accept(listener: &TcpListener) -> Obs {
+            let (stream, _) = timeout(IO_TIMEOUT, listener.accept())
+                .await
+                .unwrap()
+                .unwrap();
+
+            Obs {
+                websocket: accept_async(stream).await.unwrap(),
+            }
+        }
+
+        async fn send(&mut self, op: u64, data: Value) {
+            let message = json!({ "op": op, "d": data }).to_string();
+            self.websocket.send(Message::Text(message)).await.unwrap();
+        }
+
+        /// The data of the next message, which has to have `op`.
+        async fn receive(&mut self, op: u64) -> Value {
+            let message = timeout(IO_TIMEOUT, self.websocket.next()).await.unwrap();
+            let message: Value = match message.unwrap().unwrap() {
+                Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                message => panic!("not a text message: {:?}", message),
+            };
+
+            assert_eq!(message["op"], op, "{}", message);
+            message["d"].clone()
+        }
+
+        /// Answers the next request, which has to be a `request_type`,
+        /// returning its data.
+        async fn respond(&mut self, request_type: &str, data: Value) -> Value {
+            let request = self.receive(REQUEST).await;
+            assert_eq!(request["requestType"], request_type, "{}", request);
+
+            let response = json!({
+                "requestType": request_type,
+                "requestId": request["requestId"],
+                "requestStatus": { "result": true, "code": 100 },
+                "responseData": data,
+            });
+            self.send(REQUEST_RESPONSE, response).await;
+
+            request["requestData"].clone()
+        }
+    }
+
+    fn char(text: char) -> Tagged {
+        Tagged {
+            output: Output::Char { text },
+            device: None,
+            injected: false,
+            marked: false,
+            time: Timestamp::from_micros(1),
+        }
+    }
+
+    #[test]
+    fn authentication_as_documented() {
+        // The example in the obs-websocket protocol documentation.
+        let salt = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+        let challenge = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+
+        assert_eq!(
+            authentication("supersecretpassword", salt, challenge),
+            "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+        );
+    }
+
+    #[test]
+    fn groups_follow_the_theme() {
+        let theme = ThemeConfig {
+            group_ms: 100,
+            group_chars: 3,
+            lifetime_ms: 1000,
+            ..ThemeConfig::default()
+        };
+        let mut groups = Groups::new(&theme);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        for (i, glyph) in ["a", "b", "c"].iter().enumerate() {
+            groups.push(glyph, at(i as u64 * 10));
+        }
+
+        // A pause exactly as long as the gap still counts as typing on, and
+        // like the overlay a group only ends once it is over `group_chars`.
+        groups.push("d", at(120));
+        groups.push("e", at(130));
+        groups.push("f", at(231));
+        assert_eq!(groups.text(), "abcd\ne\nf");
+
+        assert!(!groups.fade(at(999)));
+        assert!(groups.fade(at(1000)));
+        assert_eq!(groups.text(), "e\nf");
+        assert!(groups.fade(at(1231)));
+        assert!(groups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn shows_keystrokes_in_the_text_source() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ObsConfig {
+            url: format!("ws://{}", listener.local_addr().unwrap()),
+            password: Some("supersecretpassword".to_string()),
+            toggle_visibility: true,
+            theme: ThemeConfig {
+                group_chars: 1,
+                ..ThemeConfig::default()
+            },
+            ..ObsConfig::default()
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        let mut obs = Obs::accept(&listener).await;
+        let salt = "lM1GncleQOaCu9lT1yeUZhFYnqhsLLP1G5lAGo3ixaI=";
+        let challenge = "+IxH4CnCiqpX1rM9scsNynZzbOe4KhDeYcTNS3PDaeY=";
+        let hello = json!({
+            "obsWebSocketVersion": "5.1.0",
+            "rpcVersion": 1,
+            "authentication": { "challenge": challenge, "salt": salt },
+        });
+        obs.send(HELLO, hello).await;
+
+        let identify = obs.receive(IDENTIFY).await;
+        assert_eq!(identify["rpcVersion"], RPC_VERSION);
+        assert_eq!(
+            identify["authentication"],
+            "1Ct943GAT+6YQUUX47Ia/ncufilbe6+oD6lY+5kaCu4="
+        );
+        obs.send(IDENTIFIED, json!({ "negotiatedRpcVersion": 1 }))
+            .await;
+
+        let scene = json!({ "currentProgramSceneName": "Gameplay" });
+        obs.respond("GetCurrentProgramScene", scene).await;
+        let item = obs
+            .respond("GetSceneItemId", json!({ "sceneItemId": 3 }))
+            .await;
+        assert_eq!(
+            item,
+            json!({ "sceneName": "Gameplay", "sourceName": "keydisp" })
+        );
+
+        // Cleared and hidden to begin with.
+        let text = |settings: Value| settings["inputSettings"]["text"].clone();
+        let enabled = |item: Value| {
+            (
+                item["sceneItemId"].clone(),
+                item["sceneItemEnabled"].clone(),
+            )
+        };
+        assert_eq!(text(obs.respond("SetInputSettings", json!({})).await), "");
+        assert_eq!(
+            enabled(obs.respond("SetSceneItemEnabled", json!({})).await),
+            (json!(3), json!(false))
+        );
+
+        for c in &['a', 'b', 'c'] {
+            events.send(char(*c)).unwrap();
+        }
+
+        assert_eq!(text(obs.respond("SetInputSettings", json!({})).await), "a");
+        assert_eq!(
+            enabled(obs.respond("SetSceneItemEnabled", json!({})).await),
+            (json!(3), json!(true))
+        );
+        assert_eq!(text(obs.respond("SetInputSettings", json!({})).await), "ab");
+        assert_eq!(
+            text(obs.respond("SetInputSettings", json!({})).await),
+            "ab\nc"
+        );
+
+        // Done once keydisp stops broadcasting.
+        drop(events);
+        timeout(IO_TIMEOUT, sink).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_passwords_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ObsConfig {
+            url: format!("ws://{}", listener.local_addr().unwrap()),
+            password: Some("wrong".to_string()),
+            ..ObsConfig::default()
+        };
+
+        let obs = tokio::spawn(async move {
+            let mut obs = Obs::accept(&listener).await;
+            let auth = json!({ "challenge": "c", "salt": "s" });
+            obs.send(HELLO, json!({ "rpcVersion": 1, "authentication": auth }))
+                .await;
+            obs.receive(IDENTIFY).await;
+
+            let frame = tungstenite::protocol::CloseFrame {
+                code: 4009.into(),
+                reason: "Authentication failed.".into(),
+            };
+            obs.websocket.close(Some(frame)).await.unwrap();
+        });
+
+        match Session::connect(&config).await {
+            Err(ObsError::Closed(reason)) => assert_eq!(reason, "4009 Authentication failed."),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("connected with the wrong password"),
+        }
+
+        obs.await.unwrap();
+    }
+}
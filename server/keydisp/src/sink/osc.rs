@@ -0,0 +1,392 @@
+//! A sink sending Open Sound Control messages over UDP, for visuals and
+//! lighting software like TouchDesigner or Resolume.
+//!
+//! Each event becomes one message, its address made from the template for
+//! its type and its arguments taken from the event as sent to JSON clients:
+//!
+//! ```text
+//! /keydisp/key/down       ,sh  key time
+//! /keydisp/key/up         ,sh  key time
+//! /keydisp/char           ,s   text
+//! /keydisp/pending        ,s   text
+//! /keydisp/lock           ,si  lock on
+//! /keydisp/mouse/button   ,sh  button time
+//! /keydisp/mouse/scroll   ,ff  dx dy
+//! /keydisp/mouse/move     ,ii  x y
+//! /keydisp/gamepad/button ,sh  button time
+//! /keydisp/gamepad/axis   ,sf  axis value
+//! ```
+//!
+//! `time` is an OSC 1.1 64 bit integer in microseconds, flags are sent as
+//! 0 or 1 since not every receiver knows the `T` and `F` types.
+
+use crate::config::{OscAddresses, OscConfig};
+use crate::metrics;
+use crate::output::Tagged;
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use serde_json::{Map, Value};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// The fields sent as arguments, per event type.
+const ARGUMENTS: &[(&str, &[&str])] = &[
+    ("key", &["key", "time"]),
+    ("release", &["key", "time"]),
+    ("char", &["text"]),
+    ("pending", &["text"]),
+    ("lock", &["lock", "on"]),
+    ("mouse_button", &["button", "time"]),
+    ("scroll", &["dx", "dy"]),
+    ("mouse_move", &["x", "y"]),
+    ("gamepad_button", &["button", "time"]),
+    ("gamepad_axis", &["axis", "value"]),
+];
+
+enum Arg {
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Str(String),
+}
+
+impl Arg {
+    fn from_field(name: &str, value: &Value) -> Option<Arg> {
+        match value {
+            Value::String(s) => Some(Arg::Str(s.clone())),
+            Value::Bool(b) => Some(Arg::Int(i32::from(*b))),
+            Value::Number(n) if name == "time" => n.as_i64().map(Arg::Long),
+            Value::Number(n) if n.is_f64() => n.as_f64().map(|f| Arg::Float(f as f32)),
+            Value::Number(n) => n.as_i64().map(|i| Arg::Int(i as i32)),
+            _ => None,
+        }
+    }
+
+    fn tag(&self) -> char {
+        match self {
+            Arg::Int(_) => 'i',
+            Arg::Long(_) => 'h',
+            Arg::Float(_) => 'f',
+            Arg::Str(_) => 's',
+        }
+    }
+}
+
+/// Strings are NUL terminated and padded to a multiple of four bytes.
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.resize((buf.len() + 4) & !3, 0);
+}
+
+fn encode(address: &str, args: &[Arg]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_string(&mut buf, address);
+
+    let tags: String = std::iter::once(',').chain(args.iter().map(Arg::tag)).collect();
+    push_string(&mut buf, &tags);
+
+    for arg in args {
+        match arg {
+            Arg::Int(i) => buf.extend_from_slice(&i.to_be_bytes()),
+            Arg::Long(l) => buf.extend_from_slice(&l.to_be_bytes()),
+            Arg::Float(f) => buf.extend_from_slice(&f.to_be_bytes()),
+            Arg::Str(s) => push_string(&mut buf, s),
+        }
+    }
+
+    buf
+}
+
+fn template<'a>(addresses: &'a OscAddresses, kind: &str) -> &'a str {
+    match kind {
+        "key" => &addresses.key,
+        "release" => &addresses.release,
+        "char" => &addresses.char,
+        "pending" => &addresses.pending,
+        "lock" => &addresses.lock,
+        "mouse_button" => &addresses.mouse_button,
+        "scroll" => &addresses.scroll,
+        "mouse_move" => &addresses.mouse_move,
+        "gamepad_button" => &addresses.gamepad_button,
+        "gamepad_axis" => &addresses.gamepad_axis,
+        _ => "",
+    }
+}
+
+/// Fills in `{field}` placeholders. Characters OSC gives a meaning to in
+/// addresses are replaced, so a typed `/` or `*` doesn't change the pattern.
+fn address(template: &str, fields: &Map<String, Value>) -> String {
+    let mut address = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+
+        address.push_str(&rest[..start]);
+
+        let field = match fields.get(&rest[start + 1..end]) {
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+            None => rest[start..=end].to_string(),
+        };
+
+        address.extend(field.chars().map(|c| match c {
+            ' ' | '#' | '*' | ',' | '/' | '?' | '[' | ']' | '{' | '}' => '_',
+            c => c,
+        }));
+
+        rest = &rest[end + 1..];
+    }
+
+    address.push_str(rest);
+    address
+}
+
+/// The message for an event, if its type has an address.
+fn message(addresses: &OscAddresses, tagged: &Tagged) -> Option<Vec<u8>> {
+    let fields = match serde_json::to_value(tagged) {
+        Ok(Value::Object(fields)) => fields,
+        _ => return None,
+    };
+
+    let kind = fields.get("type")?.as_str()?;
+    let template = template(addresses, kind);
+
+    if template.is_empty() {
+        return None;
+    }
+
+    let (_, names) = ARGUMENTS.iter().find(|(name, _)| *name == kind)?;
+    let args: Vec<Arg> = names
+        .iter()
+        .filter_map(|name| Arg::from_field(name, fields.get(*name)?))
+        .collect();
+
+    Some(encode(&address(template, &fields), &args))
+}
+
+/// Resolves the targets, leaving out the ones that don't resolve.
+async fn resolve(targets: &[String]) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+
+    for target in targets {
+        match lookup_host(target.as_str()).await.map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => resolved.push(addr),
+            Ok(None) => eprintln!("OSC target {} has no address", target),
+            Err(e) => eprintln!("Could not resolve OSC target {}: {}", target, e),
+        }
+    }
+
+    resolved
+}
+
+async fn bind(targets: &[SocketAddr]) -> io::Result<(Option<UdpSocket>, Option<UdpSocket>)> {
+    let mut v4 = None;
+    let mut v6 = None;
+
+    if targets.iter().any(SocketAddr::is_ipv4) {
+        v4 = Some(UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?);
+    }
+
+    if targets.iter().any(SocketAddr::is_ipv6) {
+        v6 = Some(UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?);
+    }
+
+    Ok((v4, v6))
+}
+
+pub async fn run(config: OscConfig, mut events: broadcast::Receiver<Tagged>) {
+    let targets = resolve(&config.targets).await;
+
+    if targets.is_empty() {
+        eprintln!("No OSC targets to send to");
+        return;
+    }
+
+    // Unconnected sockets, so targets nobody listens on don't report errors.
+    let (v4, v6) = match bind(&targets).await {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("Could not open OSC socket: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        let tagged = match events.recv().await {
+            Ok(tagged) => tagged,
+            Err(RecvError::Lagged(skipped)) => {
+                metrics::DROPPED_EVENTS.add(&["osc"], skipped);
+                eprintln!("OSC output too slow, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if !tagged.is_for(&config.devices) {
+            continue;
+        }
+
+        let message = match message(&config.addresses, &tagged) {
+            Some(message) => message,
+            None => continue,
+        };
+
+        for target in &targets {
+            let socket = match target {
+                SocketAddr::V4(_) => v4.as_ref(),
+                SocketAddr::V6(_) => v6.as_ref(),
+            };
+
+            if let Some(socket) = socket {
+                if let Err(e) = socket.send_to(&message, target).await {
+                    metrics::WRITE_ERRORS.inc(&["osc"]);
+                    eprintln!("Could not send OSC message to {}: {}", target, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::common::{DeviceId, Key, Lock, Timestamp};
+    use crate::output::Output;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    fn tagged(output: Output, device: Option<&str>) -> Tagged {
+        Tagged {
+            output,
+            device: device.map(|name| {
+                Arc::new(DeviceId {
+                    name: name.to_string(),
+                    vendor: 0x046d,
+                    product: 0xc31c,
+                    phys: String::new(),
+                })
+            }),
+            injected: false,
+            time: Timestamp::from_micros(1_520_331),
+            marked: false,
+        }
+    }
+
+    /// The next datagram, waiting up to a second for it.
+    async fn receive(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0u8; 512];
+        let read = timeout(Duration::from_secs(1), socket.recv(&mut buf)).await;
+        let n = read.expect("a message in time").unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test]
+    async fn sends_to_every_target() {
+        let first = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Where there is IPv6 at all.
+        let v6 = UdpSocket::bind("[::1]:0").await.ok();
+
+        let mut targets = vec![
+            first.local_addr().unwrap().to_string(),
+            format!("localhost:{}", second.local_addr().unwrap().port()),
+        ];
+        targets.extend(
+            v6.iter()
+                .map(|socket| socket.local_addr().unwrap().to_string()),
+        );
+
+        let config = OscConfig {
+            targets,
+            addresses: OscAddresses {
+                char: "/keydisp/char/{text}".to_string(),
+                release: String::new(),
+                ..OscAddresses::default()
+            },
+            devices: vec!["keyboard".to_string()],
+        };
+        let (events, receiver) = broadcast::channel(16);
+        let sink = tokio::spawn(run(config, receiver));
+
+        let key = Output::Key {
+            key: Key::LeftShift,
+            glyph: "⇧",
+        };
+        let outputs = [
+            tagged(key, Some("Logitech Keyboard")),
+            // Left out by their device, or by its empty address.
+            tagged(Output::Char { text: 'x' }, Some("Macro pad")),
+            tagged(Output::Char { text: 'y' }, None),
+            tagged(
+                Output::Release {
+                    key: Key::LeftShift,
+                },
+                None,
+            ),
+            tagged(Output::Char { text: '/' }, Some("Logitech Keyboard")),
+            tagged(
+                Output::Lock {
+                    lock: Lock::CapsLock,
+                    on: true,
+                },
+                Some("Logitech Keyboard"),
+            ),
+            tagged(
+                Output::Scroll {
+                    dx: 0.0,
+                    dy: -1.5,
+                    modifiers: vec![],
+                    glyph: "↓".to_string(),
+                },
+                Some("Logitech Keyboard"),
+            ),
+        ];
+
+        // Queued for the sink, whether or not it resolved its targets yet.
+        for output in outputs.iter().cloned() {
+            events.send(output).unwrap();
+        }
+
+        let expected: [&[u8]; 4] = [
+            b"/keydisp/key/down\0\0\0,sh\0left_shift\0\0\0\0\0\0\0\x17\x32\xcb",
+            b"/keydisp/char/_\0,s\0\0/\0\0\0",
+            b"/keydisp/lock\0\0\0,si\0caps_lock\0\0\0\0\0\0\x01",
+            b"/keydisp/mouse/scroll\0\0\0,ff\0\0\0\0\0\xbf\xc0\0\0",
+        ];
+
+        let mut sockets = vec![&first, &second];
+        sockets.extend(v6.as_ref());
+
+        for socket in sockets {
+            for message in &expected {
+                assert_eq!(receive(socket).await, *message);
+            }
+        }
+
+        drop(events);
+        sink.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn gives_up_without_targets() {
+        let config = OscConfig {
+            targets: vec!["not an address".to_string()],
+            ..OscConfig::default()
+        };
+        let (_events, receiver) = broadcast::channel(16);
+
+        timeout(Duration::from_secs(5), run(config, receiver))
+            .await
+            .unwrap();
+    }
+}
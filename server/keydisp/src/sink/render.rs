@@ -0,0 +1,202 @@
+//! A sink drawing the overlay itself, for setups where a browser source
+//! costs too much. Frames come at a fixed rate whether or not anything
+//! changed, as video encoders expect.
+//!
+//! Frames are written as raw RGBA to standard output, a file, a named pipe
+//! or a program such as ffmpeg, or as PNG with alpha, either a numbered
+//! file each in a directory or one after the other to the same places.
+//! Rendering runs on a thread of its own, drawing and compressing frames
+//! would hold up the other outputs otherwise.
+
+use crate::common::Timestamp;
+use crate::config::{RenderConfig, RenderFormat};
+use crate::metrics;
+use crate::output::{Format, Tagged};
+use crate::render::{self, Overlay, Renderer, Scene};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::task;
+
+const REOPEN_DELAY: Duration = Duration::from_secs(1);
+
+/// Falling behind by more than this skips ahead rather than catching up.
+const MAX_LAG: Duration = Duration::from_secs(1);
+
+/// Where frames go.
+enum Output {
+    Stdout(io::Stdout),
+    File(File),
+    Command(Child),
+    /// A file per frame.
+    Directory(PathBuf),
+}
+
+impl Output {
+    fn open(config: &RenderConfig) -> io::Result<Self> {
+        if let Some((program, args)) = config.command.split_first() {
+            let child = Command::new(program)
+                .args(args)
+                .stdin(Stdio::piped())
+                .spawn()?;
+
+            return Ok(Output::Command(child));
+        }
+
+        match config.path {
+            Some(ref path) if config.format == RenderFormat::Png => {
+                fs::create_dir_all(path)?;
+                Ok(Output::Directory(path.clone()))
+            }
+            // Waits for a reader when `path` is a named pipe.
+            Some(ref path) => Ok(Output::File(
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)?,
+            )),
+            None => Ok(Output::Stdout(io::stdout())),
+        }
+    }
+
+    fn write(&mut self, number: u64, frame: &[u8]) -> io::Result<()> {
+        match self {
+            Output::Stdout(stdout) => stdout.write_all(frame),
+            Output::File(file) => file.write_all(frame),
+            Output::Command(child) => match child.stdin {
+                Some(ref mut stdin) => stdin.write_all(frame),
+                None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            },
+            Output::Directory(path) => fs::write(path.join(format!("{:06}.png", number)), frame),
+        }
+    }
+}
+
+impl Drop for Output {
+    /// Lets the program finish its output, encoders write trailers at the end.
+    fn drop(&mut self) {
+        if let Output::Command(child) = self {
+            drop(child.stdin.take());
+            let _ = child.wait();
+        }
+    }
+}
+
+fn describe(config: &RenderConfig) -> String {
+    match (config.command.first(), &config.path) {
+        (Some(program), _) => program.clone(),
+        (None, Some(path)) => path.display().to_string(),
+        (None, None) => "stdout".to_string(),
+    }
+}
+
+pub async fn run(config: RenderConfig, events: broadcast::Receiver<Tagged>) {
+    if let Err(e) = task::spawn_blocking(move || render(config, events)).await {
+        eprintln!("Rendering stopped: {}", e);
+    }
+}
+
+fn render(config: RenderConfig, mut events: broadcast::Receiver<Tagged>) {
+    let mut renderer = match Renderer::new(&config.theme, config.width, config.height) {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            let font = config.theme.font.unwrap_or_default();
+            eprintln!("Could not load font {}: {}", font.display(), e);
+            return;
+        }
+    };
+
+    // Standard output and programs don't come back.
+    let reopens = config.command.is_empty() && config.path.is_some();
+
+    loop {
+        match Output::open(&config) {
+            Ok(output) => match write(&config, &mut renderer, output, &mut events) {
+                Ok(()) => return,
+                Err(e) => {
+                    metrics::WRITE_ERRORS.inc(&["render"]);
+                    eprintln!("Could not write frames to {}: {}", describe(&config), e);
+                }
+            },
+            Err(e) => eprintln!("Could not open {}: {}", describe(&config), e),
+        }
+
+        if !reopens {
+            return;
+        }
+
+        thread::sleep(REOPEN_DELAY);
+        events = events.resubscribe();
+    }
+}
+
+/// Writes a frame every tick until keydisp stops broadcasting, or writing
+/// fails. Frames only get drawn again when what is shown changed.
+fn write(
+    config: &RenderConfig,
+    renderer: &mut Renderer,
+    mut output: Output,
+    events: &mut broadcast::Receiver<Tagged>,
+) -> io::Result<()> {
+    let interval = Duration::from_secs(1) / config.fps.max(1);
+    let mut overlay = Overlay::new(&config.theme);
+    let mut shown: Option<(Scene, Vec<u8>)> = None;
+    let mut next = Instant::now();
+
+    for number in 0.. {
+        loop {
+            match events.try_recv() {
+                Ok(tagged) if tagged.is_for(&config.devices) => {
+                    if let Some(text) = Format::Text.encode(&tagged) {
+                        overlay.push(&text, tagged.time);
+                    }
+                }
+                Ok(_) => (),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(skipped)) => {
+                    metrics::DROPPED_EVENTS.add(&["render"], skipped);
+                }
+                Err(TryRecvError::Closed) => return Ok(()),
+            }
+        }
+
+        let scene = overlay.scene(Timestamp::now());
+
+        let frame = match shown {
+            Some((ref shown_scene, ref frame)) if *shown_scene == scene => frame,
+            _ => {
+                let drawn = renderer.draw(&scene);
+                let frame = match config.format {
+                    RenderFormat::Raw => drawn.pixels.clone(),
+                    RenderFormat::Png => render::encode_png(drawn),
+                };
+
+                &shown.insert((scene, frame)).1
+            }
+        };
+
+        output.write(number, frame)?;
+
+        next += interval;
+        let now = Instant::now();
+
+        if next > now {
+            thread::sleep(next - now);
+        } else if now - next > MAX_LAG {
+            eprintln!(
+                "Rendering can't keep up with {} frames per second",
+                config.fps
+            );
+            next = now;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,752 @@
+//! A sink posting batches of JSON to a URL, for tools that would rather
+//! receive HTTP requests than hold a websocket open.
+//!
+//! Events are collected for `batch_ms` and posted together as
+//!
+//! ```text
+//! {"sent_at": 1700000000000, "time": 81234567, "events": [...]}
+//! ```
+//!
+//! where `sent_at` is milliseconds since the Unix epoch and `time` is the
+//! clock events are stamped with, so receivers can place events in wall time.
+//! With `payload = "sessions"` a summary is sent in place of `events` for
+//! each stretch of typing, once `session_gap_ms` passes without keyboard
+//! input:
+//!
+//! ```text
+//! {"start": 80012345, "end": 81234567, "keys": 42, "chars": 37,
+//!  "shortcuts": {"ctrl+c": 2, "ctrl+shift+t": 1}}
+//! ```
+//!
+//! With a `secret`, each body is signed with HMAC-SHA256, sent hex encoded
+//! as `X-Keydisp-Signature: sha256=...`. Requests that fail or get a 5xx or
+//! 429 answer are tried again with growing delays, other answers are final.
+
+use crate::common::{Key, KeyState, ModifierState, Timestamp};
+use crate::config::{WebhookConfig, WebhookPayload};
+use crate::metrics;
+use crate::output::{Format, Output, Tagged};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, sleep_until, timeout, Instant};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::TlsConnector;
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Batches waiting to be posted while earlier ones are retried, further
+/// batches are dropped.
+const MAX_QUEUED: usize = 16;
+
+/// Longest response head read before giving up on the server.
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+#[derive(Debug)]
+enum WebhookError {
+    Io(io::Error),
+    Timeout,
+    /// The server answered, but not with 2xx.
+    Status(u16),
+    Protocol(String),
+}
+
+impl WebhookError {
+    /// Whether trying again could go differently.
+    fn is_transient(&self) -> bool {
+        match self {
+            WebhookError::Io(_) | WebhookError::Timeout => true,
+            WebhookError::Status(status) => *status >= 500 || *status == 429,
+            WebhookError::Protocol(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebhookError::Io(e) => write!(f, "{}", e),
+            WebhookError::Timeout => write!(f, "timed out"),
+            WebhookError::Status(status) => write!(f, "answered {}", status),
+            WebhookError::Protocol(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for WebhookError {
+    fn from(e: io::Error) -> Self {
+        WebhookError::Io(e)
+    }
+}
+
+/// Where requests go, parsed from the configured URL.
+struct Target {
+    tls: Option<TlsConnector>,
+    host: String,
+    port: u16,
+    /// The host and port as written, for the `Host` header.
+    authority: String,
+    path: String,
+}
+
+impl Target {
+    fn new(config: &WebhookConfig) -> Result<Self, String> {
+        let (https, rest) = if let Some(rest) = config.url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = config.url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err("only http:// and https:// URLs are supported".to_string());
+        };
+
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, "/".to_string()),
+        };
+        let path = path.split('#').next().unwrap_or("/").to_string();
+
+        // IPv6 addresses are written in brackets, their colons aren't ports.
+        let (host, port) = match authority.rfind(':') {
+            Some(i) if !authority[i..].contains(']') => {
+                let port = authority[i + 1..]
+                    .parse()
+                    .map_err(|_| format!("{} is not a port", &authority[i + 1..]))?;
+                (&authority[..i], port)
+            }
+            _ if https => (authority, 443),
+            _ => (authority, 80),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+
+        if host.is_empty() {
+            return Err("the URL has no host".to_string());
+        }
+
+        let tls = if https {
+            Some(connector(config.ca_file.as_deref())?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+            authority: authority.to_string(),
+            path,
+        })
+    }
+}
+
+/// Trusts the certificates in `ca_file`, or the system's, as kept by the
+/// platform's certificate store.
+fn connector(ca_file: Option<&Path>) -> Result<TlsConnector, String> {
+    let mut config = ClientConfig::new();
+
+    match ca_file {
+        Some(path) => {
+            let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            match config.root_store.add_pem_file(&mut BufReader::new(file)) {
+                Ok((valid, _)) if valid > 0 => (),
+                _ => return Err(format!("{} has no usable certificates", path.display())),
+            }
+        }
+        // A few certificates that can't be read don't stop the rest from being used.
+        None => match rustls_native_certs::load_native_certs() {
+            Ok(store) | Err((Some(store), _)) if !store.is_empty() => config.root_store = store,
+            _ => return Err("no system certificates found, set ca_file".to_string()),
+        },
+    }
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// HMAC-SHA256 as in RFC 2104, hex encoded.
+fn sign(secret: &[u8], body: &[u8]) -> String {
+    const BLOCK: usize = 64;
+
+    let mut key = [0; BLOCK];
+
+    if secret.len() > BLOCK {
+        key[..32].copy_from_slice(&Sha256::digest(secret));
+    } else {
+        key[..secret.len()].copy_from_slice(secret);
+    }
+
+    let pad = |byte: u8| -> Vec<u8> { key.iter().map(|k| k ^ byte).collect() };
+
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    inner.update(body);
+
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+
+    outer
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Posts `body` once, with a fresh connection.
+async fn post(target: &Target, secret: Option<&str>, body: &[u8]) -> Result<(), WebhookError> {
+    let stream = TcpStream::connect((target.host.as_str(), target.port)).await?;
+    stream.set_nodelay(true)?;
+
+    let mut stream: Box<dyn Connection> = match target.tls {
+        Some(ref connector) => {
+            let name = DNSNameRef::try_from_ascii_str(&target.host).map_err(|_| {
+                WebhookError::Protocol(format!("{} is not a DNS name", target.host))
+            })?;
+            Box::new(connector.connect(name, stream).await?)
+        }
+        None => Box::new(stream),
+    };
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: keydisp/{}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        target.path,
+        target.authority,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+
+    if let Some(secret) = secret {
+        request.push_str(&format!(
+            "X-Keydisp-Signature: sha256={}\r\n",
+            sign(secret.as_bytes(), body)
+        ));
+    }
+
+    request.push_str("\r\n");
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let status = read_status(&mut stream).await?;
+
+    if (200..300).contains(&status) {
+        Ok(())
+    } else {
+        Err(WebhookError::Status(status))
+    }
+}
+
+/// Reads up to the end of the response head, the body isn't of interest.
+async fn read_status(stream: &mut Box<dyn Connection>) -> Result<u16, WebhookError> {
+    let mut buf = Vec::new();
+
+    loop {
+        let mut chunk = [0; 1024];
+        let n = stream.read(&mut chunk).await?;
+
+        // Like any other connection failure, the server may well answer next time.
+        if n == 0 {
+            return Err(WebhookError::Io(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed without an answer",
+            )));
+        }
+
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut response = httparse::Response::new(&mut headers);
+
+        match response.parse(&buf) {
+            Ok(httparse::Status::Complete(_)) => {
+                return response
+                    .code
+                    .ok_or_else(|| WebhookError::Protocol("no status code".to_string()));
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_RESPONSE_HEAD => continue,
+            Ok(httparse::Status::Partial) => {
+                return Err(WebhookError::Protocol("response head too long".to_string()))
+            }
+            Err(e) => return Err(WebhookError::Protocol(format!("bad response: {}", e))),
+        }
+    }
+}
+
+/// Posts batches in order, retrying each until it goes through or runs out
+/// of attempts.
+async fn deliver(config: WebhookConfig, target: Target, mut batches: mpsc::Receiver<Vec<String>>) {
+    let key = match config.payload {
+        WebhookPayload::Events => "events",
+        WebhookPayload::Sessions => "sessions",
+    };
+    let request_timeout = Duration::from_millis(config.timeout_ms);
+
+    while let Some(batch) = batches.recv().await {
+        let sent_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let body = format!(
+            "{{\"sent_at\":{},\"time\":{},\"{}\":[{}]}}",
+            sent_at,
+            Timestamp::now().as_micros(),
+            key,
+            batch.join(",")
+        );
+        let body = body.as_bytes();
+
+        let mut backoff = MIN_BACKOFF;
+        let mut attempt = 1;
+
+        loop {
+            let result = match timeout(
+                request_timeout,
+                post(&target, config.secret.as_deref(), body),
+            )
+            .await
+            {
+                Ok(result) => result,
+                Err(_) => Err(WebhookError::Timeout),
+            };
+
+            let e = match result {
+                Ok(()) => break,
+                Err(e) => e,
+            };
+
+            metrics::WRITE_ERRORS.inc(&["webhook"]);
+
+            if !e.is_transient() || attempt >= config.max_attempts {
+                metrics::DROPPED_EVENTS.add(&["webhook"], batch.len() as u64);
+                eprintln!(
+                    "Webhook {} {}, dropped {} {}",
+                    config.url,
+                    e,
+                    batch.len(),
+                    key
+                );
+                break;
+            }
+
+            eprintln!(
+                "Webhook {} {}, trying again in {}s",
+                config.url,
+                e,
+                backoff.as_secs()
+            );
+
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+}
+
+/// Keyboard activity between two pauses of at least `session_gap_ms`.
+#[derive(Serialize)]
+struct Session {
+    start: Timestamp,
+    end: Timestamp,
+    keys: u64,
+    chars: u64,
+    shortcuts: BTreeMap<String, u64>,
+}
+
+impl Session {
+    fn new(time: Timestamp) -> Self {
+        Self {
+            start: time,
+            end: time,
+            keys: 0,
+            chars: 0,
+            shortcuts: BTreeMap::new(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        serde_json::to_string(self).expect("session to serialize.")
+    }
+}
+
+/// Builds typing sessions from key events. Every key sends a release,
+/// even the ones only shown through the characters they type, so key
+/// presses and shortcuts are counted on release.
+struct Sessions {
+    modifiers: ModifierState,
+    current: Option<Session>,
+}
+
+impl Sessions {
+    /// Counts `tagged` into the current session, starting one if needed.
+    /// Returns whether it was keyboard input.
+    fn add(&mut self, tagged: &Tagged) -> bool {
+        let session = match tagged.output {
+            Output::Key { .. } | Output::Release { .. } | Output::Char { .. } => self
+                .current
+                .get_or_insert_with(|| Session::new(tagged.time)),
+            _ => return false,
+        };
+
+        session.end = tagged.time;
+
+        match tagged.output {
+            Output::Key { key, .. } => {
+                self.modifiers.update(key, KeyState::Pressed);
+            }
+            Output::Release { key } => {
+                if !is_modifier(key) {
+                    session.keys += 1;
+
+                    if let Some(shortcut) = shortcut(&self.modifiers, key) {
+                        *session.shortcuts.entry(shortcut).or_insert(0) += 1;
+                    }
+                }
+
+                self.modifiers.update(key, KeyState::Released);
+            }
+            Output::Char { .. } => session.chars += 1,
+            _ => (),
+        }
+
+        true
+    }
+}
+
+fn is_modifier(key: Key) -> bool {
+    matches!(
+        key,
+        Key::LeftShift
+            | Key::RightShift
+            | Key::LeftControl
+            | Key::RightControl
+            | Key::LeftAlt
+            | Key::RightAlt
+            | Key::AltGr
+            | Key::LeftMeta
+            | Key::RightMeta
+    )
+}
+
+/// `ctrl+shift+t` for `key` with the held modifiers, if they make it a
+/// shortcut rather than typing. Sides aren't told apart.
+fn shortcut(modifiers: &ModifierState, key: Key) -> Option<String> {
+    let mut parts = Vec::new();
+
+    for held in modifiers.held.keys() {
+        let name = match held {
+            Key::LeftControl | Key::RightControl => "ctrl",
+            Key::LeftAlt | Key::RightAlt => "alt",
+            Key::LeftShift | Key::RightShift => "shift",
+            Key::LeftMeta | Key::RightMeta => "meta",
+            _ => continue,
+        };
+
+        if !parts.contains(&name) {
+            parts.push(name);
+        }
+    }
+
+    // Shift alone, or AltGr, types characters.
+    if parts.iter().all(|name| *name == "shift") {
+        return None;
+    }
+
+    parts.push(key.name());
+    Some(parts.join("+"))
+}
+
+/// Hands `batch` to the delivery task, emptying it.
+fn flush(batch: &mut Vec<String>, queue: &mpsc::Sender<Vec<String>>, url: &str) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let full = std::mem::take(batch);
+    let len = full.len() as u64;
+
+    if queue.try_send(full).is_err() {
+        metrics::DROPPED_EVENTS.add(&["webhook"], len);
+        eprintln!("Webhook {} is behind, dropped {} items", url, len);
+    }
+}
+
+pub async fn run(config: WebhookConfig, mut events: broadcast::Receiver<Tagged>) {
+    let target = match Target::new(&config) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("Webhook {:?} not used: {}", config.url, e);
+            return;
+        }
+    };
+
+    let (queue, batches) = mpsc::channel(MAX_QUEUED);
+    tokio::spawn(deliver(config.clone(), target, batches));
+
+    let batch_window = Duration::from_millis(config.batch_ms);
+    let session_gap = Duration::from_millis(config.session_gap_ms);
+    let max_batch = config.max_batch.max(1);
+
+    let mut batch = Vec::new();
+    let mut flush_at = Instant::now();
+    let mut sessions = Sessions {
+        modifiers: ModifierState::default(),
+        current: None,
+    };
+    let mut session_ends = Instant::now();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let tagged = match event {
+                    Ok(tagged) => tagged,
+                    Err(RecvError::Lagged(skipped)) => {
+                        metrics::DROPPED_EVENTS.add(&["webhook"], skipped);
+                        eprintln!("Webhook output too slow, skipped {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+
+                if !tagged.is_for(&config.devices)
+                    || !(config.types.is_empty() || config.types.iter().any(|t| t == tagged.output.kind()))
+                {
+                    continue;
+                }
+
+                match config.payload {
+                    WebhookPayload::Events => {
+                        if batch.is_empty() {
+                            flush_at = Instant::now() + batch_window;
+                        }
+
+                        batch.extend(Format::Json.encode(&tagged));
+                    }
+                    WebhookPayload::Sessions => {
+                        if sessions.add(&tagged) {
+                            session_ends = Instant::now() + session_gap;
+                        }
+                    }
+                }
+
+                if batch.len() >= max_batch {
+                    flush(&mut batch, &queue, &config.url);
+                }
+            }
+            _ = sleep_until(flush_at), if !batch.is_empty() => {
+                flush(&mut batch, &queue, &config.url);
+            }
+            _ = sleep_until(session_ends), if sessions.current.is_some() => {
+                if let Some(session) = sessions.current.take() {
+                    if batch.is_empty() {
+                        flush_at = Instant::now() + batch_window;
+                    }
+
+                    batch.push(session.encode());
+                }
+
+                if batch.len() >= max_batch {
+                    flush(&mut batch, &queue, &config.url);
+                }
+            }
+        }
+    }
+
+    // Whatever was collected still goes out before keydisp stops.
+    if let Some(session) = sessions.current.take() {
+        batch.push(session.encode());
+    }
+
+    flush(&mut batch, &queue, &config.url);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    use tokio::net::TcpListener;
+
+    /// A request as the stand-in received it.
+    struct Received {
+        head: String,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines().find_map(|line| {
+                let (key, value) = line.split_once(": ")?;
+                if key.eq_ignore_ascii_case(name) {
+                    Some(value)
+                } else {
+                    None
+                }
+            })
+        }
+
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+    }
+
+    /// A local stand-in for the receiving end, answering each request with
+    /// the next of `answers`. `None` closes the connection without answering.
+    async fn stand_in(answers: Vec<Option<u16>>) -> (u16, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received, requests) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            for answer in answers {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = Vec::new();
+
+                let head_len = loop {
+                    let mut chunk = [0; 1024];
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+
+                    if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break i + 4;
+                    }
+                };
+
+                let head = String::from_utf8(buf[..head_len].to_vec()).unwrap();
+                let mut request = Received {
+                    head,
+                    body: buf[head_len..].to_vec(),
+                };
+                let len: usize = request.header("Content-Length").unwrap().parse().unwrap();
+
+                while request.body.len() < len {
+                    let mut chunk = [0; 1024];
+                    let n = stream.read(&mut chunk).await.unwrap();
+                    request.body.extend_from_slice(&chunk[..n]);
+                }
+
+                if let Some(status) = answer {
+                    let response =
+                        format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                }
+
+                received.send(request).unwrap();
+            }
+        });
+
+        (port, requests)
+    }
+
+    fn start(config: WebhookConfig) -> broadcast::Sender<Tagged> {
+        let (events, receiver) = broadcast::channel(16);
+        tokio::spawn(run(config, receiver));
+        events
+    }
+
+    fn config(port: u16) -> WebhookConfig {
+        WebhookConfig {
+            url: format!("http://127.0.0.1:{}/hook?from=keydisp", port),
+            batch_ms: 50,
+            ..WebhookConfig::default()
+        }
+    }
+
+    fn char(text: char) -> Tagged {
+        Tagged {
+            output: Output::Char { text },
+            device: None,
+            injected: false,
+            marked: false,
+            time: Timestamp::from_micros(1),
+        }
+    }
+
+    async fn next(requests: &mut mpsc::UnboundedReceiver<Received>) -> Received {
+        timeout(Duration::from_secs(5), requests.recv())
+            .await
+            .expect("a request")
+            .unwrap()
+    }
+
+    #[test]
+    fn signs_as_rfc_4231() {
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn posts_signed_batches() {
+        let (port, mut requests) = stand_in(vec![Some(204)]).await;
+        let events = start(WebhookConfig {
+            secret: Some("secret".to_string()),
+            ..config(port)
+        });
+
+        events.send(char('a')).unwrap();
+        events.send(char('b')).unwrap();
+
+        let request = next(&mut requests).await;
+        assert!(request
+            .head
+            .starts_with("POST /hook?from=keydisp HTTP/1.1\r\n"));
+        assert_eq!(request.header("Content-Type"), Some("application/json"));
+        assert_eq!(
+            request.header("X-Keydisp-Signature"),
+            Some(format!("sha256={}", sign(b"secret", &request.body)).as_str())
+        );
+
+        let body = request.json();
+        assert_eq!(body["events"][0]["text"], "a");
+        assert_eq!(body["events"][1]["text"], "b");
+        assert!(body["sent_at"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn connections_closed_without_an_answer_are_tried_again() {
+        let (port, mut requests) = stand_in(vec![None, Some(200)]).await;
+        let events = start(config(port));
+
+        events.send(char('a')).unwrap();
+
+        let first = next(&mut requests).await;
+        let second = next(&mut requests).await;
+        assert_eq!(first.json()["events"], second.json()["events"]);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_final() {
+        let (port, mut requests) = stand_in(vec![Some(400), Some(200)]).await;
+        let events = start(config(port));
+
+        events.send(char('a')).unwrap();
+        assert_eq!(next(&mut requests).await.json()["events"][0]["text"], "a");
+
+        events.send(char('b')).unwrap();
+        assert_eq!(next(&mut requests).await.json()["events"][0]["text"], "b");
+    }
+}
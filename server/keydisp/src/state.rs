@@ -0,0 +1,150 @@
+use crate::config::{Config, FilterMode};
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+pub type SharedState = Arc<Mutex<State>>;
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Stats {
+    /// Input events seen by the hook.
+    pub events: u64,
+    /// Characters broadcast to clients.
+    pub sent: u64,
+    /// Currently connected websocket clients.
+    pub clients: usize,
+}
+
+/// Runtime state shared between the hook, the server threads and the control socket.
+#[derive(Debug)]
+pub struct State {
+    pub config: Config,
+    pub input_window: Option<usize>,
+    pub paused: bool,
+    pub filter_mode: FilterMode,
+    /// Which window has focus can't be told, so input from every window is
+    /// displayed whatever the config says.
+    pub focus_unknown: bool,
+    pub stats: Stats,
+    /// Told whenever the selected window, pausing or the filter mode changes.
+    changed: watch::Sender<()>,
+}
+
+impl State {
+    pub fn new(config: Config) -> Self {
+        Self {
+            input_window: None,
+            paused: false,
+            filter_mode: config.filter_mode,
+            focus_unknown: false,
+            stats: Stats::default(),
+            changed: watch::Sender::new(()),
+            config,
+        }
+    }
+
+    pub fn shared(self) -> SharedState {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Notified of every change to the selected window, pausing or the filter
+    /// mode, made through the setters below.
+    pub fn watch(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub fn select_window(&mut self, window: Option<usize>) {
+        self.input_window = window;
+        self.changed.send_replace(());
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.changed.send_replace(());
+    }
+
+    /// Has no effect once focus is unknown, only displaying everything works then.
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        if !self.focus_unknown {
+            self.filter_mode = mode;
+            self.changed.send_replace(());
+        }
+    }
+
+    /// Displays input from every window from now on, for want of focus tracking.
+    pub fn set_focus_unknown(&mut self) {
+        self.focus_unknown = true;
+        self.filter_mode = FilterMode::Everything;
+        self.changed.send_replace(());
+    }
+
+    /// Applies a freshly loaded config. The bind address is only read at startup.
+    pub fn reload(&mut self, config: Config) {
+        self.set_filter_mode(config.filter_mode);
+        self.config = config;
+    }
+
+    pub fn should_display(&self, window: usize) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        match self.filter_mode {
+            FilterMode::Window => self.input_window == Some(window),
+            FilterMode::Everything => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reload_applies_the_filter_mode() {
+        let mut state = State::new(Config::default());
+        state.filter_mode = FilterMode::Everything;
+
+        state.reload(Config::default());
+        assert_eq!(state.filter_mode, FilterMode::Window);
+    }
+
+    #[test]
+    fn reload_keeps_displaying_everything_without_focus_tracking() {
+        let mut state = State::new(Config::default());
+        state.set_focus_unknown();
+
+        state.reload(Config::default());
+        assert_eq!(state.filter_mode, FilterMode::Everything);
+        assert!(state.should_display(0));
+    }
+
+    #[test]
+    fn setters_notify_watchers() {
+        let mut state = State::new(Config::default());
+        let mut changed = state.watch();
+        assert!(!changed.has_changed().unwrap());
+
+        state.select_window(Some(3));
+        assert!(changed.has_changed().unwrap());
+        changed.mark_unchanged();
+
+        state.set_paused(true);
+        assert!(changed.has_changed().unwrap());
+        changed.mark_unchanged();
+
+        state.set_filter_mode(FilterMode::Everything);
+        assert!(changed.has_changed().unwrap());
+    }
+
+    #[test]
+    fn filter_mode_stays_everything_without_focus_tracking() {
+        let mut state = State::new(Config::default());
+        state.set_focus_unknown();
+
+        state.set_filter_mode(FilterMode::Window);
+        assert_eq!(state.filter_mode, FilterMode::Everything);
+        assert!(state.should_display(0));
+    }
+}
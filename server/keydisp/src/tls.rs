@@ -0,0 +1,375 @@
+//! TLS termination for `wss://` connections.
+//!
+//! The certificate chain and key are read from PEM files. When they don't
+//! exist yet keydisp can generate a self-signed pair, its SHA-256 fingerprint
+//! is printed at startup so overlays on other machines can pin it.
+
+use crate::config::TlsConfig;
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use rcgen::{CertificateParams, DnType, RcgenError, SanType};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig, TLSError};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// An accepted connection, with or without TLS.
+pub enum ServerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(io::Error),
+    Pem(&'static str),
+    Rustls(TLSError),
+    Generate(RcgenError),
+    /// One of the certificate and key exists, generating would overwrite it.
+    Unpaired(PathBuf),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TlsError::Io(e) => write!(f, "{}", e),
+            TlsError::Pem(e) => write!(f, "{}", e),
+            TlsError::Rustls(e) => write!(f, "{}", e),
+            TlsError::Generate(e) => write!(f, "could not generate certificate: {}", e),
+            TlsError::Unpaired(missing) => write!(
+                f,
+                "{} is missing, not generating a certificate over the other half of the pair",
+                missing.display()
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(e: io::Error) -> Self {
+        TlsError::Io(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct Tls {
+    config: Arc<ServerConfig>,
+    /// SHA-256 of the leaf certificate, formatted the way browsers display it.
+    pub fingerprint: String,
+}
+
+impl Tls {
+    pub fn load(config: &TlsConfig) -> Result<Tls, TlsError> {
+        if config.generate_self_signed {
+            match (config.cert.exists(), config.key.exists()) {
+                (false, false) => generate_self_signed(config)?,
+                (true, false) => return Err(TlsError::Unpaired(config.key.clone())),
+                (false, true) => return Err(TlsError::Unpaired(config.cert.clone())),
+                (true, true) => (),
+            }
+        }
+
+        let certs = pemfile::certs(&mut BufReader::new(File::open(&config.cert)?))
+            .map_err(|_| TlsError::Pem("invalid certificate file"))?;
+        let key = load_key(&config.key)?;
+
+        let fingerprint = match certs.first() {
+            Some(cert) => fingerprint(cert),
+            None => return Err(TlsError::Pem("no certificates in certificate file")),
+        };
+
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config
+            .set_single_cert(certs, key)
+            .map_err(TlsError::Rustls)?;
+
+        Ok(Tls {
+            config: Arc::new(server_config),
+            fingerprint,
+        })
+    }
+
+    /// Performs the TLS handshake on an accepted connection, if TLS is enabled.
+    pub async fn wrap(tls: Option<&Tls>, stream: TcpStream) -> io::Result<ServerStream> {
+        match tls {
+            Some(tls) => {
+                let acceptor = TlsAcceptor::from(tls.config.clone());
+                Ok(ServerStream::Tls(Box::new(acceptor.accept(stream).await?)))
+            }
+            None => Ok(ServerStream::Plain(stream)),
+        }
+    }
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            ServerStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            ServerStream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ServerStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            ServerStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, TlsError> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| TlsError::Pem("invalid private key file"))?;
+
+    if let Some(key) = pkcs8.into_iter().next() {
+        return Ok(key);
+    }
+
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?))
+        .map_err(|_| TlsError::Pem("invalid private key file"))?;
+
+    rsa.into_iter()
+        .next()
+        .ok_or(TlsError::Pem("no private key in key file"))
+}
+
+fn generate_self_signed(config: &TlsConfig) -> Result<(), TlsError> {
+    let mut params = CertificateParams::default();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "keydisp self-signed");
+    params.subject_alt_names = config
+        .hostnames
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
+
+    let cert = rcgen::Certificate::from_params(params).map_err(TlsError::Generate)?;
+
+    fs::write(&config.cert, cert.serialize_pem().map_err(TlsError::Generate)?)?;
+    write_private(&config.key, cert.serialize_private_key_pem().as_bytes())?;
+
+    eprintln!("Generated self-signed certificate {}", config.cert.display());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    File::create(path)?.write_all(contents)
+}
+
+fn fingerprint(cert: &Certificate) -> String {
+    Sha256::digest(&cert.0)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rcgen::{BasicConstraints, IsCa};
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+
+    fn config(dir: &TempDir, generate_self_signed: bool) -> TlsConfig {
+        TlsConfig {
+            cert: dir.path().join("keydisp.crt"),
+            key: dir.path().join("keydisp.key"),
+            generate_self_signed,
+            hostnames: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+        }
+    }
+
+    /// A CA, and a certificate for `localhost` it signed written to `config`.
+    fn issue(config: &TlsConfig) -> rcgen::Certificate {
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "keydisp test CA");
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+
+        let params = CertificateParams::new(vec!["localhost".to_string()]);
+        let leaf = rcgen::Certificate::from_params(params).unwrap();
+
+        let chain = leaf.serialize_pem_with_signer(&ca).unwrap() + &ca.serialize_pem().unwrap();
+        fs::write(&config.cert, chain).unwrap();
+        fs::write(&config.key, leaf.serialize_private_key_pem()).unwrap();
+
+        ca
+    }
+
+    /// Connects through `tls` trusting `root`, and echoes a line back.
+    async fn handshake(tls: Tls, root: &[u8]) -> Result<String, io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            if let Ok(mut stream) = Tls::wrap(Some(&tls), stream).await {
+                let mut buf = [0; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let mut client_config = ClientConfig::new();
+        client_config
+            .root_store
+            .add(&Certificate(root.to_vec()))
+            .unwrap();
+
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let name = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = TcpStream::connect(addr).await?;
+        let mut stream = connector.connect(name, stream).await?;
+
+        stream.write_all(b"hello").await?;
+        let mut echoed = String::new();
+        stream.read_to_string(&mut echoed).await.ok();
+        Ok(echoed)
+    }
+
+    #[tokio::test]
+    async fn serves_a_certificate_signed_by_a_local_ca() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, false);
+        let ca = issue(&config);
+
+        let tls = Tls::load(&config).unwrap();
+        let chain = File::open(&config.cert).unwrap();
+        let leaf = pemfile::certs(&mut BufReader::new(chain)).unwrap();
+        assert_eq!(tls.fingerprint, fingerprint(&leaf[0]));
+
+        let echoed = handshake(tls, &ca.serialize_der().unwrap()).await.unwrap();
+        assert_eq!(echoed, "hello");
+    }
+
+    #[tokio::test]
+    async fn clients_refuse_other_issuers() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, false);
+        issue(&config);
+
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = Tls::load(&config).unwrap();
+
+        let result = handshake(tls, &other.serialize_der().unwrap()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn generated_certificates_are_kept() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, true);
+
+        let generated = Tls::load(&config).unwrap();
+        assert_eq!(generated.fingerprint.len(), 32 * 3 - 1);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&config.key).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reloaded = Tls::load(&config).unwrap();
+        assert_eq!(reloaded.fingerprint, generated.fingerprint);
+
+        // Self-signed, so it is its own root.
+        let cert = File::open(&config.cert).unwrap();
+        let cert = pemfile::certs(&mut BufReader::new(cert)).unwrap();
+        let echoed = handshake(reloaded, &cert[0].0).await.unwrap();
+        assert_eq!(echoed, "hello");
+    }
+
+    #[test]
+    fn half_a_pair_is_not_overwritten() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, true);
+        issue(&config);
+        let cert = fs::read(&config.cert).unwrap();
+
+        let missing = |config: &TlsConfig| match Tls::load(config) {
+            Err(TlsError::Unpaired(missing)) => Some(missing),
+            _ => None,
+        };
+
+        fs::remove_file(&config.key).unwrap();
+        assert_eq!(missing(&config), Some(config.key.clone()));
+        assert_eq!(fs::read(&config.cert).unwrap(), cert);
+
+        fs::write(&config.key, b"kept").unwrap();
+        fs::remove_file(&config.cert).unwrap();
+        assert_eq!(missing(&config), Some(config.cert.clone()));
+        assert_eq!(fs::read(&config.key).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn missing_files_are_errors_without_generating() {
+        let dir = TempDir::new().unwrap();
+        let config = config(&dir, false);
+
+        assert!(matches!(Tls::load(&config), Err(TlsError::Io(_))));
+        assert!(!config.cert.exists());
+    }
+}
@@ -1,95 +1,189 @@
-use common::{Event, KeyState};
+pub mod synthetic;
 
-use scancode::Scancode;
+use crate::common::{Event, Input, Key, KeyState, Locks, ModifierState, MouseButton, Timestamp};
 
 use std::cell::RefCell;
-use std::char::{from_u32_unchecked, REPLACEMENT_CHARACTER};
-use std::collections::vec_deque::VecDeque;
 use std::io::Error;
+use std::time::Duration;
 use std::{mem, ptr};
 
-use winapi::shared::minwindef::{BYTE, UINT};
-use winapi::shared::windef::{HHOOK, HWND};
-use winapi::um::winuser;
+use winapi::shared::minwindef::{BYTE, DWORD, HIWORD, UINT};
+use winapi::shared::windef::{HHOOK, HWND, POINT};
+use winapi::um::sysinfoapi::GetTickCount;
 use winapi::um::winuser::{
-    CallNextHookEx, GetForegroundWindow, GetKeyState, GetKeyboardState, GetMessageW,
-    SetWindowsHookExW, ToUnicode, UnhookWindowsHookEx,
+    CallNextHookEx, GetForegroundWindow, GetKeyState, GetKeyboardLayout, GetMessageW,
+    GetWindowThreadProcessId, ScreenToClient, SetWindowsHookExW, ToUnicodeEx,
+    UnhookWindowsHookEx,
 };
-use winapi::um::winuser::{KBDLLHOOKSTRUCT, WH_KEYBOARD_LL, WM_KEYDOWN, WM_KEYUP};
+use winapi::um::winuser::{
+    KBDLLHOOKSTRUCT, LLKHF_EXTENDED, LLKHF_INJECTED, VK_CAPITAL, VK_CONTROL, VK_LCONTROL, VK_LMENU,
+    VK_LSHIFT, VK_MENU, VK_NUMLOCK, VK_PACKET, VK_RCONTROL, VK_RETURN, VK_RMENU, VK_RSHIFT,
+    VK_SCROLL, VK_SHIFT, WH_KEYBOARD_LL, WM_KEYDOWN, WM_KEYUP, WM_SYSKEYDOWN, WM_SYSKEYUP,
+};
+use winapi::um::winuser::{
+    LLMHF_INJECTED, MSLLHOOKSTRUCT, WHEEL_DELTA, WH_MOUSE_LL, WM_LBUTTONDOWN, WM_LBUTTONUP, WM_MBUTTONDOWN,
+    WM_MBUTTONUP, WM_MOUSEHWHEEL, WM_MOUSEMOVE, WM_MOUSEWHEEL, WM_RBUTTONDOWN, WM_RBUTTONUP,
+    WM_XBUTTONDOWN, WM_XBUTTONUP, XBUTTON1,
+};
+
+pub fn get_fg_window() -> usize {
+    unsafe { GetForegroundWindow() as usize }
+}
+
+/// A position on the screen relative to the top left corner of `window`'s
+/// client area.
+pub fn window_position(window: usize, x: i32, y: i32) -> Option<(i32, i32)> {
+    let mut point = POINT { x, y };
+
+    if unsafe { ScreenToClient(window as HWND, &mut point) } == 0 {
+        return None;
+    }
+
+    Some((point.x, point.y))
+}
+
+/// Reads which locks are engaged, the low bit of a key's state is its toggle.
+pub fn get_lock_state() -> Locks {
+    let toggled = |vk| unsafe { GetKeyState(vk) } & 1 != 0;
 
-pub fn get_fg_window() -> HWND {
-    unsafe { GetForegroundWindow() }
+    Locks {
+        caps_lock: toggled(VK_CAPITAL),
+        num_lock: toggled(VK_NUMLOCK),
+        scroll_lock: toggled(VK_SCROLL),
+    }
+}
+
+/// What pressing `vk` types in the layout `window` uses, with `modifiers`
+/// held: a dead key, or UTF-16 units of text.
+pub fn translate(vk: u32, scan: u32, window: usize, modifiers: &ModifierState) -> Vec<Event> {
+    let held = modifiers.held;
+    let mut kb_state: [BYTE; 256] = [0; 256];
+    // AltGr is control and right alt as far as layouts are concerned.
+    let pressed = [
+        (held.left_shift, &[VK_SHIFT, VK_LSHIFT][..]),
+        (held.right_shift, &[VK_SHIFT, VK_RSHIFT][..]),
+        (held.left_control, &[VK_CONTROL, VK_LCONTROL][..]),
+        (held.right_control, &[VK_CONTROL, VK_RCONTROL][..]),
+        (held.left_alt, &[VK_MENU, VK_LMENU][..]),
+        (held.right_alt, &[VK_MENU, VK_RMENU][..]),
+        (held.alt_gr, &[VK_CONTROL, VK_LCONTROL, VK_MENU, VK_RMENU][..]),
+    ];
+
+    for &(_, vks) in pressed.iter().filter(|(held, _)| *held) {
+        for &vk in vks {
+            kb_state[vk as usize] = 0x80;
+        }
+    }
+
+    kb_state[VK_CAPITAL as usize] = modifiers.locks.caps_lock as BYTE;
+    kb_state[VK_NUMLOCK as usize] = modifiers.locks.num_lock as BYTE;
+
+    let mut buffer = [0; 10];
+
+    // Each thread has a layout of its own, 0 is the processing thread's.
+    // Translating a dead key normally stores it in the keyboard state,
+    // taking it from the focused application. The flag leaves the state
+    // alone, the sequence is composed by the processor instead.
+    let written = unsafe {
+        let layout = GetKeyboardLayout(GetWindowThreadProcessId(window as HWND, ptr::null_mut()));
+
+        ToUnicodeEx(
+            vk,
+            scan,
+            kb_state.as_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len() as i32,
+            TOUNICODE_KEEP_KEYBOARD_STATE,
+            layout,
+        )
+    };
+
+    match written {
+        // A dead key, the buffer holds its spacing accent.
+        -1 => char::from_u32(u32::from(buffer[0]))
+            .map(Event::DeadKey)
+            .into_iter()
+            .collect(),
+        n if n > 0 => buffer[..n as usize]
+            .iter()
+            .map(|&unit| Event::Utf16(unit))
+            .collect(),
+        _ => vec![],
+    }
 }
 
 pub struct Hook {
     keyboard_hook_id: HHOOK,
-    callback: Box<FnMut(Event)>,
-    char_iter: BufferedUtf16Iterator,
+    mouse_hook_id: HHOOK,
+    callback: Box<dyn FnMut(Input)>,
+    /// Right alt is acting as AltGr, see `Hook::key_from_hook`.
+    alt_gr: bool,
 }
 
-thread_local!(static HOOK: RefCell<Option<Hook>> = RefCell::new(None));
+/// Hooks are given the tick count, in milliseconds, at which the event happened.
+fn event_time(ticks: DWORD) -> Timestamp {
+    let age = unsafe { GetTickCount() }.wrapping_sub(ticks);
+    Timestamp::ago(Duration::from_millis(u64::from(age)))
+}
+
+/// Bit 2 of `ToUnicodeEx`'s flags, available since Windows 10 1607.
+const TOUNICODE_KEEP_KEYBOARD_STATE: UINT = 1 << 2;
 
-const FORCE_KB_STATE_KEYS: [i32; 4] = [
-    winuser::VK_SHIFT,
-    winuser::VK_LSHIFT,
-    winuser::VK_CAPITAL,
-    winuser::VK_RSHIFT,
-];
+thread_local!(static HOOK: RefCell<Option<Hook>> = RefCell::new(None));
 
 unsafe extern "system" fn wh_keyboard_callback(code: i32, w_param: usize, l_param: isize) -> isize {
     HOOK.with(|hook| {
         let mut borrowed = hook.borrow_mut();
         let hook = borrowed.as_mut().expect("Hook should be initialized.");
-        let callback = &mut hook.callback;
-
         let kb_hook: KBDLLHOOKSTRUCT = mem::transmute(*(l_param as *const KBDLLHOOKSTRUCT));
+        let message = w_param as UINT;
+        let key = hook.key_from_hook(&kb_hook, message == WM_KEYDOWN || message == WM_SYSKEYDOWN);
+        let injected = kb_hook.flags & LLKHF_INJECTED != 0;
+        let time = event_time(kb_hook.time);
+        let window = get_fg_window();
+        let callback = &mut hook.callback;
+        // Low level hooks don't say which device the input came from.
+        let mut callback = |event: Event| {
+            callback(Input {
+                event,
+                device: None,
+                injected,
+                time,
+                window,
+            })
+        };
 
-        match w_param as UINT {
-            WM_KEYUP => if let Some(sc) = VK_SCANCODE_MAPPING[kb_hook.vkCode as usize] {
+        match message {
+            WM_KEYUP | WM_SYSKEYUP => if let Some(key) = key {
                 callback(Event::Key {
-                    scancode: sc,
+                    key,
                     key_state: KeyState::Released,
                 })
             },
+            // Alt, and keys pressed while it is held, don't type characters.
+            WM_SYSKEYDOWN => if let Some(key) = key {
+                callback(Event::Key {
+                    key,
+                    key_state: KeyState::Pressed,
+                })
+            },
+            // Unicode injected with SendInput, one UTF-16 unit per event.
+            WM_KEYDOWN if kb_hook.vkCode == VK_PACKET as u32 => {
+                callback(Event::Utf16(kb_hook.scanCode as u16))
+            }
             WM_KEYDOWN => {
-                if let Some(sc) = VK_SCANCODE_MAPPING[kb_hook.vkCode as usize] {
+                if let Some(key) = key {
                     callback(Event::Key {
-                        scancode: sc,
+                        key,
                         key_state: KeyState::Pressed,
                     })
                 }
 
-                let mut buffer = [0; 10];
-                let mut kb_state: [BYTE; 256] = [0; 256];
-
-                GetKeyboardState(kb_state.as_mut_ptr()); // This does not work properly for SHIFT and others
-
-                for i in 0..FORCE_KB_STATE_KEYS.len() {
-                    kb_state[FORCE_KB_STATE_KEYS[i] as usize] =
-                        GetKeyState(FORCE_KB_STATE_KEYS[i]) as u8;
-                }
-
-                match ToUnicode(
-                    kb_hook.vkCode,
-                    kb_hook.scanCode,
-                    kb_state.as_mut_ptr(),
-                    buffer.as_mut_ptr(),
-                    buffer.len() as i32,
-                    0,
-                ) {
-                    -1 => (), // Dead key
-                    0 => (),  // No char
-                    n => {
-                        // n chars written to buffer
-                        for i in 0..n as usize {
-                            hook.char_iter.push_u16(buffer[i]);
-                        }
-
-                        while let Some(c) = hook.char_iter.next() {
-                            callback(Event::Char(c.unwrap_or(REPLACEMENT_CHARACTER)));
-                        }
-                    }
-                };
+                // Typed in the focused window's layout by the processor.
+                callback(Event::Untranslated {
+                    vk: kb_hook.vkCode,
+                    scan: kb_hook.scanCode,
+                });
             }
             _ => (),
         }
@@ -98,8 +192,92 @@ unsafe extern "system" fn wh_keyboard_callback(code: i32, w_param: usize, l_para
     })
 }
 
+unsafe extern "system" fn wh_mouse_callback(code: i32, w_param: usize, l_param: isize) -> isize {
+    HOOK.with(|hook| {
+        let mut borrowed = hook.borrow_mut();
+        let hook = borrowed.as_mut().expect("Hook should be initialized.");
+        let mouse_hook = &*(l_param as *const MSLLHOOKSTRUCT);
+        // Signed for the wheel, button number for the side buttons.
+        let data = HIWORD(mouse_hook.mouseData);
+        let injected = mouse_hook.flags & LLMHF_INJECTED != 0;
+        let callback = &mut hook.callback;
+
+        let button = |button, key_state| Event::MouseButton { button, key_state };
+        let side_button = if data == XBUTTON1 {
+            MouseButton::Back
+        } else {
+            MouseButton::Forward
+        };
+
+        let event = match w_param as UINT {
+            WM_LBUTTONDOWN => Some(button(MouseButton::Left, KeyState::Pressed)),
+            WM_LBUTTONUP => Some(button(MouseButton::Left, KeyState::Released)),
+            WM_RBUTTONDOWN => Some(button(MouseButton::Right, KeyState::Pressed)),
+            WM_RBUTTONUP => Some(button(MouseButton::Right, KeyState::Released)),
+            WM_MBUTTONDOWN => Some(button(MouseButton::Middle, KeyState::Pressed)),
+            WM_MBUTTONUP => Some(button(MouseButton::Middle, KeyState::Released)),
+            WM_XBUTTONDOWN => Some(button(side_button, KeyState::Pressed)),
+            WM_XBUTTONUP => Some(button(side_button, KeyState::Released)),
+            WM_MOUSEWHEEL => Some(Event::Scroll {
+                dx: 0.0,
+                dy: f32::from(data as i16) / WHEEL_DELTA as f32,
+            }),
+            WM_MOUSEHWHEEL => Some(Event::Scroll {
+                dx: f32::from(data as i16) / WHEEL_DELTA as f32,
+                dy: 0.0,
+            }),
+            WM_MOUSEMOVE => Some(Event::MouseMove {
+                x: mouse_hook.pt.x,
+                y: mouse_hook.pt.y,
+            }),
+            _ => None,
+        };
+
+        if let Some(event) = event {
+            callback(Input {
+                event,
+                device: None,
+                injected,
+                time: event_time(mouse_hook.time),
+                window: get_fg_window(),
+            });
+        }
+
+        CallNextHookEx(hook.mouse_hook_id, code, w_param, l_param)
+    })
+}
+
 impl Hook {
-    pub fn run_forever(callback: impl FnMut(Event) + 'static) -> Result<(), Error> {
+    fn key_from_hook(&mut self, kb_hook: &KBDLLHOOKSTRUCT, pressed: bool) -> Option<Key> {
+        let vk = kb_hook.vkCode as i32;
+
+        // Layouts with AltGr send a fake left control, with this scancode,
+        // ahead of right alt. It is dropped and right alt reported as AltGr.
+        if vk == VK_LCONTROL && kb_hook.scanCode == 0x21D {
+            if pressed {
+                self.alt_gr = true;
+            }
+
+            return None;
+        }
+
+        if vk == VK_RMENU && self.alt_gr {
+            if !pressed {
+                self.alt_gr = false;
+            }
+
+            return Some(Key::AltGr);
+        }
+
+        // The keypad enter key reports the same virtual key as the main one.
+        if vk == VK_RETURN && kb_hook.flags & LLKHF_EXTENDED != 0 {
+            return Some(Key::KeypadEnter);
+        }
+
+        Key::from_vk(kb_hook.vkCode as u16)
+    }
+
+    pub fn run_forever(callback: impl FnMut(Input) + 'static) -> Result<(), Error> {
         let key_hook_id = unsafe {
             SetWindowsHookExW(
                 WH_KEYBOARD_LL,
@@ -113,11 +291,24 @@ impl Hook {
             return Err(Error::last_os_error());
         }
 
+        let mouse_hook_id = unsafe {
+            SetWindowsHookExW(WH_MOUSE_LL, Some(wh_mouse_callback), ptr::null_mut(), 0)
+        };
+
+        if mouse_hook_id == ptr::null_mut() {
+            let e = Error::last_os_error();
+            unsafe {
+                UnhookWindowsHookEx(key_hook_id);
+            }
+            return Err(e);
+        }
+
         HOOK.with(move |hook| {
             *hook.borrow_mut() = Some(Hook {
                 keyboard_hook_id: key_hook_id,
+                mouse_hook_id,
                 callback: Box::new(callback),
-                char_iter: BufferedUtf16Iterator::new(),
+                alt_gr: false,
             });
         });
 
@@ -130,7 +321,7 @@ impl Hook {
                 break;
             } else if ret < 0 {
                 // FIXME: GetLastError?
-                println!("Message loop error {}", ret);
+                eprintln!("Message loop error {}", ret);
                 break;
             } else {
                 break;
@@ -139,292 +330,9 @@ impl Hook {
 
         unsafe {
             UnhookWindowsHookEx(key_hook_id);
+            UnhookWindowsHookEx(mouse_hook_id);
         }
 
         Ok(())
     }
 }
-
-struct BufferedUtf16Iterator {
-    buffer: VecDeque<u16>,
-    decoding_buf: Option<u16>,
-}
-
-impl BufferedUtf16Iterator {
-    fn new() -> Self {
-        Self {
-            buffer: VecDeque::new(),
-            decoding_buf: None,
-        }
-    }
-
-    fn push_u16(&mut self, item: u16) {
-        self.buffer.push_back(item)
-    }
-}
-
-struct DecodeUtf16Error {
-    code: u16,
-}
-
-impl Iterator for BufferedUtf16Iterator {
-    type Item = Result<char, DecodeUtf16Error>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let u = match self.decoding_buf.take() {
-            Some(buf) => buf,
-            None => self.buffer.pop_front()?,
-        };
-
-        if u < 0xD800 || 0xDFFF < u {
-            // not a surrogate
-            Some(Ok(unsafe { from_u32_unchecked(u as u32) }))
-        } else if u >= 0xDC00 {
-            // a trailing surrogate
-            Some(Err(DecodeUtf16Error { code: u }))
-        } else {
-            let u2 = match self.buffer.pop_front() {
-                Some(u2) => u2,
-                // eof, should wait for next u16 instead of err'ing?
-                None => return Some(Err(DecodeUtf16Error { code: u })),
-            };
-            if u2 < 0xDC00 || u2 > 0xDFFF {
-                // not a trailing surrogate so we're not a valid
-                // surrogate pair, so rewind to redecode u2 next time.
-                self.decoding_buf = Some(u2);
-                return Some(Err(DecodeUtf16Error { code: u }));
-            }
-
-            // all ok, so lets decode it.
-            let c = (((u - 0xD800) as u32) << 10 | (u2 - 0xDC00) as u32) + 0x1_0000;
-            Some(Ok(unsafe { from_u32_unchecked(c) }))
-        }
-    }
-}
-
-lazy_static! {
-    static ref VK_SCANCODE_MAPPING: [Option<Scancode>; 256] = {
-        let mut codes = vec![
-            None,                        // Not in spec
-            None,                        // VK_LBUTTON,
-            None,                        // VK_RBUTTON,
-            None,                        // VK_CANCEL,
-            None,                        // VK_MBUTTON,
-            None,                        // VK_XBUTTON1,
-            None,                        // VK_XBUTTON2,
-            None,                        // Undefined
-            Some(Scancode::Backspace),   // VK_BACK,
-            Some(Scancode::Tab),         // VK_TAB,
-            None,                        // Reserved
-            None,                        // Reserved
-            None,                        // VK_CLEAR,
-            Some(Scancode::Enter),       // VK_RETURN
-            None,                        // Undefined
-            None,                        // Undefined
-            None,                        // VK_SHIFT
-            None,                        // VK_CONTROL
-            None,                        // VK_MENU
-            Some(Scancode::Pause),       // VK_PAUSE
-            Some(Scancode::CapsLock),    // VK_CAPITAL
-            None,                        // VK_KANA | VK_HANGUEL | VK_HANGUL
-            None,                        // Undefined
-            None,                        // VK_JUNJA
-            None,                        // VK_FINAL
-            None,                        // VK_HANJA | VK_KANJI
-            None,                        // Undefined
-            Some(Scancode::Escape),      // VK_ESCAPE
-            None,                        // VK_CONVERT
-            None,                        // VK_NONCONVERT
-            None,                        // VK_ACCEPT
-            None,                        // VK_MODECHANGE
-            Some(Scancode::Space),       // VK_SPACE
-            Some(Scancode::PageUp),      // VK_PRIOR
-            Some(Scancode::PageDown),    // VK_NEXT
-            Some(Scancode::End),         // VK_END
-            Some(Scancode::Home),        // VK_HOME
-            Some(Scancode::Left),        // VK_LEFT
-            Some(Scancode::Up),          // VK_UP
-            Some(Scancode::Right),       // VK_RIGHT
-            Some(Scancode::Down),        // VK_DOWN
-            None,                        // VK_SELECT
-            Some(Scancode::PrintScreen), // VK_PRINT
-            None,                        // VK_EXECUTE
-            None,                        // VK_SNAPSHOT
-            Some(Scancode::Insert),      // VK_INSERT
-            Some(Scancode::Delete),      // VK_DELETE
-            None,                        // VK_HELP
-            Some(Scancode::Num0),        // 0 key
-            Some(Scancode::Num1),        // 1 key
-            Some(Scancode::Num2),        // 2 key
-            Some(Scancode::Num3),        // 3 key
-            Some(Scancode::Num4),        // 4 key
-            Some(Scancode::Num5),        // 5 key
-            Some(Scancode::Num6),        // 6 key
-            Some(Scancode::Num7),        // 7 key
-            Some(Scancode::Num8),        // 8 key
-            Some(Scancode::Num9),        // 9 key
-        ];
-
-        codes.extend(vec![None; 7]); // Undefined
-        codes.extend(vec![
-            Some(Scancode::A),           // A key
-            Some(Scancode::B),           // B key
-            Some(Scancode::C),           // C key
-            Some(Scancode::D),           // D key
-            Some(Scancode::E),           // E key
-            Some(Scancode::F),           // F key
-            Some(Scancode::G),           // G key
-            Some(Scancode::H),           // H key
-            Some(Scancode::I),           // I key
-            Some(Scancode::J),           // J key
-            Some(Scancode::K),           // K key
-            Some(Scancode::L),           // L key
-            Some(Scancode::M),           // M key
-            Some(Scancode::N),           // N key
-            Some(Scancode::O),           // O key
-            Some(Scancode::P),           // P key
-            Some(Scancode::Q),           // Q key
-            Some(Scancode::R),           // R key
-            Some(Scancode::S),           // S key
-            Some(Scancode::T),           // T key
-            Some(Scancode::U),           // U key
-            Some(Scancode::V),           // V key
-            Some(Scancode::W),           // W key
-            Some(Scancode::X),           // X key
-            Some(Scancode::Y),           // Y key
-            Some(Scancode::Z),           // Z key
-            None,                        // VK_LWIN
-            None,                        // VK_RWIN
-            None,                        // VK_APPS
-            None,                        // Reserved
-            None,                        // VK_SLEEP
-            Some(Scancode::Pad0),        // VK_NUMPAD0
-            Some(Scancode::Pad1),        // VK_NUMPAD1
-            Some(Scancode::Pad2),        // VK_NUMPAD2
-            Some(Scancode::Pad3),        // VK_NUMPAD3
-            Some(Scancode::Pad4),        // VK_NUMPAD4
-            Some(Scancode::Pad5),        // VK_NUMPAD5
-            Some(Scancode::Pad6),        // VK_NUMPAD6
-            Some(Scancode::Pad7),        // VK_NUMPAD7
-            Some(Scancode::Pad8),        // VK_NUMPAD8
-            Some(Scancode::Pad9),        // VK_NUMPAD9
-            Some(Scancode::PadMultiply), // VK_MULTIPLY
-            Some(Scancode::PadPlus),     // VK_ADD
-            None,                        // VK_SEPARATOR
-            Some(Scancode::PadMinus),    // VK_SUBTRACT
-            Some(Scancode::PadDecimal),  // VK_DECIMAL
-            Some(Scancode::PadDivide),   // VK_DIVIDE
-            Some(Scancode::F1),          // VK_F1
-            Some(Scancode::F2),          // VK_F2
-            Some(Scancode::F3),          // VK_F3
-            Some(Scancode::F4),          // VK_F4
-            Some(Scancode::F5),          // VK_F5
-            Some(Scancode::F6),          // VK_F6
-            Some(Scancode::F7),          // VK_F7
-            Some(Scancode::F8),          // VK_F8
-            Some(Scancode::F9),          // VK_F9
-            Some(Scancode::F10),         // VK_F10
-            Some(Scancode::F11),         // VK_F11
-            Some(Scancode::F12),         // VK_F12
-            None,                        // VK_F13
-            None,                        // VK_F14
-            None,                        // VK_F15
-            None,                        // VK_F16
-            None,                        // VK_F17
-            None,                        // VK_F18
-            None,                        // VK_F19
-            None,                        // VK_F20
-            None,                        // VK_F21
-            None,                        // VK_F22
-            None,                        // VK_F23
-            None,                        // VK_F24
-        ]);
-
-        codes.extend(vec![None; 8]); // Unassigned
-        codes.extend(vec![
-            Some(Scancode::NumLock),    // VK_NUMLOCK
-            Some(Scancode::ScrollLock), // VK_SCROLL
-        ]);
-
-        codes.extend(vec![None; 5]); // OEM specific x 4
-        codes.extend(vec![None; 9]); // Unassigned x 6
-
-        codes.extend(vec![
-            Some(Scancode::LeftShift),    // VK_LSHIFT
-            Some(Scancode::RightShift),   // VK_RSHIFT
-            Some(Scancode::LeftControl),  // VK_LCONTROL
-            Some(Scancode::RightControl), // VK_RCONTROL
-            Some(Scancode::LeftAlt),      // VK_LMENU
-            Some(Scancode::RightAlt),     // VK_RMENU
-            None,                         // VK_BROWSER_BACK
-            None,                         // VK_BROWSER_FORWARD
-            None,                         // VK_BROWSER_REFRESH
-            None,                         // VK_BROWSER_STOP
-            None,                         // VK_BROWSER_SEARCH
-            None,                         // VK_BROWSER_FAVORITES
-            None,                         // VK_BROWSER_HOME
-            None,                         // VK_VOLUME_MUTE
-            None,                         // VK_VOLUME_DOWN
-            None,                         // VK_VOLUME_UP
-            None,                         // VK_MEDIA_NEXT_TRACK
-            None,                         // VK_MEDIA_PREV_TRACK
-            None,                         // VK_MEDIA_STOP
-            None,                         // VK_MEDIA_PLAY_PAUSE
-            None,                         // VK_LAUNCH_MAIL
-            None,                         // VK_LAUNCH_MEDIA_SELECT
-            None,                         // VK_LAUNCH_APP1
-            None,                         // VK_LAUNCH_APP2
-            None,                         // Reserved
-            None,                         // Reserved
-            Some(Scancode::Semicolon),    // VK_OEM_1
-            Some(Scancode::Equals),       // VK_OEM_PLUS
-            Some(Scancode::Comma),        // VK_OEM_COMMA
-            Some(Scancode::Minus),        // VK_OEM_MINUS
-            Some(Scancode::Period),       // VK_OEM_PERIOD
-            Some(Scancode::Slash),        // VK_OEM_2
-            Some(Scancode::Grave),        // VK_OEM_3
-        ]);
-
-        codes.extend(vec![None; 23]); // Reserved
-        codes.extend(vec![None; 3]);  // Unassigned
-
-        codes.extend(vec![
-            Some(Scancode::LeftBracket),  // VK_OEM_4
-            Some(Scancode::Backslash),    // VK_OEM_5
-            Some(Scancode::RightBracket), // VK_OEM_6
-            Some(Scancode::Apostrophe),   // VK_OEM_7
-            None,                         // VK_OEM_8
-            None,                         // Reserved
-            None,                         // OEM Specific
-            None,                         // VK_OEM_102
-            None,                         // OEM specific
-            None,                         // OEM specific
-            None,                         // VK_PROCESSKEY
-            None,                         // OEM specific
-            None,                         // VK_PACKET
-            None,                         // Unassigned
-        ]);
-
-        codes.extend(vec![None; 13]); // OEM specific
-        codes.extend(vec![
-            None, // VK_ATTN
-            None, // VK_CRSEL
-            None, // VK_EXSEL
-            None, // VK_EREOF
-            None, // VK_PLAY
-            None, // VK_ZOOM
-            None, // VK_NONAME
-            None, // VK_PA1
-            None, // VK_OEM_CLEAR
-        ]);
-
-        assert!(codes.len() == 0xff);
-
-        let mut result = [None; 256];
-        for (i, code) in codes.iter_mut().enumerate() {
-            result[i] = *code;
-        }
-
-        result
-    };
-}
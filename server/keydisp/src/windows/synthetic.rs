@@ -0,0 +1,55 @@
+//! Made up typing, to measure keydisp itself rather than a keyboard.
+//!
+//! Produces what the low level keyboard hook would for a keyboard typing
+//! the alphabet over and over: each keystroke is a press, its virtual key
+//! and scan code left for the processor to translate, and a release, all
+//! stamped when they are handed over.
+
+use crate::common::{Event, Input, Key, KeyState, Timestamp};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use winapi::um::winuser::{MapVirtualKeyW, MAPVK_VK_TO_VSC};
+
+/// Types `keystrokes` keys, one every `interval`, on the calling thread.
+pub fn run(keystrokes: usize, interval: Duration, mut callback: impl FnMut(Input)) {
+    let start = Instant::now();
+    // Letters have the virtual keys of their upper case ASCII codes.
+    let letters: Vec<(Key, u32, u32)> = (b'A'..=b'Z')
+        .filter_map(|vk| {
+            let key = Key::from_vk(u16::from(vk))?;
+            let scan = unsafe { MapVirtualKeyW(u32::from(vk), MAPVK_VK_TO_VSC) };
+            Some((key, u32::from(vk), scan))
+        })
+        .collect();
+
+    for (i, &(key, vk, scan)) in letters.iter().cycle().take(keystrokes).enumerate() {
+        // Paced from the start, so time spent in the callback isn't added.
+        if let Some(wait) = (start + interval * i as u32).checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+
+        let events = [
+            Event::Key {
+                key,
+                key_state: KeyState::Pressed,
+            },
+            Event::Untranslated { vk, scan },
+            Event::Key {
+                key,
+                key_state: KeyState::Released,
+            },
+        ];
+
+        for &event in &events {
+            callback(Input {
+                event,
+                device: None,
+                injected: false,
+                time: Timestamp::now(),
+                window: super::get_fg_window(),
+            });
+        }
+    }
+}